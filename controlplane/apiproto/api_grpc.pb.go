@@ -19,6 +19,7 @@ const _ = grpc.SupportPackageIsVersion7
 // For semantics around ctx use and closing/ending streaming RPCs, please refer to https://pkg.go.dev/google.golang.org/grpc/?tab=doc#ClientConn.NewStream.
 type InspektorClient interface {
 	Auth(ctx context.Context, in *AuthRequest, opts ...grpc.CallOption) (*AuthResponse, error)
+	GetCredential(ctx context.Context, in *CredentialRequest, opts ...grpc.CallOption) (*CredentialResponse, error)
 	Policy(ctx context.Context, in *Empty, opts ...grpc.CallOption) (Inspektor_PolicyClient, error)
 	GetDataSource(ctx context.Context, in *Empty, opts ...grpc.CallOption) (*DataSourceResponse, error)
 	SendMetrics(ctx context.Context, in *MetricsRequest, opts ...grpc.CallOption) (*Empty, error)
@@ -42,6 +43,15 @@ func (c *inspektorClient) Auth(ctx context.Context, in *AuthRequest, opts ...grp
 	return out, nil
 }
 
+func (c *inspektorClient) GetCredential(ctx context.Context, in *CredentialRequest, opts ...grpc.CallOption) (*CredentialResponse, error) {
+	out := new(CredentialResponse)
+	err := c.cc.Invoke(ctx, "/api.Inspektor/GetCredential", in, out, opts...)
+	if err != nil {
+		return nil, err
+	}
+	return out, nil
+}
+
 func (c *inspektorClient) Policy(ctx context.Context, in *Empty, opts ...grpc.CallOption) (Inspektor_PolicyClient, error) {
 	stream, err := c.cc.NewStream(ctx, &Inspektor_ServiceDesc.Streams[0], "/api.Inspektor/Policy", opts...)
 	if err != nil {
@@ -106,6 +116,7 @@ func (c *inspektorClient) GetIntegrationConfig(ctx context.Context, in *Empty, o
 // for forward compatibility
 type InspektorServer interface {
 	Auth(context.Context, *AuthRequest) (*AuthResponse, error)
+	GetCredential(context.Context, *CredentialRequest) (*CredentialResponse, error)
 	Policy(*Empty, Inspektor_PolicyServer) error
 	GetDataSource(context.Context, *Empty) (*DataSourceResponse, error)
 	SendMetrics(context.Context, *MetricsRequest) (*Empty, error)
@@ -120,6 +131,9 @@ type UnimplementedInspektorServer struct {
 func (UnimplementedInspektorServer) Auth(context.Context, *AuthRequest) (*AuthResponse, error) {
 	return nil, status.Errorf(codes.Unimplemented, "method Auth not implemented")
 }
+func (UnimplementedInspektorServer) GetCredential(context.Context, *CredentialRequest) (*CredentialResponse, error) {
+	return nil, status.Errorf(codes.Unimplemented, "method GetCredential not implemented")
+}
 func (UnimplementedInspektorServer) Policy(*Empty, Inspektor_PolicyServer) error {
 	return status.Errorf(codes.Unimplemented, "method Policy not implemented")
 }
@@ -163,6 +177,24 @@ func _Inspektor_Auth_Handler(srv interface{}, ctx context.Context, dec func(inte
 	return interceptor(ctx, in, info, handler)
 }
 
+func _Inspektor_GetCredential_Handler(srv interface{}, ctx context.Context, dec func(interface{}) error, interceptor grpc.UnaryServerInterceptor) (interface{}, error) {
+	in := new(CredentialRequest)
+	if err := dec(in); err != nil {
+		return nil, err
+	}
+	if interceptor == nil {
+		return srv.(InspektorServer).GetCredential(ctx, in)
+	}
+	info := &grpc.UnaryServerInfo{
+		Server:     srv,
+		FullMethod: "/api.Inspektor/GetCredential",
+	}
+	handler := func(ctx context.Context, req interface{}) (interface{}, error) {
+		return srv.(InspektorServer).GetCredential(ctx, req.(*CredentialRequest))
+	}
+	return interceptor(ctx, in, info, handler)
+}
+
 func _Inspektor_Policy_Handler(srv interface{}, stream grpc.ServerStream) error {
 	m := new(Empty)
 	if err := stream.RecvMsg(m); err != nil {
@@ -249,6 +281,10 @@ var Inspektor_ServiceDesc = grpc.ServiceDesc{
 			MethodName: "Auth",
 			Handler:    _Inspektor_Auth_Handler,
 		},
+		{
+			MethodName: "GetCredential",
+			Handler:    _Inspektor_GetCredential_Handler,
+		},
 		{
 			MethodName: "GetDataSource",
 			Handler:    _Inspektor_GetDataSource_Handler,
@@ -17,7 +17,10 @@ import (
 
 	"go.uber.org/zap"
 	"google.golang.org/grpc"
+	"google.golang.org/grpc/codes"
 	"google.golang.org/grpc/metadata"
+	"google.golang.org/grpc/status"
+	"gorm.io/gorm"
 )
 
 type CtxKey string
@@ -51,19 +54,9 @@ func (r *RpcServer) Auth(ctx context.Context, req *apiproto.AuthRequest) (*apipr
 		return nil, err
 	}
 	session.UnmarshalMeta()
-	expiresAt := int64(0)
-	roles := []string{}
-	// retrive roles from the meta if the session is temporary session.
-	if session.SessionMeta.ExpiresAt != 0 || session.SessionMeta.ServiceAccountName != "" {
-		expiresAt = session.SessionMeta.ExpiresAt
-		roles = session.SessionMeta.TempRoles
-	} else {
-		var err error
-		roles, err = r.store.GetRolesForObjectID(session.UserID, models.UserType)
-		if err != nil {
-			utils.Logger.Error("error while retriving roles", zap.String("err_msg", err.Error()))
-			return nil, err
-		}
+	roles, expiresAt, err := r.getSessionRoles(session)
+	if err != nil {
+		return nil, err
 	}
 	return &apiproto.AuthResponse{
 		Groups:      roles,
@@ -72,6 +65,54 @@ func (r *RpcServer) Auth(ctx context.Context, req *apiproto.AuthRequest) (*apipr
 	}, nil
 }
 
+// GetCredential returns the password verifiers of the given postgres user, so that
+// the dataplane can authenticate the user with SCRAM-SHA-256 or md5.
+func (r *RpcServer) GetCredential(ctx context.Context, req *apiproto.CredentialRequest) (*apiproto.CredentialResponse, error) {
+	dataSource, ok := ctx.Value(DataSource).(*models.DataSource)
+	if !ok {
+		return nil, errors.New("unable to find the datasource")
+	}
+	session, err := r.store.GetSessionForUsername(dataSource.ID, req.UserName)
+	if err != nil {
+		if err == gorm.ErrRecordNotFound {
+			return nil, status.Error(codes.NotFound, "credential not found")
+		}
+		utils.Logger.Error("error while retriving session for credential", zap.String("err_msg", err.Error()))
+		return nil, err
+	}
+	session.UnmarshalMeta()
+	roles, expiresAt, err := r.getSessionRoles(session)
+	if err != nil {
+		return nil, err
+	}
+	verifier, err := scramVerifier(session.SessionMeta.PostgresPassword)
+	if err != nil {
+		utils.Logger.Error("error while deriving scram verifier", zap.String("err_msg", err.Error()))
+		return nil, err
+	}
+	return &apiproto.CredentialResponse{
+		ScramVerifier: verifier,
+		Md5Hash:       md5Hash(session.SessionMeta.PostgresUsername, session.SessionMeta.PostgresPassword),
+		Groups:        roles,
+		ExpiresAt:     expiresAt,
+		Passthrough:   session.SessionMeta.Passthrough,
+	}, nil
+}
+
+// getSessionRoles returns the roles and the expiry of the given session.
+func (r *RpcServer) getSessionRoles(session *models.Session) ([]string, int64, error) {
+	// retrive roles from the meta if the session is temporary session.
+	if session.SessionMeta.ExpiresAt != 0 || session.SessionMeta.ServiceAccountName != "" {
+		return session.SessionMeta.TempRoles, session.SessionMeta.ExpiresAt, nil
+	}
+	roles, err := r.store.GetRolesForObjectID(session.UserID, models.UserType)
+	if err != nil {
+		utils.Logger.Error("error while retriving roles", zap.String("err_msg", err.Error()))
+		return nil, 0, err
+	}
+	return roles, 0, nil
+}
+
 func (r *RpcServer) Policy(req *apiproto.Empty, stream apiproto.Inspektor_PolicyServer) error {
 	sendPolicy := func() error {
 		byteCode, err := r.policy.GetPolicy()
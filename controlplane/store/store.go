@@ -238,6 +238,12 @@ func (s *Store) GetSessionForAuth(objectID uint, username string, password strin
 	return session, err
 }
 
+func (s *Store) GetSessionForUsername(objectID uint, username string) (*models.Session, error) {
+	session := &models.Session{}
+	err := s.db.Model(&models.Session{}).Where("object_id = ? AND meta->>'postgresUsername' = ?", objectID, username).First(session).Error
+	return session, err
+}
+
 func (s *Store) CreateSessionForUser(userID uint, datasourceID uint, passthrough bool) error {
 	return s.db.Transaction(func(tx *gorm.DB) error {
 		var count int64
@@ -20,6 +20,7 @@ use crate::auditlog::stdout::StdOutLogs;
 use async_trait::async_trait;
 use std::thread;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 #[async_trait]
 pub trait AuditLog {
@@ -28,16 +29,18 @@ pub trait AuditLog {
 
 /// start_audit_worker will start the audit log worker. it listens for audit logs from
 /// data source driver and push the audit logs to the configured audit log destination.
-pub async fn start_audit_worker(cfg: IntegrationConfigResponse) -> mpsc::Sender<String> {
+/// worker exits once all the senders are dropped and the pending audit logs are pushed.
+pub async fn start_audit_worker(
+    cfg: IntegrationConfigResponse,
+) -> (mpsc::Sender<String>, JoinHandle<()>) {
     // mpsc channels are used communicate between audit worker and from the
     // audit producer.
     let (tx, mut rx) = mpsc::channel(32);
 
-    tokio::spawn(async move {
+    let worker = tokio::spawn(async move {
         // retrive audit log client from the configuration.
         let mut audit_client = get_audit_client(cfg).await;
-        loop {
-            let log = rx.recv().await.unwrap();
+        while let Some(log) = rx.recv().await {
             if audit_client.is_none() {
                 continue;
             }
@@ -46,7 +49,7 @@ pub async fn start_audit_worker(cfg: IntegrationConfigResponse) -> mpsc::Sender<
             }
         }
     });
-    tx
+    (tx, worker)
 }
 
 /// get_audit_client retuns the respective audit log client based on the given
@@ -13,8 +13,35 @@
 // limitations under the License.
 
 use anyhow::anyhow;
+use openssl::rand::rand_bytes;
+use openssl::ssl::SslVersion;
 use serde::Deserialize;
 use serde_yaml;
+use std::time::Duration;
+
+// RESERVED_STARTUP_PARAMS are set by the proxy itself, so the client values are never
+// forwarded.
+pub const RESERVED_STARTUP_PARAMS: [&str; 4] =
+    ["user", "database", "client_encoding", "replication"];
+
+// BLOCKED_STARTUP_PARAMS escalates the privilege of the target session. connections
+// asking for them are refused.
+pub const BLOCKED_STARTUP_PARAMS: [&str; 2] = ["role", "session_authorization"];
+
+// MIN_MASK_HASH_SECRET_LEN is the minimum length of the hash secret, so that it can't be
+// guessed to find the hashed values.
+pub const MIN_MASK_HASH_SECRET_LEN: usize = 16;
+
+// DEFAULT_STARTUP_PARAMS are forwarded if the forwarded parameters are not configured.
+// options and search_path change how the queries are resolved on the target, so they
+// are forwarded only if configured explicitly.
+pub const DEFAULT_STARTUP_PARAMS: [&str; 5] = [
+    "application_name",
+    "datestyle",
+    "timezone",
+    "intervalstyle",
+    "extra_float_digits",
+];
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -65,6 +92,245 @@ pub struct PostgresConfig {
     pub target_password: Option<String>,
     pub target_port: Option<String>,
     pub proxy_listen_port: Option<String>,
+    // tls_cert_path and tls_key_path are used to terminate tls for the incoming
+    // client connections. tls is disabled if they are not set.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // tls_ca_path is the ca bundle used to verify the client certificates. the
+    // certificate is verified whenever the client presents one.
+    pub tls_ca_path: Option<String>,
+    // tls_min_version is the minimum tls protocol version accepted from the
+    // client. eg: TLSv1.2
+    pub tls_min_version: Option<String>,
+    // tls_ciphers is the openssl cipher list used for TLSv1.2 and below.
+    pub tls_ciphers: Option<String>,
+    // require_tls will refuse all the client connections which are not
+    // upgraded to tls.
+    pub require_tls: Option<bool>,
+    // target_sslmode is the libpq style sslmode used while connecting to the
+    // target postgres. eg: disable, prefer, require, verify-ca, verify-full
+    pub target_sslmode: Option<String>,
+    // target_ssl_root_cert is the ca bundle used to verify the target postgres
+    // certificate. system ca bundle is used if it's not set.
+    pub target_ssl_root_cert: Option<String>,
+    // target_ssl_server_name is the hostname used for SNI and hostname verification.
+    // host of the target_addr is used if it's not set.
+    pub target_ssl_server_name: Option<String>,
+    // client_auth_method is the authentication method used for the clients.
+    // eg: password, scram-sha-256, md5
+    pub client_auth_method: Option<String>,
+    // credential_store_path is the yaml file which contains the password verifiers
+    // of the users that are not managed by controlplane.
+    pub credential_store_path: Option<String>,
+    // client_cert_mapping_path is the yaml file which maps the client certificate
+    // subject to user name or groups.
+    pub client_cert_mapping_path: Option<String>,
+    // unsupported_sql decides whether the statements with sql constructs which can't be
+    // validated are refused (fail-closed) or forwarded (fail-open).
+    pub unsupported_sql: Option<String>,
+    // forwarded_startup_params are the startup parameters of the client which are
+    // forwarded to the target postgres. eg: application_name, search_path, options
+    pub forwarded_startup_params: Option<Vec<String>>,
+    // pool_mode decides how the target connections are reused across the client
+    // sessions. eg: none, session, transaction
+    pub pool_mode: Option<String>,
+    // pool_size is the maximum number of idle target connections kept for each
+    // database and startup parameters.
+    pub pool_size: Option<usize>,
+    // schema_change_channel is the channel which is listened for the schema change
+    // notifications sent by the event trigger of the target database.
+    pub schema_change_channel: Option<String>,
+    // shutdown_grace_period is the time in seconds given to the client sessions to
+    // finish on shutdown. remaining sessions are terminated after that.
+    pub shutdown_grace_period: Option<u64>,
+    // session_expiry_warning is the time in seconds before the session expiry at which
+    // the client is warned about the expiry. zero disables the warning.
+    pub session_expiry_warning: Option<u64>,
+    // idle_session_timeout is the time in seconds after which the session is terminated
+    // if it's idle outside of a transaction. zero disables the timeout.
+    pub idle_session_timeout: Option<u64>,
+    // idle_in_transaction_timeout is the time in seconds after which the session is
+    // terminated if it's idle in an open transaction. zero disables the timeout.
+    pub idle_in_transaction_timeout: Option<u64>,
+    // max_session_duration is the maximum lifetime of the session in seconds. zero
+    // disables the limit.
+    pub max_session_duration: Option<u64>,
+    // cert_session_ttl is the lifetime of the sessions authenticated with the client
+    // certificate in seconds. the controlplane expiry is used if it's earlier.
+    pub cert_session_ttl: Option<u64>,
+    // error_verbosity decides how much is told to the client about the rejected
+    // statements. eg: terse, default, verbose
+    pub error_verbosity: Option<String>,
+    // rewrite_notice decides how the user is notified about the queries rewritten by
+    // the policy. policy can override it for the groups. eg: none, summary, verbose
+    pub rewrite_notice: Option<String>,
+    // mask_hash_secret is the secret used to hash the values of the columns masked with
+    // the hash strategy. hashed values are same across the proxies and restarts only
+    // if it's configured. random secret is used otherwise.
+    pub mask_hash_secret: Option<String>,
+}
+
+// ClientAuthMethod tells how the clients are authenticated by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuthMethod {
+    // Password asks the client for clear text password and verifies it with
+    // the controlplane.
+    Password,
+    // ScramSha256 authenticates the client with the scram verifier of the user.
+    ScramSha256,
+    // Md5 is same as postgres md5 method. scram is used if the user have scram verifier,
+    // otherwise md5 is used for the legacy drivers.
+    Md5,
+    // Cert authenticates the client with the tls client certificate.
+    Cert,
+}
+
+impl ClientAuthMethod {
+    pub fn from_str(method: &str) -> Result<ClientAuthMethod, anyhow::Error> {
+        match method {
+            "password" => Ok(ClientAuthMethod::Password),
+            "scram-sha-256" => Ok(ClientAuthMethod::ScramSha256),
+            "md5" => Ok(ClientAuthMethod::Md5),
+            "cert" => Ok(ClientAuthMethod::Cert),
+            _ => Err(anyhow!(
+                "unsupported client_auth_method {}. expected one of password, scram-sha-256, md5, cert",
+                method
+            )),
+        }
+    }
+}
+
+// UnsupportedSqlMode tells what to do with the statements which have sql constructs that
+// the query rewriter can't validate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnsupportedSqlMode {
+    FailClosed,
+    FailOpen,
+}
+
+impl UnsupportedSqlMode {
+    pub fn from_str(mode: &str) -> Result<UnsupportedSqlMode, anyhow::Error> {
+        match mode {
+            "fail-closed" => Ok(UnsupportedSqlMode::FailClosed),
+            "fail-open" => Ok(UnsupportedSqlMode::FailOpen),
+            _ => Err(anyhow!(
+                "unsupported unsupported_sql mode {}. expected one of fail-closed, fail-open",
+                mode
+            )),
+        }
+    }
+}
+
+// PoolMode tells when the target connection is returned to the pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolMode {
+    // None opens a new target connection for every client session.
+    None,
+    // Session returns the target connection when the client session ends.
+    Session,
+    // Transaction returns the target connection at the end of every transaction.
+    Transaction,
+}
+
+impl PoolMode {
+    pub fn from_str(mode: &str) -> Result<PoolMode, anyhow::Error> {
+        match mode {
+            "none" => Ok(PoolMode::None),
+            "session" => Ok(PoolMode::Session),
+            "transaction" => Ok(PoolMode::Transaction),
+            _ => Err(anyhow!(
+                "unsupported pool_mode {}. expected one of none, session, transaction",
+                mode
+            )),
+        }
+    }
+}
+
+// ErrorVerbosity tells how much of the error is sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorVerbosity {
+    // Terse leaves out the table and column names, so that the client doesn't learn
+    // about the objects which it may not know about.
+    Terse,
+    // Default sends the object names along with the hint and position of the error.
+    Default,
+    // Verbose sends the internal cause of the errors as well.
+    Verbose,
+}
+
+impl ErrorVerbosity {
+    pub fn from_str(verbosity: &str) -> Result<ErrorVerbosity, anyhow::Error> {
+        match verbosity {
+            "terse" => Ok(ErrorVerbosity::Terse),
+            "default" => Ok(ErrorVerbosity::Default),
+            "verbose" => Ok(ErrorVerbosity::Verbose),
+            _ => Err(anyhow!(
+                "unsupported error_verbosity {}. expected one of terse, default, verbose",
+                verbosity
+            )),
+        }
+    }
+}
+
+// RewriteNotice tells how the user is notified about the rewritten queries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RewriteNotice {
+    None,
+    // Summary tells the number of masked columns and added predicates.
+    Summary,
+    // Verbose lists the masked columns and added predicates along with the reason.
+    Verbose,
+}
+
+impl RewriteNotice {
+    pub fn from_str(notice: &str) -> Result<RewriteNotice, anyhow::Error> {
+        match notice {
+            "none" => Ok(RewriteNotice::None),
+            "summary" => Ok(RewriteNotice::Summary),
+            "verbose" => Ok(RewriteNotice::Verbose),
+            _ => Err(anyhow!(
+                "unsupported rewrite_notice {}. expected one of none, summary, verbose",
+                notice
+            )),
+        }
+    }
+}
+
+// SslMode tells how the tls is negotiated with the target postgres. It follows
+// the libpq sslmode semantics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl SslMode {
+    pub fn from_str(mode: &str) -> Result<SslMode, anyhow::Error> {
+        match mode {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(anyhow!(
+                "unsupported sslmode {}. expected one of disable, prefer, require, verify-ca, verify-full",
+                mode
+            )),
+        }
+    }
+
+    // as_tokio_postgres_mode returns the sslmode understood by tokio_postgres. certificate
+    // verification of verify-ca and verify-full are handled by the ssl connector.
+    pub fn as_tokio_postgres_mode(&self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            _ => "require",
+        }
+    }
 }
 
 impl Default for PostgresConfig {
@@ -75,6 +341,32 @@ impl Default for PostgresConfig {
             target_password: Some(String::from("debuggerpassword")),
             proxy_listen_port: Some(String::from("8080")),
             target_port: Some(String::from("5432")),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_ca_path: None,
+            tls_min_version: None,
+            tls_ciphers: None,
+            require_tls: None,
+            target_sslmode: None,
+            target_ssl_root_cert: None,
+            target_ssl_server_name: None,
+            client_auth_method: None,
+            credential_store_path: None,
+            client_cert_mapping_path: None,
+            unsupported_sql: None,
+            forwarded_startup_params: None,
+            pool_mode: None,
+            pool_size: None,
+            schema_change_channel: None,
+            shutdown_grace_period: None,
+            session_expiry_warning: None,
+            idle_session_timeout: None,
+            idle_in_transaction_timeout: None,
+            max_session_duration: None,
+            cert_session_ttl: None,
+            error_verbosity: None,
+            rewrite_notice: None,
+            mask_hash_secret: None,
         }
     }
 }
@@ -96,8 +388,229 @@ impl PostgresConfig {
         if let None = self.proxy_listen_port {
             self.proxy_listen_port = Some("8080".to_string())
         }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err(anyhow!(
+                "both tls_cert_path and tls_key_path are required to enable tls"
+            ));
+        }
+        if self.is_tls_required() && !self.is_tls_enabled() {
+            return Err(anyhow!(
+                "require_tls is set but tls_cert_path and tls_key_path are not configured"
+            ));
+        }
+        if let Some(version) = &self.tls_min_version {
+            parse_tls_version(version)?;
+        }
+        if let Some(mode) = &self.target_sslmode {
+            let mode = SslMode::from_str(mode)?;
+            if mode == SslMode::Disable && self.target_ssl_root_cert.is_some() {
+                return Err(anyhow!(
+                    "target_ssl_root_cert is set but target_sslmode is disable"
+                ));
+            }
+        }
+        if let Some(method) = &self.client_auth_method {
+            let method = ClientAuthMethod::from_str(method)?;
+            // client certificates can be verified only if the proxy terminates tls with
+            // the client ca bundle.
+            if method == ClientAuthMethod::Cert
+                && (!self.is_tls_enabled() || self.tls_ca_path.is_none())
+            {
+                return Err(anyhow!(
+                    "cert client_auth_method requires tls_cert_path, tls_key_path and tls_ca_path"
+                ));
+            }
+        }
+        if let Some(mode) = &self.unsupported_sql {
+            UnsupportedSqlMode::from_str(mode)?;
+        }
+        if let Some(mode) = &self.pool_mode {
+            PoolMode::from_str(mode)?;
+        }
+        if let Some(verbosity) = &self.error_verbosity {
+            ErrorVerbosity::from_str(verbosity)?;
+        }
+        if let Some(notice) = &self.rewrite_notice {
+            RewriteNotice::from_str(notice)?;
+        }
+        if self.cert_session_ttl == Some(0) {
+            return Err(anyhow!("cert_session_ttl should be greater than zero"));
+        }
+        if self.pool_size == Some(0) {
+            return Err(anyhow!("pool_size should be greater than zero"));
+        }
+        if self.schema_change_channel == Some(String::new()) {
+            return Err(anyhow!("schema_change_channel should not be empty"));
+        }
+        match &self.mask_hash_secret {
+            Some(secret) if secret.len() < MIN_MASK_HASH_SECRET_LEN => {
+                return Err(anyhow!(
+                    "mask_hash_secret should be at least {} characters",
+                    MIN_MASK_HASH_SECRET_LEN
+                ));
+            }
+            Some(_) => {}
+            None => {
+                let mut secret = [0; 32];
+                rand_bytes(&mut secret)?;
+                self.mask_hash_secret = Some(
+                    secret
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>(),
+                );
+            }
+        }
+        if let Some(params) = &self.forwarded_startup_params {
+            for param in params {
+                let param = param.to_lowercase();
+                if RESERVED_STARTUP_PARAMS.contains(&param.as_str())
+                    || BLOCKED_STARTUP_PARAMS.contains(&param.as_str())
+                {
+                    return Err(anyhow!("startup parameter {} can't be forwarded", param));
+                }
+            }
+        }
         Ok(())
     }
+
+    // unsupported_sql_mode returns how the unsupported sql constructs are handled. fail-closed
+    // is the default one.
+    pub fn unsupported_sql_mode(&self) -> UnsupportedSqlMode {
+        match &self.unsupported_sql {
+            Some(mode) => {
+                UnsupportedSqlMode::from_str(mode).unwrap_or(UnsupportedSqlMode::FailClosed)
+            }
+            None => UnsupportedSqlMode::FailClosed,
+        }
+    }
+
+    // error_verbosity returns how much of the error is sent to the client.
+    pub fn error_verbosity(&self) -> ErrorVerbosity {
+        match &self.error_verbosity {
+            Some(verbosity) => {
+                ErrorVerbosity::from_str(verbosity).unwrap_or(ErrorVerbosity::Default)
+            }
+            None => ErrorVerbosity::Default,
+        }
+    }
+
+    // rewrite_notice returns how the user is notified about the rewritten queries. users
+    // are not notified by default.
+    pub fn rewrite_notice(&self) -> RewriteNotice {
+        match &self.rewrite_notice {
+            Some(notice) => RewriteNotice::from_str(notice).unwrap_or(RewriteNotice::None),
+            None => RewriteNotice::None,
+        }
+    }
+
+    // pool_mode returns how the target connections are pooled. pooling is disabled
+    // by default.
+    pub fn pool_mode(&self) -> PoolMode {
+        match &self.pool_mode {
+            Some(mode) => PoolMode::from_str(mode).unwrap_or(PoolMode::None),
+            None => PoolMode::None,
+        }
+    }
+
+    // pool_size returns the maximum number of idle target connections of a pool.
+    pub fn pool_size(&self) -> usize {
+        self.pool_size.unwrap_or(20)
+    }
+
+    // mask_hash_secret returns the secret of the hashed column values.
+    pub fn mask_hash_secret(&self) -> Vec<u8> {
+        self.mask_hash_secret
+            .clone()
+            .unwrap_or_default()
+            .into_bytes()
+    }
+
+    // schema_change_channel returns the notification channel of the schema changes.
+    pub fn schema_change_channel(&self) -> String {
+        self.schema_change_channel
+            .clone()
+            .unwrap_or_else(|| String::from("inspektor_schema_change"))
+    }
+
+    // shutdown_grace_period returns the grace period of the shutdown.
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_period.unwrap_or(30))
+    }
+
+    // session_expiry_warning returns the time before the session expiry at which the
+    // client is warned.
+    pub fn session_expiry_warning(&self) -> Duration {
+        Duration::from_secs(self.session_expiry_warning.unwrap_or(5 * 60))
+    }
+
+    // cert_session_ttl returns the lifetime of the certificate authenticated sessions.
+    pub fn cert_session_ttl(&self) -> Duration {
+        Duration::from_secs(self.cert_session_ttl.unwrap_or(60 * 60))
+    }
+
+    // forwarded_startup_params returns the startup parameters which are forwarded to the
+    // target postgres.
+    pub fn forwarded_startup_params(&self) -> Vec<String> {
+        match &self.forwarded_startup_params {
+            Some(params) => params.clone(),
+            None => DEFAULT_STARTUP_PARAMS
+                .iter()
+                .map(|param| param.to_string())
+                .collect(),
+        }
+    }
+
+    // client_auth_method returns the client authentication method. password is the default
+    // one to stay compatible with the controlplane auth.
+    pub fn client_auth_method(&self) -> ClientAuthMethod {
+        match &self.client_auth_method {
+            Some(method) => ClientAuthMethod::from_str(method).unwrap_or(ClientAuthMethod::Password),
+            None => ClientAuthMethod::Password,
+        }
+    }
+
+    // target_ssl_mode returns the sslmode used for the target postgres. prefer is
+    // the default one as libpq.
+    pub fn target_ssl_mode(&self) -> SslMode {
+        match &self.target_sslmode {
+            Some(mode) => SslMode::from_str(mode).unwrap_or(SslMode::Prefer),
+            None => SslMode::Prefer,
+        }
+    }
+
+    // target_ssl_server_name returns the hostname used for SNI and hostname verification.
+    pub fn target_ssl_server_name(&self) -> String {
+        match &self.target_ssl_server_name {
+            Some(name) => name.clone(),
+            None => host_of_addr(self.target_addr.as_ref().unwrap()),
+        }
+    }
+
+    // is_tls_enabled tells whether the proxy can terminate tls for the clients.
+    pub fn is_tls_enabled(&self) -> bool {
+        self.tls_cert_path.is_some() && self.tls_key_path.is_some()
+    }
+
+    // is_tls_required tells whether non tls client connections have to be refused.
+    // certificate authentication implies tls.
+    pub fn is_tls_required(&self) -> bool {
+        self.require_tls.unwrap_or(false) || self.client_auth_method() == ClientAuthMethod::Cert
+    }
+}
+
+/// parse_tls_version converts the configured tls version to openssl version.
+pub fn parse_tls_version(version: &str) -> Result<SslVersion, anyhow::Error> {
+    match version {
+        "TLSv1" => Ok(SslVersion::TLS1),
+        "TLSv1.1" => Ok(SslVersion::TLS1_1),
+        "TLSv1.2" => Ok(SslVersion::TLS1_2),
+        "TLSv1.3" => Ok(SslVersion::TLS1_3),
+        _ => Err(anyhow!(
+            "unsupported tls version {}. expected one of TLSv1, TLSv1.1, TLSv1.2, TLSv1.3",
+            version
+        )),
+    }
 }
 
 pub fn read_config(config_path: &std::path::Path) -> Result<Config, anyhow::Error> {
@@ -106,6 +619,21 @@ pub fn read_config(config_path: &std::path::Path) -> Result<Config, anyhow::Erro
     Ok(serde_yaml::from_slice::<Config>(&config_buf[..])?)
 }
 
+// host_of_addr returns the host part of the address by stripping the port.
+// eg: localhost:5432 => localhost, [::1]:5432 => ::1
+fn host_of_addr(addr: &str) -> String {
+    if let Some(rest) = addr.strip_prefix('[') {
+        if let Some((host, _)) = rest.split_once(']') {
+            return host.to_string();
+        }
+    }
+    match addr.rsplit_once(':') {
+        // more than one colon without brackets is an ipv6 address without port.
+        Some((host, _)) if !host.contains(':') => host.to_string(),
+        _ => addr.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,4 +644,220 @@ mod tests {
         let mut config = read_config(&path.join("src/config/test_config.yaml")).unwrap();
         config.validate().unwrap();
     }
+
+    #[test]
+    fn test_tls_config() {
+        let mut config = PostgresConfig::default();
+        config.tls_cert_path = Some(String::from("cert.pem"));
+        assert!(config.validate().is_err());
+
+        config.tls_key_path = Some(String::from("key.pem"));
+        config.require_tls = Some(true);
+        config.tls_min_version = Some(String::from("TLSv1.2"));
+        config.validate().unwrap();
+        assert!(config.is_tls_required());
+
+        config.tls_min_version = Some(String::from("SSLv3"));
+        assert!(config.validate().is_err());
+
+        let mut config = PostgresConfig::default();
+        config.require_tls = Some(true);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_target_sslmode() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.target_ssl_mode(), SslMode::Prefer);
+        assert_eq!(config.target_ssl_server_name(), "localhost");
+        config.target_addr = Some(String::from("[::1]:5432"));
+        assert_eq!(config.target_ssl_server_name(), "::1");
+        config.target_addr = Some(String::from("::1"));
+        assert_eq!(config.target_ssl_server_name(), "::1");
+        config.target_addr = Some(String::from("db.internal"));
+        assert_eq!(config.target_ssl_server_name(), "db.internal");
+        config.target_addr = Some(String::from("localhost:5432"));
+
+        config.target_sslmode = Some(String::from("verify-full"));
+        config.target_ssl_server_name = Some(String::from("db.example.com"));
+        config.validate().unwrap();
+        assert_eq!(config.target_ssl_mode(), SslMode::VerifyFull);
+        assert_eq!(config.target_ssl_mode().as_tokio_postgres_mode(), "require");
+        assert_eq!(config.target_ssl_server_name(), "db.example.com");
+
+        config.target_sslmode = Some(String::from("allow"));
+        assert!(config.validate().is_err());
+
+        config.target_sslmode = Some(String::from("disable"));
+        config.target_ssl_root_cert = Some(String::from("/etc/ssl/ca.pem"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_client_auth_method() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.client_auth_method(), ClientAuthMethod::Password);
+
+        config.client_auth_method = Some(String::from("scram-sha-256"));
+        config.validate().unwrap();
+        assert_eq!(config.client_auth_method(), ClientAuthMethod::ScramSha256);
+
+        config.client_auth_method = Some(String::from("trust"));
+        assert!(config.validate().is_err());
+
+        config.client_auth_method = Some(String::from("cert"));
+        config.tls_cert_path = Some(String::from("cert.pem"));
+        config.tls_key_path = Some(String::from("key.pem"));
+        assert!(config.validate().is_err());
+        config.tls_ca_path = Some(String::from("cert.pem"));
+        config.validate().unwrap();
+        assert_eq!(config.client_auth_method(), ClientAuthMethod::Cert);
+    }
+
+    #[test]
+    fn test_unsupported_sql_mode() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(
+            config.unsupported_sql_mode(),
+            UnsupportedSqlMode::FailClosed
+        );
+
+        config.unsupported_sql = Some(String::from("fail-open"));
+        config.validate().unwrap();
+        assert_eq!(config.unsupported_sql_mode(), UnsupportedSqlMode::FailOpen);
+
+        config.unsupported_sql = Some(String::from("ignore"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_error_verbosity() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.error_verbosity(), ErrorVerbosity::Default);
+
+        config.error_verbosity = Some(String::from("terse"));
+        config.validate().unwrap();
+        assert_eq!(config.error_verbosity(), ErrorVerbosity::Terse);
+
+        config.error_verbosity = Some(String::from("sqlstate"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_rewrite_notice() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.rewrite_notice(), RewriteNotice::None);
+
+        config.rewrite_notice = Some(String::from("verbose"));
+        config.validate().unwrap();
+        assert_eq!(config.rewrite_notice(), RewriteNotice::Verbose);
+
+        config.rewrite_notice = Some(String::from("all"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_pool_mode() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.pool_mode(), PoolMode::None);
+        assert_eq!(config.pool_size(), 20);
+
+        config.pool_mode = Some(String::from("transaction"));
+        config.pool_size = Some(5);
+        config.validate().unwrap();
+        assert_eq!(config.pool_mode(), PoolMode::Transaction);
+        assert_eq!(config.pool_size(), 5);
+
+        config.pool_size = Some(0);
+        assert!(config.validate().is_err());
+        config.pool_size = None;
+        config.pool_mode = Some(String::from("statement"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_schema_change_channel() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.schema_change_channel(), "inspektor_schema_change");
+
+        config.schema_change_channel = Some(String::from("ddl_events"));
+        config.validate().unwrap();
+        assert_eq!(config.schema_change_channel(), "ddl_events");
+
+        config.schema_change_channel = Some(String::new());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_shutdown_grace_period() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.shutdown_grace_period(), Duration::from_secs(30));
+
+        config.shutdown_grace_period = Some(0);
+        config.validate().unwrap();
+        assert_eq!(config.shutdown_grace_period(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_session_expiry_warning() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.session_expiry_warning(), Duration::from_secs(300));
+
+        config.session_expiry_warning = Some(60);
+        config.validate().unwrap();
+        assert_eq!(config.session_expiry_warning(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_cert_session_ttl() {
+        let mut config = PostgresConfig::default();
+        assert_eq!(config.cert_session_ttl(), Duration::from_secs(3600));
+
+        config.cert_session_ttl = Some(0);
+        assert!(config.validate().is_err());
+        config.cert_session_ttl = Some(600);
+        config.validate().unwrap();
+        assert_eq!(config.cert_session_ttl(), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_mask_hash_secret() {
+        // random secret is used if it's not configured.
+        let mut config = PostgresConfig::default();
+        config.validate().unwrap();
+        let secret = config.mask_hash_secret();
+        assert_eq!(secret.len(), 64);
+        let mut other = PostgresConfig::default();
+        other.validate().unwrap();
+        assert_ne!(secret, other.mask_hash_secret());
+
+        config.mask_hash_secret = Some(String::from("short"));
+        assert!(config.validate().is_err());
+        config.mask_hash_secret = Some(String::from("0123456789abcdef"));
+        config.validate().unwrap();
+        assert_eq!(config.mask_hash_secret(), b"0123456789abcdef".to_vec());
+    }
+
+    #[test]
+    fn test_forwarded_startup_params() {
+        let mut config = PostgresConfig::default();
+        assert!(config
+            .forwarded_startup_params()
+            .contains(&String::from("application_name")));
+        assert!(!config
+            .forwarded_startup_params()
+            .contains(&String::from("options")));
+        assert!(!config
+            .forwarded_startup_params()
+            .contains(&String::from("search_path")));
+
+        config.forwarded_startup_params = Some(vec![String::from("search_path")]);
+        config.validate().unwrap();
+        assert_eq!(config.forwarded_startup_params(), vec!["search_path"]);
+
+        config.forwarded_startup_params = Some(vec![String::from("Session_Authorization")]);
+        assert!(config.validate().is_err());
+        config.forwarded_startup_params = Some(vec![String::from("user")]);
+        assert!(config.validate().is_err());
+    }
 }
@@ -34,12 +34,18 @@ use grpcio::{ChannelBuilder, EnvBuilder};
 use log::*;
 use openssl_probe;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::watch;
 use tonic::transport::Channel;
 use tonic::{
     codegen::InterceptedService, metadata::MetadataValue, service::Interceptor, Request, Status,
 };
 
+// AUDIT_FLUSH_TIMEOUT is the maximum time taken to push the pending audit logs on
+// shutdown.
+const AUDIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
@@ -78,19 +84,57 @@ async fn main() {
         .await
         .expect("error while retriving integration config");
     // start audit worker.
-    let audit_sender = auditlog::start_audit_worker(integration_config.into_inner()).await;
+    let (audit_sender, audit_worker) =
+        auditlog::start_audit_worker(integration_config.into_inner()).await;
     // look for policy changes.
     let policy_watcher = look_for_policy_update(client.clone()).await;
+    let postgres_config = config.postgres_config.unwrap();
+    // build the ssl acceptor upfront so that misconfigured certificates fails
+    // the startup instead of the client handshake.
+    let ssl_acceptor = postgres_driver::driver::get_ssl_acceptor(&postgres_config)
+        .expect("error while building ssl acceptor. check tls config");
+    let target_ssl_connector = postgres_driver::driver::get_target_ssl_connector(&postgres_config)
+        .expect("error while building target ssl connector. check target ssl config");
+    let credential_store = postgres_driver::driver::get_credential_store(&postgres_config)
+        .expect("error while loading credential store");
+    let cert_mapper = postgres_driver::driver::get_cert_mapper(&postgres_config)
+        .expect("error while loading client certificate mapping");
+    let target_pool = postgres_driver::driver::get_target_pool(&postgres_config);
     let driver = postgres_driver::driver::PostgresDriver {
-        postgres_config: config.postgres_config.unwrap(),
+        postgres_config: postgres_config,
         policy_watcher: policy_watcher,
         datasource: source,
         client: client,
         token: config.secret_token.as_ref().unwrap().clone(),
         audit_sender: audit_sender,
-        ssl_acceptor: None,
+        ssl_acceptor: ssl_acceptor,
+        target_ssl_connector: target_ssl_connector,
+        credential_store: credential_store,
+        cert_mapper: cert_mapper,
+        cancel_registry: Default::default(),
+        target_pool: target_pool,
+        schema_cache: Default::default(),
     };
-    driver.start().await;
+    driver.start(shutdown_signal()).await;
+    // audit worker exits once the audit senders of the driver are dropped, so the pending
+    // audit logs are pushed before exiting.
+    drop(driver);
+    if tokio::time::timeout(AUDIT_FLUSH_TIMEOUT, audit_worker)
+        .await
+        .is_err()
+    {
+        warn!("timed out while flushing the audit logs");
+    }
+    info!("inspektor is stopped");
+}
+
+// shutdown_signal completes once SIGTERM or SIGINT is received.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("error while listening for SIGTERM");
+    tokio::select! {
+        _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
+    }
 }
 
 /// get_controlplane_client returns the controlplane's grpc client and call option.
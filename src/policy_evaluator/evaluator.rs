@@ -30,6 +30,27 @@ pub struct PolicyResult {
     pub allow: bool,
     pub allowed_attributes: Vec<String>,
     pub protected_attributes: Vec<String>,
+    // row_filters holds the sql predicate of the tables. eg:
+    // {"postgres.public.orders": "tenant_id = 'acme'"}
+    pub row_filters: HashMap<String, String>,
+    // protected_filter_action tells how to handle the protected columns used in the
+    // filters. either reject or neutralise.
+    pub protected_filter_action: Option<String>,
+    // masked_columns holds the mask strategy of the columns which are masked in the
+    // result rows. eg: {"postgres.public.kids.phone": "partial"}
+    pub masked_columns: HashMap<String, String>,
+    // protected_column_masks holds the masking strategy of the protected columns which
+    // are rewritten in the query. eg: {"postgres.public.kids.phone": "last4"}
+    pub protected_column_masks: HashMap<String, String>,
+    // session_timeouts holds the session timeouts in seconds. eg:
+    // {"idle_session_timeout": 600, "idle_in_transaction_timeout": 60}
+    pub session_timeouts: HashMap<String, u64>,
+    // rewrite_notice tells how the user is notified about the rewritten queries. eg:
+    // none, summary, verbose
+    pub rewrite_notice: Option<String>,
+    // startup_params holds the startup parameters which can be forwarded to the target.
+    // None if the policy doesn't restrict them. eg: ["application_name", "timezone"]
+    pub startup_params: Option<Vec<String>>,
 }
 
 impl PolicyResult {
@@ -91,6 +112,29 @@ impl PolicyEvaluator {
             String::from("protected_attributes"),
             evaluator.entrypoint_id(&"inspektor/resource/acl/protected_attributes")?,
         );
+        // row filters are optional, so that the policies which are written before
+        // row level filters keeps working.
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/row_filters") {
+            entrypoints.insert(String::from("row_filters"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/protected_filter_action") {
+            entrypoints.insert(String::from("protected_filter_action"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/masked_columns") {
+            entrypoints.insert(String::from("masked_columns"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/protected_column_masks") {
+            entrypoints.insert(String::from("protected_column_masks"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/session_timeouts") {
+            entrypoints.insert(String::from("session_timeouts"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/rewrite_notice") {
+            entrypoints.insert(String::from("rewrite_notice"), id);
+        }
+        if let Ok(id) = evaluator.entrypoint_id(&"inspektor/resource/acl/startup_params") {
+            entrypoints.insert(String::from("startup_params"), id);
+        }
         Ok(PolicyEvaluator {
             evaluator,
             entrypoints,
@@ -128,6 +172,13 @@ impl PolicyEvaluator {
                 allow: false,
                 allowed_attributes: vec![],
                 protected_attributes: vec![],
+                row_filters: HashMap::default(),
+                protected_filter_action: None,
+                masked_columns: HashMap::default(),
+                protected_column_masks: HashMap::default(),
+                session_timeouts: HashMap::default(),
+                rewrite_notice: None,
+                startup_params: None,
             });
         }
         // get allowed attributes for the user.
@@ -169,13 +220,139 @@ impl PolicyEvaluator {
             _ => Vec::new(),
         };
 
+        let row_filters = self.get_string_map("row_filters", &input, &data)?;
+        let masked_columns = self.get_string_map("masked_columns", &input, &data)?;
+        let protected_column_masks =
+            self.get_string_map("protected_column_masks", &input, &data)?;
+        let session_timeouts = self.get_number_map("session_timeouts", &input, &data)?;
+        let protected_filter_action = self.get_string("protected_filter_action", &input, &data)?;
+        let rewrite_notice = self.get_string("rewrite_notice", &input, &data)?;
+        let startup_params = self.get_string_list("startup_params", &input, &data)?;
+
         Ok(PolicyResult {
             allow: allow,
             allowed_attributes: allowed_attributes,
             protected_attributes: protected_attributes,
+            row_filters: row_filters,
+            protected_filter_action: protected_filter_action,
+            masked_columns: masked_columns,
+            protected_column_masks: protected_column_masks,
+            session_timeouts: session_timeouts,
+            rewrite_notice: rewrite_notice,
+            startup_params: startup_params,
+        })
+    }
+
+    // get_string returns the string value of the given optional entrypoint. eg: protected
+    // filter action.
+    fn get_string(
+        &mut self,
+        entrypoint: &str,
+        input: &Value,
+        data: &Value,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let entrypoint_id = match self.entrypoints.get(entrypoint) {
+            Some(entrypoint_id) => *entrypoint_id,
+            None => return Ok(None),
+        };
+        let result = self.evaluator.evaluate(entrypoint_id, input, data)?;
+        match self.get_result(result) {
+            Value::String(val) => Ok(Some(val)),
+            _ => Ok(None),
+        }
+    }
+
+    // get_string_list returns the string array of the given optional entrypoint. None is
+    // returned if the entrypoint is not defined. eg: startup parameters.
+    fn get_string_list(
+        &mut self,
+        entrypoint: &str,
+        input: &Value,
+        data: &Value,
+    ) -> Result<Option<Vec<String>>, anyhow::Error> {
+        let entrypoint_id = match self.entrypoints.get(entrypoint) {
+            Some(entrypoint_id) => *entrypoint_id,
+            None => return Ok(None),
+        };
+        let result = self.evaluator.evaluate(entrypoint_id, input, data)?;
+        let vals = match self.get_result(result) {
+            Value::Array(vals) => vals,
+            _ => return Ok(Some(vec![])),
+        };
+        let mut values = Vec::with_capacity(vals.len());
+        for val in vals {
+            match val {
+                Value::String(val) => values.push(val),
+                _ => return Err(anyhow::anyhow!("expected string values for {}", entrypoint)),
+            }
+        }
+        Ok(Some(values))
+    }
+
+    // get_string_map returns the object of string values of the given optional entrypoint.
+    // eg: row filters of the tables or mask strategy of the columns.
+    fn get_string_map(
+        &mut self,
+        entrypoint: &str,
+        input: &Value,
+        data: &Value,
+    ) -> Result<HashMap<String, String>, anyhow::Error> {
+        self.get_map(entrypoint, input, data, "string", |val| match val {
+            Value::String(val) => Some(val),
+            _ => None,
         })
     }
 
+    // get_number_map returns the object of non negative integer values of the given
+    // optional entrypoint. eg: session timeouts.
+    fn get_number_map(
+        &mut self,
+        entrypoint: &str,
+        input: &Value,
+        data: &Value,
+    ) -> Result<HashMap<String, u64>, anyhow::Error> {
+        self.get_map(entrypoint, input, data, "non negative integer", |val| {
+            val.as_u64()
+        })
+    }
+
+    fn get_map<T, F>(
+        &mut self,
+        entrypoint: &str,
+        input: &Value,
+        data: &Value,
+        kind: &str,
+        convert: F,
+    ) -> Result<HashMap<String, T>, anyhow::Error>
+    where
+        F: Fn(Value) -> Option<T>,
+    {
+        let entrypoint_id = match self.entrypoints.get(entrypoint) {
+            Some(entrypoint_id) => *entrypoint_id,
+            None => return Ok(HashMap::default()),
+        };
+        let result = self.evaluator.evaluate(entrypoint_id, input, data)?;
+        let mut values = HashMap::default();
+        if let Value::Object(vals) = self.get_result(result) {
+            for (key, val) in vals {
+                match convert(val) {
+                    Some(val) => {
+                        values.insert(key, val);
+                    }
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "expected {} value for the key {} of {}",
+                            kind,
+                            key,
+                            entrypoint
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(values)
+    }
+
     // get_result will returns result value from the policy value.
     pub fn get_result(&self, value: serde_json::Value) -> Value {
         if let Value::Array(mut objs) = value {
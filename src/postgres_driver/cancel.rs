@@ -0,0 +1,128 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use anyhow::anyhow;
+use byteorder::{ByteOrder, NetworkEndian};
+use openssl::rand::rand_bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// BackendKey is the process id and secret key pair which is sent in the
+/// BackendKeyData message and used by the client to cancel the running query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BackendKey {
+    pub process_id: i32,
+    pub secret_key: i32,
+}
+
+/// CancelRegistry maps the backend keys issued by the proxy to the backend keys of the
+/// target connections. It's shared across all the client connections, since the cancel
+/// request comes on a new connection. target key is None while the session doesn't hold
+/// any target connection in transaction pooling.
+#[derive(Debug, Clone, Default)]
+pub struct CancelRegistry {
+    keys: Arc<Mutex<HashMap<BackendKey, Option<BackendKey>>>>,
+}
+
+impl CancelRegistry {
+    // register issues a new proxy key for the given target key. returned handle removes
+    // the mapping once the session is dropped.
+    pub fn register(&self, target_key: Option<BackendKey>) -> Result<CancelHandle, anyhow::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        loop {
+            let mut buf = [0; 8];
+            rand_bytes(&mut buf)
+                .map_err(|e| anyhow!("error while generating backend key {:?}", e))?;
+            let proxy_key = BackendKey {
+                process_id: NetworkEndian::read_i32(&buf[..4]) & i32::MAX,
+                secret_key: NetworkEndian::read_i32(&buf[4..]),
+            };
+            // keys are random, so collision is rare. but one session must not be
+            // able to cancel the queries of another session.
+            if keys.contains_key(&proxy_key) {
+                continue;
+            }
+            keys.insert(proxy_key, target_key);
+            return Ok(CancelHandle {
+                registry: self.clone(),
+                proxy_key,
+            });
+        }
+    }
+
+    // get_target_key returns the target key of the given proxy key.
+    pub fn get_target_key(&self, proxy_key: &BackendKey) -> Option<BackendKey> {
+        self.keys.lock().unwrap().get(proxy_key).cloned().flatten()
+    }
+
+    fn set_target_key(&self, proxy_key: &BackendKey, target_key: Option<BackendKey>) {
+        if let Some(key) = self.keys.lock().unwrap().get_mut(proxy_key) {
+            *key = target_key;
+        }
+    }
+
+    fn remove(&self, proxy_key: &BackendKey) {
+        self.keys.lock().unwrap().remove(proxy_key);
+    }
+}
+
+/// CancelHandle holds the proxy key of the session.
+#[derive(Debug)]
+pub struct CancelHandle {
+    registry: CancelRegistry,
+    pub proxy_key: BackendKey,
+}
+
+impl CancelHandle {
+    // set_target_key updates the target key of the session, when the session switches
+    // the target connection.
+    pub fn set_target_key(&self, target_key: Option<BackendKey>) {
+        self.registry.set_target_key(&self.proxy_key, target_key);
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        self.registry.remove(&self.proxy_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_registry() {
+        let registry = CancelRegistry::default();
+        let target_key = BackendKey {
+            process_id: 4242,
+            secret_key: 1234,
+        };
+        let handle = registry.register(Some(target_key)).unwrap();
+        assert_ne!(handle.proxy_key, target_key);
+        assert!(handle.proxy_key.process_id >= 0);
+        assert_eq!(registry.get_target_key(&handle.proxy_key), Some(target_key));
+        // target key must not be accepted as the proxy key.
+        assert_eq!(registry.get_target_key(&target_key), None);
+
+        let other = registry.register(None).unwrap();
+        assert_ne!(other.proxy_key, handle.proxy_key);
+        assert_eq!(registry.get_target_key(&other.proxy_key), None);
+        other.set_target_key(Some(target_key));
+
+        let proxy_key = handle.proxy_key;
+        drop(handle);
+        assert_eq!(registry.get_target_key(&proxy_key), None);
+        assert_eq!(registry.get_target_key(&other.proxy_key), Some(target_key));
+    }
+}
@@ -1,3 +1,4 @@
+use openssl::nid::Nid;
 use std::pin::Pin;
 
 use std::task::Poll;
@@ -11,6 +12,43 @@ pub enum PostgresConn {
     Secured(SslStream<TcpStream>),
 }
 
+impl PostgresConn {
+    // is_secured tells whether the connection is upgraded to tls.
+    pub fn is_secured(&self) -> bool {
+        match self {
+            PostgresConn::Secured(_) => true,
+            PostgresConn::Unsecured(_) => false,
+        }
+    }
+
+    // peer_certificate_identities returns the common name and subject alternative names
+    // of the verified client certificate.
+    pub fn peer_certificate_identities(&self) -> Vec<String> {
+        let cert = match self {
+            PostgresConn::Secured(inner) => match inner.ssl().peer_certificate() {
+                Some(cert) => cert,
+                None => return vec![],
+            },
+            PostgresConn::Unsecured(_) => return vec![],
+        };
+        let mut identities = vec![];
+        for entry in cert.subject_name().entries_by_nid(Nid::COMMONNAME) {
+            if let Ok(name) = entry.data().as_utf8() {
+                identities.push(name.to_string());
+            }
+        }
+        if let Some(names) = cert.subject_alt_names() {
+            for name in names.iter() {
+                let name = name.dnsname().or_else(|| name.email()).or_else(|| name.uri());
+                if let Some(name) = name {
+                    identities.push(name.to_string());
+                }
+            }
+        }
+        identities
+    }
+}
+
 impl AsyncRead for PostgresConn {
     fn poll_read(
         self: std::pin::Pin<&mut Self>,
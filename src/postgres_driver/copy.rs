@@ -0,0 +1,611 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::postgres_driver::masking::{mask_value, MaskStrategy, MaskedTables, TableColumn};
+use crate::postgres_driver::message::Value;
+use crate::sql::copy::{CopyFormat, CopyFormatKind};
+use anyhow::anyhow;
+use sqlparser::ast::{Expr, Ident, Query, SelectItem, SetExpr, TableFactor};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+const TEXT_FORMAT: i16 = 0;
+const BINARY_FORMAT: i16 = 1;
+// BINARY_SIGNATURE starts the binary copy. refer the binary format section of the COPY
+// documentation.
+const BINARY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// CopyMasker masks the values of the masked columns in the CopyData messages of a
+/// COPY ... TO STDOUT.
+#[derive(Debug, Clone)]
+pub struct CopyMasker {
+    format: CopyFormat,
+    // columns holds the mask strategy and type oid of the masked columns.
+    columns: Vec<Option<(MaskStrategy, u32)>>,
+    // header_pending is set until the header row or the header of the binary copy is sent.
+    header_pending: bool,
+    hash_key: Vec<u8>,
+}
+
+impl CopyMasker {
+    pub fn new(
+        format: CopyFormat,
+        columns: Vec<Option<TableColumn>>,
+        hash_key: Vec<u8>,
+    ) -> CopyMasker {
+        let columns = columns
+            .into_iter()
+            .map(|column| column.and_then(|column| Some((column.strategy?, column.type_oid))))
+            .collect();
+        CopyMasker {
+            header_pending: format.header || format.kind == CopyFormatKind::Binary,
+            format,
+            columns,
+            hash_key,
+        }
+    }
+
+    // is_empty tells whether the copied rows have any masked column.
+    pub fn is_empty(&self) -> bool {
+        self.columns.iter().all(|column| column.is_none())
+    }
+
+    // validate checks the CopyOutResponse of the target against the copied columns.
+    pub fn validate(&self, binary: bool, columns: usize) -> Result<(), anyhow::Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if binary != (self.format.kind == CopyFormatKind::Binary) || columns != self.columns.len() {
+            return Err(anyhow!(
+                "copy response of the target doesn't match the copied columns"
+            ));
+        }
+        Ok(())
+    }
+
+    // mask_data masks the rows of the given CopyData message. target sends every row in
+    // its own CopyData message.
+    pub fn mask_data(&mut self, data: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let masked = match self.format.kind {
+            CopyFormatKind::Binary => self.mask_binary(data)?,
+            _ if self.header_pending => {
+                self.header_pending = false;
+                return Ok(());
+            }
+            CopyFormatKind::Text => self.mask_text(data)?,
+            CopyFormatKind::Csv => self.mask_csv(data)?,
+        };
+        *data = masked;
+        Ok(())
+    }
+
+    fn mask_text(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let row = data.strip_suffix(b"\n").unwrap_or(data);
+        // delimiter is always escaped inside the value, so the row can be split by it.
+        let fields = row
+            .split(|b| *b == self.format.delimiter)
+            .collect::<Vec<&[u8]>>();
+        self.check_columns(fields.len())?;
+        let mut masked = Vec::with_capacity(data.len());
+        for (idx, field) in fields.into_iter().enumerate() {
+            if idx > 0 {
+                masked.push(self.format.delimiter);
+            }
+            match &self.columns[idx] {
+                Some((strategy, type_oid)) if field != self.format.null.as_bytes() => {
+                    let value = text_unescape(field);
+                    match mask_value(*strategy, *type_oid, TEXT_FORMAT, &value, &self.hash_key) {
+                        Value::NotNull(value) => {
+                            masked.extend(text_escape(&value, self.format.delimiter))
+                        }
+                        Value::Null => masked.extend(self.format.null.as_bytes()),
+                    }
+                }
+                _ => masked.extend(field),
+            }
+        }
+        masked.push(b'\n');
+        Ok(masked)
+    }
+
+    fn mask_csv(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let row = data.strip_suffix(b"\n").unwrap_or(data);
+        let fields = self.split_csv(row)?;
+        self.check_columns(fields.len())?;
+        let mut masked = Vec::with_capacity(data.len());
+        for (idx, (raw, value, quoted)) in fields.into_iter().enumerate() {
+            if idx > 0 {
+                masked.push(self.format.delimiter);
+            }
+            // only the unquoted value can be null.
+            let is_null = !quoted && raw == self.format.null.as_bytes();
+            match &self.columns[idx] {
+                Some((strategy, type_oid)) if !is_null => {
+                    match mask_value(*strategy, *type_oid, TEXT_FORMAT, &value, &self.hash_key) {
+                        Value::NotNull(value) => masked.extend(self.csv_quote(&value)),
+                        Value::Null => masked.extend(self.format.null.as_bytes()),
+                    }
+                }
+                _ => masked.extend(raw),
+            }
+        }
+        masked.push(b'\n');
+        Ok(masked)
+    }
+
+    // split_csv returns the raw bytes, the value and whether the value is quoted for every
+    // field of the row.
+    fn split_csv<'a>(
+        &self,
+        row: &'a [u8],
+    ) -> Result<Vec<(&'a [u8], Vec<u8>, bool)>, anyhow::Error> {
+        let (delimiter, quote, escape) =
+            (self.format.delimiter, self.format.quote, self.format.escape);
+        let mut fields = vec![];
+        let mut start = 0;
+        loop {
+            let mut pos = start;
+            let mut value = vec![];
+            let mut quoted = false;
+            while pos < row.len() && row[pos] != delimiter {
+                if row[pos] != quote {
+                    value.push(row[pos]);
+                    pos += 1;
+                    continue;
+                }
+                // quoted part of the value.
+                quoted = true;
+                pos += 1;
+                loop {
+                    if pos >= row.len() {
+                        return Err(anyhow!("unterminated quoted value in the copy row"));
+                    }
+                    if row[pos] == escape
+                        && pos + 1 < row.len()
+                        && (row[pos + 1] == quote || row[pos + 1] == escape)
+                    {
+                        value.push(row[pos + 1]);
+                        pos += 2;
+                        continue;
+                    }
+                    if row[pos] == quote {
+                        pos += 1;
+                        break;
+                    }
+                    value.push(row[pos]);
+                    pos += 1;
+                }
+            }
+            fields.push((&row[start..pos], value, quoted));
+            if pos >= row.len() {
+                return Ok(fields);
+            }
+            start = pos + 1;
+        }
+    }
+
+    fn csv_quote(&self, value: &[u8]) -> Vec<u8> {
+        let (quote, escape) = (self.format.quote, self.format.escape);
+        let mut quoted = vec![quote];
+        for b in value {
+            if *b == quote || *b == escape {
+                quoted.push(escape);
+            }
+            quoted.push(*b);
+        }
+        quoted.push(quote);
+        quoted
+    }
+
+    fn mask_binary(&mut self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut masked = Vec::with_capacity(data.len());
+        let mut pos = 0;
+        if self.header_pending {
+            // signature, flags and the header extension.
+            if !data.starts_with(BINARY_SIGNATURE) {
+                return Err(anyhow!("invalid binary copy signature"));
+            }
+            pos = BINARY_SIGNATURE.len() + 4;
+            let extension = read_i32(data, pos)?;
+            pos += 4 + extension.max(0) as usize;
+            if pos > data.len() {
+                return Err(anyhow!("invalid binary copy header"));
+            }
+            masked.extend(&data[..pos]);
+            self.header_pending = false;
+        }
+        while pos < data.len() {
+            let count = read_i16(data, pos)?;
+            masked.extend(&data[pos..pos + 2]);
+            pos += 2;
+            // trailer of the binary copy.
+            if count == -1 {
+                continue;
+            }
+            self.check_columns(count as usize)?;
+            for idx in 0..count as usize {
+                let len = read_i32(data, pos)?;
+                pos += 4;
+                if len < 0 {
+                    masked.extend((-1i32).to_be_bytes());
+                    continue;
+                }
+                let end = pos + len as usize;
+                if end > data.len() {
+                    return Err(anyhow!("invalid binary copy row"));
+                }
+                let value = match &self.columns[idx] {
+                    Some((strategy, type_oid)) => mask_value(
+                        *strategy,
+                        *type_oid,
+                        BINARY_FORMAT,
+                        &data[pos..end],
+                        &self.hash_key,
+                    ),
+                    None => Value::NotNull(data[pos..end].to_vec()),
+                };
+                match value {
+                    Value::NotNull(value) => {
+                        masked.extend((value.len() as i32).to_be_bytes());
+                        masked.extend(value);
+                    }
+                    Value::Null => masked.extend((-1i32).to_be_bytes()),
+                }
+                pos = end;
+            }
+        }
+        Ok(masked)
+    }
+
+    fn check_columns(&self, columns: usize) -> Result<(), anyhow::Error> {
+        if columns != self.columns.len() {
+            return Err(anyhow!(
+                "copy row has {} columns but expected {}",
+                columns,
+                self.columns.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// copy_columns resolves the columns of the rows copied by the given query to the masked
+// columns. rows are not masked if the query doesn't refer any table which has masked
+// columns. only the columns of a single table can be resolved, so the other queries
+// which refer the masked table are rejected.
+pub fn copy_columns(
+    query: &Query,
+    tables: &MaskedTables,
+) -> Result<Vec<Option<TableColumn>>, anyhow::Error> {
+    let words = words(&query.to_string());
+    let referred = tables.keys().any(|table| {
+        let table_name = table.rsplit('.').next().unwrap_or(table);
+        words.iter().any(|word| word == table_name)
+    });
+    if !referred {
+        return Ok(vec![]);
+    }
+    let unresolved =
+        || anyhow!("copy of the masked table supports only the columns of a single table");
+    let select = match &query.body {
+        SetExpr::Select(select)
+            if query.with.is_none()
+                && select.from.len() == 1
+                && select.from[0].joins.len() == 0 =>
+        {
+            select
+        }
+        _ => return Err(unresolved()),
+    };
+    let table_name = match &select.from[0].relation {
+        TableFactor::Table { name, .. } => &name.0,
+        _ => return Err(unresolved()),
+    };
+    let columns = match find_table(tables, table_name)? {
+        Some(columns) => columns,
+        None => return Ok(vec![]),
+    };
+    let mut copied = vec![];
+    for item in &select.projection {
+        let expr = match item {
+            SelectItem::Wildcard | SelectItem::QualifiedWildcard(_) => {
+                copied.extend(columns.iter().map(|column| Some(column.clone())));
+                continue;
+            }
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr,
+        };
+        let column_name = match expr {
+            Expr::Identifier(ident) => Some(ident_value(ident)),
+            Expr::CompoundIdentifier(idents) => idents.last().map(ident_value),
+            _ => None,
+        };
+        if let Some(column_name) = column_name {
+            copied.push(
+                columns
+                    .iter()
+                    .find(|column| column.name == column_name)
+                    .cloned(),
+            );
+            continue;
+        }
+        // masked value can't be found in the computed value, so it's rejected.
+        let words = words(&expr.to_string());
+        if columns
+            .iter()
+            .any(|column| column.strategy.is_some() && words.contains(&column.name))
+        {
+            return Err(unresolved());
+        }
+        copied.push(None);
+    }
+    Ok(copied)
+}
+
+// find_table finds the columns of the masked table. unqualified table is found by the
+// table name and it's rejected if the name is ambiguous.
+fn find_table<'a>(
+    tables: &'a MaskedTables,
+    table_name: &Vec<Ident>,
+) -> Result<Option<&'a Vec<TableColumn>>, anyhow::Error> {
+    let table_name = table_name
+        .iter()
+        .map(ident_value)
+        .collect::<Vec<String>>()
+        .join(".");
+    if table_name.contains('.') {
+        return Ok(tables.get(&table_name));
+    }
+    let suffix = format!(".{}", table_name);
+    let mut found = tables.iter().filter(|(name, _)| name.ends_with(&suffix));
+    match (found.next(), found.next()) {
+        (Some((_, columns)), None) => Ok(Some(columns)),
+        (None, _) => Ok(None),
+        _ => Err(anyhow!("masked table {} is ambiguous", table_name)),
+    }
+}
+
+// ident_value returns the name as postgres sees it. unquoted names are case insensitive.
+fn ident_value(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+// words returns the identifiers of the sql.
+fn words(sql: &str) -> Vec<String> {
+    let dialect = PostgreSqlDialect {};
+    Tokenizer::new(&dialect, sql)
+        .tokenize()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Word(word) => Some(ident_value(&Ident {
+                value: word.value,
+                quote_style: word.quote_style,
+            })),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_i16(data: &[u8], pos: usize) -> Result<i16, anyhow::Error> {
+    match data.get(pos..pos + 2) {
+        Some(buf) => Ok(i16::from_be_bytes([buf[0], buf[1]])),
+        None => Err(anyhow!("unexpected end of binary copy data")),
+    }
+}
+
+fn read_i32(data: &[u8], pos: usize) -> Result<i32, anyhow::Error> {
+    match data.get(pos..pos + 4) {
+        Some(buf) => Ok(i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])),
+        None => Err(anyhow!("unexpected end of binary copy data")),
+    }
+}
+
+// text_unescape decodes the backslash escapes of the text format.
+fn text_unescape(field: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(field.len());
+    let mut iter = field.iter().peekable();
+    while let Some(b) = iter.next() {
+        if *b != b'\\' {
+            value.push(*b);
+            continue;
+        }
+        let escaped = match iter.next() {
+            Some(escaped) => *escaped,
+            None => break,
+        };
+        let decoded = match escaped {
+            b'b' => 8,
+            b'f' => 12,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 11,
+            b'0'..=b'7' => {
+                let mut octal = (escaped - b'0') as u32;
+                for _ in 0..2 {
+                    match iter.peek() {
+                        Some(digit) if (b'0'..=b'7').contains(*digit) => {
+                            octal = octal * 8 + (**digit - b'0') as u32;
+                            iter.next();
+                        }
+                        _ => break,
+                    }
+                }
+                octal as u8
+            }
+            b'x' => {
+                let mut hex = 0u32;
+                for _ in 0..2 {
+                    match iter.peek().and_then(|digit| (**digit as char).to_digit(16)) {
+                        Some(digit) => {
+                            hex = hex * 16 + digit;
+                            iter.next();
+                        }
+                        None => break,
+                    }
+                }
+                hex as u8
+            }
+            other => other,
+        };
+        value.push(decoded);
+    }
+    value
+}
+
+// text_escape encodes the value in the text format.
+fn text_escape(value: &[u8], delimiter: u8) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(value.len());
+    for b in value {
+        match *b {
+            b'\\' => escaped.extend(b"\\\\"),
+            b'\n' => escaped.extend(b"\\n"),
+            b'\r' => escaped.extend(b"\\r"),
+            b'\t' => escaped.extend(b"\\t"),
+            b if b == delimiter => escaped.extend([b'\\', b]),
+            b => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::copy::CopyStatement;
+    use std::collections::HashMap;
+
+    const INT4_OID: u32 = 23;
+    const TEXT_OID: u32 = 25;
+
+    fn kids() -> MaskedTables {
+        let column = |name: &str, type_oid: u32, strategy: Option<MaskStrategy>| TableColumn {
+            name: name.to_string(),
+            type_oid,
+            strategy,
+        };
+        HashMap::from([(
+            String::from("public.kids"),
+            vec![
+                column("id", INT4_OID, None),
+                column("phone", TEXT_OID, Some(MaskStrategy::Partial)),
+                column("name", TEXT_OID, None),
+            ],
+        )])
+    }
+
+    fn masker(sql: &str) -> CopyMasker {
+        let copy = CopyStatement::parse(sql).unwrap().unwrap();
+        let query = match &copy.source {
+            crate::sql::copy::CopySource::Query(query) => query,
+            _ => unreachable!(),
+        };
+        CopyMasker::new(
+            copy.format(),
+            copy_columns(query, &kids()).unwrap(),
+            b"secret".to_vec(),
+        )
+    }
+
+    fn mask(masker: &mut CopyMasker, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        masker.mask_data(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_copy_columns() {
+        let columns = |sql: &str| {
+            let copy = CopyStatement::parse(sql).unwrap().unwrap();
+            match &copy.source {
+                crate::sql::copy::CopySource::Query(query) => {
+                    copy_columns(query, &kids()).map(|columns| {
+                        columns
+                            .into_iter()
+                            .map(|column| column.map(|column| column.name))
+                            .collect::<Vec<_>>()
+                    })
+                }
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(
+            columns("COPY (SELECT * FROM kids) TO STDOUT").unwrap(),
+            vec![
+                Some(String::from("id")),
+                Some(String::from("phone")),
+                Some(String::from("name"))
+            ]
+        );
+        assert_eq!(
+            columns("COPY (SELECT k.phone AS p, 1 FROM public.kids AS k) TO STDOUT").unwrap(),
+            vec![Some(String::from("phone")), None]
+        );
+        assert_eq!(
+            columns("COPY (SELECT * FROM transactions) TO STDOUT").unwrap(),
+            Vec::<Option<String>>::new()
+        );
+        // masked column can't be resolved in the computed value or join.
+        assert!(columns("COPY (SELECT upper(phone) FROM kids) TO STDOUT").is_err());
+        assert!(columns(
+            "COPY (SELECT * FROM kids JOIN transactions ON kids.id = transactions.kid_id) TO STDOUT"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_copy_masker() {
+        let mut text = masker("COPY (SELECT * FROM kids) TO STDOUT");
+        assert_eq!(
+            mask(&mut text, b"1\t98765\\t43210\tkid\n"),
+            b"1\t*******3210\tkid\n".to_vec()
+        );
+        assert_eq!(mask(&mut text, b"2\t\\N\tkid\n"), b"2\t\\N\tkid\n".to_vec());
+
+        let mut csv = masker("COPY (SELECT * FROM kids) TO STDOUT WITH (FORMAT csv, HEADER)");
+        assert_eq!(
+            mask(&mut csv, b"id,phone,name\n"),
+            b"id,phone,name\n".to_vec()
+        );
+        assert_eq!(
+            mask(&mut csv, b"1,\"98,\"\"76543210\",\"kid, one\"\n"),
+            b"1,\"********3210\",\"kid, one\"\n".to_vec()
+        );
+        assert_eq!(mask(&mut csv, b"2,,kid\n"), b"2,,kid\n".to_vec());
+        assert!(csv.mask_data(&mut b"1,2\n".to_vec()).is_err());
+
+        let mut binary = masker("COPY (SELECT id, phone FROM kids) TO STDOUT WITH (FORMAT binary)");
+        let mut data = BINARY_SIGNATURE.to_vec();
+        data.extend(0i32.to_be_bytes());
+        data.extend(0i32.to_be_bytes());
+        let header_len = data.len();
+        data.extend(2i16.to_be_bytes());
+        data.extend(4i32.to_be_bytes());
+        data.extend(1i32.to_be_bytes());
+        data.extend(10i32.to_be_bytes());
+        data.extend(b"9876543210");
+        let masked = mask(&mut binary, &data);
+        assert_eq!(&masked[..header_len + 10], &data[..header_len + 10]);
+        assert_eq!(&masked[header_len + 14..], b"******3210");
+        assert_eq!(
+            mask(&mut binary, &(-1i16).to_be_bytes()),
+            (-1i16).to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::apiproto::apiproto::{AuthResponse, CredentialResponse};
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Credential holds the password verifiers of the user along with the
+/// session details that are needed after a successful authentication.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Credential {
+    pub user_name: String,
+    pub scram_verifier: Option<String>,
+    pub md5_hash: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub expires_at: i64,
+    #[serde(default)]
+    pub passthrough: bool,
+}
+
+impl Credential {
+    // from_response converts the controlplane credential response to credential.
+    pub fn from_response(user_name: &String, res: CredentialResponse) -> Credential {
+        let non_empty = |val: String| if val == "" { None } else { Some(val) };
+        Credential {
+            user_name: user_name.clone(),
+            scram_verifier: non_empty(res.scram_verifier),
+            md5_hash: non_empty(res.md5_hash),
+            groups: res.groups,
+            expires_at: res.expires_at,
+            passthrough: res.passthrough,
+        }
+    }
+
+    pub fn to_auth_response(&self) -> AuthResponse {
+        AuthResponse {
+            groups: self.groups.clone(),
+            expires_at: self.expires_at,
+            passthrough: self.passthrough,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CredentialFile {
+    users: Vec<Credential>,
+}
+
+/// CredentialStore is the local credential store of the users. It's used by
+/// the service accounts which are not managed by the controlplane.
+#[derive(Debug, Default)]
+pub struct CredentialStore {
+    credentials: HashMap<String, Credential>,
+}
+
+impl CredentialStore {
+    // load reads the credential store from the given yaml file.
+    pub fn load(path: &std::path::Path) -> Result<CredentialStore, anyhow::Error> {
+        let buf = std::fs::read(path)
+            .map_err(|e| anyhow!("error while reading credential store. err: {:?}", e))?;
+        CredentialStore::from_slice(&buf[..])
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<CredentialStore, anyhow::Error> {
+        let file = serde_yaml::from_slice::<CredentialFile>(buf)?;
+        let mut credentials = HashMap::with_capacity(file.users.len());
+        for credential in file.users {
+            if credential.scram_verifier.is_none() && credential.md5_hash.is_none() {
+                return Err(anyhow!(
+                    "user {} doesn't have scram_verifier or md5_hash",
+                    credential.user_name
+                ));
+            }
+            credentials.insert(credential.user_name.clone(), credential);
+        }
+        Ok(CredentialStore { credentials })
+    }
+
+    pub fn get(&self, user_name: &String) -> Option<&Credential> {
+        self.credentials.get(user_name)
+    }
+}
+
+/// CertificateMapping maps the client certificate subject to the user name or groups.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateMapping {
+    // subject is the common name or subject alternative name of the certificate.
+    pub subject: String,
+    // user_name is the user that the certificate is allowed to login as. subject
+    // is used as user name if it's not set.
+    pub user_name: Option<String>,
+    // groups are assigned to the session. certificate authenticated users don't
+    // have a password, so the groups are not fetched from the controlplane.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl CertificateMapping {
+    pub fn get_user_name(&self) -> String {
+        match &self.user_name {
+            Some(user_name) => user_name.clone(),
+            None => self.subject.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CertificateMappingFile {
+    mappings: Vec<CertificateMapping>,
+}
+
+/// CertificateMapper resolves the client certificate identities to the configured
+/// mapping.
+#[derive(Debug, Default)]
+pub struct CertificateMapper {
+    mappings: HashMap<String, CertificateMapping>,
+}
+
+impl CertificateMapper {
+    // load reads the certificate mapping from the given yaml file.
+    pub fn load(path: &std::path::Path) -> Result<CertificateMapper, anyhow::Error> {
+        let buf = std::fs::read(path)
+            .map_err(|e| anyhow!("error while reading certificate mapping. err: {:?}", e))?;
+        CertificateMapper::from_slice(&buf[..])
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<CertificateMapper, anyhow::Error> {
+        let file = serde_yaml::from_slice::<CertificateMappingFile>(buf)?;
+        let mut mappings = HashMap::with_capacity(file.mappings.len());
+        for mapping in file.mappings {
+            if mapping.groups.is_empty() {
+                return Err(anyhow!(
+                    "certificate mapping {} doesn't have groups",
+                    mapping.subject
+                ));
+            }
+            mappings.insert(mapping.subject.clone(), mapping);
+        }
+        Ok(CertificateMapper { mappings })
+    }
+
+    // resolve returns the mapping of the first certificate identity that is mapped.
+    pub fn resolve(&self, identities: &Vec<String>) -> Option<&CertificateMapping> {
+        identities
+            .iter()
+            .find_map(|identity| self.mappings.get(identity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credential_store() {
+        let store = CredentialStore::from_slice(
+            br#"
+users:
+  - user_name: "reporting"
+    scram_verifier: "SCRAM-SHA-256$4096:c2FsdA==$a2V5:a2V5"
+    groups: ["analyst"]
+  - user_name: "legacy"
+    md5_hash: "md5a3556571e93b0d20722ba62be61e8c2d"
+"#,
+        )
+        .unwrap();
+        let credential = store.get(&String::from("reporting")).unwrap();
+        assert_eq!(credential.groups, vec![String::from("analyst")]);
+        assert!(credential.md5_hash.is_none());
+        assert!(store.get(&String::from("legacy")).is_some());
+        assert!(store.get(&String::from("unknown")).is_none());
+
+        assert!(CredentialStore::from_slice(
+            br#"
+users:
+  - user_name: "reporting"
+"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_certificate_mapper() {
+        let mapper = CertificateMapper::from_slice(
+            br#"
+mappings:
+  - subject: "etl.svc.internal"
+    user_name: "etl"
+    groups: ["etl"]
+  - subject: "metabase"
+    groups: ["analyst"]
+"#,
+        )
+        .unwrap();
+        let mapping = mapper
+            .resolve(&vec![
+                String::from("etl-worker"),
+                String::from("etl.svc.internal"),
+            ])
+            .unwrap();
+        assert_eq!(mapping.get_user_name(), "etl");
+        let mapping = mapper.resolve(&vec![String::from("metabase")]).unwrap();
+        assert_eq!(mapping.get_user_name(), "metabase");
+        assert_eq!(mapping.groups, vec![String::from("analyst")]);
+        assert!(mapper.resolve(&vec![String::from("unknown")]).is_none());
+
+        assert!(CertificateMapper::from_slice(
+            br#"
+mappings:
+  - subject: "etl.svc.internal"
+    user_name: "etl"
+"#
+        )
+        .is_err());
+    }
+}
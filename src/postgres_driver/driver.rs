@@ -1,29 +1,61 @@
 use std::collections::HashMap;
 
-use crate::apiproto::apiproto::{AuthRequest, AuthResponse, DataSourceResponse};
+use crate::apiproto::apiproto::{
+    AuthRequest, AuthResponse, CredentialRequest, DataSourceResponse,
+};
 use crate::apiproto::apiproto::inspektor_client::InspektorClient;
 use crate::apiproto::InspektorClientCommon;
-use crate::config::PostgresConfig;
+use crate::config::{parse_tls_version, ClientAuthMethod, PostgresConfig, SslMode};
 use crate::policy_evaluator::evaluator::PolicyEvaluator;
+use crate::postgres_driver::cancel::{BackendKey, CancelRegistry};
 use crate::postgres_driver::conn::PostgresConn;
+use crate::postgres_driver::credentials::{CertificateMapper, Credential, CredentialStore};
 use crate::postgres_driver::errors::DecoderError;
 use crate::postgres_driver::message::*;
+use crate::postgres_driver::pool::TargetPool;
 use crate::postgres_driver::protocol_handler::*;
+use crate::postgres_driver::schema::SchemaCache;
+use crate::postgres_driver::scram::{
+    verify_md5_password, ScramServer, ScramVerifier, SCRAM_SHA_256,
+};
+use crate::postgres_driver::shutdown::ShutdownState;
+use crate::postgres_driver::startup::build_startup_params;
 use crate::postgres_driver::utils::*;
 use anyhow::anyhow;
 use grpcio::CallOption;
 use log::*;
-use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use openssl::rand::rand_bytes;
+use openssl::ssl::{
+    select_next_proto, AlpnError, Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod,
+    SslVerifyMode,
+};
 
+use std::future::Future;
 use std::pin::Pin;
-use tonic::Request;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::{Code, Request};
 
 use tokio;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::Sender;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Sender};
 use tokio::sync::watch;
+use tokio::time as tokio_time;
 use tokio_openssl::SslStream;
+
+// TERMINATE_TIMEOUT is the time given to the terminated sessions to notify the clients.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// CredentialLookup is the result of looking up the credential of the user.
+enum CredentialLookup {
+    Found(Credential),
+    NotFound,
+    // Unavailable tells that the controlplane doesn't serve the credentials, so no user
+    // outside of the local credential store has a password verifier.
+    Unavailable,
+}
+
 #[derive(Clone)]
 pub struct PostgresDriver {
     pub postgres_config: PostgresConfig,
@@ -33,12 +65,26 @@ pub struct PostgresDriver {
     pub datasource: DataSourceResponse,
     pub audit_sender: Sender<String>,
     pub ssl_acceptor: Option<SslAcceptor>,
+    pub target_ssl_connector: Option<SslConnector>,
+    pub credential_store: Option<Arc<CredentialStore>>,
+    pub cert_mapper: Option<Arc<CertificateMapper>>,
+    // cancel_registry maps the backend keys issued to the clients to the target's
+    // backend keys.
+    pub cancel_registry: CancelRegistry,
+    // target_pool holds the target connections which are shared across the sessions.
+    pub target_pool: TargetPool,
+    // schema_cache holds the schema of the target databases.
+    pub schema_cache: SchemaCache,
 }
 
 impl PostgresDriver {
     /// start will start listening for postgres connection from the configured
-    /// listening port.
-    pub async  fn start(&self) {
+    /// listening port. once the shutdown signal is received, it stops accepting new
+    /// connections and waits for the client sessions to finish within the grace period.
+    pub async fn start<F>(&self, shutdown_signal: F)
+    where
+        F: Future<Output = ()>,
+    {
         let listener = TcpListener::bind(format!(
             "0.0.0.0:{}",
             self.postgres_config.proxy_listen_port.as_ref().unwrap()
@@ -50,40 +96,67 @@ impl PostgresDriver {
             "postgres driver listeneing at 0.0.0.0:{}",
             self.postgres_config.proxy_listen_port.as_ref().unwrap()
         );
+        let (shutdown_sender, shutdown_receiver) = watch::channel(ShutdownState::Running);
+        // every session holds a drain sender, so the drain receiver is closed once all the
+        // sessions are finished.
+        let (drain_sender, mut drain_receiver) = mpsc::channel::<()>(1);
+        tokio::pin!(shutdown_signal);
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
+            let socket = tokio::select! {
+                res = listener.accept() => res.unwrap().0,
+                _ = &mut shutdown_signal => break,
+            };
             //       let acceptor = acceptor.clone();
             let mut driver = self.clone();
             let socket = PostgresConn::Unsecured(socket);
+            let shutdown = shutdown_receiver.clone();
+            let drain = drain_sender.clone();
             tokio::spawn(async move {
-                if let Err(e) = driver.handle_client_conn(socket).await {
+                if let Err(e) = driver.handle_client_conn(socket, shutdown).await {
                     error!("error while handling client connection {:?}", e);
                 }
-                ()
+                drop(drain);
             });
         }
+        // stop accepting new connections.
+        drop(listener);
+        drop(drain_sender);
+        info!("draining client connections");
+        let _ = shutdown_sender.send(ShutdownState::Draining);
+        let grace_period = self.postgres_config.shutdown_grace_period();
+        if tokio_time::timeout(grace_period, drain_receiver.recv())
+            .await
+            .is_err()
+        {
+            warn!("grace period is over, terminating the remaining client connections");
+            let _ = shutdown_sender.send(ShutdownState::Terminating);
+            let _ = tokio_time::timeout(TERMINATE_TIMEOUT, drain_receiver.recv()).await;
+        }
+        info!("postgres driver is stopped");
     }
 
-    // get_ssl_acceptor will get ssl acceptor if the sidecar is set to run on tls
-    // mode.
-    // fn get_ssl_acceptor(&self) -> SslAcceptor {
-    //     let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    //     acceptor
-    //         .set_private_key_file("/home/poonai/inspektor/key.pem", SslFiletype::PEM)
-    //         .unwrap();
-    //     acceptor
-    //         .set_certificate_chain_file("/home/poonai/inspektor/cert.pem")
-    //         .unwrap();
-    //     let acceptor = acceptor.build();
-    //     acceptor
-    // }
-
     /// handle_client_conn will handle the tcp connection of the client.
-    async fn handle_client_conn(&mut self, conn: PostgresConn) -> Result<(), anyhow::Error> {
+    async fn handle_client_conn(
+        &mut self,
+        conn: PostgresConn,
+        shutdown: watch::Receiver<ShutdownState>,
+    ) -> Result<(), anyhow::Error> {
+        let conn = accept_direct_tls(&self.ssl_acceptor, conn).await?;
         let (startup_msg, mut conn) = self.get_startup_msg(conn).await?;
 
         let params = match startup_msg {
             FrontendMessage::Startup { params, .. } => params,
+            FrontendMessage::CancelRequest {
+                process_id,
+                secret_key,
+            } => {
+                return self
+                    .cancel_query(BackendKey {
+                        process_id,
+                        secret_key,
+                    })
+                    .await
+            }
             _ => unreachable!(),
         };
         // authenticate client connection.
@@ -128,10 +201,24 @@ impl PostgresDriver {
             return Err(anyhow!("unautorized db access"));
         }
 
+        // only the startup parameters allowed by the config and the policy are forwarded
+        // to the target.
+        let forwarded_params = self.postgres_config.forwarded_startup_params();
+        let startup_params =
+            match build_startup_params(&params, &forwarded_params, &result.startup_params) {
+                Ok(startup_params) => startup_params,
+                Err(e) => {
+                    let rsp = BackendMessage::fatal_msg("42501", format!("{}", e));
+                    conn.write_all(&rsp.encode()).await?;
+                    return Err(anyhow!("refusing the startup parameters {:?}", e));
+                }
+            };
+
         let mut handler = match ProtocolHandler::initialize(
             self.postgres_config.clone(),
             conn,
             params,
+            startup_params,
             self.policy_watcher.clone(),
             groups,
             evaluator,
@@ -140,6 +227,11 @@ impl PostgresDriver {
             self.token.clone(),
             auth_res.passthrough,
             self.audit_sender.clone(),
+            self.target_ssl_connector.clone(),
+            self.cancel_registry.clone(),
+            self.target_pool.clone(),
+            self.schema_cache.clone(),
+            shutdown,
         )
         .await
         {
@@ -174,46 +266,142 @@ impl PostgresDriver {
                 // sometimes client asks to upgrade the connection to tls. So, upgrade
                 // before decoding the startup message.
                 FrontendMessage::SslRequest => {
-                    conn = self.upgrade_to_tls(conn).await?;
+                    if self.ssl_acceptor.is_none() {
+                        // tls is not configured for the proxy so let the client know
+                        // that it has to continue with unsecured connection.
+                        conn.write_all(&[REJECT_SSL_ENCRYPTION]).await?;
+                        continue;
+                    }
+                    conn = upgrade_to_tls(&self.ssl_acceptor, conn).await?;
                     continue;
                 }
-                FrontendMessage::Startup { .. } => return Ok((msg, conn)),
+                // gss encryption is not supported, so the client continues with
+                // ssl request or startup message.
+                FrontendMessage::GssEncRequest => {
+                    conn.write_all(&[REJECT_GSS_ENCRYPTION]).await?;
+                    continue;
+                }
+                FrontendMessage::Startup {
+                    mut params,
+                    version,
+                } => {
+                    if self.postgres_config.is_tls_required() && !conn.is_secured() {
+                        let rsp = BackendMessage::fatal_msg(
+                            "28000",
+                            String::from("inspektor requires an SSL connection"),
+                        );
+                        conn.write_all(&rsp.encode()).await?;
+                        return Err(anyhow!("refusing the client connection without tls"));
+                    }
+                    if let Some(rsp) = negotiate_protocol_version(version, &mut params) {
+                        conn.write_all(&rsp.encode()).await?;
+                    }
+                    return Ok((FrontendMessage::Startup { params, version }, conn));
+                }
+                // cancel request comes on a new connection without startup message.
+                FrontendMessage::CancelRequest { .. } => return Ok((msg, conn)),
                 _ => return Err(anyhow!("invalid message")),
             }
         }
     }
 
-    /// upgrade_to_tls will upgrade the given unsecured connection to secured connection.
-    async fn upgrade_to_tls(&self, mut conn: PostgresConn) -> Result<PostgresConn, anyhow::Error> {
-        if let None = self.ssl_acceptor {
-            return Err(anyhow!(
-                "don't have ssl acceptor to upgrade the connection to tls"
-            ));
-        }
-        // upgrade the connection to tls only if the given connection is
-        // insecured.
-        if let PostgresConn::Unsecured(mut inner) = conn {
-            if let Err(e) = inner.write_all(&[ACCEPT_SSL_ENCRYPTION]).await {
-                return Err(anyhow!(
-                    "error while sending ACCEPT_SSL_ENCRYPTION to client {:?}",
-                    e
-                ));
+    // cancel_query forwards the cancel request to the target with the target's backend key.
+    // unknown keys are ignored silently like postgres does, So that the keys of other
+    // sessions can't be guessed.
+    async fn cancel_query(&self, proxy_key: BackendKey) -> Result<(), anyhow::Error> {
+        let target_key = match self.cancel_registry.get_target_key(&proxy_key) {
+            Some(key) => key,
+            None => {
+                debug!("ignoring cancel request of unknown backend key");
+                return Ok(());
             }
-            let ssl = Ssl::new(self.ssl_acceptor.as_ref().unwrap().context()).unwrap();
-            let mut stream = SslStream::new(ssl, inner).unwrap();
-            Pin::new(&mut stream).accept().await.unwrap();
-            conn = PostgresConn::Secured(stream);
-            return Ok(conn);
-        }
-        Err(anyhow!("can't upgrade secured connection"))
+        };
+        ProtocolHandler::cancel_target_query(
+            &self.postgres_config,
+            &self.target_ssl_connector,
+            target_key,
+        )
+        .await
     }
 
-    // verfiy_client_params will verify the client password with the dataplane.
+    // verfiy_client_params will verify the client password with the configured auth method.
     // if it's succeed it'll retrive all group assigned to the user.
     async fn verfiy_client_params(
         &mut self,
         params: &HashMap<String, String>,
         client_conn: &mut PostgresConn,
+    ) -> Result<AuthResponse, anyhow::Error> {
+        let user_name = params
+            .get("user")
+            .ok_or_else(|| anyhow!("user is missing in the startup message"))?
+            .clone();
+        let method = self.postgres_config.client_auth_method();
+        match method {
+            ClientAuthMethod::Password => {
+                return self.verify_cleartext_password(&user_name, client_conn).await
+            }
+            ClientAuthMethod::Cert => {
+                return self.verify_client_certificate(&user_name, client_conn).await
+            }
+            _ => {}
+        }
+        let credential = match self.get_credential(&user_name).await? {
+            CredentialLookup::Found(credential) => Some(credential),
+            CredentialLookup::NotFound => None,
+            CredentialLookup::Unavailable => {
+                let rsp = BackendMessage::fatal_msg(
+                    "28000",
+                    format!(
+                        "password authentication is not available for user \"{}\": no password verifier is configured",
+                        user_name
+                    ),
+                );
+                client_conn.write_all(&rsp.encode()).await?;
+                return Err(anyhow!("no password verifier for user {}", user_name));
+            }
+        };
+        let result = match &credential {
+            Some(Credential {
+                scram_verifier: Some(verifier),
+                ..
+            }) => {
+                let verifier = ScramVerifier::parse(verifier)?;
+                self.authenticate_scram(verifier, client_conn).await
+            }
+            Some(Credential {
+                md5_hash: Some(md5_hash),
+                ..
+            }) if method == ClientAuthMethod::Md5 => {
+                self.authenticate_md5(md5_hash, client_conn).await
+            }
+            // let the unknown users go through the scram exchange, so that they
+            // can't figure out whether the user exist or not.
+            _ => {
+                self.authenticate_scram(ScramVerifier::mock()?, client_conn)
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            error!("client authentication failed for user {}. err: {:?}", user_name, e);
+            let rsp = BackendMessage::fatal_msg(
+                "28P01",
+                format!("password authentication failed for user \"{}\"", user_name),
+            );
+            client_conn.write_all(&rsp.encode()).await?;
+            return Err(anyhow!("password authentication failed"));
+        }
+        match credential {
+            Some(credential) => Ok(credential.to_auth_response()),
+            None => Err(anyhow!("password authentication failed")),
+        }
+    }
+
+    // verify_cleartext_password asks the client for the clear text password and verifies
+    // it with the controlplane.
+    async fn verify_cleartext_password(
+        &mut self,
+        user_name: &String,
+        client_conn: &mut PostgresConn,
     ) -> Result<AuthResponse, anyhow::Error> {
         let buf = BackendMessage::AuthenticationCleartextPassword.encode();
         client_conn.write_all(&buf).await.map_err(|e| {
@@ -232,14 +420,196 @@ impl PostgresDriver {
                 unreachable!("expectected password message while decoding for password message");
             }
         };
-        let mut auth_req = Request::new(AuthRequest{
+        let auth_req = Request::new(AuthRequest {
             password: password,
-            user_name: params.get("user").unwrap().clone()
+            user_name: user_name.clone(),
         });
         let res = self.client.auth(auth_req).await?;
         Ok(res.into_inner())
     }
 
+    // verify_client_certificate authenticates the client using the verified tls client
+    // certificate. certificate identity is mapped to the user name and the groups are
+    // taken either from the mapping or from the controlplane.
+    async fn verify_client_certificate(
+        &mut self,
+        user_name: &String,
+        client_conn: &mut PostgresConn,
+    ) -> Result<AuthResponse, anyhow::Error> {
+        let identities = client_conn.peer_certificate_identities();
+        let mapping = self
+            .cert_mapper
+            .as_ref()
+            .and_then(|mapper| mapper.resolve(&identities))
+            .cloned();
+        let mapped_user = match &mapping {
+            Some(mapping) => Some(mapping.get_user_name()),
+            None => identities.first().cloned(),
+        };
+        if mapped_user.as_ref() != Some(user_name) {
+            error!(
+                "client certificate {:?} is not allowed to login as {}",
+                identities, user_name
+            );
+            let rsp = BackendMessage::fatal_msg(
+                "28000",
+                format!("certificate authentication failed for user \"{}\"", user_name),
+            );
+            client_conn.write_all(&rsp.encode()).await?;
+            return Err(anyhow!("certificate authentication failed"));
+        }
+        // certificate stays valid till it's expiry, so the session is limited by the ttl.
+        let expires_at = (SystemTime::now().duration_since(UNIX_EPOCH)?
+            + self.postgres_config.cert_session_ttl())
+        .as_secs() as i64;
+        // mapped certificates carry their groups, only the plain common name is looked
+        // up in the credential store or the controlplane.
+        if let Some(mapping) = mapping {
+            return Ok(AuthResponse {
+                groups: mapping.groups,
+                expires_at,
+                passthrough: false,
+            });
+        }
+        match self.lookup_credential(user_name).await? {
+            CredentialLookup::Found(credential) => {
+                let mut res = credential.to_auth_response();
+                if res.expires_at <= 0 || res.expires_at > expires_at {
+                    res.expires_at = expires_at;
+                }
+                Ok(res)
+            }
+            CredentialLookup::NotFound | CredentialLookup::Unavailable => {
+                let rsp = BackendMessage::fatal_msg(
+                    "28000",
+                    format!("certificate authentication failed for user \"{}\"", user_name),
+                );
+                client_conn.write_all(&rsp.encode()).await?;
+                Err(anyhow!("user {} is not known to the controlplane", user_name))
+            }
+        }
+    }
+
+    // get_credential returns the credential of the user which has a password verifier.
+    async fn get_credential(
+        &mut self,
+        user_name: &String,
+    ) -> Result<CredentialLookup, anyhow::Error> {
+        match self.lookup_credential(user_name).await? {
+            CredentialLookup::Found(credential)
+                if credential.scram_verifier.is_none() && credential.md5_hash.is_none() =>
+            {
+                Ok(CredentialLookup::NotFound)
+            }
+            lookup => Ok(lookup),
+        }
+    }
+
+    // lookup_credential returns the credential of the user. local credential store is
+    // preferred over the controlplane.
+    async fn lookup_credential(
+        &mut self,
+        user_name: &String,
+    ) -> Result<CredentialLookup, anyhow::Error> {
+        if let Some(store) = &self.credential_store {
+            if let Some(credential) = store.get(user_name) {
+                return Ok(CredentialLookup::Found(credential.clone()));
+            }
+        }
+        let req = Request::new(CredentialRequest {
+            user_name: user_name.clone(),
+        });
+        match self.client.get_credential(req).await {
+            Ok(res) => Ok(CredentialLookup::Found(Credential::from_response(
+                user_name,
+                res.into_inner(),
+            ))),
+            Err(status) if status.code() == Code::NotFound => Ok(CredentialLookup::NotFound),
+            Err(status) if status.code() == Code::Unimplemented => {
+                error!(
+                    "controlplane doesn't serve the password verifiers, configure credential_store_path to authenticate {}",
+                    user_name
+                );
+                Ok(CredentialLookup::Unavailable)
+            }
+            Err(e) => Err(anyhow!(
+                "error while retriving credential from controlplane {:?}",
+                e
+            )),
+        }
+    }
+
+    // authenticate_scram authenticates the client using SCRAM-SHA-256.
+    async fn authenticate_scram(
+        &mut self,
+        verifier: ScramVerifier,
+        client_conn: &mut PostgresConn,
+    ) -> Result<(), anyhow::Error> {
+        let mut server = ScramServer::new(verifier)?;
+        client_conn
+            .write_all(
+                &BackendMessage::AuthenticationSASL {
+                    mechanisms: vec![SCRAM_SHA_256.to_string()],
+                }
+                .encode(),
+            )
+            .await?;
+        let body = match decode_sasl_initial_response(&mut *client_conn).await? {
+            FrontendMessage::SASLInitialResponse { mechanism, body } => {
+                if mechanism != SCRAM_SHA_256 {
+                    return Err(anyhow!("unsupported sasl mechanism {}", mechanism));
+                }
+                body
+            }
+            _ => return Err(anyhow!("expected sasl initial response")),
+        };
+        let server_first = server.handle_client_first(&body)?;
+        client_conn
+            .write_all(
+                &BackendMessage::AuthenticationSASLContinue {
+                    data: server_first.into_bytes(),
+                }
+                .encode(),
+            )
+            .await?;
+        let body = match decode_sasl_response(&mut *client_conn).await? {
+            FrontendMessage::SASLResponse { body } => body,
+            _ => return Err(anyhow!("expected sasl response")),
+        };
+        let server_final = server.handle_client_final(&body)?;
+        client_conn
+            .write_all(
+                &BackendMessage::AuthenticationSASLFinal {
+                    data: server_final.into_bytes(),
+                }
+                .encode(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // authenticate_md5 authenticates the client using md5 password. It's only
+    // used for the legacy drivers which don't support scram.
+    async fn authenticate_md5(
+        &mut self,
+        md5_hash: &String,
+        client_conn: &mut PostgresConn,
+    ) -> Result<(), anyhow::Error> {
+        let mut salt = vec![0; 4];
+        rand_bytes(&mut salt)?;
+        client_conn
+            .write_all(&BackendMessage::AuthenticationMD5Password { salt: salt.clone() }.encode())
+            .await?;
+        let password = match decode_password_message(&mut *client_conn).await? {
+            FrontendMessage::PasswordMessage { password } => password,
+            _ => return Err(anyhow!("expected password message")),
+        };
+        if !verify_md5_password(md5_hash, &salt, &password) {
+            return Err(anyhow!("md5 password mismatch"));
+        }
+        Ok(())
+    }
+
     /// get_call_opt return call option with control plane auth token. So that
     /// it can used with grpc client while talking to
     fn get_call_opt(&self) -> CallOption {
@@ -251,3 +621,335 @@ impl PostgresDriver {
         return grpcio::CallOption::default().headers(meta);
     }
 }
+
+// accept_direct_tls performs the tls handshake if the client starts the tls handshake
+// without sending ssl request (sslnegotiation=direct). otherwise the connection is
+// returned as it is.
+pub async fn accept_direct_tls(
+    acceptor: &Option<SslAcceptor>,
+    conn: PostgresConn,
+) -> Result<PostgresConn, anyhow::Error> {
+    let inner = match conn {
+        PostgresConn::Unsecured(inner) => inner,
+        secured => return Ok(secured),
+    };
+    let mut buf = [0; 1];
+    let n = inner.peek(&mut buf).await?;
+    if n == 0 {
+        return Err(anyhow!("client closed the connection before startup"));
+    }
+    if buf[0] != TLS_HANDSHAKE_RECORD {
+        return Ok(PostgresConn::Unsecured(inner));
+    }
+    let stream = accept_tls(acceptor, inner).await?;
+    // alpn is mandatory for direct tls, so that the tls connection of other
+    // protocols is not mistaken for postgres.
+    if stream.ssl().selected_alpn_protocol() != Some(&ALPN_POSTGRESQL[1..]) {
+        return Err(anyhow!(
+            "client didn't negotiate postgresql alpn for direct tls"
+        ));
+    }
+    Ok(PostgresConn::Secured(stream))
+}
+
+/// upgrade_to_tls will upgrade the given unsecured connection to secured connection.
+pub async fn upgrade_to_tls(
+    acceptor: &Option<SslAcceptor>,
+    conn: PostgresConn,
+) -> Result<PostgresConn, anyhow::Error> {
+    // upgrade the connection to tls only if the given connection is
+    // insecured.
+    if let PostgresConn::Unsecured(mut inner) = conn {
+        if let Err(e) = inner.write_all(&[ACCEPT_SSL_ENCRYPTION]).await {
+            return Err(anyhow!(
+                "error while sending ACCEPT_SSL_ENCRYPTION to client {:?}",
+                e
+            ));
+        }
+        let stream = accept_tls(acceptor, inner).await?;
+        return Ok(PostgresConn::Secured(stream));
+    }
+    Err(anyhow!("can't upgrade secured connection"))
+}
+
+// accept_tls accepts the tls handshake of the client.
+async fn accept_tls(
+    acceptor: &Option<SslAcceptor>,
+    inner: TcpStream,
+) -> Result<SslStream<TcpStream>, anyhow::Error> {
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            return Err(anyhow!(
+                "don't have ssl acceptor to upgrade the connection to tls"
+            ));
+        }
+    };
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, inner)?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .map_err(|e| anyhow!("error while accepting tls handshake {:?}", e))?;
+    Ok(stream)
+}
+
+/// get_ssl_acceptor builds the ssl acceptor from the postgres config. None is returned
+/// if the proxy is not configured to terminate tls.
+pub fn get_ssl_acceptor(config: &PostgresConfig) -> Result<Option<SslAcceptor>, anyhow::Error> {
+    if !config.is_tls_enabled() {
+        return Ok(None);
+    }
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    acceptor
+        .set_private_key_file(config.tls_key_path.as_ref().unwrap(), SslFiletype::PEM)
+        .map_err(|e| anyhow!("error while loading tls private key {:?}", e))?;
+    acceptor
+        .set_certificate_chain_file(config.tls_cert_path.as_ref().unwrap())
+        .map_err(|e| anyhow!("error while loading tls certificate {:?}", e))?;
+    acceptor.check_private_key()?;
+    if let Some(ca_path) = &config.tls_ca_path {
+        acceptor
+            .set_ca_file(ca_path)
+            .map_err(|e| anyhow!("error while loading tls ca bundle {:?}", e))?;
+    }
+    if let Some(version) = &config.tls_min_version {
+        acceptor.set_min_proto_version(Some(parse_tls_version(version)?))?;
+    }
+    if let Some(ciphers) = &config.tls_ciphers {
+        acceptor.set_cipher_list(ciphers)?;
+    }
+    // clients must present a certificate signed by the configured ca, if they are
+    // authenticated using certificate. otherwise the certificate is optional, but the
+    // handshake fails if the presented certificate is not signed by the configured ca.
+    if config.client_auth_method() == ClientAuthMethod::Cert {
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    } else if config.tls_ca_path.is_some() {
+        acceptor.set_verify(SslVerifyMode::PEER);
+    }
+    // postgresql is the only alpn protocol accepted. clients which don't send alpn can
+    // still use ssl request.
+    acceptor.set_alpn_select_callback(|_, client_protocols| {
+        select_next_proto(ALPN_POSTGRESQL, client_protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(Some(acceptor.build()))
+}
+
+/// get_target_ssl_connector builds the ssl connector used to connect with the target
+/// postgres. None is returned if the sslmode is disable.
+pub fn get_target_ssl_connector(
+    config: &PostgresConfig,
+) -> Result<Option<SslConnector>, anyhow::Error> {
+    if config.target_ssl_mode() == SslMode::Disable {
+        return Ok(None);
+    }
+    let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+    if let Some(root_cert) = &config.target_ssl_root_cert {
+        connector
+            .set_ca_file(root_cert)
+            .map_err(|e| anyhow!("error while loading target ssl root cert {:?}", e))?;
+    }
+    Ok(Some(connector.build()))
+}
+
+/// get_credential_store loads the local credential store if it's configured.
+pub fn get_credential_store(
+    config: &PostgresConfig,
+) -> Result<Option<Arc<CredentialStore>>, anyhow::Error> {
+    match &config.credential_store_path {
+        Some(path) => Ok(Some(Arc::new(CredentialStore::load(
+            &std::path::PathBuf::from(path),
+        )?))),
+        None => Ok(None),
+    }
+}
+
+/// get_cert_mapper loads the client certificate mapping if it's configured.
+pub fn get_cert_mapper(
+    config: &PostgresConfig,
+) -> Result<Option<Arc<CertificateMapper>>, anyhow::Error> {
+    match &config.client_cert_mapping_path {
+        Some(path) => Ok(Some(Arc::new(CertificateMapper::load(
+            &std::path::PathBuf::from(path),
+        )?))),
+        None => Ok(None),
+    }
+}
+
+/// get_target_pool builds the target connection pool for the configured pool mode.
+pub fn get_target_pool(config: &PostgresConfig) -> TargetPool {
+    TargetPool::new(config.pool_mode(), config.pool_size())
+}
+
+// negotiate_protocol_version removes the protocol options from the startup params and
+// returns NegotiateProtocolVersion message if the client asked for newer minor version or
+// any protocol options.
+pub fn negotiate_protocol_version(
+    version: i32,
+    params: &mut HashMap<String, String>,
+) -> Option<BackendMessage> {
+    let mut options: Vec<String> = params
+        .keys()
+        .filter(|key| key.starts_with(PROTOCOL_OPTION_PREFIX))
+        .cloned()
+        .collect();
+    options.sort();
+    for option in &options {
+        params.remove(option);
+    }
+    if version & 0xffff <= PROTOCOL_MINOR_VERSION && options.is_empty() {
+        return None;
+    }
+    Some(BackendMessage::NegotiateProtocolVersion {
+        minor: PROTOCOL_MINOR_VERSION,
+        options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use std::env;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_ssl_acceptor() {
+        let path = env::current_dir().unwrap();
+        let mut config = PostgresConfig::default();
+        assert!(get_ssl_acceptor(&config).unwrap().is_none());
+
+        config.tls_cert_path = Some(path.join("cert.pem").to_str().unwrap().to_string());
+        config.tls_key_path = Some(path.join("key.pem").to_str().unwrap().to_string());
+        config.tls_min_version = Some(String::from("TLSv1.2"));
+        assert!(get_ssl_acceptor(&config).unwrap().is_some());
+
+        config.tls_key_path = Some(path.join("missing.pem").to_str().unwrap().to_string());
+        assert!(get_ssl_acceptor(&config).is_err());
+    }
+
+    fn test_acceptor() -> Option<SslAcceptor> {
+        let path = env::current_dir().unwrap();
+        let mut config = PostgresConfig::default();
+        config.tls_cert_path = Some(path.join("cert.pem").to_str().unwrap().to_string());
+        config.tls_key_path = Some(path.join("key.pem").to_str().unwrap().to_string());
+        get_ssl_acceptor(&config).unwrap()
+    }
+
+    // loopback returns the client and server side of a local tcp connection.
+    async fn loopback() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    async fn connect_tls(
+        stream: TcpStream,
+        alpn: Option<&[u8]>,
+    ) -> Result<SslStream<TcpStream>, anyhow::Error> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_verify(SslVerifyMode::NONE);
+        if let Some(alpn) = alpn {
+            builder.set_alpn_protos(alpn)?;
+        }
+        let ssl = builder.build().configure()?.into_ssl("localhost")?;
+        let mut stream = SslStream::new(ssl, stream)?;
+        Pin::new(&mut stream).connect().await?;
+        Ok(stream)
+    }
+
+    #[tokio::test]
+    async fn test_accept_direct_tls() {
+        let acceptor = test_acceptor();
+
+        let (client, server) = loopback().await;
+        let (client, server) = tokio::join!(
+            connect_tls(client, Some(ALPN_POSTGRESQL)),
+            accept_direct_tls(&acceptor, PostgresConn::Unsecured(server))
+        );
+        assert_eq!(
+            client.unwrap().ssl().selected_alpn_protocol(),
+            Some(&b"postgresql"[..])
+        );
+        assert!(server.unwrap().is_secured());
+
+        // alpn is mandatory for direct tls.
+        for alpn in [None, Some(&b"\x08http/1.1"[..])] {
+            let (client, server) = loopback().await;
+            let (_, server) = tokio::join!(
+                connect_tls(client, alpn),
+                accept_direct_tls(&acceptor, PostgresConn::Unsecured(server))
+            );
+            assert!(server.is_err());
+        }
+
+        // ssl request is still upgraded after checking for direct tls.
+        let (mut client, server) = loopback().await;
+        let client = async move {
+            let mut buf = BytesMut::new();
+            FrontendMessage::SslRequest.encode(&mut buf);
+            client.write_all(&buf).await?;
+            let mut rsp = [0; 1];
+            client.read_exact(&mut rsp).await?;
+            assert_eq!(rsp[0], ACCEPT_SSL_ENCRYPTION);
+            connect_tls(client, None).await
+        };
+        let server = async {
+            let mut conn = accept_direct_tls(&acceptor, PostgresConn::Unsecured(server)).await?;
+            assert!(!conn.is_secured());
+            assert!(matches!(
+                decode_init_startup_message(&mut conn).await?,
+                FrontendMessage::SslRequest
+            ));
+            upgrade_to_tls(&acceptor, conn).await
+        };
+        let (client, server) = tokio::join!(client, server);
+        assert!(client.is_ok());
+        assert!(server.unwrap().is_secured());
+    }
+
+    #[test]
+    fn test_target_ssl_connector() {
+        let path = env::current_dir().unwrap();
+        let mut config = PostgresConfig::default();
+        assert!(get_target_ssl_connector(&config).unwrap().is_some());
+
+        config.target_sslmode = Some(String::from("disable"));
+        assert!(get_target_ssl_connector(&config).unwrap().is_none());
+
+        config.target_sslmode = Some(String::from("verify-ca"));
+        config.target_ssl_root_cert = Some(path.join("cert.pem").to_str().unwrap().to_string());
+        assert!(get_target_ssl_connector(&config).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        let mut params = HashMap::from([
+            ("user".to_string(), "debuggeruser".to_string()),
+            ("database".to_string(), "postgres".to_string()),
+        ]);
+        assert!(negotiate_protocol_version(VERSION_3, &mut params).is_none());
+        assert_eq!(params.len(), 2);
+
+        match negotiate_protocol_version(VERSION_3 + 2, &mut params) {
+            Some(BackendMessage::NegotiateProtocolVersion { minor, options }) => {
+                assert_eq!(minor, 0);
+                assert!(options.is_empty());
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        params.insert("_pq_.compression".to_string(), "on".to_string());
+        params.insert("_pq_.command_tag".to_string(), "1".to_string());
+        match negotiate_protocol_version(VERSION_3, &mut params) {
+            Some(BackendMessage::NegotiateProtocolVersion { minor, options }) => {
+                assert_eq!(minor, 0);
+                assert_eq!(options, vec!["_pq_.command_tag", "_pq_.compression"]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(params.len(), 2);
+        assert!(!params.contains_key("_pq_.compression"));
+    }
+}
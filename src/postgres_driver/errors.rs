@@ -1,7 +1,17 @@
+use crate::config::ErrorVerbosity;
+use crate::postgres_driver::message::ErrorFields;
 use crate::sql::error::QueryRewriterError;
+use sqlparser::parser::ParserError;
 use std::io;
 use thiserror::Error;
 
+// sqlstates of the errors sent to the client.
+const SYNTAX_ERROR: &str = "42601";
+const INSUFFICIENT_PRIVILEGE: &str = "42501";
+const FEATURE_NOT_SUPPORTED: &str = "0A000";
+const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+const INTERNAL_ERROR: &str = "XX000";
+
 #[derive(Error, Debug)]
 pub enum DecoderError {
     #[error("inspektor only support postgres version 3")]
@@ -19,9 +29,272 @@ pub enum ProtocolHandlerError {
     #[error("unauthorized insert")]
     UnathorizedInsert,
     #[error("unable to parse the query")]
-    ErrParsingQuery,
+    ErrParsingQuery(ParserError),
+    #[error("prepared statement {0:?} is invalidated by the policy change")]
+    StalePreparedStatement(String),
+    #[error("{0} is not supported in transaction pool mode")]
+    UnsupportedInTransactionPool(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
     #[error("{0}")]
     RewriterError(#[from] QueryRewriterError),
 }
+
+impl ProtocolHandlerError {
+    // error_fields returns the fields of the error response sent to the client. query is
+    // the query string of the rejected message, which is used to find the position of
+    // the error.
+    pub fn error_fields(&self, query: Option<&str>, verbosity: ErrorVerbosity) -> ErrorFields {
+        if verbosity == ErrorVerbosity::Terse {
+            let mut fields = ErrorFields::new(self.sqlstate(), self.terse_msg());
+            // position of the syntax error doesn't tell anything about the objects.
+            if let Some(e) = self.parser_error() {
+                fields.position = query.and_then(|query| parser_error_position(query, e));
+            }
+            return fields;
+        }
+        let mut fields = ErrorFields::new(self.sqlstate(), self.to_string());
+        fields.detail = self.detail(verbosity);
+        fields.hint = self.hint();
+        fields.position = query.and_then(|query| self.position(query));
+        fields
+    }
+
+    // sqlstate returns the error code of the error.
+    pub fn sqlstate(&self) -> &'static str {
+        match self {
+            ProtocolHandlerError::PolicyRejected | ProtocolHandlerError::UnathorizedInsert => {
+                INSUFFICIENT_PRIVILEGE
+            }
+            ProtocolHandlerError::ErrParsingQuery(_) => SYNTAX_ERROR,
+            ProtocolHandlerError::StalePreparedStatement(_) => INVALID_SQL_STATEMENT_NAME,
+            ProtocolHandlerError::UnsupportedInTransactionPool(_) => FEATURE_NOT_SUPPORTED,
+            ProtocolHandlerError::Other(_) => INTERNAL_ERROR,
+            ProtocolHandlerError::RewriterError(e) => match e {
+                QueryRewriterError::PaserError(_) => SYNTAX_ERROR,
+                QueryRewriterError::FromNeedAlias | QueryRewriterError::Unsupported(_) => {
+                    FEATURE_NOT_SUPPORTED
+                }
+                QueryRewriterError::RewriteExpr { .. } => INTERNAL_ERROR,
+                _ => INSUFFICIENT_PRIVILEGE,
+            },
+        }
+    }
+
+    // terse_msg returns the error message without the table and column names.
+    fn terse_msg(&self) -> String {
+        match self {
+            ProtocolHandlerError::Other(_) => String::from("internal error"),
+            ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn((
+                Some(_),
+                column,
+            ))) if column.is_empty() => String::from("unauthorized table"),
+            ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn(_)) => {
+                String::from("unauthorized column")
+            }
+            ProtocolHandlerError::RewriterError(QueryRewriterError::RewriteExpr { .. }) => {
+                String::from("unable to rewrite the query")
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    fn detail(&self, verbosity: ErrorVerbosity) -> Option<String> {
+        if let Some(e) = self.parser_error() {
+            return Some(e.to_string());
+        }
+        match self {
+            // internal cause may have anything, so it's sent only if it's asked for.
+            ProtocolHandlerError::Other(e) if verbosity == ErrorVerbosity::Verbose => {
+                Some(format!("{:#}", e))
+            }
+            ProtocolHandlerError::RewriterError(QueryRewriterError::Unsupported(_)) => Some(
+                String::from("The statement can't be validated against the policy by the proxy."),
+            ),
+            _ => None,
+        }
+    }
+
+    fn hint(&self) -> Option<String> {
+        let hint = match self {
+            ProtocolHandlerError::StalePreparedStatement(_) => "Prepare the statement again.",
+            ProtocolHandlerError::UnsupportedInTransactionPool(_) => {
+                "Use the protocol level prepared statements, which are prepared again on every transaction."
+            }
+            ProtocolHandlerError::RewriterError(QueryRewriterError::FromNeedAlias) => {
+                "Add an alias to the subquery in FROM."
+            }
+            ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedCopyTarget) => {
+                "Use COPY TO STDOUT or COPY FROM STDIN instead."
+            }
+            ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn(_)) => {
+                "Ask the administrator to grant access in the policy."
+            }
+            _ => return None,
+        };
+        Some(String::from(hint))
+    }
+
+    // position returns the one based character position of the error in the query.
+    fn position(&self, query: &str) -> Option<usize> {
+        if let Some(e) = self.parser_error() {
+            return parser_error_position(query, e);
+        }
+        match self {
+            ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn((
+                table,
+                column,
+            ))) => {
+                if !column.is_empty() {
+                    return identifier_position(query, column);
+                }
+                let table = table.as_ref()?;
+                identifier_position(query, table).or_else(|| {
+                    let name = table.rsplit('.').next()?;
+                    identifier_position(query, name)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn parser_error(&self) -> Option<&ParserError> {
+        match self {
+            ProtocolHandlerError::ErrParsingQuery(e)
+            | ProtocolHandlerError::RewriterError(QueryRewriterError::PaserError(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// parser_error_position returns the position of the tokenizer error, which is reported
+// with the line and column of the error. eg: "... at Line: 1, Column 15"
+fn parser_error_position(query: &str, e: &ParserError) -> Option<usize> {
+    let msg = e.to_string();
+    let location = &msg[msg.rfind("Line: ")? + "Line: ".len()..];
+    let (line, column) = location.split_once(", Column")?;
+    let line = line.trim().parse::<usize>().ok()?;
+    let column = column
+        .trim_start_matches(':')
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    if line == 0 || column == 0 {
+        return None;
+    }
+    let mut lines = query.split('\n');
+    let mut position = 0;
+    for _ in 1..line {
+        position += lines.next()?.chars().count() + 1;
+    }
+    Some(position + column)
+}
+
+// identifier_position returns the position of the first occurrence of the given identifier
+// in the query. identifiers are matched case insensitively.
+fn identifier_position(query: &str, identifier: &str) -> Option<usize> {
+    if identifier.is_empty() {
+        return None;
+    }
+    let haystack = query.to_ascii_lowercase();
+    let needle = identifier.to_ascii_lowercase();
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    for (start, _) in haystack.match_indices(&needle) {
+        let end = start + needle.len();
+        let before = haystack[..start].chars().next_back();
+        let after = haystack[end..].chars().next();
+        if before.map_or(true, |c| !is_ident_char(c)) && after.map_or(true, |c| !is_ident_char(c)) {
+            return Some(query[..start].chars().count() + 1);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_fields() {
+        let query = "SELECT id, Secret FROM users";
+        let err = ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn((
+            Some(String::from("users")),
+            String::from("secret"),
+        )));
+        let fields = err.error_fields(Some(query), ErrorVerbosity::Default);
+        assert_eq!(fields.code, INSUFFICIENT_PRIVILEGE);
+        assert_eq!(
+            fields.message,
+            "unauthorized column \"secret\" for the table \"users\""
+        );
+        assert_eq!(fields.position, Some(12));
+        assert!(fields.hint.is_some());
+
+        // object names are not sent in terse mode.
+        let fields = err.error_fields(Some(query), ErrorVerbosity::Terse);
+        assert_eq!(
+            fields,
+            ErrorFields::new(INSUFFICIENT_PRIVILEGE, String::from("unauthorized column"))
+        );
+
+        let err = ProtocolHandlerError::RewriterError(QueryRewriterError::UnAuthorizedColumn((
+            Some(String::from("public.users")),
+            String::new(),
+        )));
+        let fields = err.error_fields(Some("select * from users"), ErrorVerbosity::Default);
+        assert_eq!(fields.position, Some(15));
+        let fields = err.error_fields(Some("select * from users"), ErrorVerbosity::Terse);
+        assert_eq!(fields.message, "unauthorized table");
+
+        let err = ProtocolHandlerError::ErrParsingQuery(ParserError::TokenizerError(String::from(
+            "Unterminated string literal at Line: 2, Column 8",
+        )));
+        let fields = err.error_fields(Some("select 1;\nselect 'a"), ErrorVerbosity::Terse);
+        assert_eq!(fields.code, SYNTAX_ERROR);
+        assert_eq!(fields.position, Some(18));
+        let fields = err.error_fields(Some("select 1;\nselect 'a"), ErrorVerbosity::Default);
+        assert!(fields.detail.is_some());
+
+        let err = ProtocolHandlerError::Other(anyhow::anyhow!("policy evaluation failed"));
+        let fields = err.error_fields(None, ErrorVerbosity::Default);
+        assert_eq!(fields.code, INTERNAL_ERROR);
+        assert_eq!(fields.detail, None);
+        let fields = err.error_fields(None, ErrorVerbosity::Verbose);
+        assert_eq!(
+            fields.detail,
+            Some(String::from("policy evaluation failed"))
+        );
+        let fields = err.error_fields(None, ErrorVerbosity::Terse);
+        assert_eq!(fields.message, "internal error");
+
+        let err = ProtocolHandlerError::RewriterError(QueryRewriterError::Unsupported(
+            String::from("LATERAL"),
+        ));
+        assert_eq!(err.sqlstate(), FEATURE_NOT_SUPPORTED);
+        let err = ProtocolHandlerError::StalePreparedStatement(String::from("s1"));
+        assert_eq!(err.sqlstate(), INVALID_SQL_STATEMENT_NAME);
+        let err = ProtocolHandlerError::UnsupportedInTransactionPool(String::from("PREPARE"));
+        let fields = err.error_fields(Some("PREPARE s1 AS SELECT 1"), ErrorVerbosity::Default);
+        assert_eq!(fields.code, FEATURE_NOT_SUPPORTED);
+        assert_eq!(
+            fields.message,
+            "PREPARE is not supported in transaction pool mode"
+        );
+        assert!(fields.hint.is_some());
+    }
+
+    #[test]
+    fn test_identifier_position() {
+        assert_eq!(identifier_position("select ssn from users", "ssn"), Some(8));
+        // identifier must not be a part of another identifier.
+        assert_eq!(
+            identifier_position("select ssn_hash, ssn from users", "ssn"),
+            Some(18)
+        );
+        assert_eq!(
+            identifier_position("select 'é', ssn from users", "ssn"),
+            Some(13)
+        );
+        assert_eq!(identifier_position("select id from users", "ssn"), None);
+    }
+}
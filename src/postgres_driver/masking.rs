@@ -0,0 +1,829 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::postgres_driver::copy::CopyMasker;
+use crate::postgres_driver::message::{BackendMessage, FieldDescription, FrontendMessage, Value};
+use crate::postgres_driver::schema::Schema;
+use crate::sql::masking::MaskingStrategy;
+use anyhow::anyhow;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::collections::{HashMap, VecDeque};
+
+// oids of the postgres types that masking knows to encode. refer pg_type.dat of postgres.
+const BOOL_OID: u32 = 16;
+const BYTEA_OID: u32 = 17;
+const NAME_OID: u32 = 19;
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const TEXT_OID: u32 = 25;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+const UNKNOWN_OID: u32 = 705;
+const BPCHAR_OID: u32 = 1042;
+const VARCHAR_OID: u32 = 1043;
+const NUMERIC_OID: u32 = 1700;
+
+const BINARY_FORMAT: i16 = 1;
+const REDACTED: &str = "****";
+const PARTIAL_VISIBLE_CHARS: usize = 4;
+
+/// MaskStrategy tells how the value of a masked column is replaced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskStrategy {
+    // Redact replaces the whole value.
+    Redact,
+    // Hash replaces the value with a deterministic keyed hash, so that the masked values
+    // can still be grouped and joined. hash is keyed with the secret of the deployment,
+    // so that the values can't be found by hashing the guessed values.
+    Hash,
+    // Partial keeps the last few characters of the value. eg: ******1234
+    Partial,
+}
+
+impl MaskStrategy {
+    pub fn from_str(strategy: &str) -> Result<MaskStrategy, anyhow::Error> {
+        match strategy {
+            "redact" => Ok(MaskStrategy::Redact),
+            "hash" => Ok(MaskStrategy::Hash),
+            "partial" => Ok(MaskStrategy::Partial),
+            _ => Err(anyhow!(
+                "unsupported mask strategy {}. expected one of redact, hash, partial",
+                strategy
+            )),
+        }
+    }
+}
+
+/// ColumnMasks holds the mask strategy of the table columns. columns are keyed by the table
+/// oid and attribute number, which are the one sent in RowDescription message.
+pub type ColumnMasks = HashMap<(u32, i16), MaskStrategy>;
+
+/// TableColumn is the column of the table which has masked columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableColumn {
+    pub name: String,
+    pub type_oid: u32,
+    pub strategy: Option<MaskStrategy>,
+}
+
+/// MaskedTables holds the columns of the tables which have masked columns, in the order
+/// of the attribute number. tables are keyed by schema.table. COPY doesn't describe the
+/// copied columns, so the columns are resolved by the name.
+pub type MaskedTables = HashMap<String, Vec<TableColumn>>;
+
+/// ResultMasker masks the DataRow values of a single result set.
+#[derive(Debug, Clone, Default)]
+pub struct ResultMasker {
+    // columns holds the mask strategy, type oid and format of the masked columns.
+    columns: Vec<Option<(MaskStrategy, u32, i16)>>,
+    hash_key: Vec<u8>,
+}
+
+impl ResultMasker {
+    // new returns masker for the given row description. result_formats are the format codes
+    // of the bind message, which decides the actual format of the columns.
+    pub fn new(
+        fields: &Vec<FieldDescription>,
+        result_formats: &Vec<i16>,
+        masks: &ColumnMasks,
+        hash_key: &[u8],
+    ) -> ResultMasker {
+        let columns = fields
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let strategy = masks.get(&(field.table_oid, field.column_id))?;
+                let format = match result_formats.len() {
+                    0 => field.format,
+                    1 => result_formats[0],
+                    _ => *result_formats.get(idx).unwrap_or(&field.format),
+                };
+                Some((*strategy, field.type_oid, format))
+            })
+            .collect::<Vec<_>>();
+        ResultMasker {
+            columns,
+            hash_key: hash_key.to_vec(),
+        }
+    }
+
+    // is_empty tells whether the result set has any masked column.
+    pub fn is_empty(&self) -> bool {
+        self.columns.iter().all(|column| column.is_none())
+    }
+
+    // mask_row replaces the values of the masked columns in the given row.
+    pub fn mask_row(&self, values: &mut Vec<Value>) {
+        for (column, value) in self.columns.iter().zip(values.iter_mut()) {
+            let (strategy, type_oid, format) = match column {
+                Some(column) => column,
+                None => continue,
+            };
+            if let Value::NotNull(val) = value {
+                *value = mask_value(*strategy, *type_oid, *format, val, &self.hash_key);
+            }
+        }
+    }
+}
+
+// PendingResponse is the client request whose response is not yet received from the target.
+#[derive(Debug, Clone, PartialEq)]
+enum PendingResponse {
+    DescribeStatement(String),
+    DescribePortal(String),
+    Execute(String),
+    Query,
+    Sync,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Portal {
+    statement: String,
+    result_formats: Vec<i16>,
+    // fields are set if the client describes the portal.
+    fields: Option<Vec<FieldDescription>>,
+}
+
+/// ResultTracker follows the client requests and target responses to know the columns of
+/// every DataRow message. extended query protocol sends the RowDescription only for the
+/// Describe message, so the description of the prepared statements and the formats of
+/// the portals are remembered.
+#[derive(Debug, Default)]
+pub struct ResultTracker {
+    statements: HashMap<String, Vec<FieldDescription>>,
+    portals: HashMap<String, Portal>,
+    pending: VecDeque<PendingResponse>,
+    masker: Option<ResultMasker>,
+    // next_copy is the masker of the COPY statement which is going to be forwarded.
+    next_copy: Option<CopyMasker>,
+    // copy maskers of the prepared statements and the pending queries.
+    statement_copies: HashMap<String, CopyMasker>,
+    query_copies: VecDeque<Option<CopyMasker>>,
+    copy: Option<CopyMasker>,
+    // hash_key is the secret used to hash the masked values.
+    hash_key: Vec<u8>,
+}
+
+impl ResultTracker {
+    pub fn new(hash_key: Vec<u8>) -> ResultTracker {
+        ResultTracker {
+            hash_key,
+            ..Default::default()
+        }
+    }
+
+    // expect_copy sets the masker of the COPY statement of the next Query or Parse message.
+    pub fn expect_copy(&mut self, masker: CopyMasker) {
+        self.next_copy = Some(masker);
+    }
+
+    // on_frontend tracks the client message which is forwarded to the target.
+    pub fn on_frontend(&mut self, msg: &FrontendMessage) {
+        match msg {
+            FrontendMessage::Query { .. } => {
+                self.pending.push_back(PendingResponse::Query);
+                self.query_copies.push_back(self.next_copy.take());
+            }
+            FrontendMessage::Parse { name, .. } => {
+                self.statements.remove(name);
+                match self.next_copy.take() {
+                    Some(masker) => self.statement_copies.insert(name.clone(), masker),
+                    None => self.statement_copies.remove(name),
+                };
+            }
+            FrontendMessage::Bind {
+                destination_portal_name,
+                prepared_statement_name,
+                result_column_format_codes,
+                ..
+            } => {
+                self.portals.insert(
+                    destination_portal_name.clone(),
+                    Portal {
+                        statement: prepared_statement_name.clone(),
+                        result_formats: result_column_format_codes.clone(),
+                        fields: None,
+                    },
+                );
+            }
+            FrontendMessage::Describe {
+                is_prepared_statement,
+                name,
+            } => {
+                let pending = match is_prepared_statement {
+                    true => PendingResponse::DescribeStatement(name.clone()),
+                    false => PendingResponse::DescribePortal(name.clone()),
+                };
+                self.pending.push_back(pending);
+            }
+            FrontendMessage::Execute { name, .. } => self
+                .pending
+                .push_back(PendingResponse::Execute(name.clone())),
+            FrontendMessage::Close { is_portal, name } => {
+                if *is_portal {
+                    self.portals.remove(name);
+                } else {
+                    self.statements.remove(name);
+                    self.statement_copies.remove(name);
+                }
+            }
+            FrontendMessage::Sync => self.pending.push_back(PendingResponse::Sync),
+            _ => {}
+        }
+    }
+
+    // on_backend tracks the target message and masks the values of the masked columns
+    // if it's a DataRow message. rows are not forwarded if the columns of the rows
+    // can't be found.
+    pub fn on_backend(
+        &mut self,
+        msg: &mut BackendMessage,
+        masks: &ColumnMasks,
+    ) -> Result<(), anyhow::Error> {
+        match msg {
+            BackendMessage::RowDescription { fields } => match self.pending.front() {
+                Some(PendingResponse::DescribeStatement(name)) => {
+                    self.statements.insert(name.clone(), fields.clone());
+                    self.pending.pop_front();
+                }
+                Some(PendingResponse::DescribePortal(name)) => {
+                    if let Some(portal) = self.portals.get_mut(name) {
+                        portal.fields = Some(fields.clone());
+                    }
+                    self.pending.pop_front();
+                }
+                _ => self.masker = Some(ResultMasker::new(fields, &vec![], masks, &self.hash_key)),
+            },
+            BackendMessage::DataRow { values } => {
+                if masks.len() == 0 {
+                    return Ok(());
+                }
+                if self.masker.is_none() {
+                    self.masker = Some(self.portal_masker(masks)?);
+                }
+                let masker = self.masker.as_ref().unwrap();
+                if !masker.is_empty() {
+                    masker.mask_row(values);
+                }
+            }
+            // NoData is the response of the Describe which doesn't return rows.
+            BackendMessage::Message { meta: b'n', .. } => match self.pending.front() {
+                Some(PendingResponse::DescribeStatement(_))
+                | Some(PendingResponse::DescribePortal(_)) => {
+                    self.pending.pop_front();
+                }
+                _ => {}
+            },
+            // CommandComplete, EmptyQueryResponse and PortalSuspended ends the result set.
+            BackendMessage::Message { meta: b'C', .. }
+            | BackendMessage::Message { meta: b'I', .. }
+            | BackendMessage::Message { meta: b's', .. } => {
+                self.masker = None;
+                if let Some(PendingResponse::Execute(_)) = self.pending.front() {
+                    self.pending.pop_front();
+                }
+            }
+            // CopyOutResponse starts the copied rows.
+            BackendMessage::Message { meta: b'H', data } => {
+                let masker = match self.pending.front() {
+                    Some(PendingResponse::Query) => self
+                        .query_copies
+                        .front_mut()
+                        .and_then(|masker| masker.take()),
+                    Some(PendingResponse::Execute(name)) => self
+                        .portals
+                        .get(name)
+                        .and_then(|portal| self.statement_copies.get(&portal.statement))
+                        .cloned(),
+                    _ => None,
+                };
+                self.copy = match masker {
+                    Some(masker) => {
+                        if data.len() < 3 {
+                            return Err(anyhow!("invalid copy out response"));
+                        }
+                        let columns = i16::from_be_bytes([data[1], data[2]]);
+                        masker.validate(data[0] == 1, columns as usize)?;
+                        Some(masker)
+                    }
+                    None if masks.len() == 0 => None,
+                    None => return Err(anyhow!("unable to find the columns of the copy to mask")),
+                };
+            }
+            // CopyData of the copied rows.
+            BackendMessage::Message { meta: b'd', data } => {
+                if let Some(copy) = self.copy.as_mut() {
+                    copy.mask_data(data)?;
+                }
+            }
+            // CopyDone ends the copied rows.
+            BackendMessage::Message { meta: b'c', .. } => self.copy = None,
+            BackendMessage::ErrorMsg(..) => {
+                // target skips all the messages until Sync after an error.
+                self.masker = None;
+                self.copy = None;
+                while let Some(pending) = self.pending.front() {
+                    if *pending == PendingResponse::Sync || *pending == PendingResponse::Query {
+                        break;
+                    }
+                    self.pending.pop_front();
+                }
+            }
+            BackendMessage::ReadyForQuery { .. } => {
+                self.masker = None;
+                self.copy = None;
+                if let Some(PendingResponse::Query) = self.pending.pop_front() {
+                    self.query_copies.pop_front();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // portal_masker returns the masker of the portal which is being executed.
+    fn portal_masker(&self, masks: &ColumnMasks) -> Result<ResultMasker, anyhow::Error> {
+        let portal = match self.pending.front() {
+            Some(PendingResponse::Execute(name)) => self.portals.get(name),
+            _ => None,
+        };
+        let portal =
+            portal.ok_or_else(|| anyhow!("unable to find the portal of the result rows"))?;
+        let fields = match &portal.fields {
+            Some(fields) => fields,
+            None => self
+                .statements
+                .get(&portal.statement)
+                .ok_or_else(|| anyhow!("unable to find the columns of the result rows to mask"))?,
+        };
+        Ok(ResultMasker::new(
+            fields,
+            &portal.result_formats,
+            masks,
+            &self.hash_key,
+        ))
+    }
+}
+
+// masked_relations returns the masked columns of the tables along with the views which
+// read the masked tables, keyed by schema.table. result rows are masked by the table oid
+// and attribute number of the column, which are not sent for the computed values and the
+// columns of the views. so the masked columns can be selected only as it is and the views
+// of the masked tables are protected entirely.
+pub fn masked_relations(
+    schema: &Schema,
+    masked_tables: &MaskedTables,
+) -> HashMap<String, Vec<String>> {
+    let mut relations = masked_tables
+        .iter()
+        .map(|(table, columns)| {
+            let masked = columns
+                .iter()
+                .filter(|column| column.strategy.is_some())
+                .map(|column| column.name.clone())
+                .collect::<Vec<String>>();
+            (table.clone(), masked)
+        })
+        .collect::<HashMap<String, Vec<String>>>();
+    // views can be defined over the other views.
+    loop {
+        let mut changed = false;
+        for dependency in &schema.views {
+            let view = format!("{}.{}", dependency.view_schema, dependency.view);
+            let table = format!("{}.{}", dependency.schema, dependency.table);
+            if !relations.contains_key(&view) && relations.contains_key(&table) {
+                relations.insert(view, vec![]);
+                changed = true;
+            }
+        }
+        if !changed {
+            return relations;
+        }
+    }
+}
+
+// protect_masked_relations adds the masked relations to the protected columns of the
+// rewriter. masked columns which are not protected by the policy are kept as it is in the
+// projection and empty columns protect the whole view.
+pub fn protect_masked_relations(
+    masked_relations: &HashMap<String, Vec<String>>,
+    protected_columns: &mut HashMap<String, Vec<String>>,
+    column_masks: &mut HashMap<String, HashMap<String, MaskingStrategy>>,
+) {
+    for (table, masked_columns) in masked_relations {
+        if masked_columns.is_empty() {
+            protected_columns.insert(table.clone(), vec![]);
+            continue;
+        }
+        let protected = protected_columns.contains_key(table);
+        let columns = protected_columns.entry(table.clone()).or_default();
+        // whole table is protected by the policy.
+        if protected && columns.is_empty() {
+            continue;
+        }
+        for column in masked_columns {
+            // column protected by the policy is rewritten by the policy.
+            if columns.contains(column) {
+                continue;
+            }
+            columns.push(column.clone());
+            column_masks
+                .entry(table.clone())
+                .or_default()
+                .insert(column.clone(), MaskingStrategy::DataRow);
+        }
+    }
+}
+
+// mask_value returns the masked value for the given value. masked value is encoded in the
+// same type and format, so that the client can still decode it. types which can't be
+// masked in place are replaced with NULL. hash_key is the secret of the hash strategy.
+pub fn mask_value(
+    strategy: MaskStrategy,
+    type_oid: u32,
+    format: i16,
+    value: &[u8],
+    hash_key: &[u8],
+) -> Value {
+    let binary = format == BINARY_FORMAT;
+    match type_oid {
+        // text types have the same representation in text and binary format.
+        TEXT_OID | VARCHAR_OID | BPCHAR_OID | NAME_OID | UNKNOWN_OID => {
+            let masked = match strategy {
+                MaskStrategy::Redact => REDACTED.to_string(),
+                MaskStrategy::Hash => match hmac_sha256(hash_key, value) {
+                    Some(digest) => to_hex(&digest),
+                    None => return Value::Null,
+                },
+                MaskStrategy::Partial => mask_partial(&String::from_utf8_lossy(value)),
+            };
+            Value::NotNull(masked.into_bytes())
+        }
+        INT2_OID | INT4_OID | INT8_OID => {
+            let masked = match (strategy, decode_int(type_oid, binary, value)) {
+                (MaskStrategy::Hash, Some(val)) => {
+                    match hash_to_int(hash_key, &val.to_string(), type_oid) {
+                        Some(hash) => hash,
+                        None => return Value::Null,
+                    }
+                }
+                _ => 0,
+            };
+            Value::NotNull(encode_int(masked, type_oid, binary))
+        }
+        BYTEA_OID => {
+            let masked = match strategy {
+                MaskStrategy::Hash => match hmac_sha256(hash_key, value) {
+                    Some(digest) => digest,
+                    None => return Value::Null,
+                },
+                _ => vec![],
+            };
+            if binary {
+                return Value::NotNull(masked);
+            }
+            Value::NotNull(format!("\\x{}", to_hex(&masked)).into_bytes())
+        }
+        FLOAT4_OID | FLOAT8_OID | NUMERIC_OID | BOOL_OID => {
+            if !binary {
+                let zero = if type_oid == BOOL_OID { "f" } else { "0" };
+                return Value::NotNull(zero.as_bytes().to_vec());
+            }
+            // zero value of these types is all zero bytes in binary format.
+            let len = match type_oid {
+                BOOL_OID => 1,
+                FLOAT4_OID => 4,
+                _ => 8,
+            };
+            Value::NotNull(vec![0; len])
+        }
+        _ => Value::Null,
+    }
+}
+
+// mask_partial masks all the characters except the last few. short values are masked
+// entirely.
+fn mask_partial(value: &str) -> String {
+    let chars = value.chars().collect::<Vec<char>>();
+    let visible = if chars.len() > PARTIAL_VISIBLE_CHARS {
+        PARTIAL_VISIBLE_CHARS
+    } else {
+        0
+    };
+    let mut masked = "*".repeat(chars.len() - visible);
+    masked.extend(&chars[chars.len() - visible..]);
+    masked
+}
+
+fn decode_int(type_oid: u32, binary: bool, value: &[u8]) -> Option<i64> {
+    if !binary {
+        return std::str::from_utf8(value).ok()?.parse::<i64>().ok();
+    }
+    match (type_oid, value.len()) {
+        (INT2_OID, 2) => Some(i16::from_be_bytes([value[0], value[1]]) as i64),
+        (INT4_OID, 4) => Some(i32::from_be_bytes([value[0], value[1], value[2], value[3]]) as i64),
+        (INT8_OID, 8) => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(value);
+            Some(i64::from_be_bytes(buf))
+        }
+        _ => None,
+    }
+}
+
+fn encode_int(value: i64, type_oid: u32, binary: bool) -> Vec<u8> {
+    if !binary {
+        return value.to_string().into_bytes();
+    }
+    match type_oid {
+        INT2_OID => (value as i16).to_be_bytes().to_vec(),
+        INT4_OID => (value as i32).to_be_bytes().to_vec(),
+        _ => value.to_be_bytes().to_vec(),
+    }
+}
+
+// hmac_sha256 returns the HMAC-SHA256 of the value keyed with the given secret. None is
+// returned if openssl fails to compute it.
+fn hmac_sha256(key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    let key = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(value).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+// hash_to_int returns non negative integer derived from the keyed hash of the value, which
+// fits in the given integer type.
+fn hash_to_int(key: &[u8], value: &str, type_oid: u32) -> Option<i64> {
+    let digest = hmac_sha256(key, value.as_bytes())?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&digest[..8]);
+    let hash = u64::from_be_bytes(buf);
+    let max = match type_oid {
+        INT2_OID => i16::MAX as u64,
+        INT4_OID => i32::MAX as u64,
+        _ => i64::MAX as u64,
+    };
+    Some((hash % max) as i64)
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres_driver::schema::ViewDependency;
+
+    const KEY: &[u8] = b"secret";
+
+    fn field(table_oid: u32, column_id: i16, type_oid: u32) -> FieldDescription {
+        FieldDescription {
+            name: String::from("col"),
+            table_oid,
+            column_id,
+            type_oid,
+            type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+
+    #[test]
+    fn test_mask_value() {
+        assert_eq!(
+            mask_value(MaskStrategy::Partial, TEXT_OID, 0, b"9876543210", KEY),
+            Value::NotNull(b"******3210".to_vec())
+        );
+        assert_eq!(
+            mask_value(MaskStrategy::Partial, VARCHAR_OID, 1, b"123", KEY),
+            Value::NotNull(b"***".to_vec())
+        );
+        assert_eq!(
+            mask_value(MaskStrategy::Redact, TEXT_OID, 0, b"secret", KEY),
+            Value::NotNull(b"****".to_vec())
+        );
+        // hash of the integer is same for both text and binary format.
+        let text = mask_value(MaskStrategy::Hash, INT4_OID, 0, b"42", KEY);
+        let binary = mask_value(MaskStrategy::Hash, INT4_OID, 1, &42i32.to_be_bytes(), KEY);
+        let hash = hash_to_int(KEY, "42", INT4_OID).unwrap() as i32;
+        assert_eq!(text, Value::NotNull(hash.to_string().into_bytes()));
+        assert_eq!(binary, Value::NotNull(hash.to_be_bytes().to_vec()));
+        // hash is keyed with the secret. refer the test case 2 of RFC 4231.
+        assert_eq!(
+            mask_value(
+                MaskStrategy::Hash,
+                TEXT_OID,
+                0,
+                b"what do ya want for nothing?",
+                b"Jefe"
+            ),
+            Value::NotNull(
+                b"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843".to_vec()
+            )
+        );
+        assert_ne!(
+            mask_value(MaskStrategy::Hash, TEXT_OID, 0, b"9876543210", KEY),
+            mask_value(MaskStrategy::Hash, TEXT_OID, 0, b"9876543210", b"other")
+        );
+        assert_eq!(
+            mask_value(MaskStrategy::Redact, INT8_OID, 1, &42i64.to_be_bytes(), KEY),
+            Value::NotNull(vec![0; 8])
+        );
+        assert_eq!(
+            mask_value(MaskStrategy::Redact, BYTEA_OID, 0, b"\\x0102", KEY),
+            Value::NotNull(b"\\x".to_vec())
+        );
+        // date can't be masked in place.
+        assert_eq!(
+            mask_value(MaskStrategy::Redact, 1082, 0, b"2022-01-01", KEY),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_result_masker() {
+        let masks = ColumnMasks::from([((16384, 2), MaskStrategy::Redact)]);
+        let fields = vec![field(16384, 1, INT4_OID), field(16384, 2, INT4_OID)];
+        let masker = ResultMasker::new(&fields, &vec![], &masks, KEY);
+        assert!(!masker.is_empty());
+        let mut row = vec![
+            Value::NotNull(b"1".to_vec()),
+            Value::NotNull(b"9876".to_vec()),
+        ];
+        masker.mask_row(&mut row);
+        assert_eq!(
+            row,
+            vec![Value::NotNull(b"1".to_vec()), Value::NotNull(b"0".to_vec())]
+        );
+
+        // bind format decides the format of the columns.
+        let masker = ResultMasker::new(&fields, &vec![1], &masks, KEY);
+        let mut row = vec![Value::NotNull(1i32.to_be_bytes().to_vec()), Value::Null];
+        masker.mask_row(&mut row);
+        assert_eq!(row[1], Value::Null);
+        let mut row = vec![
+            Value::NotNull(1i32.to_be_bytes().to_vec()),
+            Value::NotNull(9876i32.to_be_bytes().to_vec()),
+        ];
+        masker.mask_row(&mut row);
+        assert_eq!(row[1], Value::NotNull(vec![0; 4]));
+
+        let masker = ResultMasker::new(&vec![field(0, 0, TEXT_OID)], &vec![], &masks, KEY);
+        assert!(masker.is_empty());
+    }
+
+    #[test]
+    fn test_result_tracker() {
+        let masks = ColumnMasks::from([((16384, 2), MaskStrategy::Partial)]);
+        let fields = vec![field(16384, 1, INT4_OID), field(16384, 2, TEXT_OID)];
+        let mut tracker = ResultTracker::default();
+        // prepared statement is described once and executed later with binary format.
+        for msg in [
+            FrontendMessage::Parse {
+                name: String::from("s1"),
+                query: String::from("SELECT id, phone FROM kids"),
+                object_ids: vec![],
+            },
+            FrontendMessage::Describe {
+                is_prepared_statement: true,
+                name: String::from("s1"),
+            },
+            FrontendMessage::Sync,
+        ] {
+            tracker.on_frontend(&msg);
+        }
+        for mut msg in [
+            BackendMessage::RowDescription {
+                fields: fields.clone(),
+            },
+            BackendMessage::ReadyForQuery {
+                state: crate::postgres_driver::message::TransactionStatus::Idle,
+            },
+        ] {
+            tracker.on_backend(&mut msg, &masks).unwrap();
+        }
+        for msg in [
+            FrontendMessage::Bind {
+                destination_portal_name: String::from(""),
+                prepared_statement_name: String::from("s1"),
+                parameter_format_codes: vec![],
+                parameter_values: vec![],
+                result_column_format_codes: vec![1],
+            },
+            FrontendMessage::Execute {
+                name: String::from(""),
+                max_no_of_rows: 0,
+            },
+            FrontendMessage::Sync,
+        ] {
+            tracker.on_frontend(&msg);
+        }
+        let mut row = BackendMessage::DataRow {
+            values: vec![
+                Value::NotNull(1i32.to_be_bytes().to_vec()),
+                Value::NotNull(b"9876543210".to_vec()),
+            ],
+        };
+        tracker.on_backend(&mut row, &masks).unwrap();
+        match row {
+            BackendMessage::DataRow { values } => {
+                assert_eq!(values[1], Value::NotNull(b"******3210".to_vec()))
+            }
+            _ => unreachable!(),
+        }
+
+        // rows of the unknown portal are not forwarded.
+        let mut tracker = ResultTracker::default();
+        tracker.on_frontend(&FrontendMessage::Execute {
+            name: String::from("p1"),
+            max_no_of_rows: 0,
+        });
+        let mut row = BackendMessage::DataRow {
+            values: vec![Value::NotNull(b"1".to_vec())],
+        };
+        assert!(tracker.on_backend(&mut row, &masks).is_err());
+
+        // copy which is not known to the tracker is not forwarded either.
+        let mut tracker = ResultTracker::default();
+        tracker.on_frontend(&FrontendMessage::Query {
+            query_string: String::from("COPY kids TO STDOUT"),
+        });
+        let mut copy_out = BackendMessage::Message {
+            meta: b'H',
+            data: vec![0, 0, 1, 0, 0],
+        };
+        assert!(tracker.on_backend(&mut copy_out, &masks).is_err());
+    }
+
+    #[test]
+    fn test_masked_relations() {
+        let view = |view: &str, table: &str| ViewDependency {
+            view_schema: String::from("public"),
+            view: String::from(view),
+            schema: String::from("public"),
+            table: String::from(table),
+        };
+        let schema = Schema {
+            columns: vec![],
+            views: vec![
+                view("kids_report", "kids_view"),
+                view("kids_view", "kids"),
+                view("parents_view", "parents"),
+            ],
+        };
+        let column = |name: &str, strategy: Option<MaskStrategy>| TableColumn {
+            name: String::from(name),
+            type_oid: TEXT_OID,
+            strategy,
+        };
+        let masked_tables = MaskedTables::from([(
+            String::from("public.kids"),
+            vec![
+                column("id", None),
+                column("phone", Some(MaskStrategy::Partial)),
+                column("email", Some(MaskStrategy::Redact)),
+            ],
+        )]);
+        let relations = masked_relations(&schema, &masked_tables);
+        assert_eq!(relations.len(), 3);
+        assert_eq!(
+            relations["public.kids"],
+            vec![String::from("phone"), String::from("email")]
+        );
+        // views of the masked table are protected entirely.
+        assert!(relations["public.kids_view"].is_empty());
+        assert!(relations["public.kids_report"].is_empty());
+
+        let mut protected_columns = HashMap::from([
+            (String::from("public.kids"), vec![String::from("email")]),
+            (String::from("public.kids_view"), vec![String::from("id")]),
+        ]);
+        let mut column_masks = HashMap::new();
+        protect_masked_relations(&relations, &mut protected_columns, &mut column_masks);
+        assert_eq!(
+            protected_columns["public.kids"],
+            vec![String::from("email"), String::from("phone")]
+        );
+        assert!(protected_columns["public.kids_view"].is_empty());
+        assert!(protected_columns["public.kids_report"].is_empty());
+        // email is protected by the policy, so it's not masked in the result rows.
+        assert_eq!(
+            column_masks["public.kids"],
+            HashMap::from([(String::from("phone"), MaskingStrategy::DataRow)])
+        );
+    }
+}
@@ -11,9 +11,24 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const VERSION_3: i32 = 0x30000;
 pub const VERSION_SSL: i32 = (1234 << 16) + 5679;
+pub const VERSION_CANCEL: i32 = (1234 << 16) + 5678;
+pub const VERSION_GSSENC: i32 = (1234 << 16) + 5680;
+// PROTOCOL_MAJOR_VERSION and PROTOCOL_MINOR_VERSION are the newest protocol version
+// supported by inspektor.
+pub const PROTOCOL_MAJOR_VERSION: i32 = 3;
+pub const PROTOCOL_MINOR_VERSION: i32 = 0;
+// PROTOCOL_OPTION_PREFIX is the prefix of the protocol options in the startup message.
+pub const PROTOCOL_OPTION_PREFIX: &str = "_pq_.";
 pub const ACCEPT_SSL_ENCRYPTION: u8 = b'S';
+pub const REJECT_SSL_ENCRYPTION: u8 = b'N';
+pub const REJECT_GSS_ENCRYPTION: u8 = b'N';
+// TLS_HANDSHAKE_RECORD is the first byte of the tls client hello. startup packets can't
+// start with it, since it's the most significant byte of the packet length.
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+// ALPN_POSTGRESQL is the wire format of the postgresql alpn protocol.
+pub const ALPN_POSTGRESQL: &[u8] = b"\x0apostgresql";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     NotNull(Vec<u8>),
@@ -45,6 +60,43 @@ impl TransactionStatus {
     }
 }
 
+/// FieldDescription describes a column of the result set sent by RowDescription message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    // table_oid and column_id are zero if the column is not a plain table column.
+    pub table_oid: u32,
+    pub column_id: i16,
+    pub type_oid: u32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    // format is zero for text and one for binary.
+    pub format: i16,
+}
+
+/// ErrorFields are the fields of the ErrorResponse sent to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorFields {
+    pub code: String,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    // position is the one based character index of the error in the query string.
+    pub position: Option<usize>,
+}
+
+impl ErrorFields {
+    pub fn new(code: &str, message: String) -> ErrorFields {
+        ErrorFields {
+            code: code.to_string(),
+            message,
+            detail: None,
+            hint: None,
+            position: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum BackendMessage {
     ErrorMsg(Vec<u8>),
@@ -55,6 +107,10 @@ pub enum BackendMessage {
     AuthenticationSASLContinue { data: Vec<u8> },
     AuthenticationSASLFinal { data: Vec<u8> },
     ReadyForQuery { state: TransactionStatus },
+    RowDescription { fields: Vec<FieldDescription> },
+    DataRow { values: Vec<Value> },
+    BackendKeyData { process_id: i32, secret_key: i32 },
+    NegotiateProtocolVersion { minor: i32, options: Vec<String> },
     Message { data: Vec<u8>, meta: u8 },
 }
 
@@ -68,6 +124,46 @@ impl BackendMessage {
                 buf.put_u32(3);
                 buf
             }
+            BackendMessage::AuthenticationMD5Password { salt } => {
+                buf.put_u8(b'R');
+                buf.put_u32(12);
+                buf.put_u32(5);
+                buf.extend_from_slice(&salt[..4]);
+                buf
+            }
+            BackendMessage::AuthenticationSASL { mechanisms } => {
+                buf.put_u8(b'R');
+                write_message(&mut buf, |buf| {
+                    buf.put_u32(10);
+                    for mechanism in mechanisms {
+                        write_cstr(buf, mechanism.as_bytes())?;
+                    }
+                    buf.put_u8(0);
+                    Ok(())
+                })
+                .unwrap();
+                buf
+            }
+            BackendMessage::AuthenticationSASLContinue { data } => {
+                buf.put_u8(b'R');
+                write_message(&mut buf, |buf| {
+                    buf.put_u32(11);
+                    buf.extend_from_slice(data);
+                    Ok(())
+                })
+                .unwrap();
+                buf
+            }
+            BackendMessage::AuthenticationSASLFinal { data } => {
+                buf.put_u8(b'R');
+                write_message(&mut buf, |buf| {
+                    buf.put_u32(12);
+                    buf.extend_from_slice(data);
+                    Ok(())
+                })
+                .unwrap();
+                buf
+            }
             BackendMessage::AuthenticationOk { success } => {
                 buf.put_u8(b'R');
                 buf.put_u32(8);
@@ -93,6 +189,71 @@ impl BackendMessage {
                 buf.put_u8(state.get_state_byte());
                 buf
             }
+            BackendMessage::RowDescription { fields } => {
+                buf.put_u8(b'T');
+                write_message(&mut buf, |buf| {
+                    write_counted_message(
+                        fields,
+                        |field, buf| {
+                            write_cstr(buf, field.name.as_bytes())?;
+                            buf.put_u32(field.table_oid);
+                            buf.put_i16(field.column_id);
+                            buf.put_u32(field.type_oid);
+                            buf.put_i16(field.type_size);
+                            buf.put_i32(field.type_modifier);
+                            buf.put_i16(field.format);
+                            Ok(())
+                        },
+                        buf,
+                    )
+                })
+                .unwrap();
+                buf
+            }
+            BackendMessage::DataRow { values } => {
+                buf.put_u8(b'D');
+                write_message(&mut buf, |buf| {
+                    write_counted_message(
+                        values,
+                        |value, buf| {
+                            match value {
+                                Value::Null => buf.put_i32(-1),
+                                Value::NotNull(val) => {
+                                    buf.put_i32(val.len() as i32);
+                                    buf.extend_from_slice(val);
+                                }
+                            }
+                            Ok(())
+                        },
+                        buf,
+                    )
+                })
+                .unwrap();
+                buf
+            }
+            BackendMessage::BackendKeyData {
+                process_id,
+                secret_key,
+            } => {
+                buf.put_u8(b'K');
+                buf.put_u32(12);
+                buf.put_i32(*process_id);
+                buf.put_i32(*secret_key);
+                buf
+            }
+            BackendMessage::NegotiateProtocolVersion { minor, options } => {
+                buf.put_u8(b'v');
+                write_message(&mut buf, |buf| {
+                    buf.put_i32(*minor);
+                    buf.put_i32(options.len() as i32);
+                    for option in options {
+                        write_cstr(buf, option.as_bytes())?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+                buf
+            }
             BackendMessage::Message { meta, data } => {
                 buf.put_u8(*meta);
                 write_message(&mut buf, |buf| {
@@ -109,15 +270,67 @@ impl BackendMessage {
     }
 
     pub fn err_msg(msg: String) -> BackendMessage {
+        BackendMessage::error_response("ERROR", "42501", msg)
+    }
+
+    // error_msg returns the error message with the given fields.
+    pub fn error_msg(fields: &ErrorFields) -> BackendMessage {
+        BackendMessage::ErrorMsg(BackendMessage::response_fields("ERROR", fields))
+    }
+
+    // fatal_msg returns the error message which tells the client that the
+    // connection is going to be terminated.
+    pub fn fatal_msg(code: &str, msg: String) -> BackendMessage {
+        BackendMessage::error_response("FATAL", code, msg)
+    }
+
+    // notice_msg returns the warning notice, which can be sent to the client at any time
+    // without interrupting the running query.
+    pub fn notice_msg(code: &str, msg: String) -> BackendMessage {
+        BackendMessage::Message {
+            meta: b'N',
+            data: BackendMessage::response_fields("WARNING", &ErrorFields::new(code, msg)),
+        }
+    }
+
+    // info_msg returns the notice which informs the client without warning it.
+    pub fn info_msg(fields: &ErrorFields) -> BackendMessage {
+        BackendMessage::Message {
+            meta: b'N',
+            data: BackendMessage::response_fields("NOTICE", fields),
+        }
+    }
+
+    fn error_response(severity: &str, code: &str, msg: String) -> BackendMessage {
+        BackendMessage::ErrorMsg(BackendMessage::response_fields(
+            severity,
+            &ErrorFields::new(code, msg),
+        ))
+    }
+
+    // response_fields encodes the fields of the ErrorResponse and NoticeResponse.
+    fn response_fields(severity: &str, fields: &ErrorFields) -> Vec<u8> {
         let mut buf = BytesMut::new();
         buf.put_u8(b'S');
-        write_cstr(&mut buf, "ERROR".to_string().as_bytes()).unwrap();
+        write_cstr(&mut buf, severity.as_bytes()).unwrap();
         buf.put_u8(b'C');
-        write_cstr(&mut buf, "42501".to_string().as_bytes()).unwrap();
+        write_cstr(&mut buf, fields.code.as_bytes()).unwrap();
         buf.put_u8(b'M');
-        write_cstr(&mut buf, msg.as_bytes()).unwrap();
+        write_cstr(&mut buf, fields.message.as_bytes()).unwrap();
+        if let Some(detail) = &fields.detail {
+            buf.put_u8(b'D');
+            write_cstr(&mut buf, detail.as_bytes()).unwrap();
+        }
+        if let Some(hint) = &fields.hint {
+            buf.put_u8(b'H');
+            write_cstr(&mut buf, hint.as_bytes()).unwrap();
+        }
+        if let Some(position) = fields.position {
+            buf.put_u8(b'P');
+            write_cstr(&mut buf, position.to_string().as_bytes()).unwrap();
+        }
         buf.put_u8(b'\0');
-        BackendMessage::ErrorMsg(buf.to_vec())
+        buf.to_vec()
     }
 
     pub async fn decode<T>(mut conn: T) -> Result<BackendMessage, anyhow::Error>
@@ -185,6 +398,49 @@ impl BackendMessage {
                 let state = TransactionStatus::from_u8(buf[0]);
                 return Ok(BackendMessage::ReadyForQuery { state: state });
             }
+            b'T' => {
+                let fields = read_counted_message(&mut buf, |buf| {
+                    let name = read_cstr(buf)?;
+                    if buf.len() < 18 {
+                        return Err(anyhow!("invalid row description"));
+                    }
+                    Ok(FieldDescription {
+                        name,
+                        table_oid: buf.get_u32(),
+                        column_id: buf.get_i16(),
+                        type_oid: buf.get_u32(),
+                        type_size: buf.get_i16(),
+                        type_modifier: buf.get_i32(),
+                        format: buf.get_i16(),
+                    })
+                })?;
+                return Ok(BackendMessage::RowDescription { fields });
+            }
+            b'D' => {
+                let values = read_counted_message(&mut buf, |buf| {
+                    if buf.len() < 4 {
+                        return Err(anyhow!("invalid data row"));
+                    }
+                    let len = buf.get_i32();
+                    if len < 0 {
+                        return Ok(Value::Null);
+                    }
+                    if buf.len() < len as usize {
+                        return Err(anyhow!("invalid data row"));
+                    }
+                    Ok(Value::NotNull(buf.split_to(len as usize).to_vec()))
+                })?;
+                return Ok(BackendMessage::DataRow { values });
+            }
+            b'K' => {
+                if buf.len() < 8 {
+                    return Err(anyhow!("invalid backend key data"));
+                }
+                return Ok(BackendMessage::BackendKeyData {
+                    process_id: buf.get_i32(),
+                    secret_key: buf.get_i32(),
+                });
+            }
             _ => {
                 return Ok(BackendMessage::Message {
                     data: buf.to_vec(),
@@ -201,6 +457,11 @@ pub enum FrontendMessage {
         password: String,
     },
     SslRequest,
+    GssEncRequest,
+    CancelRequest {
+        process_id: i32,
+        secret_key: i32,
+    },
     Startup {
         params: HashMap<String, String>,
         version: i32,
@@ -270,6 +531,19 @@ impl FrontendMessage {
                 buf.put_u32(8);
                 buf.put_i32(VERSION_SSL);
             }
+            FrontendMessage::GssEncRequest => {
+                buf.put_u32(8);
+                buf.put_i32(VERSION_GSSENC);
+            }
+            FrontendMessage::CancelRequest {
+                process_id,
+                secret_key,
+            } => {
+                buf.put_u32(16);
+                buf.put_i32(VERSION_CANCEL);
+                buf.put_i32(*process_id);
+                buf.put_i32(*secret_key);
+            }
             FrontendMessage::Startup { params, version } => {
                 write_message(buf, |buf| {
                     buf.put_i32(*version);
@@ -1,8 +1,21 @@
+mod cancel;
 mod conn;
+mod copy;
+mod credentials;
 pub mod driver;
 mod errors;
+mod masking;
 mod message;
+mod notice;
+mod pipeline;
+mod pool;
 mod protocol_handler;
+mod schema;
+mod scram;
+mod shutdown;
+mod startup;
+mod statements;
+mod timeouts;
 mod utils;
 
 // how transactions are handled.
@@ -0,0 +1,187 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::RewriteNotice;
+use crate::postgres_driver::message::{BackendMessage, ErrorFields};
+use crate::sql::query_rewriter::Rewrite;
+
+// SUCCESSFUL_COMPLETION is the sqlstate of the informational notices.
+const SUCCESSFUL_COMPLETION: &str = "00000";
+
+// rewrite_notice returns the notice which tells the user how the query is rewritten by
+// the policy. None is returned if the query is not rewritten or the notice is disabled.
+pub fn rewrite_notice(rewrites: &[Rewrite], level: RewriteNotice) -> Option<BackendMessage> {
+    if rewrites.is_empty() || level == RewriteNotice::None {
+        return None;
+    }
+    // same column can be masked by more than one statement of the query.
+    let mut rewrites = rewrites.to_vec();
+    rewrites.sort();
+    rewrites.dedup();
+    let mut columns = 0;
+    let mut filters = 0;
+    let mut row_filters = 0;
+    for rewrite in &rewrites {
+        match rewrite {
+            Rewrite::MaskedColumn { .. }
+            | Rewrite::OmittedColumn { .. }
+            | Rewrite::NulledExpression { .. } => columns += 1,
+            Rewrite::NeutralisedFilter { .. } => filters += 1,
+            Rewrite::RowFilter { .. } => row_filters += 1,
+        }
+    }
+    let mut changes = vec![];
+    if columns > 0 {
+        changes.push(format!("{} masked", count(columns, "column", "columns")));
+    }
+    if filters > 0 {
+        changes.push(format!(
+            "{} on protected columns neutralised",
+            count(filters, "filter", "filters")
+        ));
+    }
+    if row_filters > 0 {
+        changes.push(format!(
+            "{} added",
+            count(row_filters, "row filter", "row filters")
+        ));
+    }
+    let mut fields = ErrorFields::new(
+        SUCCESSFUL_COMPLETION,
+        format!("query is rewritten by the policy: {}", changes.join(", ")),
+    );
+    if level == RewriteNotice::Verbose {
+        fields.detail = Some(
+            rewrites
+                .iter()
+                .map(describe_rewrite)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+    }
+    Some(BackendMessage::info_msg(&fields))
+}
+
+// describe_rewrite tells what is changed in the query and why.
+fn describe_rewrite(rewrite: &Rewrite) -> String {
+    match rewrite {
+        Rewrite::MaskedColumn {
+            table,
+            column,
+            strategy,
+        } if strategy == "null" => format!(
+            "column \"{}\" of \"{}\" is replaced with NULL, since it's protected.",
+            column, table
+        ),
+        Rewrite::MaskedColumn {
+            table,
+            column,
+            strategy,
+        } => format!(
+            "column \"{}\" of \"{}\" is masked with {}, since it's protected.",
+            column, table, strategy
+        ),
+        Rewrite::OmittedColumn { table, column } => format!(
+            "column \"{}\" of \"{}\" is left out of the wildcard, since it's protected.",
+            column, table
+        ),
+        Rewrite::NulledExpression { alias } => format!(
+            "expression \"{}\" is replaced with NULL, since it refers a protected column.",
+            alias
+        ),
+        Rewrite::NeutralisedFilter {
+            table: Some(table),
+            column,
+        } => format!(
+            "filter on column \"{}\" of \"{}\" is replaced with NULL, since the column is protected.",
+            column, table
+        ),
+        Rewrite::NeutralisedFilter { table: None, column } => format!(
+            "filter on column \"{}\" is replaced with NULL, since the column is protected.",
+            column
+        ),
+        Rewrite::RowFilter { table, predicate } => format!(
+            "row filter {} is added to \"{}\" by the row level policy.",
+            predicate, table
+        ),
+    }
+}
+
+fn count(n: usize, singular: &str, plural: &str) -> String {
+    match n {
+        1 => format!("1 {}", singular),
+        _ => format!("{} {}", n, plural),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice_fields(msg: BackendMessage) -> Vec<String> {
+        match msg {
+            BackendMessage::Message { meta: b'N', data } => String::from_utf8(data)
+                .unwrap()
+                .split('\0')
+                .filter(|field| !field.is_empty())
+                .map(|field| field.to_string())
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_notice() {
+        let rewrites = vec![
+            Rewrite::RowFilter {
+                table: String::from("kids"),
+                predicate: String::from("kids.tenant_id = 'acme'"),
+            },
+            Rewrite::MaskedColumn {
+                table: String::from("kids"),
+                column: String::from("phone"),
+                strategy: String::from("last4"),
+            },
+            Rewrite::MaskedColumn {
+                table: String::from("kids"),
+                column: String::from("name"),
+                strategy: String::from("null"),
+            },
+            Rewrite::MaskedColumn {
+                table: String::from("kids"),
+                column: String::from("name"),
+                strategy: String::from("null"),
+            },
+        ];
+        assert!(rewrite_notice(&rewrites, RewriteNotice::None).is_none());
+        assert!(rewrite_notice(&[], RewriteNotice::Verbose).is_none());
+
+        let fields = notice_fields(rewrite_notice(&rewrites, RewriteNotice::Summary).unwrap());
+        assert_eq!(
+            fields,
+            vec![
+                "SNOTICE",
+                "C00000",
+                "Mquery is rewritten by the policy: 2 columns masked, 1 row filter added",
+            ]
+        );
+
+        let fields = notice_fields(rewrite_notice(&rewrites, RewriteNotice::Verbose).unwrap());
+        assert_eq!(
+            fields[3],
+            "Dcolumn \"name\" of \"kids\" is replaced with NULL, since it's protected.\n\
+             column \"phone\" of \"kids\" is masked with last4, since it's protected.\n\
+             row filter kids.tenant_id = 'acme' is added to \"kids\" by the row level policy."
+        );
+    }
+}
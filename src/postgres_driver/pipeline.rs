@@ -0,0 +1,491 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::postgres_driver::message::{BackendMessage, ErrorFields, FrontendMessage};
+use std::collections::VecDeque;
+
+// REJECTED_STATEMENT_PREFIX is the prefix of the statement which is described in place of
+// the rejected message. target fails to describe it, so the error is delivered in the
+// same order as the target would have delivered it.
+const REJECTED_STATEMENT_PREFIX: &str = "inspektor_rejected_";
+
+// Rejection is the error of the message which is rejected by the policy.
+#[derive(Debug)]
+struct Rejection {
+    // sync is the sequence of the Sync which ends the rejected pipeline.
+    sync: u64,
+    statement: String,
+    error: ErrorFields,
+}
+
+/// Pipeline tracks the extended query messages of the client, so that the messages after
+/// an error are ignored until Sync as the target does. It lets pipelining clients stay in
+/// sync with the target after an error.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    // ignoring is set after an error in the current pipeline.
+    ignoring: bool,
+    // in_pipeline is set if the extended query messages are forwarded after the last Sync.
+    in_pipeline: bool,
+    // sent_syncs is the number of Sync and Query messages forwarded to the target.
+    sent_syncs: u64,
+    // answered_syncs is the number of ReadyForQuery messages received from the target.
+    answered_syncs: u64,
+    rejections: VecDeque<Rejection>,
+    // parses holds the sync sequence of the Parse messages which are waiting for
+    // ParseComplete, and whether the Parse is injected by the proxy.
+    parses: VecDeque<(u64, bool)>,
+}
+
+impl Pipeline {
+    // should_ignore tells whether the client message has to be ignored because of an error
+    // in the current pipeline. Flush is still forwarded so that the error reaches the client.
+    pub fn should_ignore(&self, msg: &FrontendMessage) -> bool {
+        match msg {
+            FrontendMessage::Sync | FrontendMessage::Flush | FrontendMessage::Terminate => false,
+            _ => self.ignoring,
+        }
+    }
+
+    // on_forward tracks the client message which is forwarded to the target.
+    pub fn on_forward(&mut self, msg: &FrontendMessage) {
+        match msg {
+            FrontendMessage::Sync => {
+                self.ignoring = false;
+                self.in_pipeline = false;
+                self.sent_syncs += 1;
+            }
+            FrontendMessage::Query { .. } => self.sent_syncs += 1,
+            FrontendMessage::Parse { .. } => {
+                self.in_pipeline = true;
+                self.parses.push_back((self.sent_syncs, false));
+            }
+            FrontendMessage::Bind { .. }
+            | FrontendMessage::Describe { .. }
+            | FrontendMessage::Execute { .. }
+            | FrontendMessage::Close { .. } => self.in_pipeline = true,
+            _ => {}
+        }
+    }
+
+    // on_inject tracks the Parse message which is injected by the proxy to prepare the
+    // statement of the client on the current target connection. the response of it is
+    // not forwarded to the client.
+    pub fn on_inject(&mut self) {
+        self.in_pipeline = true;
+        self.parses.push_back((self.sent_syncs, true));
+    }
+
+    // is_injected_response tells whether the target message is the response of the
+    // injected Parse message.
+    pub fn is_injected_response(&mut self, msg: &BackendMessage) -> bool {
+        match msg {
+            BackendMessage::Message { meta: b'1', .. } => match self.parses.pop_front() {
+                Some((_, injected)) => injected,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // is_synced tells whether all the forwarded messages are answered by the target.
+    pub fn is_synced(&self) -> bool {
+        !self.in_pipeline && self.sent_syncs == self.answered_syncs
+    }
+
+    // reject records the error of the rejected extended query message and returns the
+    // message which has to be forwarded in place of it. the error of the returned message
+    // is replaced with the given error when the target responds.
+    pub fn reject(&mut self, error: ErrorFields) -> FrontendMessage {
+        let statement = format!("{}{}", REJECTED_STATEMENT_PREFIX, self.sent_syncs);
+        self.ignoring = true;
+        self.rejections.push_back(Rejection {
+            sync: self.sent_syncs,
+            statement: statement.clone(),
+            error,
+        });
+        FrontendMessage::Describe {
+            is_prepared_statement: true,
+            name: statement,
+        }
+    }
+
+    // on_backend tracks the target message. target error of the rejected message is
+    // replaced with the error of the policy.
+    pub fn on_backend(&mut self, msg: &mut BackendMessage) {
+        match msg {
+            BackendMessage::ErrorMsg(data) => {
+                // target ignores rest of the pipeline if the error belongs to the pipeline
+                // which is not synced yet, so does the proxy.
+                if self.in_pipeline && self.sent_syncs == self.answered_syncs {
+                    self.ignoring = true;
+                }
+                // target skips the Parse messages after the error until Sync.
+                let answered_syncs = self.answered_syncs;
+                self.parses.retain(|(sync, _)| *sync != answered_syncs);
+                let rejected = match self.rejections.front() {
+                    Some(rejection) => {
+                        rejection.sync == self.answered_syncs
+                            && contains(data, rejection.statement.as_bytes())
+                    }
+                    None => false,
+                };
+                if rejected {
+                    let rejection = self.rejections.pop_front().unwrap();
+                    *msg = BackendMessage::error_msg(&rejection.error);
+                }
+            }
+            BackendMessage::ReadyForQuery { .. } => {
+                if self.answered_syncs < self.sent_syncs {
+                    self.answered_syncs += 1;
+                }
+                // rejected message is ignored by the target if any of the previous
+                // message failed. so, the error is not delivered.
+                let answered_syncs = self.answered_syncs;
+                self.rejections
+                    .retain(|rejection| rejection.sync >= answered_syncs);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres_driver::message::TransactionStatus;
+    use std::collections::HashSet;
+
+    // Target mimics the extended query protocol handling of postgres.
+    #[derive(Default)]
+    struct Target {
+        statements: HashSet<String>,
+        ignoring: bool,
+    }
+
+    impl Target {
+        fn handle(&mut self, msg: &FrontendMessage) -> Vec<BackendMessage> {
+            if self.ignoring {
+                match msg {
+                    FrontendMessage::Sync => {}
+                    _ => return vec![],
+                }
+            }
+            let message = |meta: u8| BackendMessage::Message { meta, data: vec![] };
+            let result = match msg {
+                FrontendMessage::Parse { name, query, .. } => {
+                    if query.contains("syntax error") {
+                        Err(String::from("syntax error"))
+                    } else {
+                        self.statements.insert(name.clone());
+                        Ok(vec![message(b'1')])
+                    }
+                }
+                FrontendMessage::Bind {
+                    prepared_statement_name,
+                    ..
+                } => self.statement(prepared_statement_name, message(b'2')),
+                FrontendMessage::Describe {
+                    is_prepared_statement: true,
+                    name,
+                } => self.statement(name, message(b'n')),
+                FrontendMessage::Describe { .. } => Ok(vec![message(b'n')]),
+                FrontendMessage::Execute { .. } => Ok(vec![message(b'C')]),
+                FrontendMessage::Query { .. } => Ok(vec![
+                    message(b'C'),
+                    BackendMessage::ReadyForQuery {
+                        state: TransactionStatus::Idle,
+                    },
+                ]),
+                FrontendMessage::Sync => {
+                    self.ignoring = false;
+                    Ok(vec![BackendMessage::ReadyForQuery {
+                        state: TransactionStatus::Idle,
+                    }])
+                }
+                _ => Ok(vec![]),
+            };
+            result.unwrap_or_else(|err| {
+                self.ignoring = true;
+                vec![BackendMessage::err_msg(err)]
+            })
+        }
+
+        fn statement(
+            &self,
+            name: &String,
+            rsp: BackendMessage,
+        ) -> Result<Vec<BackendMessage>, String> {
+            if !self.statements.contains(name) {
+                return Err(format!("prepared statement \"{}\" does not exist", name));
+            }
+            Ok(vec![rsp])
+        }
+    }
+
+    // replay forwards the client messages through the pipeline and returns the messages
+    // received by the client. messages which have a query with "secret" are rejected by
+    // the policy. target responds after every message if interleave is set, otherwise it
+    // responds after all the messages are forwarded.
+    fn replay(msgs: Vec<FrontendMessage>, interleave: bool) -> Vec<String> {
+        let mut pipeline = Pipeline::default();
+        let mut target = Target::default();
+        let mut forwarded = VecDeque::new();
+        let mut received = vec![];
+        let error = ErrorFields::new("42501", String::from("unauthorized column secret"));
+        let mut respond = |pipeline: &mut Pipeline, forwarded: &mut VecDeque<FrontendMessage>| {
+            while let Some(msg) = forwarded.pop_front() {
+                for mut rsp in target.handle(&msg) {
+                    pipeline.on_backend(&mut rsp);
+                    received.push(summary(&rsp));
+                }
+            }
+        };
+        for msg in msgs {
+            if pipeline.should_ignore(&msg) {
+                continue;
+            }
+            let msg = match &msg {
+                FrontendMessage::Parse { query, .. } if query.contains("secret") => {
+                    pipeline.reject(error.clone())
+                }
+                _ => msg,
+            };
+            pipeline.on_forward(&msg);
+            forwarded.push_back(msg);
+            if interleave {
+                respond(&mut pipeline, &mut forwarded);
+            }
+        }
+        respond(&mut pipeline, &mut forwarded);
+        received
+    }
+
+    fn summary(msg: &BackendMessage) -> String {
+        match msg {
+            BackendMessage::Message { meta, .. } => (*meta as char).to_string(),
+            BackendMessage::ReadyForQuery { .. } => String::from("Z"),
+            BackendMessage::ErrorMsg(data) => {
+                let data = String::from_utf8_lossy(data);
+                let msg = data
+                    .split('\0')
+                    .find(|field| field.starts_with('M'))
+                    .unwrap();
+                format!("E {}", &msg[1..])
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse(name: &str, query: &str) -> FrontendMessage {
+        FrontendMessage::Parse {
+            name: name.to_string(),
+            query: query.to_string(),
+            object_ids: vec![],
+        }
+    }
+
+    fn bind(statement: &str) -> FrontendMessage {
+        FrontendMessage::Bind {
+            destination_portal_name: String::from(""),
+            prepared_statement_name: statement.to_string(),
+            parameter_format_codes: vec![],
+            parameter_values: vec![],
+            result_column_format_codes: vec![],
+        }
+    }
+
+    fn describe_portal() -> FrontendMessage {
+        FrontendMessage::Describe {
+            is_prepared_statement: false,
+            name: String::from(""),
+        }
+    }
+
+    fn describe_statement(name: &str) -> FrontendMessage {
+        FrontendMessage::Describe {
+            is_prepared_statement: true,
+            name: name.to_string(),
+        }
+    }
+
+    fn execute() -> FrontendMessage {
+        FrontendMessage::Execute {
+            name: String::from(""),
+            max_no_of_rows: 0,
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        // jdbc batch sends all the statements of the batch before Sync.
+        let jdbc_batch = |query: &str| {
+            let mut msgs = vec![];
+            for q in [
+                "INSERT INTO kids VALUES (1)",
+                query,
+                "INSERT INTO kids VALUES (3)",
+            ] {
+                msgs.extend([parse("", q), bind(""), describe_portal(), execute()]);
+            }
+            msgs.push(FrontendMessage::Sync);
+            msgs.push(FrontendMessage::Query {
+                query_string: String::from("SELECT 1"),
+            });
+            msgs
+        };
+        for interleave in [true, false] {
+            assert_eq!(
+                replay(jdbc_batch("SELECT secret FROM kids"), interleave),
+                vec![
+                    "1",
+                    "2",
+                    "n",
+                    "C",
+                    "E unauthorized column secret",
+                    "Z",
+                    "C",
+                    "Z"
+                ]
+            );
+            // target errors are left as it is.
+            assert_eq!(
+                replay(jdbc_batch("syntax error"), interleave),
+                vec!["1", "2", "n", "C", "E syntax error", "Z", "C", "Z"]
+            );
+        }
+
+        // pgx prepares the statement with Describe and Sync before executing it.
+        let pgx = vec![
+            parse("stmtcache_1", "SELECT secret FROM kids"),
+            describe_statement("stmtcache_1"),
+            FrontendMessage::Sync,
+            parse("stmtcache_2", "SELECT id FROM kids"),
+            describe_statement("stmtcache_2"),
+            FrontendMessage::Sync,
+            bind("stmtcache_2"),
+            execute(),
+            FrontendMessage::Sync,
+        ];
+        assert_eq!(
+            replay(pgx, false),
+            vec![
+                "E unauthorized column secret",
+                "Z",
+                "1",
+                "n",
+                "Z",
+                "2",
+                "C",
+                "Z"
+            ]
+        );
+
+        // asyncpg flushes to get the statement description before Sync.
+        let asyncpg = vec![
+            parse("__asyncpg_stmt_1__", "SELECT id FROM kids"),
+            describe_statement("__asyncpg_stmt_1__"),
+            FrontendMessage::Flush,
+            parse("__asyncpg_stmt_2__", "SELECT secret FROM kids"),
+            describe_statement("__asyncpg_stmt_2__"),
+            FrontendMessage::Flush,
+            FrontendMessage::Sync,
+        ];
+        assert_eq!(
+            replay(asyncpg, true),
+            vec!["1", "n", "E unauthorized column secret", "Z"]
+        );
+
+        // error of the rejected message is not delivered if the target failed before it.
+        let pipelined = vec![
+            parse("", "syntax error"),
+            bind(""),
+            execute(),
+            parse("", "SELECT secret FROM kids"),
+            FrontendMessage::Sync,
+            parse("", "SELECT secret FROM kids"),
+            FrontendMessage::Sync,
+        ];
+        assert_eq!(
+            replay(pipelined, false),
+            vec!["E syntax error", "Z", "E unauthorized column secret", "Z"]
+        );
+    }
+
+    #[test]
+    fn test_injected_parse() {
+        // replay_injected forwards the messages, injected ones are the Parse messages of
+        // the statements which are prepared on another target connection.
+        let replay_injected = |msgs: Vec<(FrontendMessage, bool)>, interleave: bool| {
+            let mut pipeline = Pipeline::default();
+            let mut target = Target::default();
+            let mut forwarded = VecDeque::new();
+            let mut received = vec![];
+            let mut respond = |pipeline: &mut Pipeline, forwarded: &mut VecDeque<_>| {
+                while let Some(msg) = forwarded.pop_front() {
+                    for mut rsp in target.handle(&msg) {
+                        if pipeline.is_injected_response(&rsp) {
+                            continue;
+                        }
+                        pipeline.on_backend(&mut rsp);
+                        received.push(summary(&rsp));
+                    }
+                }
+            };
+            for (msg, injected) in msgs {
+                if injected {
+                    pipeline.on_inject();
+                } else {
+                    pipeline.on_forward(&msg);
+                }
+                forwarded.push_back(msg);
+                if interleave {
+                    respond(&mut pipeline, &mut forwarded);
+                }
+            }
+            respond(&mut pipeline, &mut forwarded);
+            assert!(pipeline.is_synced());
+            received
+        };
+        for interleave in [true, false] {
+            let msgs = vec![
+                (parse("", "SELECT id FROM kids"), false),
+                (parse("s1", "SELECT id FROM kids"), true),
+                (bind("s1"), false),
+                (execute(), false),
+                (FrontendMessage::Sync, false),
+            ];
+            assert_eq!(replay_injected(msgs, interleave), vec!["1", "2", "C", "Z"]);
+
+            // response of the client Parse is not swallowed if the injected Parse fails.
+            let msgs = vec![
+                (parse("", "SELECT id FROM kids"), false),
+                (parse("s1", "syntax error"), true),
+                (bind("s1"), false),
+                (parse("s2", "SELECT id FROM kids"), false),
+                (FrontendMessage::Sync, false),
+                (parse("s3", "SELECT id FROM kids"), true),
+                (parse("s4", "SELECT id FROM kids"), false),
+                (FrontendMessage::Sync, false),
+            ];
+            assert_eq!(
+                replay_injected(msgs, interleave),
+                vec!["1", "E syntax error", "Z", "1", "Z"]
+            );
+        }
+    }
+}
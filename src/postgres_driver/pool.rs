@@ -0,0 +1,249 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::PoolMode;
+use crate::postgres_driver::cancel::BackendKey;
+use crate::postgres_driver::conn::PostgresConn;
+use crate::postgres_driver::message::{BackendMessage, FrontendMessage, TransactionStatus};
+use anyhow::anyhow;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+// RESET_QUERY discards the session state of the target connection before it's leased to
+// another client session.
+const RESET_QUERY: &str = "DISCARD ALL";
+
+// IDLE_TIMEOUT is the time after which the idle target connections are closed, so that
+// the connections which are closed by the target are not leased.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// TargetConn is the authenticated target connection along with the startup response
+/// of the target.
+pub struct TargetConn {
+    pub conn: PostgresConn,
+    pub backend_key: BackendKey,
+    // parameters are the ParameterStatus messages of the target.
+    pub parameters: Vec<BackendMessage>,
+}
+
+impl TargetConn {
+    // reset discards the session state of the connection. ParameterStatus messages sent
+    // while resetting replaces the old ones.
+    pub async fn reset(&mut self) -> Result<(), anyhow::Error> {
+        self.conn
+            .write_all(
+                &FrontendMessage::Query {
+                    query_string: RESET_QUERY.to_string(),
+                }
+                .encode_without_buf(),
+            )
+            .await?;
+        loop {
+            let msg = BackendMessage::decode(&mut self.conn).await?;
+            match msg {
+                BackendMessage::ErrorMsg(_) => {
+                    return Err(anyhow!("error while resetting the target connection"));
+                }
+                BackendMessage::ReadyForQuery {
+                    state: TransactionStatus::Idle,
+                } => return Ok(()),
+                BackendMessage::ReadyForQuery { .. } => {
+                    return Err(anyhow!("target connection is not idle after reset"));
+                }
+                BackendMessage::Message { meta: b'S', .. } => self.set_parameter(msg),
+                _ => {}
+            }
+        }
+    }
+
+    // set_parameter replaces the ParameterStatus message of the same parameter.
+    pub fn set_parameter(&mut self, msg: BackendMessage) {
+        let name = parameter_name(&msg).to_vec();
+        self.parameters
+            .retain(|parameter| parameter_name(parameter) != &name[..]);
+        self.parameters.push(msg);
+    }
+}
+
+// parameter_name returns the parameter name of the ParameterStatus message.
+fn parameter_name(msg: &BackendMessage) -> &[u8] {
+    match msg {
+        BackendMessage::Message { data, .. } => match data.iter().position(|b| *b == 0) {
+            Some(pos) => &data[..pos],
+            None => &data[..],
+        },
+        _ => &[],
+    }
+}
+
+/// PoolKey identifies the target connections which can be shared. target connections are
+/// shared only if they are started with the same database, user and startup parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey(Vec<(String, String)>);
+
+impl PoolKey {
+    pub fn new(startup_params: &HashMap<String, String>) -> PoolKey {
+        let mut params = startup_params
+            .iter()
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect::<Vec<_>>();
+        params.sort();
+        PoolKey(params)
+    }
+}
+
+struct IdleConn {
+    conn: TargetConn,
+    idle_since: Instant,
+}
+
+/// TargetPool holds the idle target connections, so that the client sessions doesn't go
+/// through the target handshake every time. nothing is pooled if the pool mode is none.
+#[derive(Clone)]
+pub struct TargetPool {
+    mode: PoolMode,
+    size: usize,
+    idle_conns: Arc<Mutex<HashMap<PoolKey, Vec<IdleConn>>>>,
+}
+
+impl TargetPool {
+    pub fn new(mode: PoolMode, size: usize) -> TargetPool {
+        TargetPool {
+            mode,
+            size,
+            idle_conns: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn mode(&self) -> PoolMode {
+        self.mode
+    }
+
+    // get leases the recently used idle connection of the given key.
+    pub fn get(&self, key: &PoolKey) -> Option<TargetConn> {
+        let mut idle_conns = self.idle_conns.lock().unwrap();
+        let conns = idle_conns.get_mut(key)?;
+        conns.retain(|idle| idle.idle_since.elapsed() < IDLE_TIMEOUT);
+        conns.pop().map(|idle| idle.conn)
+    }
+
+    // put adds the idle connection to the pool. connection is closed if the pool is full.
+    pub fn put(&self, key: PoolKey, conn: TargetConn) {
+        if self.mode == PoolMode::None {
+            return;
+        }
+        let mut idle_conns = self.idle_conns.lock().unwrap();
+        let conns = idle_conns.entry(key).or_insert_with(Vec::new);
+        if conns.len() >= self.size {
+            debug!("target pool is full, closing the target connection");
+            return;
+        }
+        conns.push(IdleConn {
+            conn,
+            idle_since: Instant::now(),
+        });
+    }
+
+    // release resets the connection and adds it to the pool. reset is done in the
+    // background, so that the client doesn't wait for it.
+    pub fn release(&self, key: PoolKey, mut conn: TargetConn) {
+        if self.mode == PoolMode::None {
+            return;
+        }
+        let pool = self.clone();
+        tokio::spawn(async move {
+            match conn.reset().await {
+                Ok(()) => pool.put(key, conn),
+                Err(e) => debug!("closing the target connection after reset error {:?}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn target_conn(listener: &TcpListener, process_id: i32) -> TargetConn {
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        TargetConn {
+            conn: PostgresConn::Unsecured(conn),
+            backend_key: BackendKey {
+                process_id,
+                secret_key: 0,
+            },
+            parameters: vec![],
+        }
+    }
+
+    fn parameter(name: &str, val: &str) -> BackendMessage {
+        BackendMessage::Message {
+            meta: b'S',
+            data: format!("{}\0{}\0", name, val).into_bytes(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_target_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let key = PoolKey::new(&HashMap::from([
+            ("user".to_string(), "debuggeruser".to_string()),
+            ("database".to_string(), "postgres".to_string()),
+        ]));
+        let other_key = PoolKey::new(&HashMap::from([
+            ("user".to_string(), "debuggeruser".to_string()),
+            ("database".to_string(), "inspektor".to_string()),
+        ]));
+
+        let pool = TargetPool::new(PoolMode::Transaction, 2);
+        pool.put(key.clone(), target_conn(&listener, 1).await);
+        pool.put(key.clone(), target_conn(&listener, 2).await);
+        // pool is full.
+        pool.put(key.clone(), target_conn(&listener, 3).await);
+        assert!(pool.get(&other_key).is_none());
+        assert_eq!(pool.get(&key).unwrap().backend_key.process_id, 2);
+        assert_eq!(pool.get(&key).unwrap().backend_key.process_id, 1);
+        assert!(pool.get(&key).is_none());
+
+        let pool = TargetPool::new(PoolMode::None, 2);
+        pool.put(key.clone(), target_conn(&listener, 1).await);
+        assert!(pool.get(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_parameter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = target_conn(&listener, 1).await;
+        conn.set_parameter(parameter("TimeZone", "UTC"));
+        conn.set_parameter(parameter("DateStyle", "ISO, MDY"));
+        conn.set_parameter(parameter("TimeZone", "Asia/Kolkata"));
+        let parameters = conn
+            .parameters
+            .iter()
+            .map(|parameter| match parameter {
+                BackendMessage::Message { data, .. } => String::from_utf8(data.clone()).unwrap(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parameters,
+            vec!["DateStyle\0ISO, MDY\0", "TimeZone\0Asia/Kolkata\0"]
+        );
+    }
+}
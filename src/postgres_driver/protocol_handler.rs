@@ -14,29 +14,49 @@
 use crate::apiproto::apiproto::{Metric, MetricsRequest};
 use crate::auditlog::build_audit_msg;
 use crate::bytespool::BUF_POOL;
-use crate::config::PostgresConfig;
+use crate::config::{PoolMode, PostgresConfig, RewriteNotice, SslMode, UnsupportedSqlMode};
 use crate::policy_evaluator::evaluator::PolicyEvaluator;
+use crate::postgres_driver::cancel::{BackendKey, CancelHandle, CancelRegistry};
 use crate::postgres_driver::conn::PostgresConn;
+use crate::postgres_driver::copy::{copy_columns, CopyMasker};
 use crate::postgres_driver::errors::ProtocolHandlerError;
+use crate::postgres_driver::masking::{
+    masked_relations, protect_masked_relations, ColumnMasks, MaskStrategy, MaskedTables,
+    ResultTracker, TableColumn,
+};
 use crate::postgres_driver::message::*;
+use crate::postgres_driver::notice::rewrite_notice;
+use crate::postgres_driver::pipeline::Pipeline;
+use crate::postgres_driver::pool::{PoolKey, TargetConn, TargetPool};
+use crate::postgres_driver::schema::{is_schema_change, Schema, SchemaCache, SchemaWatcher};
+use crate::postgres_driver::shutdown::{shutdown_msg, ShutdownState};
+use crate::postgres_driver::statements::{PreparedStatement, StatementKind, StatementRegistry};
+use crate::postgres_driver::timeouts::SessionTimeouts;
+use crate::sql::copy::{CopySource, CopyStatement};
 use crate::sql::ctx::Ctx;
-use crate::sql::query_rewriter::QueryRewriter;
-use crate::sql::rule_engine::HardRuleEngine;
+use crate::sql::dml::DmlStatement;
+use crate::sql::error::QueryRewriterError;
+use crate::sql::masking::MaskingStrategy;
+use crate::sql::query_rewriter::{QueryRewriter, Rewrite};
+use crate::sql::rule_engine::{parse_row_filter, HardRuleEngine, ProtectedFilterAction};
 use anyhow::*;
 use bytes::{BufMut, BytesMut};
 use grpcio::CallOption;
 use log::*;
 use md5::{Digest, Md5};
-use openssl::ssl::{ SslConnector, SslMethod};
+use openssl::error::ErrorStack;
+use openssl::ssl::{ConnectConfiguration, SslConnector, SslVerifyMode};
+use openssl::x509::verify::X509CheckFlags;
 use postgres_protocol::authentication::sasl;
 
-use sqlparser::ast::Statement;
+use sqlparser::ast::{Expr, Statement};
 use core::result::Result::Ok;
 use std::borrow::BorrowMut;
 use std::cell::Ref;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::mem;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -46,11 +66,51 @@ use tokio::sync::watch;
 use tokio::time as tokio_time;
 use anyhow::Result;
 use tokio_openssl::SslStream;
-use tokio_postgres::tls::TlsConnect;
 use crate::apiproto::apiproto::inspektor_client::InspektorClient;
 use crate::apiproto::InspektorClientCommon;
 use tonic::Request;
 
+// SCHEMA_REFRESH_TIMEOUT is the maximum wait time for the schema refresh after a schema
+// change of the session.
+const SCHEMA_REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// SCHEMA_REFRESH_ATTEMPTS is the number of times the schema refresh is asked before
+// giving up on the session.
+const SCHEMA_REFRESH_ATTEMPTS: usize = 3;
+
+// SESSION_EXPIRY_WARNING is the sqlstate of the session expiry notice.
+const SESSION_EXPIRY_WARNING: &str = "01000";
+
+// SESSION_EXPIRED is the sqlstate sent to the client when the session is expired.
+const SESSION_EXPIRED: &str = "28000";
+
+// IDLE_SESSION_TIMEOUT is the sqlstate sent to the client when the session is idle for
+// too long.
+const IDLE_SESSION_TIMEOUT: &str = "57P05";
+
+// IDLE_IN_TRANSACTION_TIMEOUT is the sqlstate sent to the client when the session is
+// idle in a transaction for too long.
+const IDLE_IN_TRANSACTION_TIMEOUT: &str = "25P03";
+
+// MAX_SESSION_DURATION_EXCEEDED is the sqlstate sent to the client when the session
+// lived longer than the maximum session duration.
+const MAX_SESSION_DURATION_EXCEEDED: &str = "57P01";
+
+// SCHEMA_NOT_REFRESHED is the sqlstate sent to the client when the schema is not
+// refreshed after the schema change of the session.
+const SCHEMA_NOT_REFRESHED: &str = "58000";
+
+// session_deadline converts the expiry epoch of the session to the instant. zero means
+// the session never expires.
+fn session_deadline(expires_at: i64) -> Option<tokio_time::Instant> {
+    if expires_at <= 0 {
+        return None;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let remaining = Duration::from_secs(expires_at as u64).saturating_sub(now);
+    tokio_time::Instant::now().checked_add(remaining)
+}
+
 fn md5_password(username: &String, password: &String, salt: Vec<u8>) -> String {
     let mut md5 = Md5::new();
     md5.update(password);
@@ -61,115 +121,284 @@ fn md5_password(username: &String, password: &String, salt: Vec<u8>) -> String {
     format!("md5{:x}", md5.finalize())
 }
 
+// configure_target_ssl configures the target ssl session for the given sslmode. SNI and
+// hostname verification are done with the server name instead of the connected host, So
+// that same configuration can be used by the metadata client as well.
+pub fn configure_target_ssl(
+    ssl_config: &mut ConnectConfiguration,
+    ssl_mode: SslMode,
+    server_name: &str,
+) -> Result<(), ErrorStack> {
+    ssl_config.set_use_server_name_indication(false);
+    ssl_config.set_verify_hostname(false);
+    let server_ip = server_name.parse::<IpAddr>();
+    // SNI is not allowed for ip address.
+    if server_ip.is_err() {
+        ssl_config.set_hostname(server_name)?;
+    }
+    match ssl_mode {
+        SslMode::VerifyFull => {
+            let param = ssl_config.param_mut();
+            param.set_hostflags(X509CheckFlags::NO_PARTIAL_WILDCARDS);
+            match server_ip {
+                Ok(ip) => param.set_ip(ip)?,
+                Err(_) => param.set_host(server_name)?,
+            }
+        }
+        // verify-ca only verifies the certificate chain.
+        SslMode::VerifyCa => {}
+        _ => ssl_config.set_verify(SslVerifyMode::NONE),
+    }
+    Ok(())
+}
+
 pub struct ProtocolHandler {
     policy_watcher: watch::Receiver<Vec<u8>>,
     client_conn: PostgresConn,
-    target_conn: PostgresConn,
+    // target is None while the session doesn't hold a target connection in transaction
+    // pooling.
+    target: Option<TargetConn>,
+    target_pool: TargetPool,
+    pool_key: PoolKey,
+    startup_params: HashMap<String, String>,
+    target_ssl_connector: Option<SslConnector>,
+    // target_idle is set if the target is not in a transaction block.
+    target_idle: bool,
+    // target_statements are the statements prepared on the current target connection.
+    target_statements: HashSet<String>,
     policy_evaluator: PolicyEvaluator,
     groups: Vec<String>,
     config: PostgresConfig,
     connected_db: String,
     datasource_name: String,
-    pending_error: Option<ProtocolHandlerError>,
+    pending_error: Option<ErrorFields>,
     current_transaction_status: TransactionStatus,
     client: InspektorClient<InspektorClientCommon>,
     pending_metrics: Vec<Metric>,
     token: String,
     passthrough: bool,
     audit_sender: Sender<String>,
-    postgres_client: tokio_postgres::Client,
+    // schema_watcher gives the cached schema of the connected database.
+    schema_watcher: SchemaWatcher,
+    // schema_changed is set once a schema change statement is forwarded.
+    schema_changed: bool,
+    // schema_refreshing is set while waiting for the schema refresh of the committed
+    // schema change.
+    schema_refreshing: bool,
+    result_tracker: ResultTracker,
+    // policy_version is incremented on every policy update.
+    policy_version: u64,
+    statement_registry: StatementRegistry,
+    pipeline: Pipeline,
+    // cancel_handle holds the backend key issued to the client.
+    cancel_handle: CancelHandle,
+    // shutdown tells the session to terminate when the proxy is shutting down.
+    shutdown: watch::Receiver<ShutdownState>,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct TableInfo {
     column_relation: HashMap<String, Vec<String>>,
     schemas: Vec<String>,
+    // column_types holds the data type of the columns of the protected tables.
+    column_types: HashMap<String, HashMap<String, String>>,
+    // column_masks holds the masked columns of the result rows.
+    column_masks: ColumnMasks,
+    // masked_tables holds the columns of the tables which have masked columns.
+    masked_tables: MaskedTables,
+    // masked_relations holds the masked columns and the views of the masked tables, which
+    // are protected by the rewriter.
+    masked_relations: HashMap<String, Vec<String>>,
+    // rewrite_notice tells how the user is notified about the rewritten queries.
+    rewrite_notice: RewriteNotice,
+}
+
+impl TableInfo {
+    // ctx builds the rewriter context of the protected tables.
+    fn ctx(&self) -> Ctx {
+        let mut ctx = Ctx::new(self.column_relation.clone());
+        ctx.set_column_types(self.column_types.clone());
+        ctx
+    }
 }
 
 impl ProtocolHandler {
-    // get_table_info get table info of the protected tables.
-    async fn get_table_info(&mut self) -> Result<TableInfo, anyhow::Error> {
+    // get_table_info get table info of the protected tables from the cached schema.
+    fn get_table_info(&mut self) -> Result<TableInfo, anyhow::Error> {
         let result = self.policy_evaluator.evaluate(
             &self.datasource_name,
             &"view".to_string(),
             &self.groups,
         )?;
+        let schema = self.schema_watcher.schema();
+        let (column_masks, masked_tables) =
+            self.get_column_masks(&schema, &result.masked_columns)?;
+        let masked_relations = masked_relations(&schema, &masked_tables);
         let protected_tables = result.get_protected_tables(&self.connected_db);
+        let rewrite_notice = match &result.rewrite_notice {
+            Some(notice) => RewriteNotice::from_str(notice)?,
+            None => self.config.rewrite_notice(),
+        };
 
-        if protected_tables.len() == 0 {
-            return Ok(TableInfo::default());
-        }
-
-        // query rewriter needs only the table info of the protected table, so
-        // query only neccessary info.
-        let mut schema_selection = String::from("(");
-        let mut table_selection = String::from("(");
-        let mut delim = "";
-        for protected_table in protected_tables {
-            schema_selection.push_str(delim);
-            table_selection.push_str(delim);
-            delim = ",";
-            schema_selection.push('\'');
-            schema_selection.push_str(protected_table.0);
-            schema_selection.push('\'');
-            table_selection.push('\'');
-            table_selection.push_str(protected_table.1);
-            table_selection.push('\'');
-        }
-        schema_selection.push(')');
-        table_selection.push(')');
-        let query = format!(
-            r#"
-        SELECT 
-          table_schema, 
-          table_name, 
-          column_name, 
-          data_type 
-        FROM 
-          information_schema.columns 
-        where 
-          table_schema in {}
-          and table_name in {}
-        "#,
-            schema_selection, table_selection
-        );
-
-        let rows = self.postgres_client.query(&query, &[]).await?;
-
+        // query rewriter needs only the table info of the protected table.
         let mut column_relation: HashMap<String, Vec<String>> = HashMap::default();
+        let mut column_types: HashMap<String, HashMap<String, String>> = HashMap::default();
         let mut schemas: HashSet<String> = HashSet::default();
-        for row in rows {
-            let schema_name = row.get::<usize, String>(0);
+        for column in &schema.columns {
+            let data_type = match &column.data_type {
+                Some(data_type) => data_type,
+                None => continue,
+            };
             // table name is format of both schema and table.
-            let table_name: String = format!("{}.{}", &schema_name, row.get::<usize, String>(1));
-            if !schemas.contains(&schema_name) {
-                schemas.insert(schema_name);
-            }
-            let column_name: String = row.get(2);
-            if let Some(columns) = column_relation.get_mut(&table_name) {
-                columns.push(column_name);
+            let table_name = format!("{}.{}", column.schema, column.table);
+            if !protected_tables.contains(&(column.schema.as_str(), column.table.as_str()))
+                && !masked_relations.contains_key(&table_name)
+            {
                 continue;
             }
-            column_relation.insert(table_name, vec![column_name]);
+            if !schemas.contains(&column.schema) {
+                schemas.insert(column.schema.clone());
+            }
+            column_types
+                .entry(table_name.clone())
+                .or_default()
+                .insert(column.name.clone(), data_type.clone());
+            column_relation
+                .entry(table_name)
+                .or_default()
+                .push(column.name.clone());
         }
         let schemas = schemas.into_iter().collect::<Vec<_>>();
         Ok(TableInfo {
             column_relation,
             schemas,
+            column_types,
+            column_masks,
+            masked_tables,
+            masked_relations,
+            rewrite_notice,
         })
     }
 
-    // serve will listen to client packets and decide whether to process
-    // the packet based on the opa policy.
+    // get_column_masks resolves the masked columns of the policy to the table oid and
+    // attribute number, which are used to find the masked columns in the RowDescription.
+    // columns of the masked tables are returned as well to find the copied columns.
+    fn get_column_masks(
+        &self,
+        schema: &Schema,
+        masked_columns: &HashMap<String, String>,
+    ) -> Result<(ColumnMasks, MaskedTables), anyhow::Error> {
+        let mut strategies = HashMap::new();
+        for (column, strategy) in masked_columns {
+            // masked columns are in the format of db.schema.table.column
+            let splits = column.split(".").collect::<Vec<&str>>();
+            if splits.len() != 4 || splits[0] != self.connected_db {
+                continue;
+            }
+            strategies.insert(
+                (
+                    splits[1].to_string(),
+                    splits[2].to_string(),
+                    splits[3].to_string(),
+                ),
+                MaskStrategy::from_str(strategy)?,
+            );
+        }
+        let mut column_masks = ColumnMasks::default();
+        let mut masked_tables = MaskedTables::default();
+        if strategies.len() == 0 {
+            return Ok((column_masks, masked_tables));
+        }
+        let tables = strategies
+            .keys()
+            .map(|key| (key.0.clone(), key.1.clone()))
+            .collect::<HashSet<(String, String)>>();
+        for column in &schema.columns {
+            if !tables.contains(&(column.schema.clone(), column.table.clone())) {
+                continue;
+            }
+            let key = (
+                column.schema.clone(),
+                column.table.clone(),
+                column.name.clone(),
+            );
+            let strategy = strategies.get(&key).copied();
+            if let Some(strategy) = strategy {
+                column_masks.insert((column.table_oid, column.attnum), strategy);
+            }
+            masked_tables
+                .entry(format!("{}.{}", key.0, key.1))
+                .or_default()
+                .push(TableColumn {
+                    name: key.2,
+                    type_oid: column.type_oid,
+                    strategy,
+                });
+        }
+        // tables are removed if the masked columns doesn't exist anymore.
+        masked_tables.retain(|_, columns| columns.iter().any(|column| column.strategy.is_some()));
+        Ok((column_masks, masked_tables))
+    }
+
+    // serve serves the client session and returns the target connection to the pool once
+    // the session ends. target connection is reused only if it's not in the middle of a
+    // transaction or a query.
     pub async fn serve(&mut self, expires_at: i64) -> Result<(), anyhow::Error> {
+        let result = self.serve_session(expires_at).await;
+        if self.is_idle() {
+            self.release_target();
+        }
+        self.flush_metrics().await;
+        result
+    }
+
+    // is_idle tells whether the target is not in the middle of a transaction or a query.
+    fn is_idle(&self) -> bool {
+        self.target_idle && self.pipeline.is_synced()
+    }
+
+    // should_shutdown tells whether the session has to be terminated for the shutdown.
+    fn should_shutdown(&self) -> bool {
+        self.shutdown.borrow().should_terminate(self.is_idle())
+    }
+
+    // terminate_session tells the client that the session is terminated by the shutdown.
+    async fn terminate_session(&mut self) -> Result<(), anyhow::Error> {
+        info!("terminating the session due to shutdown");
+        self.client_conn
+            .write_all(&shutdown_msg().encode())
+            .await
+            .map_err(|e| anyhow!("error while sending shutdown message {:?}", e))
+    }
+
+    // serve_session will listen to client packets and decide whether to process
+    // the packet based on the opa policy.
+    async fn serve_session(&mut self, expires_at: i64) -> Result<(), anyhow::Error> {
         debug!("started serving");
-        let mut table_info = self.get_table_info().await.map_err(|e| {
+        let mut table_info = self.get_table_info().map_err(|e| {
             error!("error while getting table meta {:?}", e);
             return anyhow!("error while getting table meta");
         })?;
-        // refresh table for every 2 minutes.
-        let mut table_info_refresh_ticker = tokio_time::interval(Duration::from_secs(60 * 2));
+        let mut timeouts = self.get_session_timeouts()?;
+        let started_at = tokio_time::Instant::now();
+        let mut last_activity = started_at;
+        let expiry = session_deadline(expires_at);
+        // client is warned only if the session lives longer than the warning period.
+        let mut expiry_warning = expiry
+            .and_then(|expiry| expiry.checked_sub(self.config.session_expiry_warning()))
+            .filter(|warning| *warning > tokio_time::Instant::now());
         loop {
+            // idle timeout applies only while the session is waiting for the client.
+            let idle_deadline = if self.pipeline.is_synced() {
+                timeouts
+                    .idle_timeout(!self.target_idle)
+                    .map(|timeout| last_activity + timeout)
+            } else {
+                None
+            };
+            let max_duration_deadline = timeouts
+                .max_session_duration
+                .map(|duration| started_at + duration);
             tokio::select! {
                 // policy_watcher listens for any policy changes. If there is any policy change
                 // then the new policy will be updated and check whether the current connection
@@ -181,23 +410,76 @@ impl ProtocolHandler {
                     }
                     let wasm_policy = self.policy_watcher.borrow().clone();
                     self.update_policy(wasm_policy)?;
+                    timeouts = timeouts.updated(self.get_session_timeouts());
+                    // masked columns may have changed, so the table info is refreshed.
+                    table_info = match self.get_table_info() {
+                        Ok(info) => info,
+                        Err(e) => {
+                            // prepared statements are not revalidated for the new policy,
+                            // so they are rejected as stale.
+                            error!("error while refreshing table meta {:?}", e);
+                            continue;
+                        }
+                    };
+                    self.revalidate_statements(&table_info);
+                }
+                // schema_watcher listens for the schema changes of the connected database, so
+                // we don't miss any new column of the protected tables.
+                changed = self.schema_watcher.changed() => {
+                    if changed.is_err() {
+                        return Err(anyhow!("schema watcher is closed"));
+                    }
+                    table_info = self.refresh_table_info(&table_info);
+                }
+                // shutdown listens for the shutdown of the proxy. idle sessions are terminated
+                // right away and the others are terminated once they are idle.
+                changed = self.shutdown.changed() => {
+                    if changed.is_err() || self.should_shutdown() {
+                        return self.terminate_session().await;
+                    }
+                }
+                // warn the client about the session expiry, so that it can finish the work
+                // before the session is terminated.
+                _ = ProtocolHandler::sleep_until(expiry_warning) => {
+                    expiry_warning = None;
+                    self.warn_session_expiry(expiry.unwrap()).await?;
+                }
+                // expired session is terminated even if it's idle or running a query.
+                _ = ProtocolHandler::sleep_until(expiry) => {
+                    return self.expire_session().await;
+                }
+                _ = ProtocolHandler::sleep_until(idle_deadline) => {
+                    return self.terminate_idle_session().await;
+                }
+                _ = ProtocolHandler::sleep_until(max_duration_deadline) => {
+                    info!("terminating the session since it exceeded the maximum duration");
+                    return self
+                        .cancel_and_terminate(
+                            MAX_SESSION_DURATION_EXCEEDED,
+                            "terminating connection due to maximum session duration",
+                        )
+                        .await;
                 }
                 // listen for target postgres message and tunnel it to the client
                 // if there is no pending error.
-                n = BackendMessage::decode(&mut self.target_conn) => {
+                n = ProtocolHandler::decode_target(&mut self.target) => {
                     if self.is_session_expired(expires_at) {
-                        return Ok(())
+                        return self.expire_session().await;
                     }
+                    last_activity = tokio_time::Instant::now();
                     match n {
                         Err(e) =>{
                                 println!("failed to read from socket; err = {:?}", e);
                                 return Ok(());
                         },
                         Ok(msg) =>{
-                            if let Err(e) = self.handle_target_msg(msg).await{
+                            if let Err(e) = self.handle_target_msg(msg, &table_info).await{
                                 error!("{:?}", e);
                                 return Ok(())
                             }
+                            if self.should_shutdown() {
+                                return self.terminate_session().await;
+                            }
                         }
                     }
                 }
@@ -205,14 +487,27 @@ impl ProtocolHandler {
                 // are following the policy or not.
                 n = FrontendMessage::decode(&mut self.client_conn) => {
                     if self.is_session_expired(expires_at) {
-                        return Ok(())
+                        return self.expire_session().await;
                     }
+                    last_activity = tokio_time::Instant::now();
                     match n {
                         Err(e) =>{
                                 println!("failed to read from socket; err = {:?}", e);
                                 return Ok(());
                         },
+                        // pooled target connection is not terminated with the client session.
+                        Ok(FrontendMessage::Terminate) if self.target_pool.mode() != PoolMode::None => {
+                            return Ok(());
+                        }
                         Ok(msg) =>{
+                            // schema change of the session is applied before handling the
+                            // next message.
+                            if self.schema_refreshing {
+                                if let Err(e) = self.wait_for_schema().await {
+                                    return self.terminate_stale_schema(e).await;
+                                }
+                                table_info = self.refresh_table_info(&table_info);
+                            }
                             if let Err(e) = self.handle_client_msg(msg, &table_info).await {
                                 error!("{:?}", e);
                                 return Ok(())
@@ -220,23 +515,165 @@ impl ProtocolHandler {
                         }
                     }
                 }
-                // refresh table info on certain interval so we'll know the current
-                // database layout and we don't miss any schema update.
-                _ = table_info_refresh_ticker.tick() => {
-                    debug!("refreshing table meta");
-                    if self.is_session_expired(expires_at) {
-                        return Ok(())
-                    }
-                    table_info = match  self.get_table_info().await {
-                        Ok(info) => info,
-                        Err(e) => {
-                            error!("error while refreshing table meta {:?}", e);
-                            continue;
-                        }
-                    }
+            }
+        }
+    }
+
+    // refresh_table_info rebuilds the table info with the latest schema. statements are
+    // rewritten again, since the columns of the protected tables may have changed.
+    fn refresh_table_info(&mut self, table_info: &TableInfo) -> TableInfo {
+        debug!("refreshing table meta");
+        self.schema_refreshing = false;
+        let table_info = match self.get_table_info() {
+            Ok(info) => info,
+            Err(e) => {
+                error!("error while refreshing table meta {:?}", e);
+                return table_info.clone();
+            }
+        };
+        self.revalidate_statements(&table_info);
+        table_info
+    }
+
+    // wait_for_schema waits for the schema refresh. refresh is asked again if the schema
+    // is not reloaded in time. error is returned if it's not reloaded after all the
+    // attempts, since the stale schema may miss the new columns of the protected tables.
+    async fn wait_for_schema(&mut self) -> Result<(), anyhow::Error> {
+        for attempt in 1..=SCHEMA_REFRESH_ATTEMPTS {
+            match tokio_time::timeout(SCHEMA_REFRESH_TIMEOUT, self.schema_watcher.changed()).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(_)) => return Err(anyhow!("schema watcher is closed")),
+                Err(_) => {
+                    warn!(
+                        "schema is not refreshed after the schema change, attempt {}",
+                        attempt
+                    );
+                    self.schema_watcher.refresh();
+                }
+            }
+        }
+        Err(anyhow!(
+            "schema is not refreshed after {} attempts",
+            SCHEMA_REFRESH_ATTEMPTS
+        ))
+    }
+
+    // terminate_stale_schema terminates the session if the schema is not refreshed after
+    // the schema change, since the queries can't be validated with the stale schema.
+    async fn terminate_stale_schema(&mut self, err: anyhow::Error) -> Result<(), anyhow::Error> {
+        error!("terminating the session due to stale schema {:?}", err);
+        let rsp = BackendMessage::fatal_msg(
+            SCHEMA_NOT_REFRESHED,
+            "terminating connection since the schema is not refreshed after the schema change"
+                .to_string(),
+        );
+        self.client_conn
+            .write_all(&rsp.encode())
+            .await
+            .map_err(|e| anyhow!("error while sending stale schema error {:?}", e))
+    }
+
+    // sleep_until waits until the given deadline. it waits forever if there is no deadline.
+    async fn sleep_until(deadline: Option<tokio_time::Instant>) {
+        match deadline {
+            Some(deadline) => tokio_time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    // warn_session_expiry sends a notice to the client about the upcoming session expiry.
+    async fn warn_session_expiry(
+        &mut self,
+        expiry: tokio_time::Instant,
+    ) -> Result<(), anyhow::Error> {
+        let remaining = expiry.saturating_duration_since(tokio_time::Instant::now());
+        let notice = BackendMessage::notice_msg(
+            SESSION_EXPIRY_WARNING,
+            format!(
+                "session expires in {} seconds, reconnect to start a new session",
+                remaining.as_secs()
+            ),
+        );
+        self.client_conn
+            .write_all(&notice.encode())
+            .await
+            .map_err(|e| anyhow!("error while sending session expiry notice {:?}", e))
+    }
+
+    // expire_session cancels the running query of the target and terminates the session.
+    async fn expire_session(&mut self) -> Result<(), anyhow::Error> {
+        info!("terminating the session since it's expired");
+        self.cancel_and_terminate(
+            SESSION_EXPIRED,
+            "terminating connection due to session expiry, reconnect to start a new session",
+        )
+        .await
+    }
+
+    // cancel_and_terminate cancels the running query of the target and terminates the
+    // session with the given error.
+    async fn cancel_and_terminate(&mut self, code: &str, msg: &str) -> Result<(), anyhow::Error> {
+        if let Some(target) = &self.target {
+            if !self.is_idle() {
+                if let Err(e) = ProtocolHandler::cancel_target_query(
+                    &self.config,
+                    &self.target_ssl_connector,
+                    target.backend_key,
+                )
+                .await
+                {
+                    error!("error while cancelling the running query {:?}", e);
                 }
             }
         }
+        self.client_conn
+            .write_all(&BackendMessage::fatal_msg(code, msg.to_string()).encode())
+            .await
+            .map_err(|e| anyhow!("error while sending the termination error {:?}", e))
+    }
+
+    // terminate_idle_session terminates the session which is idle for too long. target
+    // connection in the middle of a transaction is closed, so the transaction is rolled
+    // back and the locks are released.
+    async fn terminate_idle_session(&mut self) -> Result<(), anyhow::Error> {
+        let rsp = if self.target_idle {
+            info!("terminating the session due to idle-session timeout");
+            BackendMessage::fatal_msg(
+                IDLE_SESSION_TIMEOUT,
+                "terminating connection due to idle-session timeout".to_string(),
+            )
+        } else {
+            info!("terminating the session due to idle-in-transaction timeout");
+            BackendMessage::fatal_msg(
+                IDLE_IN_TRANSACTION_TIMEOUT,
+                "terminating connection due to idle-in-transaction timeout".to_string(),
+            )
+        };
+        self.client_conn
+            .write_all(&rsp.encode())
+            .await
+            .map_err(|e| anyhow!("error while sending the idle timeout error {:?}", e))
+    }
+
+    // get_session_timeouts returns the session timeouts of the groups.
+    fn get_session_timeouts(&mut self) -> Result<SessionTimeouts, anyhow::Error> {
+        let result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"view".to_string(),
+            &self.groups,
+        )?;
+        SessionTimeouts::new(&self.config, &result.session_timeouts)
+    }
+
+    // decode_target decodes the message of the target connection. it waits forever if the
+    // session doesn't hold a target connection.
+    async fn decode_target(
+        target: &mut Option<TargetConn>,
+    ) -> Result<BackendMessage, anyhow::Error> {
+        match target {
+            Some(target) => BackendMessage::decode(&mut target.conn).await,
+            None => std::future::pending().await,
+        }
     }
 
     // is_session_expired tells the current session is expired or not.
@@ -250,7 +687,19 @@ impl ProtocolHandler {
 
     /// handle_target_msg will handle target message and checks whether there is any preflight error
     /// that needs to be updated other wise message is directly forwarded to the client.
-    async fn handle_target_msg(&mut self, msg: BackendMessage) -> Result<(), anyhow::Error> {
+    async fn handle_target_msg(
+        &mut self,
+        mut msg: BackendMessage,
+        table_info: &TableInfo,
+    ) -> Result<(), anyhow::Error> {
+        // response of the Parse injected by the proxy is not forwarded to the client.
+        if self.pipeline.is_injected_response(&msg) {
+            return Ok(());
+        }
+        // values of the masked columns are replaced before forwarding the rows.
+        self.result_tracker
+            .on_backend(&mut msg, &table_info.column_masks)?;
+        self.pipeline.on_backend(&mut msg);
         if self.pending_error.is_some() {
             // check the incoming message is ready for query.
             // if it's ready for query send the error message before
@@ -258,8 +707,7 @@ impl ProtocolHandler {
             match &msg {
                 BackendMessage::ReadyForQuery { .. } => {
                     // send the pending error message.
-                    let e = self.pending_error.as_ref().unwrap();
-                    let err_rsp = BackendMessage::err_msg(format!("{}", e));
+                    let err_rsp = BackendMessage::error_msg(self.pending_error.as_ref().unwrap());
                     if let Err(e) = self.client_conn.write_all(&err_rsp.encode()).await {
                         return Err(anyhow!(
                             "error while writing the rsp message to the client {:?}",
@@ -283,6 +731,20 @@ impl ProtocolHandler {
                 e
             ));
         }
+        if let BackendMessage::ReadyForQuery { state } = &msg {
+            self.target_idle = matches!(state, TransactionStatus::Idle);
+            // schema change is visible to the schema cache only after it's committed.
+            if self.schema_changed && self.target_idle {
+                self.schema_changed = false;
+                self.schema_refreshing = true;
+                self.schema_watcher.refresh();
+            }
+            // transaction pooling returns the target connection once the transaction
+            // is completed.
+            if self.target_pool.mode() == PoolMode::Transaction && self.is_idle() {
+                self.release_target();
+            }
+        }
         return Ok(());
     }
 
@@ -293,16 +755,30 @@ impl ProtocolHandler {
         mut msg: FrontendMessage,
         table_info: &TableInfo,
     ) -> Result<(), anyhow::Error> {
+        // target ignores the extended query messages until Sync after an error.
+        if self.pipeline.should_ignore(&msg) {
+            debug!("ignoring {:?} until sync", msg);
+            return Ok(());
+        }
         // rewrite the query if possible or send error message back to the client.
-        let ctx = Ctx::new(table_info.column_relation.clone());
-        if let Err(e) = self
-            .handle_frontend_message(&mut msg, ctx, table_info.schemas.clone())
-            .await
-        {
+        if let Err(e) = self.handle_frontend_message(&mut msg, table_info).await {
             // seems like the incoming command is not adhering to the policy requirement
             // so let's send the error message back to the client.
             error!("error while handling frontend message {:?}", e);
-            let rsp = BackendMessage::err_msg(format!("{}", e));
+            let query = match &msg {
+                FrontendMessage::Query { query_string } => Some(query_string.as_str()),
+                FrontendMessage::Parse { query, .. } => Some(query.as_str()),
+                _ => None,
+            };
+            let fields = e.error_fields(query, self.config.error_verbosity());
+            if !matches!(msg, FrontendMessage::Query { .. }) {
+                // error of the extended query message is delivered by the target in place
+                // of the rejected message, so the responses of the previous messages in
+                // the pipeline are delivered before the error.
+                let rejected = self.pipeline.reject(fields);
+                return self.forward_client_msg(rejected).await;
+            }
+            let rsp = BackendMessage::error_msg(&fields);
             self.client_conn
                 .write_all(&rsp.encode())
                 .await
@@ -328,9 +804,23 @@ impl ProtocolHandler {
         }
         // the incoming command have not violated any polices or query rewritter able to rewrite
         // successfully, so forward the incoming message to the target postgres instance.
+        self.forward_client_msg(msg).await
+    }
+
+    // forward_client_msg forwards the client message to the target.
+    async fn forward_client_msg(&mut self, msg: FrontendMessage) -> Result<(), anyhow::Error> {
+        self.acquire_target().await?;
         let mut buf = self.get_buf();
+        if let Some(parse) = self.reprepare_statement(&msg) {
+            self.pipeline.on_inject();
+            parse.encode(&mut buf);
+        }
+        self.track_target_statement(&msg);
+        self.result_tracker.on_frontend(&msg);
+        self.pipeline.on_forward(&msg);
         msg.encode(&mut buf);
-        self.target_conn.write_all(&buf).await.map_err(|e| {
+        let target = self.target.as_mut().unwrap();
+        target.conn.write_all(&buf).await.map_err(|e| {
             anyhow!(
                 "error while writing the frontend message to the target {:?}",
                 e
@@ -340,6 +830,86 @@ impl ProtocolHandler {
         Ok(())
     }
 
+    // reprepare_statement returns the Parse message of the statement which is used by the
+    // client message but prepared on another target connection.
+    fn reprepare_statement(&mut self, msg: &FrontendMessage) -> Option<FrontendMessage> {
+        let name = match msg {
+            FrontendMessage::Bind {
+                prepared_statement_name,
+                ..
+            } => prepared_statement_name,
+            FrontendMessage::Describe {
+                is_prepared_statement: true,
+                name,
+            } => name,
+            _ => return None,
+        };
+        if self.target_statements.contains(name) {
+            return None;
+        }
+        let statement = self.statement_registry.get(StatementKind::Protocol, name)?;
+        let parse = FrontendMessage::Parse {
+            name: name.clone(),
+            query: statement.rewritten_query.clone(),
+            object_ids: statement.param_types.clone(),
+        };
+        self.target_statements.insert(name.clone());
+        Some(parse)
+    }
+
+    // track_target_statement tracks the statements prepared on the current target connection.
+    fn track_target_statement(&mut self, msg: &FrontendMessage) {
+        match msg {
+            FrontendMessage::Parse { name, .. } => {
+                self.target_statements.insert(name.clone());
+            }
+            FrontendMessage::Close {
+                is_portal: false,
+                name,
+            } => {
+                self.target_statements.remove(name);
+            }
+            // simple query destroys the unnamed statement.
+            FrontendMessage::Query { .. } => {
+                self.target_statements.remove("");
+            }
+            _ => {}
+        }
+    }
+
+    // acquire_target leases a target connection from the pool, if the session doesn't hold
+    // one. statements of the session are prepared again on the leased connection.
+    async fn acquire_target(&mut self) -> Result<(), anyhow::Error> {
+        if self.target.is_some() {
+            return Ok(());
+        }
+        let target = match self.target_pool.get(&self.pool_key) {
+            Some(target) => target,
+            None => {
+                ProtocolHandler::connect_target_conn(
+                    &self.config,
+                    &self.target_ssl_connector,
+                    &self.startup_params,
+                    &mut self.client_conn,
+                )
+                .await?
+            }
+        };
+        self.cancel_handle.set_target_key(Some(target.backend_key));
+        self.target_statements.clear();
+        self.target = Some(target);
+        Ok(())
+    }
+
+    // release_target returns the target connection to the pool. connection is closed if
+    // pooling is disabled.
+    fn release_target(&mut self) {
+        if let Some(target) = self.target.take() {
+            self.cancel_handle.set_target_key(None);
+            self.target_pool.release(self.pool_key.clone(), target);
+        }
+    }
+
     pub fn get_buf(&self) -> BytesMut {
         BUF_POOL.with(|pool| pool.borrow_mut().get())
     }
@@ -348,11 +918,13 @@ impl ProtocolHandler {
         BUF_POOL.with(|pool| pool.borrow_mut().put(buf))
     }
 
-    // intialize will create a new connection with target and returns initialized postgres protocol handler.
+    // intialize will create a new connection with target or lease one from the pool and
+    // returns initialized postgres protocol handler.
     pub async fn initialize(
         config: PostgresConfig,
-        client_conn: PostgresConn,
+        mut client_conn: PostgresConn,
         client_parms: HashMap<String, String>,
+        startup_params: HashMap<String, String>,
         policy_watcher: watch::Receiver<Vec<u8>>,
         groups: Vec<String>,
         evaluator: PolicyEvaluator,
@@ -361,42 +933,51 @@ impl ProtocolHandler {
         token: String,
         passthrough: bool,
         audit_sender: Sender<String>,
+        target_ssl_connector: Option<SslConnector>,
+        cancel_registry: CancelRegistry,
+        target_pool: TargetPool,
+        schema_cache: SchemaCache,
+        shutdown: watch::Receiver<ShutdownState>,
     ) -> Result<ProtocolHandler, anyhow::Error> {
         debug!("intializing protocol handler");
 
-        // create a tcp connection with target postgres server to start
-        // sending messages.
-        let mut target_conn = ProtocolHandler::connect_target(&config).await?;
-        //target_conn = ProtocolHandler::try_ssl_upgrade(&config, target_conn).await?;
-        let startup_params = HashMap::from([
-            (
-                "database".to_string(),
-                client_parms.get("database").unwrap().clone(),
-            ),
-            (
-                "user".to_string(),
-                config.target_username.as_ref().unwrap().clone(),
-            ),
-            ("client_encoding".to_string(), "UTF8".to_string()),
-            ("application_name".to_string(), "inspektor".to_string()),
-        ]);
+        // target is always connected with the configured user.
+        let mut startup_params = startup_params;
+        startup_params.insert(
+            "user".to_string(),
+            config.target_username.as_ref().unwrap().clone(),
+        );
+        let pool_key = PoolKey::new(&startup_params);
+        let target = match target_pool.get(&pool_key) {
+            Some(target) => target,
+            None => {
+                ProtocolHandler::connect_target_conn(
+                    &config,
+                    &target_ssl_connector,
+                    &startup_params,
+                    &mut client_conn,
+                )
+                .await?
+            }
+        };
 
-        let (postgres_client, connection) = tokio_postgres::connect(
-            &format!(
-                "host={} port={} user={} dbname = {} password = {}",
-                config.target_addr.as_ref().unwrap(),
-                config.target_port.as_ref().unwrap(),
-                config.target_username.as_ref().unwrap(),
+        let schema_watcher = schema_cache
+            .watch(
+                &config,
+                &target_ssl_connector,
                 client_parms.get("database").unwrap(),
-                config.target_password.as_ref().unwrap()
-            ),
-            tokio_postgres::NoTls,
-        )
-        .await?;
-        tokio::spawn(connection);
+            )
+            .await?;
+        let cancel_handle = cancel_registry.register(Some(target.backend_key))?;
 
         let mut handler = ProtocolHandler {
-            target_conn: target_conn,
+            target: None,
+            target_pool: target_pool,
+            pool_key: pool_key,
+            startup_params: startup_params,
+            target_ssl_connector: target_ssl_connector,
+            target_idle: true,
+            target_statements: HashSet::default(),
             client_conn: client_conn,
             policy_watcher: policy_watcher,
             policy_evaluator: evaluator,
@@ -411,40 +992,124 @@ impl ProtocolHandler {
             token: token,
             passthrough: passthrough,
             audit_sender: audit_sender,
-            postgres_client: postgres_client,
+            schema_watcher: schema_watcher,
+            schema_changed: false,
+            schema_refreshing: false,
+            result_tracker: ResultTracker::new(config.mask_hash_secret()),
+            policy_version: 0,
+            statement_registry: StatementRegistry::default(),
+            pipeline: Pipeline::default(),
+            cancel_handle: cancel_handle,
+            shutdown: shutdown,
         };
+        handler.start_session(target).await?;
+        Ok(handler)
+    }
 
+    // start_session sends the startup response of the target to the client. client gets
+    // the backend key issued by the proxy, so that the cancel request of the client can be
+    // mapped to the target connection.
+    async fn start_session(&mut self, target: TargetConn) -> Result<(), anyhow::Error> {
+        let mut buf = BackendMessage::AuthenticationOk { success: true }.encode();
+        for parameter in &target.parameters {
+            buf.extend_from_slice(&parameter.encode());
+        }
+        let proxy_key = self.cancel_handle.proxy_key;
+        buf.extend_from_slice(
+            &BackendMessage::BackendKeyData {
+                process_id: proxy_key.process_id,
+                secret_key: proxy_key.secret_key,
+            }
+            .encode(),
+        );
+        buf.extend_from_slice(
+            &BackendMessage::ReadyForQuery {
+                state: TransactionStatus::Idle,
+            }
+            .encode(),
+        );
+        self.client_conn.write_all(&buf).await?;
+        self.target = Some(target);
+        // transaction pooling leases the target connection only for the transactions.
+        if self.target_pool.mode() == PoolMode::Transaction {
+            self.release_target();
+        }
+        Ok(())
+    }
+
+    // connect_target_conn creates an authenticated connection with the target postgres. error
+    // sent by the target while starting the session is forwarded to the client.
+    async fn connect_target_conn(
+        config: &PostgresConfig,
+        target_ssl_connector: &Option<SslConnector>,
+        startup_params: &HashMap<String, String>,
+        client_conn: &mut PostgresConn,
+    ) -> Result<TargetConn, anyhow::Error> {
+        // create a tcp connection with target postgres server to start
+        // sending messages.
+        let conn = ProtocolHandler::connect_target(config).await?;
+        let mut conn = ProtocolHandler::try_ssl_upgrade(config, target_ssl_connector, conn).await?;
         // send startup parameters to the target postgres to initiate the
         // the initial handshake.
-        handler
-            .target_conn
-            .write_all(
-                &FrontendMessage::Startup {
-                    params: startup_params,
-                    version: VERSION_3,
-                }
-                .encode_without_buf(),
-            )
-            .await
-            .map_err(|e| {
-                error!(
-                    "error while sending startup message to target. err: {:?}",
-                    e
-                );
+        conn.write_all(
+            &FrontendMessage::Startup {
+                params: startup_params.clone(),
+                version: VERSION_3,
+            }
+            .encode_without_buf(),
+        )
+        .await
+        .map_err(|e| {
+            error!(
+                "error while sending startup message to target. err: {:?}",
                 e
-            })?;
-        handler.authenticate_target_conn().await?;
-        Ok(handler)
+            );
+            e
+        })?;
+        ProtocolHandler::authenticate_target_conn(config, &mut conn).await?;
+        // target sends the parameters and the backend key after the authentication.
+        let mut parameters = vec![];
+        let mut backend_key = None;
+        loop {
+            let msg = BackendMessage::decode(&mut conn).await?;
+            match msg {
+                BackendMessage::BackendKeyData {
+                    process_id,
+                    secret_key,
+                } => {
+                    backend_key = Some(BackendKey {
+                        process_id,
+                        secret_key,
+                    })
+                }
+                BackendMessage::Message { meta: b'S', .. } => parameters.push(msg),
+                BackendMessage::ReadyForQuery { .. } => break,
+                BackendMessage::ErrorMsg(..) => {
+                    client_conn.write_all(&msg.encode()).await?;
+                    return Err(anyhow!("target refused the session"));
+                }
+                _ => debug!("ignoring target startup message {:?}", msg),
+            }
+        }
+        let backend_key =
+            backend_key.ok_or_else(|| anyhow!("target didn't send the backend key data"))?;
+        Ok(TargetConn {
+            conn,
+            backend_key,
+            parameters,
+        })
     }
 
     /// authenticate_target_conn will authenticate the target connection with target
-    /// postgres instance and on sucessful authentication, it sends the authenticationOK
-    /// message to the client connection as well.
-    async fn authenticate_target_conn(&mut self) -> Result<(), anyhow::Error> {
+    /// postgres instance.
+    async fn authenticate_target_conn(
+        config: &PostgresConfig,
+        target_conn: &mut PostgresConn,
+    ) -> Result<(), anyhow::Error> {
         loop {
             // decode the message from the target postgres connection to figure
             // out what sort of authentication mechanism the target postgres uses.
-            let rsp_msg = BackendMessage::decode(&mut self.target_conn)
+            let rsp_msg = BackendMessage::decode(&mut *target_conn)
                 .await
                 .map_err(|e| {
                     error!("error decoding target message. error {:?}", e);
@@ -455,11 +1120,11 @@ impl ProtocolHandler {
                 // the hashed password instead of plain password.
                 BackendMessage::AuthenticationMD5Password { salt } => {
                     let password = md5_password(
-                        self.config.target_username.as_ref().unwrap(),
-                        self.config.target_password.as_ref().unwrap(),
+                        config.target_username.as_ref().unwrap(),
+                        config.target_password.as_ref().unwrap(),
                         salt,
                     );
-                    self.target_conn
+                    target_conn
                         .write_all(
                             &FrontendMessage::PasswordMessage { password }.encode_without_buf(),
                         )
@@ -473,10 +1138,10 @@ impl ProtocolHandler {
                 // AuthenticationCleartextPassword states that targer server accept
                 // plain text password to authenticate.
                 BackendMessage::AuthenticationCleartextPassword => {
-                    self.target_conn
+                    target_conn
                         .write_all(
                             &FrontendMessage::PasswordMessage {
-                                password: self.config.target_password.as_ref().unwrap().clone(),
+                                password: config.target_password.as_ref().unwrap().clone(),
                             }
                             .encode_without_buf(),
                         )
@@ -506,8 +1171,8 @@ impl ProtocolHandler {
                         ));
                     }
                     ProtocolHandler::authenticate_sasl(
-                        &mut self.target_conn,
-                        self.config.target_password.as_ref().unwrap(),
+                        &mut *target_conn,
+                        config.target_password.as_ref().unwrap(),
                     )
                     .await?;
                     debug!("sasl authentication completed successfully");
@@ -515,9 +1180,6 @@ impl ProtocolHandler {
                 }
                 // AuthenticationOk states that authentication is completed.
                 BackendMessage::AuthenticationOk { .. } => {
-                    // send authentication ok to client connection since we established connection with
-                    // target.
-                    self.client_conn.write_all(&rsp_msg.encode()).await?;
                     return Ok(());
                 }
                 _ => {
@@ -531,6 +1193,34 @@ impl ProtocolHandler {
         }
     }
 
+    // cancel_target_query sends the cancel request of the given target key on a new
+    // connection, as the target postgres expects.
+    pub async fn cancel_target_query(
+        config: &PostgresConfig,
+        target_ssl_connector: &Option<SslConnector>,
+        target_key: BackendKey,
+    ) -> Result<(), anyhow::Error> {
+        let target_conn = ProtocolHandler::connect_target(config).await?;
+        let mut target_conn =
+            ProtocolHandler::try_ssl_upgrade(config, target_ssl_connector, target_conn).await?;
+        target_conn
+            .write_all(
+                &FrontendMessage::CancelRequest {
+                    process_id: target_key.process_id,
+                    secret_key: target_key.secret_key,
+                }
+                .encode_without_buf(),
+            )
+            .await
+            .map_err(|e| {
+                error!("error while sending cancel request to target. err: {:?}", e);
+                e
+            })?;
+        // target closes the connection without any response.
+        target_conn.shutdown().await.ok();
+        Ok(())
+    }
+
     // connect_target will create an unsecured connection with target postgres instance.
     async fn connect_target(config: &PostgresConfig) -> Result<PostgresConn, anyhow::Error> {
         Ok(PostgresConn::Unsecured(
@@ -573,9 +1263,52 @@ impl ProtocolHandler {
             return Err(anyhow!("updated policy violating the existing connection"));
         }
         self.policy_evaluator = evaluator;
+        self.policy_version += 1;
         Ok(())
     }
 
+    // check_statement rejects the statements which are invalidated by the policy change.
+    fn check_statement(
+        &self,
+        kind: StatementKind,
+        name: &String,
+    ) -> Result<(), ProtocolHandlerError> {
+        if self
+            .statement_registry
+            .is_invalidated(kind, name, self.policy_version)
+        {
+            return Err(ProtocolHandlerError::StalePreparedStatement(name.clone()));
+        }
+        Ok(())
+    }
+
+    // revalidate_statements rewrites the prepared statements with the current policy. statements
+    // which are rewritten differently are invalidated, because the row description of them
+    // no longer matches the rows returned by the rewritten query.
+    fn revalidate_statements(&mut self, table_info: &TableInfo) {
+        let rule = match self.get_rule_engine(table_info) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                error!("error while building rule engine for revalidation {:?}", e);
+                None
+            }
+        };
+        let ctx = table_info.ctx();
+        let dialect = sqlparser::dialect::PostgreSqlDialect {};
+        self.statement_registry
+            .revalidate(self.policy_version, |query| {
+                let rule = rule.clone()?;
+                let mut statements = sqlparser::parser::Parser::parse_sql(&dialect, query).ok()?;
+                let mut rewriter = QueryRewriter::new(rule, table_info.schemas.clone());
+                let mut out = String::from("");
+                for statement in &mut statements {
+                    rewriter.rewrite(statement, &ctx).ok()?;
+                    out = format!("{}{};", out, statement);
+                }
+                Some(out)
+            });
+    }
+
     async fn authenticate_sasl(
         mut target_conn: &mut PostgresConn,
         password: &String,
@@ -657,66 +1390,118 @@ impl ProtocolHandler {
     }
 
     // try_ssl_upgrade will try to upgrade the unsecured postgres connection to ssl connection
-    // if the server supports. Otherwise, unsercured connection is retured back.
+    // based on the configured sslmode. Unsecured connection is returned back if the sslmode
+    // allows the target to refuse ssl.
     async fn try_ssl_upgrade(
         config: &PostgresConfig,
+        ssl_connector: &Option<SslConnector>,
         conn: PostgresConn,
     ) -> Result<PostgresConn, anyhow::Error> {
-        unreachable!();
-        // match conn {
-        //     PostgresConn::Unsecured(mut inner) => {
-        //         inner
-        //             .write_all(&FrontendMessage::SslRequest.encode_without_buf())
-        //             .await
-        //             .map_err(|e| {
-        //                 error!("unable to send ssl upgrade request to target. err: {:?}", e);
-        //                 return anyhow!("unable to send ssl upgrade request");
-        //             })?;
-        //         // check whether remote server accept ssl connection.
-        //         let mut buf = [0; 1];
-        //         inner.read_exact(&mut buf).await.map_err(|e| {
-        //             error!("error reading response message after ssl request {:?}", e);
-        //             return anyhow!("error while reading response message after ssl request");
-        //         })?;
-        //         if buf[0] != ACCEPT_SSL_ENCRYPTION {
-        //             // since postgres doesn't accept ssl. so let's drop the
-        //             // current connection and create a new unsecured connection.
-        //             return ProtocolHandler::connect_target(config).await;
-        //         }
-        //         let connector = ProtocolHandler::get_ssl_connector();
-        //         let mut stream = connector.connect( inner).unwrap();
-        //         // Pin::new(&mut stream).connect().await.map_err(|e| {
-        //         //     error!(
-        //         //         "unable to upgrade the target connection to ssl stream {:?}",
-        //         //         e
-        //         //     );
-        //         //     anyhow!("error while upgrading target connection to ssl stream")
-        //         // })?;
-        //         debug!("taget connection upgraded to tls connection");
-        //         Ok(PostgresConn::Secured(stream))
-        //     }
-        //     _ => Ok(conn),
-        // }
-    }
-
-    // fn get_ssl_connector() -> TlsConnector {
-    //     let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
-    //     builder.set_ca_file("/usr/lib/ssl/certs/ca-certificates.crt").unwrap();
-    //     TlsConnector::new(builder.build().configure().unwrap(), "streak-production-final.cyym8n3wehdv.ap-south-1.rds.amazonaws.com")
-    // }
+        // ssl connector is not there only if the sslmode is disable.
+        let connector = match ssl_connector {
+            Some(connector) => connector,
+            None => return Ok(conn),
+        };
+        let mut inner = match conn {
+            PostgresConn::Unsecured(inner) => inner,
+            secured => return Ok(secured),
+        };
+        inner
+            .write_all(&FrontendMessage::SslRequest.encode_without_buf())
+            .await
+            .map_err(|e| {
+                error!("unable to send ssl upgrade request to target. err: {:?}", e);
+                return anyhow!("unable to send ssl upgrade request");
+            })?;
+        // check whether remote server accept ssl connection.
+        let mut buf = [0; 1];
+        inner.read_exact(&mut buf).await.map_err(|e| {
+            error!("error reading response message after ssl request {:?}", e);
+            return anyhow!("error while reading response message after ssl request");
+        })?;
+        let ssl_mode = config.target_ssl_mode();
+        if buf[0] != ACCEPT_SSL_ENCRYPTION {
+            if ssl_mode == SslMode::Prefer {
+                // postgres let us continue with the same connection after
+                // refusing the ssl request.
+                debug!("target postgres refused ssl, continuing with unsecured connection");
+                return Ok(PostgresConn::Unsecured(inner));
+            }
+            return Err(anyhow!(
+                "target postgres doesn't support ssl but the sslmode is {:?}",
+                ssl_mode
+            ));
+        }
+        let server_name = config.target_ssl_server_name();
+        let mut ssl_config = connector.configure()?;
+        configure_target_ssl(&mut ssl_config, ssl_mode, &server_name)?;
+        let ssl = ssl_config.into_ssl(&server_name)?;
+        let mut stream = SslStream::new(ssl, inner)?;
+        Pin::new(&mut stream).connect().await.map_err(|e| {
+            error!(
+                "unable to upgrade the target connection to ssl stream {:?}",
+                e
+            );
+            anyhow!("error while upgrading target connection to ssl stream")
+        })?;
+        debug!("target connection upgraded to tls connection");
+        Ok(PostgresConn::Secured(stream))
+    }
 
     async fn handle_frontend_message(
         &mut self,
         msg: &mut FrontendMessage,
-        ctx: Ctx,
-        schemas: Vec<String>,
+        table_info: &TableInfo,
     ) -> Result<(), ProtocolHandlerError> {
         match msg {
             FrontendMessage::Query { query_string } => {
-                self.handle_query(query_string, ctx, schemas).await?;
+                self.handle_query(query_string, table_info).await?;
+            }
+            FrontendMessage::Parse {
+                name,
+                query,
+                object_ids,
+            } => {
+                let original = query.clone();
+                self.handle_query(query, table_info).await?;
+                let mut statement =
+                    PreparedStatement::new(original, query.clone(), self.policy_version);
+                // parameter types are needed to prepare the statement on another target
+                // connection.
+                statement.param_types = object_ids.clone();
+                self.statement_registry
+                    .prepare(StatementKind::Protocol, name.clone(), statement);
             }
-            FrontendMessage::Parse { query, .. } => {
-                self.handle_query(query, ctx, schemas).await?;
+            FrontendMessage::Bind {
+                destination_portal_name,
+                prepared_statement_name,
+                ..
+            } => {
+                self.check_statement(StatementKind::Protocol, prepared_statement_name)?;
+                self.statement_registry
+                    .bind(destination_portal_name.clone(), prepared_statement_name);
+            }
+            FrontendMessage::Describe {
+                is_prepared_statement,
+                name,
+            } => {
+                let kind = if *is_prepared_statement {
+                    StatementKind::Protocol
+                } else {
+                    StatementKind::Portal
+                };
+                self.check_statement(kind, name)?;
+            }
+            FrontendMessage::Execute { name, .. } => {
+                self.check_statement(StatementKind::Portal, name)?;
+            }
+            FrontendMessage::Close { is_portal, name } => {
+                let kind = if *is_portal {
+                    StatementKind::Portal
+                } else {
+                    StatementKind::Protocol
+                };
+                self.statement_registry.close(kind, name);
             }
             _ => {}
         }
@@ -726,14 +1511,35 @@ impl ProtocolHandler {
     async fn handle_query(
         &mut self,
         query: &mut String,
-        ctx: Ctx,
-        schemas: Vec<String>,
+        table_info: &TableInfo,
     ) -> Result<(), ProtocolHandlerError> {
         debug!("input query {}", query);
         let audit_msg = build_audit_msg(query, &self.groups);
         self.audit_sender.send(audit_msg).await;
+        // sql parser doesn't know most of the COPY statement, so it's parsed separately.
+        let copy = CopyStatement::parse(query);
         let dialect = sqlparser::dialect::PostgreSqlDialect {};
-        let mut statements = match sqlparser::parser::Parser::parse_sql(&dialect, query) {
+        let parsed = match copy {
+            Ok(Some(copy)) => {
+                let rewrites = self.handle_copy(query, copy, table_info)?;
+                return self
+                    .send_rewrite_notice(&rewrites, table_info.rewrite_notice)
+                    .await;
+            }
+            Ok(None) => match DmlStatement::parse(query) {
+                // RETURNING, ON CONFLICT and MERGE are not known to the sql parser.
+                Ok(Some(dml)) => {
+                    let rewrites = self.handle_dml(query, dml, table_info)?;
+                    return self
+                        .send_rewrite_notice(&rewrites, table_info.rewrite_notice)
+                        .await;
+                }
+                Ok(None) => sqlparser::parser::Parser::parse_sql(&dialect, query),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let mut statements = match parsed {
             Ok(statements) => statements,
             Err(e) => {
                 error!(
@@ -743,30 +1549,106 @@ impl ProtocolHandler {
                 if self.passthrough {
                     return Ok(());
                 }
-                return Err(ProtocolHandlerError::ErrParsingQuery);
+                return Err(ProtocolHandlerError::ErrParsingQuery(e));
             }
         };
-        let rule = self.get_rule_engine()?;
+        let ctx = table_info.ctx();
+        let schemas = table_info.schemas.clone();
+        let rule = self.get_rule_engine(table_info)?;
         debug!("rewriting with schema {:?}", schemas);
         let mut rewriter = QueryRewriter::new(rule, schemas);
         let mut out = String::from("");
         let mut good_to_forward = false;
+        let mut rewrites = vec![];
+        let fail_open = self.config.unsupported_sql_mode() == UnsupportedSqlMode::FailOpen;
         for statement in &mut statements {
-            match rewriter.rewrite(statement, &ctx) {
+            // rewriter may have modified the statement partially before finding the
+            // unsupported construct. so, the original one is forwarded in fail-open mode.
+            let original = if fail_open {
+                Some(statement.clone())
+            } else {
+                None
+            };
+            // PREPARE is recorded with the query sent by the client, so that it can be
+            // rewritten again on policy change.
+            let prepare_query = match statement {
+                Statement::Prepare { .. } => Some(statement.to_string()),
+                _ => None,
+            };
+            let result = match statement {
+                Statement::Execute { name, .. }
+                    if self.statement_registry.is_invalidated(
+                        StatementKind::Sql,
+                        &name.value,
+                        self.policy_version,
+                    ) =>
+                {
+                    Err(ProtocolHandlerError::StalePreparedStatement(
+                        name.value.clone(),
+                    ))
+                }
+                // DISCARD ALL drops the sql prepared statements when the target connection
+                // is released after the transaction, so they can't be executed later.
+                Statement::Prepare { .. } if self.target_pool.mode() == PoolMode::Transaction => {
+                    Err(ProtocolHandlerError::UnsupportedInTransactionPool(
+                        String::from("PREPARE"),
+                    ))
+                }
+                _ => rewriter
+                    .rewrite(statement, &ctx)
+                    .map_err(ProtocolHandlerError::RewriterError),
+            };
+            match result {
                 Ok(metrics) => {
                     debug!("pushing metrics {:?}", metrics);
                     self.push_metrics(metrics);
+                    rewrites.extend(rewriter.take_rewrites());
+                }
+                Err(ProtocolHandlerError::RewriterError(QueryRewriterError::Unsupported(
+                    construct,
+                ))) if original.is_some() => {
+                    warn!(
+                        "forwarding statement with unsupported sql construct {} as fail-open is configured",
+                        construct
+                    );
+                    // rewrites made before the unsupported construct are not part of the
+                    // forwarded statement.
+                    rewriter.take_rewrites();
+                    *statement = original.unwrap();
                 }
                 Err(e) => {
                     if !good_to_forward {
-                        return Err(ProtocolHandlerError::RewriterError(e));
+                        return Err(e);
                     }
                     debug!("error {:?} is buffered to deliver later", e);
-                    self.pending_error = Some(ProtocolHandlerError::RewriterError(e));
+                    self.pending_error =
+                        Some(e.error_fields(Some(query.as_str()), self.config.error_verbosity()));
                     break;
                 }
             }
 
+            // keep track of the statements prepared using sql.
+            match statement {
+                Statement::Prepare { name, .. } => self.statement_registry.prepare(
+                    StatementKind::Sql,
+                    name.value.clone(),
+                    PreparedStatement::new(
+                        prepare_query.unwrap(),
+                        format!("{};", statement),
+                        self.policy_version,
+                    ),
+                ),
+                Statement::Deallocate { name, .. } => {
+                    if name.value.to_uppercase() == "ALL" {
+                        self.statement_registry.close_all(StatementKind::Sql);
+                    } else {
+                        self.statement_registry
+                            .close(StatementKind::Sql, &name.value);
+                    }
+                }
+                _ => {}
+            }
+
             // update the current state of transaction.
             match statement {
                 Statement::StartTransaction { .. } => {
@@ -777,15 +1659,90 @@ impl ProtocolHandler {
                 }
                 _ => {}
             }
+            if is_schema_change(statement) {
+                self.schema_changed = true;
+            }
             good_to_forward = true;
             out = format!("{}{};", out, statement);
         }
         debug!("output query {}", out);
         *query = out;
+        self.send_rewrite_notice(&rewrites, table_info.rewrite_notice)
+            .await
+    }
+
+    // send_rewrite_notice tells the client how the query is rewritten by the policy. it's
+    // sent before the query is forwarded, so the client gets it before the result.
+    async fn send_rewrite_notice(
+        &mut self,
+        rewrites: &[Rewrite],
+        level: RewriteNotice,
+    ) -> Result<(), ProtocolHandlerError> {
+        let notice = match rewrite_notice(rewrites, level) {
+            Some(notice) => notice,
+            None => return Ok(()),
+        };
+        self.client_conn
+            .write_all(&notice.encode())
+            .await
+            .map_err(|e| anyhow!("error while sending the rewrite notice {:?}", e))?;
         Ok(())
     }
 
-    fn get_rule_engine(&mut self) -> Result<HardRuleEngine, anyhow::Error> {
+    // handle_copy rewrites the COPY statement. masker of the copied rows is set if the rows
+    // are copied to the client. changes made to the statement are returned.
+    fn handle_copy(
+        &mut self,
+        query: &mut String,
+        mut copy: CopyStatement,
+        table_info: &TableInfo,
+    ) -> Result<Vec<Rewrite>, ProtocolHandlerError> {
+        let rule = self.get_rule_engine(table_info)?;
+        let mut rewriter = QueryRewriter::new(rule, table_info.schemas.clone());
+        let metrics = rewriter.rewrite_copy(&mut copy, &table_info.ctx())?;
+        self.push_metrics(metrics);
+        if let CopySource::Query(copy_query) = &copy.source {
+            let columns = copy_columns(copy_query, &table_info.masked_tables)?;
+            self.result_tracker.expect_copy(CopyMasker::new(
+                copy.format(),
+                columns,
+                self.config.mask_hash_secret(),
+            ));
+        }
+        *query = copy.to_string();
+        debug!("output query {}", query);
+        Ok(rewriter.take_rewrites())
+    }
+
+    // handle_dml validates the INSERT, UPDATE and DELETE statements having RETURNING or
+    // ON CONFLICT clause and MERGE statement.
+    fn handle_dml(
+        &mut self,
+        query: &mut String,
+        mut dml: DmlStatement,
+        table_info: &TableInfo,
+    ) -> Result<Vec<Rewrite>, ProtocolHandlerError> {
+        let rule = self.get_rule_engine(table_info)?;
+        let mut rewriter = QueryRewriter::new(rule, table_info.schemas.clone());
+        match rewriter.rewrite_dml(&mut dml, &table_info.ctx()) {
+            Ok(metrics) => self.push_metrics(metrics),
+            Err(QueryRewriterError::Unsupported(construct))
+                if self.config.unsupported_sql_mode() == UnsupportedSqlMode::FailOpen =>
+            {
+                warn!(
+                    "forwarding statement with unsupported sql construct {} as fail-open is configured",
+                    construct
+                );
+                return Ok(vec![]);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        *query = dml.to_string();
+        debug!("output query {}", query);
+        Ok(rewriter.take_rewrites())
+    }
+
+    fn get_rule_engine(&mut self, table_info: &TableInfo) -> Result<HardRuleEngine, anyhow::Error> {
         let insert_result = self.policy_evaluator.evaluate(
             &self.datasource_name,
             &"insert".to_string(),
@@ -806,11 +1763,48 @@ impl ProtocolHandler {
             &"view".to_string(),
             &self.groups,
         )?;
+        let delete_result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"delete".to_string(),
+            &self.groups,
+        )?;
+        let truncate_result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"truncate".to_string(),
+            &self.groups,
+        )?;
+        let ddl_result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"ddl".to_string(),
+            &self.groups,
+        )?;
+        let grant_result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"grant".to_string(),
+            &self.groups,
+        )?;
+        let maintenance_result = self.policy_evaluator.evaluate(
+            &self.datasource_name,
+            &"maintenance".to_string(),
+            &self.groups,
+        )?;
 
         debug!("view result {:?}", view_result);
 
+        let protected_filter_action =
+            ProtectedFilterAction::from_policy(view_result.protected_filter_action.as_ref());
+        let mut protected_columns = self.filter_attributes_for_db(view_result.protected_attributes);
+        let mut column_masks = self.filter_column_masks_for_db(
+            &view_result.protected_column_masks,
+            &table_info.column_types,
+        )?;
+        protect_masked_relations(
+            &table_info.masked_relations,
+            &mut protected_columns,
+            &mut column_masks,
+        );
         let rule_engine = HardRuleEngine {
-            protected_columns: self.filter_attributes_for_db(view_result.protected_attributes),
+            protected_columns: protected_columns,
             insert_allowed: insert_result.allow,
             insert_allowed_attributes: self
                 .filter_attributes_for_db(insert_result.allowed_attributes),
@@ -820,11 +1814,79 @@ impl ProtocolHandler {
             update_allowed_attributes: self
                 .filter_attributes_for_db(update_result.allowed_attributes),
             view_allowed: view_result.allow,
+            delete_allowed: delete_result.allow,
+            delete_allowed_attributes: self
+                .filter_attributes_for_db(delete_result.allowed_attributes),
+            truncate_allowed: truncate_result.allow,
+            truncate_allowed_attributes: self
+                .filter_attributes_for_db(truncate_result.allowed_attributes),
+            ddl_allowed: ddl_result.allow,
+            grant_allowed: grant_result.allow,
+            maintenance_allowed: maintenance_result.allow,
+            view_row_filters: self.filter_row_filters_for_db(view_result.row_filters)?,
+            update_row_filters: self.filter_row_filters_for_db(update_result.row_filters)?,
+            delete_row_filters: self.filter_row_filters_for_db(delete_result.row_filters)?,
+            protected_filter_action: protected_filter_action,
+            column_masks: column_masks,
         };
         debug!("evaluating policy with rule {:?}", rule_engine);
         Ok(rule_engine)
     }
 
+    // filter_column_masks_for_db returns the masking strategy of the protected columns of
+    // the connected db grouped by the table. column_types are used to validate the
+    // constant of the strategy.
+    fn filter_column_masks_for_db(
+        &self,
+        column_masks: &HashMap<String, String>,
+        column_types: &HashMap<String, HashMap<String, String>>,
+    ) -> Result<HashMap<String, HashMap<String, MaskingStrategy>>, anyhow::Error> {
+        let mut filtered_column_masks: HashMap<String, HashMap<String, MaskingStrategy>> =
+            HashMap::new();
+        for (column, strategy) in column_masks {
+            // columns are in the format of db.schema.table.column
+            let splits = column.split(".").collect::<Vec<&str>>();
+            if splits.len() != 4 || splits[0] != self.connected_db {
+                continue;
+            }
+            let table = format!("{}.{}", splits[1], splits[2]);
+            let data_type = column_types
+                .get(&table)
+                .and_then(|types| types.get(splits[3]));
+            let strategy = MaskingStrategy::from_policy(strategy, data_type)?;
+            filtered_column_masks
+                .entry(table)
+                .or_default()
+                .insert(splits[3].to_string(), strategy);
+        }
+        Ok(filtered_column_masks)
+    }
+
+    // filter_row_filters_for_db returns the parsed row filters of the connected db. invalid
+    // row filter fails the query instead of skipping the filter.
+    fn filter_row_filters_for_db(
+        &self,
+        row_filters: HashMap<String, String>,
+    ) -> Result<HashMap<String, Expr>, anyhow::Error> {
+        let mut filtered_row_filters = HashMap::new();
+        for (table, predicate) in row_filters {
+            let splits = table.split(".").collect::<Vec<&str>>();
+            if splits.len() != 3 || splits[0] != self.connected_db {
+                continue;
+            }
+            let filter = parse_row_filter(&predicate).map_err(|e| {
+                anyhow!(
+                    "invalid row filter {} for the table {}: {}",
+                    predicate,
+                    table,
+                    e
+                )
+            })?;
+            filtered_row_filters.insert(format!("{}.{}", splits[1], splits[2]), filter);
+        }
+        Ok(filtered_row_filters)
+    }
+
     fn filter_attributes_for_db(&self, attributes: Vec<String>) -> HashMap<String, Vec<String>> {
         let mut filtered_attributes: HashMap<String, Vec<String>> = HashMap::new();
         for attribute in attributes {
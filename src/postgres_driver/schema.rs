@@ -0,0 +1,453 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::PostgresConfig;
+use crate::postgres_driver::protocol_handler::configure_target_ssl;
+use log::*;
+use openssl::ssl::SslConnector;
+use postgres_openssl::MakeTlsConnector;
+use sqlparser::ast::Statement;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use tokio::time as tokio_time;
+use tokio_postgres::{AsyncMessage, Client, Connection};
+
+// REFRESH_INTERVAL is the interval of the periodic refresh, which catches the schema
+// changes that are neither done through the proxy nor notified by the target.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 2);
+
+// RECONNECT_INTERVAL is the wait time between the reconnect attempts of the metadata
+// client.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+// SCHEMA_QUERY retrives the columns of all the tables of the database. data type is
+// taken from the information schema, since the query rewriter expects the sql type names.
+// array and user defined types are named by the type itself, so that the masked value
+// can be casted to it.
+const SCHEMA_QUERY: &str = r#"
+        SELECT
+          c.oid,
+          a.attnum,
+          n.nspname::text,
+          c.relname::text,
+          a.attname::text,
+          a.atttypid,
+          CASE
+            WHEN col.data_type IN ('ARRAY', 'USER-DEFINED')
+            THEN pg_catalog.format_type(a.atttypid, NULL)
+            ELSE col.data_type::text
+          END
+        FROM
+          pg_catalog.pg_attribute a
+          JOIN pg_catalog.pg_class c ON c.oid = a.attrelid
+          JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+          LEFT JOIN information_schema.columns col ON col.table_schema = n.nspname
+          AND col.table_name = c.relname
+          AND col.column_name = a.attname
+        WHERE
+          a.attnum > 0
+          AND NOT a.attisdropped
+          AND c.relkind IN ('r', 'v', 'm', 'f', 'p')
+          AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+        ORDER BY
+          c.oid,
+          a.attnum
+        "#;
+
+// VIEW_DEPENDENCY_QUERY retrives the relations read by the views. columns of the view
+// can't be traced back to the columns of the relation, so only the relations are taken.
+const VIEW_DEPENDENCY_QUERY: &str = r#"
+        SELECT DISTINCT
+          vn.nspname::text,
+          v.relname::text,
+          tn.nspname::text,
+          t.relname::text
+        FROM
+          pg_catalog.pg_rewrite r
+          JOIN pg_catalog.pg_depend d ON d.objid = r.oid
+          AND d.classid = 'pg_catalog.pg_rewrite'::regclass
+          AND d.refclassid = 'pg_catalog.pg_class'::regclass
+          JOIN pg_catalog.pg_class v ON v.oid = r.ev_class
+          JOIN pg_catalog.pg_namespace vn ON vn.oid = v.relnamespace
+          JOIN pg_catalog.pg_class t ON t.oid = d.refobjid
+          JOIN pg_catalog.pg_namespace tn ON tn.oid = t.relnamespace
+        WHERE
+          d.refobjid <> r.ev_class
+          AND v.relkind IN ('v', 'm')
+        "#;
+
+/// SchemaColumn is the column of a table in the target database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaColumn {
+    pub table_oid: u32,
+    pub attnum: i16,
+    pub schema: String,
+    pub table: String,
+    pub name: String,
+    pub type_oid: u32,
+    // data_type is None if the column is not visible in the information schema.
+    pub data_type: Option<String>,
+}
+
+/// ViewDependency is the relation read by a view or materialized view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewDependency {
+    pub view_schema: String,
+    pub view: String,
+    pub schema: String,
+    pub table: String,
+}
+
+/// Schema holds the columns of all the tables of a database.
+#[derive(Debug, Default, PartialEq)]
+pub struct Schema {
+    pub columns: Vec<SchemaColumn>,
+    // views holds the relations read by the views.
+    pub views: Vec<ViewDependency>,
+}
+
+impl Schema {
+    // load retrives the schema using the metadata client.
+    async fn load(client: &Client) -> Result<Schema, anyhow::Error> {
+        let rows = client.query(SCHEMA_QUERY, &[]).await?;
+        let columns = rows
+            .iter()
+            .map(|row| SchemaColumn {
+                table_oid: row.get(0),
+                attnum: row.get(1),
+                schema: row.get(2),
+                table: row.get(3),
+                name: row.get(4),
+                type_oid: row.get(5),
+                data_type: row.get(6),
+            })
+            .collect();
+        let rows = client.query(VIEW_DEPENDENCY_QUERY, &[]).await?;
+        let views = rows
+            .iter()
+            .map(|row| ViewDependency {
+                view_schema: row.get(0),
+                view: row.get(1),
+                schema: row.get(2),
+                table: row.get(3),
+            })
+            .collect();
+        Ok(Schema { columns, views })
+    }
+}
+
+// SharedSchema is the schema of a database shared by the sessions. refresher of the
+// schema stops once it's dropped by the last session.
+struct SharedSchema {
+    schema: watch::Receiver<Arc<Schema>>,
+    refresh: Arc<Notify>,
+}
+
+/// SchemaWatcher gives the latest schema of a database to the session.
+#[derive(Clone)]
+pub struct SchemaWatcher {
+    schema: watch::Receiver<Arc<Schema>>,
+    shared: Arc<SharedSchema>,
+}
+
+impl SchemaWatcher {
+    fn new(shared: Arc<SharedSchema>) -> SchemaWatcher {
+        SchemaWatcher {
+            schema: shared.schema.clone(),
+            shared,
+        }
+    }
+
+    // schema returns the latest schema.
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.borrow().clone()
+    }
+
+    // changed waits until the schema is reloaded.
+    pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        self.schema.changed().await
+    }
+
+    // refresh asks the cache to reload the schema. the reloaded schema is sent to the
+    // sessions even if it's not modified.
+    pub fn refresh(&self) {
+        self.shared.refresh.notify_one();
+    }
+}
+
+/// SchemaCache holds the schema of the target databases. schema of a database is loaded
+/// once and shared by all the sessions of the database. it's evicted along with the
+/// metadata connection once the last session of the database ends.
+#[derive(Clone, Default)]
+pub struct SchemaCache {
+    databases: Arc<Mutex<HashMap<String, Arc<Mutex<Weak<SharedSchema>>>>>>,
+}
+
+impl SchemaCache {
+    // watch returns the schema watcher of the given database. schema is loaded before the
+    // first session of the database starts, so that no session is served without the
+    // schema.
+    pub async fn watch(
+        &self,
+        config: &PostgresConfig,
+        ssl_connector: &Option<SslConnector>,
+        database: &String,
+    ) -> Result<SchemaWatcher, anyhow::Error> {
+        let slot = {
+            let mut databases = self.databases.lock().await;
+            // evicted databases are removed, unless a session is loading them.
+            databases.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || match slot.try_lock() {
+                        Ok(shared) => shared.strong_count() > 0,
+                        Err(_) => true,
+                    }
+            });
+            databases.entry(database.clone()).or_default().clone()
+        };
+        // schema is loaded holding the lock of the database only, so that the sessions of
+        // the other databases don't wait for it.
+        let mut slot = slot.lock().await;
+        if let Some(shared) = slot.upgrade() {
+            return Ok(SchemaWatcher::new(shared));
+        }
+        let (client, notifications) = connect_listener(config, ssl_connector, database).await?;
+        let schema = Schema::load(&client).await?;
+        let (sender, receiver) = watch::channel(Arc::new(schema));
+        let shared = Arc::new(SharedSchema {
+            schema: receiver,
+            refresh: Arc::new(Notify::new()),
+        });
+        let refresher = SchemaRefresher {
+            config: config.clone(),
+            ssl_connector: ssl_connector.clone(),
+            database: database.clone(),
+            sender,
+            refresh: shared.refresh.clone(),
+        };
+        tokio::spawn(refresher.run(client, notifications));
+        *slot = Arc::downgrade(&shared);
+        Ok(SchemaWatcher::new(shared))
+    }
+}
+
+// SchemaRefresher reloads the schema of a database.
+struct SchemaRefresher {
+    config: PostgresConfig,
+    ssl_connector: Option<SslConnector>,
+    database: String,
+    sender: watch::Sender<Arc<Schema>>,
+    refresh: Arc<Notify>,
+}
+
+impl SchemaRefresher {
+    // run reloads the schema whenever a session asks for it, the target notifies the
+    // schema change or the refresh interval is elapsed. metadata client is reconnected
+    // if the connection is closed. it returns once all the sessions of the database are
+    // ended, which closes the metadata connection.
+    async fn run(self, mut client: Client, mut notifications: mpsc::UnboundedReceiver<()>) {
+        let mut ticker = tokio_time::interval(REFRESH_INTERVAL);
+        // first tick completes immediately, but the schema is already loaded.
+        ticker.tick().await;
+        loop {
+            // periodically loaded schema is sent only if it's modified, so that the
+            // sessions doesn't rebuild the table info for nothing.
+            let force = tokio::select! {
+                _ = self.refresh.notified() => true,
+                notification = notifications.recv() => {
+                    if notification.is_none() {
+                        warn!("metadata connection of {} is closed, reconnecting", self.database);
+                        let (new_client, new_notifications) = match self.reconnect().await {
+                            Some(conn) => conn,
+                            None => return,
+                        };
+                        client = new_client;
+                        notifications = new_notifications;
+                    }
+                    true
+                }
+                _ = ticker.tick() => false,
+                _ = self.sender.closed() => {
+                    debug!("evicting schema of {}", self.database);
+                    return;
+                }
+            };
+            // burst of notifications are handled with a single reload.
+            while notifications.try_recv().is_ok() {}
+            debug!("refreshing schema of {}", self.database);
+            let schema = match Schema::load(&client).await {
+                Ok(schema) => schema,
+                Err(e) => {
+                    error!("error while refreshing schema of {} {:?}", self.database, e);
+                    continue;
+                }
+            };
+            if !force && **self.sender.borrow() == schema {
+                continue;
+            }
+            if self.sender.send(Arc::new(schema)).is_err() {
+                return;
+            }
+        }
+    }
+
+    // reconnect retries until the metadata client is connected. None is returned if all
+    // the sessions of the database are ended meanwhile.
+    async fn reconnect(&self) -> Option<(Client, mpsc::UnboundedReceiver<()>)> {
+        loop {
+            match connect_listener(&self.config, &self.ssl_connector, &self.database).await {
+                Ok(conn) => return Some(conn),
+                Err(e) => {
+                    error!(
+                        "error while reconnecting metadata client of {} {:?}",
+                        self.database, e
+                    );
+                    tokio::select! {
+                        _ = tokio_time::sleep(RECONNECT_INTERVAL) => {}
+                        _ = self.sender.closed() => return None,
+                    }
+                }
+            }
+        }
+    }
+}
+
+// connect_listener connects the metadata client and listens for the schema change
+// notifications.
+async fn connect_listener(
+    config: &PostgresConfig,
+    ssl_connector: &Option<SslConnector>,
+    database: &String,
+) -> Result<(Client, mpsc::UnboundedReceiver<()>), anyhow::Error> {
+    let (client, notifications) = connect_metadata_client(config, ssl_connector, database).await?;
+    client
+        .batch_execute(&format!(
+            "LISTEN {}",
+            quote_identifier(&config.schema_change_channel())
+        ))
+        .await?;
+    Ok((client, notifications))
+}
+
+// connect_metadata_client creates the postgres client which is used to retrive the
+// table metadata. It follows the same sslmode as the target connection.
+async fn connect_metadata_client(
+    config: &PostgresConfig,
+    ssl_connector: &Option<SslConnector>,
+    database: &String,
+) -> Result<(Client, mpsc::UnboundedReceiver<()>), anyhow::Error> {
+    let ssl_mode = config.target_ssl_mode();
+    let conn_str = format!(
+        "host={} port={} user={} dbname = {} password = {} sslmode={}",
+        config.target_addr.as_ref().unwrap(),
+        config.target_port.as_ref().unwrap(),
+        config.target_username.as_ref().unwrap(),
+        database,
+        config.target_password.as_ref().unwrap(),
+        ssl_mode.as_tokio_postgres_mode()
+    );
+    let connector = match ssl_connector {
+        Some(connector) => connector.clone(),
+        None => {
+            let (client, connection) =
+                tokio_postgres::connect(&conn_str, tokio_postgres::NoTls).await?;
+            return Ok((client, spawn_connection(connection)));
+        }
+    };
+    let server_name = config.target_ssl_server_name();
+    let mut tls = MakeTlsConnector::new(connector);
+    tls.set_callback(move |ssl_config, _| configure_target_ssl(ssl_config, ssl_mode, &server_name));
+    let (client, connection) = tokio_postgres::connect(&conn_str, tls).await?;
+    Ok((client, spawn_connection(connection)))
+}
+
+// spawn_connection drives the connection of the metadata client and forwards the
+// notifications. returned receiver is closed once the connection is closed.
+fn spawn_connection<S, T>(mut connection: Connection<S, T>) -> mpsc::UnboundedReceiver<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(msg) = futures::future::poll_fn(|cx| connection.poll_message(cx)).await {
+            match msg {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    debug!("got schema change notification {:?}", notification);
+                    let _ = sender.send(());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("metadata connection error {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+// is_schema_change tells whether the statement may change the columns of the tables.
+pub fn is_schema_change(statement: &Statement) -> bool {
+    matches!(
+        statement,
+        Statement::CreateTable { .. }
+            | Statement::CreateView { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::AlterTable { .. }
+            | Statement::Drop { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    #[test]
+    fn test_is_schema_change() {
+        let cases = vec![
+            ("CREATE TABLE public.users (id INT, ssn TEXT)", true),
+            ("ALTER TABLE public.users ADD COLUMN phone TEXT", true),
+            (
+                "CREATE VIEW public.user_view AS SELECT id FROM public.users",
+                true,
+            ),
+            ("DROP TABLE public.users", true),
+            ("CREATE SCHEMA app", true),
+            ("SELECT * FROM public.users", false),
+            ("INSERT INTO public.users (id) VALUES (1)", false),
+        ];
+        for (query, expected) in cases {
+            let statements = Parser::parse_sql(&PostgreSqlDialect {}, query).unwrap();
+            assert_eq!(is_schema_change(&statements[0]), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(
+            quote_identifier("inspektor_schema_change"),
+            "\"inspektor_schema_change\""
+        );
+        assert_eq!(quote_identifier("ddl\"events"), "\"ddl\"\"events\"");
+    }
+}
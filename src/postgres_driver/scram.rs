@@ -0,0 +1,264 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use anyhow::anyhow;
+use md5::{Digest, Md5};
+use openssl::base64::{decode_block, encode_block};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+const SCRAM_KEY_LEN: usize = 32;
+const SCRAM_NONCE_LEN: usize = 18;
+const SCRAM_DEFAULT_ITERATIONS: u32 = 4096;
+
+/// ScramVerifier is the postgres style SCRAM-SHA-256 secret of the user.
+/// eg: SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>
+#[derive(Debug, Clone)]
+pub struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    pub fn parse(verifier: &str) -> Result<ScramVerifier, anyhow::Error> {
+        let invalid = || anyhow!("invalid scram verifier");
+        let mut parts = verifier.split('$');
+        if parts.next() != Some(SCRAM_SHA_256) {
+            return Err(invalid());
+        }
+        let (iterations, salt) = parts
+            .next()
+            .and_then(|part| part.split_once(':'))
+            .ok_or_else(invalid)?;
+        let (stored_key, server_key) = parts
+            .next()
+            .and_then(|part| part.split_once(':'))
+            .ok_or_else(invalid)?;
+        let verifier = ScramVerifier {
+            iterations: iterations.parse::<u32>().map_err(|_| invalid())?,
+            salt: decode_block(salt).map_err(|_| invalid())?,
+            stored_key: decode_block(stored_key).map_err(|_| invalid())?,
+            server_key: decode_block(server_key).map_err(|_| invalid())?,
+        };
+        if verifier.stored_key.len() != SCRAM_KEY_LEN || verifier.server_key.len() != SCRAM_KEY_LEN
+        {
+            return Err(invalid());
+        }
+        Ok(verifier)
+    }
+
+    // mock returns a verifier that never authenticates. It's used for the unknown
+    // users, so that the client can't figure out whether the user exist or not.
+    pub fn mock() -> Result<ScramVerifier, anyhow::Error> {
+        let mut salt = vec![0; 16];
+        rand_bytes(&mut salt)?;
+        let mut stored_key = vec![0; SCRAM_KEY_LEN];
+        rand_bytes(&mut stored_key)?;
+        let mut server_key = vec![0; SCRAM_KEY_LEN];
+        rand_bytes(&mut server_key)?;
+        Ok(ScramVerifier {
+            iterations: SCRAM_DEFAULT_ITERATIONS,
+            salt,
+            stored_key,
+            server_key,
+        })
+    }
+}
+
+/// ScramServer handles the server side of the SCRAM-SHA-256 exchange. refer
+/// https://datatracker.ietf.org/doc/html/rfc5802 for more context.
+pub struct ScramServer {
+    verifier: ScramVerifier,
+    nonce: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl ScramServer {
+    pub fn new(verifier: ScramVerifier) -> Result<ScramServer, anyhow::Error> {
+        let mut nonce = [0; SCRAM_NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        Ok(ScramServer {
+            verifier,
+            nonce: encode_block(&nonce),
+            gs2_header: String::default(),
+            client_first_bare: String::default(),
+            server_first: String::default(),
+        })
+    }
+
+    // handle_client_first process the client first message and returns the server
+    // first message.
+    pub fn handle_client_first(&mut self, msg: &[u8]) -> Result<String, anyhow::Error> {
+        let msg = std::str::from_utf8(msg).map_err(|_| anyhow!("invalid client first message"))?;
+        // gs2 header contains channel binding flag and authzid.
+        let mut splits = msg.splitn(3, ',');
+        let cbind_flag = splits.next().unwrap_or_default();
+        let authzid = splits.next();
+        let client_first_bare = splits.next();
+        if authzid.is_none() || client_first_bare.is_none() {
+            return Err(anyhow!("invalid client first message"));
+        }
+        match cbind_flag {
+            "n" | "y" => {}
+            _ => return Err(anyhow!("channel binding is not supported")),
+        }
+        let client_first_bare = client_first_bare.unwrap();
+        let mut client_nonce = None;
+        for attribute in client_first_bare.split(',') {
+            if let Some(nonce) = attribute.strip_prefix("r=") {
+                client_nonce = Some(nonce);
+            } else if attribute.starts_with("m=") {
+                return Err(anyhow!("scram extensions are not supported"));
+            }
+        }
+        let client_nonce = client_nonce.ok_or_else(|| anyhow!("client nonce is missing"))?;
+        self.gs2_header = format!("{},{},", cbind_flag, authzid.unwrap());
+        self.client_first_bare = client_first_bare.to_string();
+        self.nonce = format!("{}{}", client_nonce, self.nonce);
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            encode_block(&self.verifier.salt),
+            self.verifier.iterations
+        );
+        Ok(self.server_first.clone())
+    }
+
+    // handle_client_final verifies the client proof and returns the server final message.
+    pub fn handle_client_final(&mut self, msg: &[u8]) -> Result<String, anyhow::Error> {
+        let msg = std::str::from_utf8(msg).map_err(|_| anyhow!("invalid client final message"))?;
+        let proof_pos = msg
+            .rfind(",p=")
+            .ok_or_else(|| anyhow!("client proof is missing"))?;
+        let without_proof = &msg[..proof_pos];
+        let proof =
+            decode_block(&msg[proof_pos + 3..]).map_err(|_| anyhow!("invalid client proof"))?;
+        let mut channel_binding = None;
+        let mut nonce = None;
+        for attribute in without_proof.split(',') {
+            if let Some(val) = attribute.strip_prefix("c=") {
+                channel_binding = Some(val);
+            } else if let Some(val) = attribute.strip_prefix("r=") {
+                nonce = Some(val);
+            }
+        }
+        if channel_binding != Some(encode_block(self.gs2_header.as_bytes()).as_str()) {
+            return Err(anyhow!("unexpected channel binding"));
+        }
+        if nonce != Some(self.nonce.as_str()) {
+            return Err(anyhow!("invalid nonce"));
+        }
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let client_signature = hmac_sha256(&self.verifier.stored_key, auth_message.as_bytes())?;
+        if proof.len() != client_signature.len() {
+            return Err(anyhow!("invalid client proof"));
+        }
+        let client_key = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<u8>>();
+        if !memcmp::eq(&sha256(&client_key), &self.verifier.stored_key) {
+            return Err(anyhow!("password authentication failed"));
+        }
+        let server_signature = hmac_sha256(&self.verifier.server_key, auth_message.as_bytes())?;
+        Ok(format!("v={}", encode_block(&server_signature)))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/// verify_md5_password verifies the client md5 password response with the stored md5
+/// hash of the user. stored hash is in the format of md5(password + username).
+pub fn verify_md5_password(md5_hash: &str, salt: &[u8], client_password: &str) -> bool {
+    let stored_hash = match md5_hash.strip_prefix("md5") {
+        Some(hash) => hash,
+        None => return false,
+    };
+    let mut md5 = Md5::new();
+    md5.update(stored_hash);
+    md5.update(salt);
+    let expected = format!("md5{:x}", md5.finalize());
+    memcmp_str(&expected, client_password)
+}
+
+fn memcmp_str(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    memcmp::eq(a.as_bytes(), b.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres_protocol::authentication::sasl;
+    use postgres_protocol::password;
+
+    fn run_scram(verifier: &str, password: &str) -> Result<(), anyhow::Error> {
+        let verifier = ScramVerifier::parse(verifier)?;
+        let mut server = ScramServer::new(verifier)?;
+        let mut client =
+            sasl::ScramSha256::new(password.as_bytes(), sasl::ChannelBinding::unsupported());
+        let server_first = server.handle_client_first(client.message())?;
+        client.update(server_first.as_bytes())?;
+        let server_final = server.handle_client_final(client.message())?;
+        client.finish(server_final.as_bytes())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scram_server() {
+        let verifier = password::scram_sha_256(b"inspektor");
+        run_scram(&verifier, "inspektor").unwrap();
+        assert!(run_scram(&verifier, "wrong password").is_err());
+    }
+
+    #[test]
+    fn test_scram_verifier() {
+        assert!(ScramVerifier::parse("md5f8a56b0d4a3a4d3b0c2e46d1b2a1f0e1").is_err());
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$4096:c2FsdA==$abc").is_err());
+        let verifier = password::scram_sha_256(b"inspektor");
+        assert!(ScramVerifier::parse(&verifier).is_ok());
+    }
+
+    #[test]
+    fn test_md5_password() {
+        let md5_hash = password::md5(b"inspektor", "postgres");
+        let salt = [1, 2, 3, 4];
+        let mut md5 = Md5::new();
+        md5.update(&md5_hash[3..]);
+        md5.update(&salt);
+        let client_password = format!("md5{:x}", md5.finalize());
+        assert!(verify_md5_password(&md5_hash, &salt, &client_password));
+        assert!(!verify_md5_password(&md5_hash, &[4, 3, 2, 1], &client_password));
+    }
+}
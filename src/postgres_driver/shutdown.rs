@@ -0,0 +1,61 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::postgres_driver::message::BackendMessage;
+
+// ADMIN_SHUTDOWN is the sqlstate sent to the clients which are terminated by the shutdown.
+const ADMIN_SHUTDOWN: &str = "57P01";
+
+/// ShutdownState tells the sessions how the proxy is shutting down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownState {
+    Running,
+    // Draining closes the sessions once they are idle. sessions in the middle of a
+    // transaction are allowed to finish it.
+    Draining,
+    // Terminating closes all the sessions, since the grace period is over.
+    Terminating,
+}
+
+impl ShutdownState {
+    // should_terminate tells whether the session has to be terminated in this state.
+    pub fn should_terminate(&self, idle: bool) -> bool {
+        match self {
+            ShutdownState::Running => false,
+            ShutdownState::Draining => idle,
+            ShutdownState::Terminating => true,
+        }
+    }
+}
+
+// shutdown_msg returns the error message sent to the client before terminating the
+// session.
+pub fn shutdown_msg() -> BackendMessage {
+    BackendMessage::fatal_msg(
+        ADMIN_SHUTDOWN,
+        "terminating connection due to administrator command".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_terminate() {
+        assert!(!ShutdownState::Running.should_terminate(true));
+        assert!(!ShutdownState::Draining.should_terminate(false));
+        assert!(ShutdownState::Draining.should_terminate(true));
+        assert!(ShutdownState::Terminating.should_terminate(false));
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::{BLOCKED_STARTUP_PARAMS, RESERVED_STARTUP_PARAMS};
+use anyhow::anyhow;
+use log::*;
+use std::collections::HashMap;
+
+// build_startup_params builds the startup parameters of the target connection from the
+// client parameters. parameters which are not allowed by the config and the policy are
+// dropped and the blocked ones refuses the connection. policy_allowed is None if the
+// policy doesn't restrict the startup parameters.
+pub fn build_startup_params(
+    client_params: &HashMap<String, String>,
+    allowed: &Vec<String>,
+    policy_allowed: &Option<Vec<String>>,
+) -> Result<HashMap<String, String>, anyhow::Error> {
+    let contains =
+        |params: &Vec<String>, name: &str| params.iter().any(|param| param.to_lowercase() == name);
+    let is_allowed = |name: &str| -> Result<bool, anyhow::Error> {
+        let name = name.to_lowercase();
+        check_blocked(&name)?;
+        Ok(!RESERVED_STARTUP_PARAMS.contains(&name.as_str())
+            && contains(allowed, &name)
+            && policy_allowed
+                .as_ref()
+                .map(|policy_allowed| contains(policy_allowed, &name))
+                .unwrap_or(true))
+    };
+    let mut params = HashMap::new();
+    for (key, val) in client_params {
+        if !is_allowed(key)? {
+            debug!("dropping startup parameter {}", key);
+            continue;
+        }
+        if key != "options" {
+            params.insert(key.clone(), val.clone());
+            continue;
+        }
+        // every setting of the options is checked like a startup parameter.
+        let mut settings = vec![];
+        for (name, val) in parse_options(val)? {
+            if !is_allowed(&name)? {
+                debug!("dropping setting {} of the startup options", name);
+                continue;
+            }
+            settings.push(format!("-c {}={}", name, escape_option(&val)));
+        }
+        if !settings.is_empty() {
+            params.insert(key.clone(), settings.join(" "));
+        }
+    }
+    let database = client_params
+        .get("database")
+        .ok_or_else(|| anyhow!("database is missing in the startup message"))?;
+    params.insert("database".to_string(), database.clone());
+    params.insert("client_encoding".to_string(), "UTF8".to_string());
+    params
+        .entry("application_name".to_string())
+        .or_insert_with(|| "inspektor".to_string());
+    Ok(params)
+}
+
+// check_blocked returns error if the given parameter is blocked.
+fn check_blocked(name: &str) -> Result<(), anyhow::Error> {
+    if BLOCKED_STARTUP_PARAMS.contains(&name) {
+        return Err(anyhow!("startup parameter \"{}\" is not allowed", name));
+    }
+    Ok(())
+}
+
+// parse_options parses the command line options of the startup message. only the run time
+// settings (-c name=value and --name=value) are accepted.
+fn parse_options(options: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let args = split_options(options);
+    let mut settings = vec![];
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        let setting = if arg == "-c" {
+            iter.next()
+                .ok_or_else(|| anyhow!("setting is missing for -c in the startup options"))?
+        } else if let Some(setting) = arg.strip_prefix("--") {
+            setting.to_string()
+        } else if let Some(setting) = arg.strip_prefix("-c") {
+            setting.to_string()
+        } else {
+            return Err(anyhow!("startup option {:?} is not allowed", arg));
+        };
+        let (name, val) = match setting.find('=') {
+            Some(pos) => (&setting[..pos], &setting[pos + 1..]),
+            None => {
+                return Err(anyhow!(
+                    "invalid setting {:?} in the startup options",
+                    setting
+                ))
+            }
+        };
+        // postgres treats the dashes of the setting name as underscores.
+        settings.push((name.replace('-', "_").to_lowercase(), val.to_string()));
+    }
+    Ok(settings)
+}
+
+// split_options splits the options by whitespace. backslash escapes the next character.
+fn split_options(options: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut chars = options.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                if let Some(ch) = chars.next() {
+                    arg.push(ch);
+                }
+            }
+            ch if ch.is_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            ch => arg.push(ch),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+fn escape_option(val: &str) -> String {
+    let mut escaped = String::with_capacity(val.len());
+    for ch in val.chars() {
+        if ch == '\\' || ch.is_whitespace() {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_STARTUP_PARAMS;
+
+    #[test]
+    fn test_build_startup_params() {
+        let mut allowed = DEFAULT_STARTUP_PARAMS
+            .iter()
+            .map(|param| param.to_string())
+            .collect::<Vec<_>>();
+        allowed.extend(["search_path".to_string(), "options".to_string()]);
+        let client_params = HashMap::from([
+            ("user".to_string(), "poonai".to_string()),
+            ("database".to_string(), "postgres".to_string()),
+            ("client_encoding".to_string(), "LATIN1".to_string()),
+            ("DateStyle".to_string(), "ISO, MDY".to_string()),
+            ("search_path".to_string(), "app".to_string()),
+            ("geqo".to_string(), "off".to_string()),
+            (
+                "options".to_string(),
+                "-c statement_timeout=5s --search-path=app -cgeqo=off -c TimeZone=Asia/Kolkata\\ x"
+                    .to_string(),
+            ),
+        ]);
+        let params = build_startup_params(&client_params, &allowed, &None).unwrap();
+        assert_eq!(
+            params,
+            HashMap::from([
+                ("database".to_string(), "postgres".to_string()),
+                ("client_encoding".to_string(), "UTF8".to_string()),
+                ("application_name".to_string(), "inspektor".to_string()),
+                ("DateStyle".to_string(), "ISO, MDY".to_string()),
+                ("search_path".to_string(), "app".to_string()),
+                (
+                    "options".to_string(),
+                    "-c search_path=app -c timezone=Asia/Kolkata\\ x".to_string()
+                ),
+            ])
+        );
+
+        // policy narrows down the configured parameters. settings of the options are
+        // checked against the policy as well.
+        let policy_allowed = Some(vec![
+            "application_name".to_string(),
+            "options".to_string(),
+            "TimeZone".to_string(),
+        ]);
+        let params = build_startup_params(&client_params, &allowed, &policy_allowed).unwrap();
+        assert_eq!(
+            params,
+            HashMap::from([
+                ("database".to_string(), "postgres".to_string()),
+                ("client_encoding".to_string(), "UTF8".to_string()),
+                ("application_name".to_string(), "inspektor".to_string()),
+                (
+                    "options".to_string(),
+                    "-c timezone=Asia/Kolkata\\ x".to_string()
+                ),
+            ])
+        );
+        let params = build_startup_params(&client_params, &allowed, &Some(vec![])).unwrap();
+        assert!(!params.contains_key("DateStyle"));
+        assert!(!params.contains_key("options"));
+
+        let mut client_params = HashMap::from([
+            ("database".to_string(), "postgres".to_string()),
+            ("application_name".to_string(), "psql".to_string()),
+        ]);
+        let params = build_startup_params(&client_params, &vec![], &None).unwrap();
+        assert_eq!(params.get("application_name").unwrap(), "inspektor");
+        let params = build_startup_params(&client_params, &allowed, &None).unwrap();
+        assert_eq!(params.get("application_name").unwrap(), "psql");
+
+        client_params.insert("options".to_string(), "-c role=postgres".to_string());
+        assert!(build_startup_params(&client_params, &allowed, &None).is_err());
+        client_params.insert(
+            "options".to_string(),
+            "--session-authorization=postgres".to_string(),
+        );
+        assert!(build_startup_params(&client_params, &allowed, &None).is_err());
+        client_params.insert("options".to_string(), "-d 5".to_string());
+        assert!(build_startup_params(&client_params, &allowed, &None).is_err());
+        // privilege escalation is refused even if it's not forwarded.
+        client_params.remove("options");
+        client_params.insert("Role".to_string(), "postgres".to_string());
+        assert!(build_startup_params(&client_params, &vec![], &None).is_err());
+        assert!(build_startup_params(&client_params, &allowed, &Some(vec![])).is_err());
+    }
+}
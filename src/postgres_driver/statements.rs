@@ -0,0 +1,176 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashMap;
+
+/// PreparedStatement is the statement prepared by the client along with the version of
+/// the policy which is used to rewrite it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreparedStatement {
+    // query is the one sent by the client.
+    pub query: String,
+    // rewritten_query is the one prepared on the target.
+    pub rewritten_query: String,
+    // param_types are the parameter types of the Parse message.
+    pub param_types: Vec<i32>,
+    pub policy_version: u64,
+    // invalidated is set if the statement is not valid for the current policy.
+    pub invalidated: bool,
+}
+
+impl PreparedStatement {
+    pub fn new(query: String, rewritten_query: String, policy_version: u64) -> PreparedStatement {
+        PreparedStatement {
+            query,
+            rewritten_query,
+            param_types: vec![],
+            policy_version,
+            invalidated: false,
+        }
+    }
+}
+
+/// StatementKind tells where the statement is prepared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatementKind {
+    // Protocol statements are prepared using the Parse message.
+    Protocol,
+    // Sql statements are prepared using the PREPARE statement.
+    Sql,
+    // Portal are created using the Bind message.
+    Portal,
+}
+
+/// StatementRegistry holds the prepared statements and portals of the session, so that
+/// the statements which are prepared with the old policy are not executed after the
+/// policy change.
+#[derive(Debug, Default)]
+pub struct StatementRegistry {
+    statements: HashMap<(StatementKind, String), PreparedStatement>,
+}
+
+impl StatementRegistry {
+    // prepare records the statement. existing statement with the same name is replaced.
+    pub fn prepare(&mut self, kind: StatementKind, name: String, statement: PreparedStatement) {
+        self.statements.insert((kind, name), statement);
+    }
+
+    // bind records the portal of the given statement. portal keeps the copy of the
+    // statement because the unnamed statement can be replaced while the portal is open.
+    pub fn bind(&mut self, portal: String, statement: &String) {
+        if let Some(statement) = self.get(StatementKind::Protocol, statement).cloned() {
+            self.prepare(StatementKind::Portal, portal, statement);
+        }
+    }
+
+    pub fn get(&self, kind: StatementKind, name: &String) -> Option<&PreparedStatement> {
+        self.statements.get(&(kind, name.clone()))
+    }
+
+    // is_invalidated tells whether the given statement is invalidated by the policy change.
+    // statement which is not revalidated for the current policy is treated as invalidated,
+    // because it may be rewritten differently now. statements which are not known to the
+    // registry are left to the target.
+    pub fn is_invalidated(&self, kind: StatementKind, name: &String, policy_version: u64) -> bool {
+        self.get(kind, name)
+            .map(|statement| statement.invalidated || statement.policy_version < policy_version)
+            .unwrap_or(false)
+    }
+
+    pub fn close(&mut self, kind: StatementKind, name: &String) {
+        self.statements.remove(&(kind, name.clone()));
+    }
+
+    // close_all removes all the statements of the given kind. eg: DEALLOCATE ALL
+    pub fn close_all(&mut self, kind: StatementKind) {
+        self.statements.retain(|key, _| key.0 != kind);
+    }
+
+    // revalidate checks the statements which are rewritten with an older policy. validate
+    // returns the rewritten query for the current policy and the statement is invalidated
+    // if the query is rejected or rewritten differently. so, the Describe response of the
+    // statement always matches the rows returned by it.
+    pub fn revalidate<F>(&mut self, policy_version: u64, mut validate: F)
+    where
+        F: FnMut(&String) -> Option<String>,
+    {
+        for statement in self.statements.values_mut() {
+            if statement.invalidated || statement.policy_version >= policy_version {
+                continue;
+            }
+            match validate(&statement.query) {
+                Some(rewritten_query) if rewritten_query == statement.rewritten_query => {
+                    statement.policy_version = policy_version;
+                }
+                _ => statement.invalidated = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statement_registry() {
+        let mut registry = StatementRegistry::default();
+        let prepare = |query: &str, rewritten_query: &str| {
+            PreparedStatement::new(query.to_string(), rewritten_query.to_string(), 0)
+        };
+        registry.prepare(
+            StatementKind::Protocol,
+            String::from("s1"),
+            prepare("SELECT id FROM kids", "SELECT id FROM kids;"),
+        );
+        registry.prepare(
+            StatementKind::Protocol,
+            String::from("s2"),
+            prepare("SELECT phone FROM kids", "SELECT phone FROM kids;"),
+        );
+        registry.prepare(
+            StatementKind::Sql,
+            String::from("s1"),
+            prepare("PREPARE s1 AS SELECT 1", "PREPARE s1 AS SELECT 1;"),
+        );
+        registry.bind(String::from(""), &String::from("s2"));
+
+        // phone is protected in the new policy.
+        registry.revalidate(1, |query| {
+            Some(format!("{};", query.replace("phone", "NULL AS \"phone\"")))
+        });
+        assert!(!registry.is_invalidated(StatementKind::Protocol, &String::from("s1"), 1));
+        assert!(registry.is_invalidated(StatementKind::Protocol, &String::from("s2"), 1));
+        assert!(registry.is_invalidated(StatementKind::Portal, &String::from(""), 1));
+        assert!(!registry.is_invalidated(StatementKind::Sql, &String::from("s1"), 1));
+        assert_eq!(
+            registry
+                .get(StatementKind::Protocol, &String::from("s1"))
+                .unwrap()
+                .policy_version,
+            1
+        );
+
+        // statements are stale if the revalidation is skipped for the new policy.
+        assert!(registry.is_invalidated(StatementKind::Protocol, &String::from("s1"), 2));
+
+        // rejected statements are invalidated as well.
+        registry.revalidate(2, |_| None);
+        assert!(registry.is_invalidated(StatementKind::Sql, &String::from("s1"), 2));
+        registry.close_all(StatementKind::Sql);
+        assert!(registry
+            .get(StatementKind::Sql, &String::from("s1"))
+            .is_none());
+        assert!(!registry.is_invalidated(StatementKind::Protocol, &String::from("unknown"), 2));
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::PostgresConfig;
+use anyhow::anyhow;
+use log::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const IDLE_SESSION_TIMEOUT: &str = "idle_session_timeout";
+const IDLE_IN_TRANSACTION_TIMEOUT: &str = "idle_in_transaction_timeout";
+const MAX_SESSION_DURATION: &str = "max_session_duration";
+
+/// SessionTimeouts are the timeouts of the client session enforced by the proxy. None
+/// disables the timeout.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionTimeouts {
+    pub idle_session: Option<Duration>,
+    pub idle_in_transaction: Option<Duration>,
+    pub max_session_duration: Option<Duration>,
+}
+
+impl SessionTimeouts {
+    // new builds the timeouts of the session. timeouts given by the policy for the groups
+    // of the session overrides the configured ones.
+    pub fn new(
+        config: &PostgresConfig,
+        policy_timeouts: &HashMap<String, u64>,
+    ) -> Result<SessionTimeouts, anyhow::Error> {
+        let mut timeouts = SessionTimeouts {
+            idle_session: to_timeout(config.idle_session_timeout),
+            idle_in_transaction: to_timeout(config.idle_in_transaction_timeout),
+            max_session_duration: to_timeout(config.max_session_duration),
+        };
+        for (name, secs) in policy_timeouts {
+            let timeout = to_timeout(Some(*secs));
+            match name.as_str() {
+                IDLE_SESSION_TIMEOUT => timeouts.idle_session = timeout,
+                IDLE_IN_TRANSACTION_TIMEOUT => timeouts.idle_in_transaction = timeout,
+                MAX_SESSION_DURATION => timeouts.max_session_duration = timeout,
+                // unknown timeout is refused, so that a typo in the policy doesn't leave
+                // the sessions without timeout.
+                _ => return Err(anyhow!("unknown session timeout {} in the policy", name)),
+            }
+        }
+        Ok(timeouts)
+    }
+
+    // idle_timeout returns the idle timeout of the session for the given transaction
+    // state.
+    pub fn idle_timeout(&self, in_transaction: bool) -> Option<Duration> {
+        if in_transaction {
+            return self.idle_in_transaction;
+        }
+        self.idle_session
+    }
+
+    // updated returns the timeouts built for the updated policy. current timeouts are
+    // kept if the timeouts of the policy are malformed, so that a bad policy update
+    // neither drops the session nor leaves it without timeouts.
+    pub fn updated(&self, timeouts: Result<SessionTimeouts, anyhow::Error>) -> SessionTimeouts {
+        match timeouts {
+            Ok(timeouts) => timeouts,
+            Err(e) => {
+                error!(
+                    "error while updating session timeouts, keeping the current ones {:?}",
+                    e
+                );
+                *self
+            }
+        }
+    }
+}
+
+fn to_timeout(secs: Option<u64>) -> Option<Duration> {
+    secs.filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_timeouts() {
+        let mut config = PostgresConfig::default();
+        config.idle_session_timeout = Some(600);
+        config.idle_in_transaction_timeout = Some(0);
+        let timeouts = SessionTimeouts::new(&config, &HashMap::default()).unwrap();
+        assert_eq!(
+            timeouts,
+            SessionTimeouts {
+                idle_session: Some(Duration::from_secs(600)),
+                idle_in_transaction: None,
+                max_session_duration: None,
+            }
+        );
+        assert_eq!(timeouts.idle_timeout(false), Some(Duration::from_secs(600)));
+        assert_eq!(timeouts.idle_timeout(true), None);
+
+        // policy overrides the configured timeouts.
+        let policy_timeouts = HashMap::from([
+            (IDLE_SESSION_TIMEOUT.to_string(), 0),
+            (IDLE_IN_TRANSACTION_TIMEOUT.to_string(), 60),
+            (MAX_SESSION_DURATION.to_string(), 8 * 60 * 60),
+        ]);
+        let timeouts = SessionTimeouts::new(&config, &policy_timeouts).unwrap();
+        assert_eq!(
+            timeouts,
+            SessionTimeouts {
+                idle_session: None,
+                idle_in_transaction: Some(Duration::from_secs(60)),
+                max_session_duration: Some(Duration::from_secs(8 * 60 * 60)),
+            }
+        );
+
+        let policy_timeouts = HashMap::from([("idle_timeout".to_string(), 60)]);
+        assert!(SessionTimeouts::new(&config, &policy_timeouts).is_err());
+
+        // malformed policy update keeps the current timeouts.
+        assert_eq!(
+            timeouts.updated(SessionTimeouts::new(&config, &policy_timeouts)),
+            timeouts
+        );
+        assert_eq!(
+            timeouts.updated(SessionTimeouts::new(&config, &HashMap::default())),
+            SessionTimeouts {
+                idle_session: Some(Duration::from_secs(600)),
+                idle_in_transaction: None,
+                max_session_duration: None,
+            }
+        );
+    }
+}
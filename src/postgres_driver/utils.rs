@@ -23,7 +23,19 @@ where
     let version_number = buf.get_i32();
     match version_number {
         VERSION_SSL => return Ok(FrontendMessage::SslRequest),
-        VERSION_3 => {
+        VERSION_GSSENC => return Ok(FrontendMessage::GssEncRequest),
+        VERSION_CANCEL => {
+            if buf.len() < 8 {
+                return Err(anyhow!("invalid cancel request"));
+            }
+            return Ok(FrontendMessage::CancelRequest {
+                process_id: buf.get_i32(),
+                secret_key: buf.get_i32(),
+            });
+        }
+        // all the minor versions of the protocol 3 are accepted here. newer minor
+        // versions are negotiated after decoding the startup message.
+        version if version >> 16 == PROTOCOL_MAJOR_VERSION => {
             let mut params = HashMap::new();
             // read all the params.
             // have to make it safe.
@@ -40,8 +52,12 @@ where
                 version: version_number,
             });
         }
-        _ => {
-            return Err(anyhow!("unsupported version"));
+        version => {
+            return Err(anyhow!(
+                "unsupported frontend protocol {}.{}",
+                version >> 16,
+                version & 0xffff
+            ));
         }
     };
 }
@@ -80,7 +96,50 @@ pub fn write_cstr(buf: &mut BytesMut, val: &[u8]) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-pub async fn decode_password_message<T>(mut conn: T) -> Result<FrontendMessage, anyhow::Error>
+pub async fn decode_password_message<T>(conn: T) -> Result<FrontendMessage, anyhow::Error>
+where
+    T: AsyncRead + AsyncReadExt + Unpin,
+{
+    let mut buf = read_password_frame(conn).await?;
+    // read the passcode.
+    let password =
+        read_cstr(&mut buf).map_err(|err| anyhow!("error while reading password {:?}", err))?;
+    Ok(FrontendMessage::PasswordMessage { password: password })
+}
+
+// decode_sasl_initial_response decodes the first sasl message of the client which
+// contains the selected mechanism and client first message.
+pub async fn decode_sasl_initial_response<T>(conn: T) -> Result<FrontendMessage, anyhow::Error>
+where
+    T: AsyncRead + AsyncReadExt + Unpin,
+{
+    let mut buf = read_password_frame(conn).await?;
+    let mechanism =
+        read_cstr(&mut buf).map_err(|err| anyhow!("error while reading mechanism {:?}", err))?;
+    if buf.len() < 4 {
+        return Err(anyhow!("invalid sasl initial response"));
+    }
+    let len = buf.get_i32();
+    let body = match len {
+        -1 => Vec::new(),
+        len if len >= 0 && len as usize <= buf.len() => buf[..len as usize].to_vec(),
+        _ => return Err(anyhow!("invalid sasl initial response length")),
+    };
+    Ok(FrontendMessage::SASLInitialResponse { mechanism, body })
+}
+
+// decode_sasl_response decodes the subsequent sasl messages of the client.
+pub async fn decode_sasl_response<T>(conn: T) -> Result<FrontendMessage, anyhow::Error>
+where
+    T: AsyncRead + AsyncReadExt + Unpin,
+{
+    let buf = read_password_frame(conn).await?;
+    Ok(FrontendMessage::SASLResponse { body: buf.to_vec() })
+}
+
+// read_password_frame reads the frame of the password message. password, sasl initial
+// response and sasl response shares the same message type.
+async fn read_password_frame<T>(mut conn: T) -> Result<BytesMut, anyhow::Error>
 where
     T: AsyncRead + AsyncReadExt + Unpin,
 {
@@ -97,10 +156,7 @@ where
     let mut buf = BytesMut::new();
     buf.resize(len, b'0');
     conn.read_exact(&mut buf).await?;
-    // read the passcode.
-    let password =
-        read_cstr(&mut buf).map_err(|err| anyhow!("error while reading password {:?}", err))?;
-    Ok(FrontendMessage::PasswordMessage { password: password })
+    Ok(buf)
 }
 
 #[inline]
@@ -0,0 +1,448 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use sqlparser::ast::{Expr, Ident, ObjectName, Query, Statement};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::{IsOptional, Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::fmt;
+
+/// CopySource is the table or the query of the COPY statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopySource {
+    Table {
+        table_name: ObjectName,
+        columns: Vec<Ident>,
+    },
+    Query(Box<Query>),
+}
+
+/// CopyTarget is where the rows are copied to or copied from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyTarget {
+    Stdin,
+    Stdout,
+    // File and Program are read or written by the postgres server itself.
+    File(String),
+    Program(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyOptionValue {
+    // Literal is the quoted string value. eg: DELIMITER ','
+    Literal(String),
+    // Raw is rendered as it is. eg: FORMAT csv, FORCE_QUOTE (id, name)
+    Raw(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyOption {
+    pub name: String,
+    pub value: Option<CopyOptionValue>,
+}
+
+/// CopyStatement is the COPY statement of postgres. sql parser only knows COPY ... FROM
+/// STDIN followed by the inline data, so the statement is parsed here.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyStatement {
+    pub source: CopySource,
+    // to is set for COPY ... TO and unset for COPY ... FROM.
+    pub to: bool,
+    pub target: CopyTarget,
+    // options are kept in the parenthesized form. legacy options are converted to it.
+    pub options: Vec<CopyOption>,
+    // selection is the WHERE clause of COPY ... FROM.
+    pub selection: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyFormatKind {
+    Text,
+    Csv,
+    Binary,
+}
+
+/// CopyFormat tells how the rows of the COPY are encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyFormat {
+    pub kind: CopyFormatKind,
+    pub delimiter: u8,
+    pub null: String,
+    pub quote: u8,
+    pub escape: u8,
+    pub header: bool,
+}
+
+impl CopyStatement {
+    // parse parses the given query as COPY statement. None is returned if the query is not
+    // a COPY statement.
+    pub fn parse(sql: &str) -> Result<Option<CopyStatement>, ParserError> {
+        let is_copy = sql
+            .trim_start()
+            .get(..4)
+            .map(|keyword| keyword.eq_ignore_ascii_case("copy"))
+            .unwrap_or(false);
+        if !is_copy {
+            return Ok(None);
+        }
+        let dialect = PostgreSqlDialect {};
+        let tokens = Tokenizer::new(&dialect, sql)
+            .tokenize()
+            .map_err(|e| ParserError::TokenizerError(e.message))?;
+        let mut parser = Parser::new(tokens, &dialect);
+        if !parse_word(&mut parser, "COPY") {
+            return Ok(None);
+        }
+        let source = if parser.consume_token(&Token::LParen) {
+            let query = parser.parse_query()?;
+            parser.expect_token(&Token::RParen)?;
+            CopySource::Query(Box::new(query))
+        } else {
+            let table_name = parser.parse_object_name()?;
+            let columns = parser.parse_parenthesized_column_list(IsOptional::Optional)?;
+            CopySource::Table {
+                table_name,
+                columns,
+            }
+        };
+        let to = if parse_word(&mut parser, "TO") {
+            true
+        } else if parse_word(&mut parser, "FROM") {
+            false
+        } else {
+            return Err(expected("TO or FROM", parser.peek_token()));
+        };
+        if let (CopySource::Query(_), false) = (&source, to) {
+            return Err(ParserError::ParserError(String::from(
+                "COPY FROM doesn't support query",
+            )));
+        }
+        let target = if parse_word(&mut parser, "STDOUT") {
+            CopyTarget::Stdout
+        } else if parse_word(&mut parser, "STDIN") {
+            CopyTarget::Stdin
+        } else if parse_word(&mut parser, "PROGRAM") {
+            CopyTarget::Program(parse_literal(&mut parser)?)
+        } else {
+            CopyTarget::File(parse_literal(&mut parser)?)
+        };
+        let options = parse_options(&mut parser)?;
+        let selection = if !to && parse_word(&mut parser, "WHERE") {
+            Some(parser.parse_expr()?)
+        } else {
+            None
+        };
+        parser.consume_token(&Token::SemiColon);
+        if parser.peek_token() != Token::EOF {
+            return Err(expected("end of COPY statement", parser.peek_token()));
+        }
+        Ok(Some(CopyStatement {
+            source,
+            to,
+            target,
+            options,
+            selection,
+        }))
+    }
+
+    // format returns the format of the copied rows.
+    pub fn format(&self) -> CopyFormat {
+        let value = |name: &str| {
+            self.options
+                .iter()
+                .rev()
+                .find(|option| option.name == name)
+                .map(|option| match &option.value {
+                    Some(CopyOptionValue::Literal(value)) | Some(CopyOptionValue::Raw(value)) => {
+                        value.clone()
+                    }
+                    None => String::from(""),
+                })
+        };
+        let kind = match value("format").map(|format| format.to_lowercase()) {
+            Some(format) if format == "csv" => CopyFormatKind::Csv,
+            Some(format) if format == "binary" => CopyFormatKind::Binary,
+            _ => CopyFormatKind::Text,
+        };
+        let default_delimiter = if kind == CopyFormatKind::Csv {
+            ","
+        } else {
+            "\t"
+        };
+        let default_null = if kind == CopyFormatKind::Csv {
+            ""
+        } else {
+            "\\N"
+        };
+        let first_byte = |value: Option<String>, default: &str| {
+            value
+                .as_ref()
+                .and_then(|value| value.bytes().next())
+                .unwrap_or(default.as_bytes()[0])
+        };
+        let quote = first_byte(value("quote"), "\"");
+        let header = match value("header").map(|header| header.to_lowercase()) {
+            Some(header) => !matches!(header.as_str(), "false" | "off" | "0"),
+            None => false,
+        };
+        CopyFormat {
+            kind,
+            delimiter: first_byte(value("delimiter"), default_delimiter),
+            null: value("null").unwrap_or(default_null.to_string()),
+            quote,
+            escape: value("escape")
+                .and_then(|escape| escape.bytes().next())
+                .unwrap_or(quote),
+            header,
+        }
+    }
+}
+
+impl fmt::Display for CopyStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "COPY ")?;
+        match &self.source {
+            CopySource::Table {
+                table_name,
+                columns,
+            } => {
+                write!(f, "{}", table_name)?;
+                if columns.len() > 0 {
+                    write!(f, " ({})", join(columns))?;
+                }
+            }
+            CopySource::Query(query) => write!(f, "({})", query)?,
+        }
+        write!(f, " {} ", if self.to { "TO" } else { "FROM" })?;
+        match &self.target {
+            CopyTarget::Stdin => write!(f, "STDIN")?,
+            CopyTarget::Stdout => write!(f, "STDOUT")?,
+            CopyTarget::File(file) => write!(f, "{}", quote_literal(file))?,
+            CopyTarget::Program(command) => write!(f, "PROGRAM {}", quote_literal(command))?,
+        }
+        if self.options.len() > 0 {
+            write!(f, " WITH ({})", join(&self.options))?;
+        }
+        if let Some(selection) = &self.selection {
+            write!(f, " WHERE {}", selection)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CopyOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name.to_uppercase())?;
+        match &self.value {
+            Some(CopyOptionValue::Literal(value)) => write!(f, " {}", quote_literal(value)),
+            Some(CopyOptionValue::Raw(value)) => write!(f, " {}", value),
+            None => Ok(()),
+        }
+    }
+}
+
+// table_query returns the query which selects the given columns of the table. all the
+// columns are selected if there is no column, as COPY does.
+pub fn table_query(table_name: &ObjectName, columns: &Vec<Ident>) -> Result<Query, ParserError> {
+    let projection = if columns.len() == 0 {
+        String::from("*")
+    } else {
+        join(columns)
+    };
+    let dialect = PostgreSqlDialect {};
+    let sql = format!("SELECT {} FROM {}", projection, table_name);
+    match Parser::parse_sql(&dialect, &sql)?.pop() {
+        Some(Statement::Query(query)) => Ok(*query),
+        _ => Err(ParserError::ParserError(format!(
+            "unable to build the query of the table {}",
+            table_name
+        ))),
+    }
+}
+
+// parse_options parses both parenthesized and legacy options of the COPY statement.
+fn parse_options(parser: &mut Parser) -> Result<Vec<CopyOption>, ParserError> {
+    let mut options = vec![];
+    parse_word(parser, "WITH");
+    if parser.consume_token(&Token::LParen) {
+        loop {
+            let name = match parser.next_token() {
+                Token::Word(word) => word.value.to_lowercase(),
+                token => return Err(expected("COPY option", token)),
+            };
+            let value = match parser.peek_token() {
+                Token::Comma | Token::RParen => None,
+                _ => Some(parse_option_value(parser)?),
+            };
+            options.push(CopyOption { name, value });
+            if parser.consume_token(&Token::Comma) {
+                continue;
+            }
+            parser.expect_token(&Token::RParen)?;
+            return Ok(options);
+        }
+    }
+    // legacy options. eg: COPY kids TO STDOUT WITH CSV HEADER
+    loop {
+        let option = |name: &str, value: Option<CopyOptionValue>| CopyOption {
+            name: name.to_string(),
+            value,
+        };
+        if parse_word(parser, "BINARY") {
+            options.push(option(
+                "format",
+                Some(CopyOptionValue::Raw("binary".into())),
+            ));
+        } else if parse_word(parser, "CSV") {
+            options.push(option("format", Some(CopyOptionValue::Raw("csv".into()))));
+        } else if parse_word(parser, "HEADER") {
+            options.push(option("header", None));
+        } else if let Some(name) = ["delimiter", "null", "quote", "escape"]
+            .iter()
+            .find(|name| parse_word(parser, name))
+        {
+            parse_word(parser, "AS");
+            let value = parse_literal(parser)?;
+            options.push(option(name, Some(CopyOptionValue::Literal(value))));
+        } else if parse_word(parser, "FORCE") {
+            let name = if parse_word(parser, "QUOTE") {
+                "force_quote"
+            } else if parse_word(parser, "NOT") && parse_word(parser, "NULL") {
+                "force_not_null"
+            } else {
+                return Err(expected("QUOTE or NOT NULL", parser.peek_token()));
+            };
+            let value = if parser.consume_token(&Token::Mul) {
+                String::from("*")
+            } else {
+                let mut columns = vec![parser.parse_identifier()?];
+                while parser.consume_token(&Token::Comma) {
+                    columns.push(parser.parse_identifier()?);
+                }
+                format!("({})", join(&columns))
+            };
+            options.push(option(name, Some(CopyOptionValue::Raw(value))));
+        } else {
+            return Ok(options);
+        }
+    }
+}
+
+fn parse_option_value(parser: &mut Parser) -> Result<CopyOptionValue, ParserError> {
+    match parser.next_token() {
+        Token::SingleQuotedString(value) => Ok(CopyOptionValue::Literal(value)),
+        Token::Word(word) => Ok(CopyOptionValue::Raw(word.to_string())),
+        Token::Number(number, _) => Ok(CopyOptionValue::Raw(number)),
+        Token::Mul => Ok(CopyOptionValue::Raw(String::from("*"))),
+        Token::LParen => {
+            let mut columns = vec![parser.parse_identifier()?];
+            while parser.consume_token(&Token::Comma) {
+                columns.push(parser.parse_identifier()?);
+            }
+            parser.expect_token(&Token::RParen)?;
+            Ok(CopyOptionValue::Raw(format!("({})", join(&columns))))
+        }
+        token => Err(expected("COPY option value", token)),
+    }
+}
+
+// parse_word consumes the next token if it's the given unquoted word. COPY keywords are
+// matched by the value, since not all of them are known to the sql parser.
+pub fn parse_word(parser: &mut Parser, word: &str) -> bool {
+    match parser.peek_token() {
+        Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word) => {
+            parser.next_token();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn parse_literal(parser: &mut Parser) -> Result<String, ParserError> {
+    match parser.next_token() {
+        Token::SingleQuotedString(value) => Ok(value),
+        token => Err(expected("quoted string", token)),
+    }
+}
+
+pub fn expected(expected: &str, found: Token) -> ParserError {
+    ParserError::ParserError(format!("expected {}, found {}", expected, found))
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+pub fn join<T: fmt::Display>(items: &Vec<T>) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sql: &str) -> CopyStatement {
+        CopyStatement::parse(sql).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_copy_statement() {
+        assert!(CopyStatement::parse("SELECT * FROM kids")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            parse("copy kids (id, phone) to stdout").to_string(),
+            "COPY kids (id, phone) TO STDOUT"
+        );
+        assert_eq!(
+            parse("COPY (SELECT phone FROM kids WHERE id = 1) TO STDOUT WITH (FORMAT csv, HEADER)")
+                .to_string(),
+            "COPY (SELECT phone FROM kids WHERE id = 1) TO STDOUT WITH (FORMAT csv, HEADER)"
+        );
+        assert_eq!(
+            parse("COPY kids TO STDOUT WITH CSV HEADER DELIMITER AS ';' FORCE QUOTE *").to_string(),
+            "COPY kids TO STDOUT WITH (FORMAT csv, HEADER, DELIMITER ';', FORCE_QUOTE *)"
+        );
+        assert_eq!(
+            parse("COPY kids FROM STDIN WITH (FORMAT binary) WHERE id > 1;").to_string(),
+            "COPY kids FROM STDIN WITH (FORMAT binary) WHERE id > 1"
+        );
+        assert_eq!(
+            parse("COPY kids TO PROGRAM 'gzip > /tmp/kid''s.gz'").target,
+            CopyTarget::Program(String::from("gzip > /tmp/kid's.gz"))
+        );
+        assert_eq!(
+            parse("COPY kids FROM '/tmp/kids.csv'").target,
+            CopyTarget::File(String::from("/tmp/kids.csv"))
+        );
+        assert!(CopyStatement::parse("COPY (SELECT 1) FROM STDIN").is_err());
+        assert!(CopyStatement::parse("COPY kids TO STDOUT; SELECT 1").is_err());
+
+        let format = parse("COPY kids TO STDOUT").format();
+        assert_eq!(format.kind, CopyFormatKind::Text);
+        assert_eq!(format.delimiter, b'\t');
+        assert_eq!(format.null, "\\N");
+        let format =
+            parse("COPY kids TO STDOUT WITH (FORMAT CSV, HEADER true, QUOTE '''', NULL 'null')")
+                .format();
+        assert_eq!(format.kind, CopyFormatKind::Csv);
+        assert_eq!(format.delimiter, b',');
+        assert_eq!((format.quote, format.escape), (b'\'', b'\''));
+        assert_eq!(format.null, "null");
+        assert!(format.header);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::sql::error::QueryRewriterError;
+use crate::sql::masking::{is_null_expr, MaskingStrategy};
+use crate::sql::query_rewriter::Rewrite;
 use sqlparser::ast::{Expr, ObjectName, SelectItem};
 use sqlparser::ast::{Ident, Value};
 use std::collections::{HashMap, HashSet};
@@ -12,6 +15,12 @@ pub struct Ctx {
     protected_columns: HashMap<String, Vec<String>>,
     // from contains the table names of the current selection/
     from: HashSet<String>,
+    // row_filters have the row predicates of the tables in the current selection.
+    row_filters: Vec<Expr>,
+    // column_types holds the data type of the table columns.
+    column_types: HashMap<String, HashMap<String, String>>,
+    // column_masks holds the masking strategy of the protected columns.
+    column_masks: HashMap<String, HashMap<String, MaskingStrategy>>,
 }
 
 impl Ctx {
@@ -21,13 +30,21 @@ impl Ctx {
         state
     }
 
+    // set_column_types sets the data type of the table columns, which is used to keep
+    // the type of the masked columns.
+    pub fn set_column_types(&mut self, column_types: HashMap<String, HashMap<String, String>>) {
+        self.column_types = column_types;
+    }
+
     // is_allowed_column will tell the given column in allowed in the given table or not.
     pub fn is_allowed_column(&self, table_name: &String, column: &String) -> bool {
         if let Some(columns) = self.protected_columns.get(table_name) {
-            return columns
-                .iter()
-                .position(|protectec_columns| *protectec_columns == *column)
-                .is_none();
+            // empty protected columns means all the columns of the table are protected.
+            return columns.len() != 0
+                && columns
+                    .iter()
+                    .position(|protectec_columns| *protectec_columns == *column)
+                    .is_none();
         }
         return true;
     }
@@ -38,9 +55,16 @@ impl Ctx {
         column: &String,
         metrics: &mut HashMap<String, HashSet<String>>,
     ) -> bool {
+        // whole row of the protected table. eg: SELECT row_to_json(kids) FROM kids
+        if self.from.contains(column) && self.protected_columns.contains_key(column) {
+            return false;
+        }
         let froms = self.from.clone().into_iter().collect::<Vec<String>>();
         for from in &froms {
             if let Some(protected_columns) = self.protected_columns.get(from) {
+                if protected_columns.len() == 0 {
+                    return false;
+                }
                 match protected_columns.iter().position(|col| *col == *column) {
                     Some(_) => return false,
                     None => {
@@ -59,7 +83,8 @@ impl Ctx {
         return true;
     }
 
-    // merge_state will merget the incoming state with the current state.
+    // merge_state will merget the incoming state with the current state. row filters
+    // are not merged because they belong to the selection which added them.
     pub fn merge_state(&mut self, state: Ctx) {
         for (key, val) in state.protected_columns {
             self.protected_columns.insert(key, val);
@@ -67,6 +92,12 @@ impl Ctx {
         for (key, val) in state.table_info {
             self.table_info.insert(key, val);
         }
+        for (key, val) in state.column_types {
+            self.column_types.insert(key, val);
+        }
+        for (key, val) in state.column_masks {
+            self.column_masks.insert(key, val);
+        }
         for val in state.from {
             self.from.insert(val);
         }
@@ -77,14 +108,17 @@ impl Ctx {
     pub fn build_allowed_column_expr(
         &self,
         metrics: &mut HashMap<String, HashSet<String>>,
-    ) -> Vec<SelectItem> {
+        rewrites: &mut Vec<Rewrite>,
+    ) -> Result<Vec<SelectItem>, QueryRewriterError> {
         let mut selections = vec![];
         let mut wildcard = true;
         let mut froms = self.from.clone().into_iter().collect::<Vec<String>>();
         froms.sort();
         for from in froms {
-            let exprs = self.column_expr_for_table(&from, false, metrics);
-            if exprs.len() == 0 {
+            let exprs = self.column_expr_for_table(&from, false, metrics, rewrites)?;
+            // protected table can have no columns in the selection if all the columns
+            // are omitted.
+            if !self.protected_columns.contains_key(&from) {
                 if let Some(properties) = metrics.get_mut(&from) {
                     properties.insert("*".to_string());
                 } else {
@@ -101,9 +135,9 @@ impl Ctx {
             selections.extend_from_slice(&exprs[..]);
         }
         if wildcard {
-            return vec![SelectItem::Wildcard];
+            return Ok(vec![SelectItem::Wildcard]);
         }
-        return selections;
+        Ok(selections)
     }
 
     // column_expr_for_table returns accepted columne expression for the given table.
@@ -112,7 +146,8 @@ impl Ctx {
         table_name: &String,
         prefix_table_name: bool,
         metrics: &mut HashMap<String, HashSet<String>>,
-    ) -> Vec<SelectItem> {
+        rewrites: &mut Vec<Rewrite>,
+    ) -> Result<Vec<SelectItem>, QueryRewriterError> {
         // should_prefix will determine whether we should prefix
         // table name as column name.
         let mut should_prefix = prefix_table_name;
@@ -123,20 +158,36 @@ impl Ctx {
         let mut selections = vec![];
         if let Some(protected_columns) = self.protected_columns.get(table_name) {
             let protected_columns_set = protected_columns.iter().collect::<HashSet<&String>>();
-            let table_columns = self.table_info.get(table_name).unwrap();
+            // wildcard of the protected table can't be expanded without knowing it's columns.
+            let table_columns = self.table_info.get(table_name).ok_or_else(|| {
+                QueryRewriterError::Unsupported(format!(
+                    "wildcard on the table {} without column information",
+                    table_name
+                ))
+            })?;
             for col in table_columns {
                 if protected_columns_set.contains(col) || protected_columns.len() == 0 {
                     let column_name = match should_prefix {
                         true => format!("{}.{}", table_name, col),
                         false => format!("{}", col),
                     };
-                    selections.push(SelectItem::ExprWithAlias {
-                        expr: Expr::Value(Value::Null),
-                        alias: Ident {
-                            value: column_name,
-                            quote_style: Some('"'),
-                        },
-                    });
+                    // column is qualified, so that the masked expression is not ambiguous.
+                    let mut idents = table_name
+                        .split(".")
+                        .map(|ident| Ident::new(ident))
+                        .collect::<Vec<Ident>>();
+                    idents.push(Ident::new(col.clone()));
+                    let column_expr = Expr::CompoundIdentifier(idents);
+                    // omitted columns are not part of the wildcard selection.
+                    if let Some(expr) = self.mask_column(table_name, col, &column_expr, rewrites)? {
+                        selections.push(SelectItem::ExprWithAlias {
+                            expr,
+                            alias: Ident {
+                                value: column_name,
+                                quote_style: Some('"'),
+                            },
+                        });
+                    }
                     continue;
                 }
                 if let Some(properties) = metrics.get_mut(table_name) {
@@ -158,7 +209,7 @@ impl Ctx {
                 ))));
             }
         }
-        return selections;
+        Ok(selections)
     }
 
     // memorize_protected_columns insert protected column to the state.
@@ -183,8 +234,164 @@ impl Ctx {
         return None;
     }
 
+    // memorize_column_masks insert masking strategy of the protected columns to the state.
+    pub fn memorize_column_masks(
+        &mut self,
+        table_name: String,
+        column_masks: HashMap<String, MaskingStrategy>,
+    ) {
+        self.column_masks.insert(table_name, column_masks);
+    }
+
+    // get_protected_table returns the table of the current selection which protects the
+    // given column.
+    pub fn get_protected_table(&self, column: &String) -> Option<String> {
+        let mut froms = self.from.iter().collect::<Vec<&String>>();
+        froms.sort();
+        let mut fully_protected = None;
+        for from in froms {
+            if let Some(protected_columns) = self.protected_columns.get(from) {
+                if protected_columns.contains(column) {
+                    return Some(from.clone());
+                }
+                if protected_columns.len() == 0 && fully_protected.is_none() {
+                    fully_protected = Some(from.clone());
+                }
+            }
+        }
+        fully_protected
+    }
+
+    // mask_column returns the masked expression of the protected column. NULL is used if
+    // the column doesn't have any masking strategy. None is returned if the column is
+    // omitted. the masking is recorded in the given rewrites.
+    pub fn mask_column(
+        &self,
+        table_name: &String,
+        column: &String,
+        column_expr: &Expr,
+        rewrites: &mut Vec<Rewrite>,
+    ) -> Result<Option<Expr>, QueryRewriterError> {
+        let strategy = self
+            .column_masks
+            .get(table_name)
+            .and_then(|masks| masks.get(column));
+        let strategy = match strategy {
+            Some(strategy) => strategy,
+            None => {
+                rewrites.push(Rewrite::MaskedColumn {
+                    table: table_name.clone(),
+                    column: column.clone(),
+                    strategy: String::from("null"),
+                });
+                return Ok(Some(Expr::Value(Value::Null)));
+            }
+        };
+        // value of the column is masked in the result rows.
+        if *strategy == MaskingStrategy::DataRow {
+            return Ok(Some(column_expr.clone()));
+        }
+        if *strategy == MaskingStrategy::Reject {
+            return Err(QueryRewriterError::UnAuthorizedColumn((
+                Some(table_name.clone()),
+                column.clone(),
+            )));
+        }
+        let data_type = self
+            .column_types
+            .get(table_name)
+            .and_then(|types| types.get(column));
+        let masked = strategy.mask_expr(column_expr, data_type);
+        rewrites.push(match &masked {
+            Some(expr) => Rewrite::MaskedColumn {
+                table: table_name.clone(),
+                column: column.clone(),
+                // strategies which can't be applied on the column type fall back
+                // to NULL.
+                strategy: if is_null_expr(expr) {
+                    String::from("null")
+                } else {
+                    strategy.name().to_string()
+                },
+            },
+            None => Rewrite::OmittedColumn {
+                table: table_name.clone(),
+                column: column.clone(),
+            },
+        });
+        Ok(masked)
+    }
+
+    // is_row_masked tells whether the value of the protected column is masked in the result
+    // rows. such column is selected as it is.
+    pub fn is_row_masked(&self, table_name: &String, column: &String) -> bool {
+        self.column_masks
+            .get(table_name)
+            .and_then(|masks| masks.get(column))
+            .map_or(false, |strategy| *strategy == MaskingStrategy::DataRow)
+    }
+
+    // natural_join_column returns the protected column of the current selection which the
+    // given selection may have. natural join on such column filters the rows by the protected
+    // value. tables of the given selection without known columns may have any column. empty
+    // column is returned if the whole table is protected and it's columns are not known.
+    pub fn natural_join_column(
+        &self,
+        other: &Ctx,
+        namespaces: &[String],
+    ) -> Option<(String, String)> {
+        let mut froms = self.from.iter().collect::<Vec<&String>>();
+        froms.sort();
+        let mut other_froms = other.from.iter().collect::<Vec<&String>>();
+        other_froms.sort();
+        for from in froms {
+            let protected_columns = match self.protected_columns.get(from) {
+                Some(columns) => columns,
+                None => continue,
+            };
+            // empty protected columns means all the columns of the table are protected.
+            let protected_columns = if protected_columns.is_empty() {
+                match self.table_columns(from, namespaces) {
+                    Some(columns) => columns,
+                    None => return Some((from.clone(), String::new())),
+                }
+            } else {
+                protected_columns
+            };
+            for other_from in &other_froms {
+                let column = match other.table_columns(other_from, namespaces) {
+                    Some(columns) => protected_columns
+                        .iter()
+                        .find(|column| columns.contains(column)),
+                    None => protected_columns.first(),
+                };
+                if let Some(column) = column {
+                    return Some((from.clone(), column.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    // table_columns returns the known columns of the given table. unqualified table name is
+    // looked up with the namespaces as well.
+    fn table_columns(&self, table_name: &String, namespaces: &[String]) -> Option<&Vec<String>> {
+        if let Some(columns) = self.table_info.get(table_name) {
+            if !columns.is_empty() {
+                return Some(columns);
+            }
+        }
+        namespaces
+            .iter()
+            .filter_map(|ns| self.table_info.get(&format!("{}.{}", ns, table_name)))
+            .find(|columns| !columns.is_empty())
+    }
+
     // overwrite_table_info will rewrite the src table name with the given alias name.
     pub fn overwrite_table_info(&mut self, table_name: &String, alias: String) {
+        if let Some(types) = self.column_types.get(table_name) {
+            self.column_types.insert(alias.clone(), types.clone());
+        }
         if let Some(columns) = self.table_info.get(table_name) {
             self.table_info.insert(alias, columns.clone());
             return;
@@ -196,4 +403,14 @@ impl Ctx {
     pub fn add_from_src(&mut self, table_name: String) {
         self.from.insert(table_name);
     }
+
+    // add_row_filter adds the row predicate of the table to the current selection.
+    pub fn add_row_filter(&mut self, filter: Expr) {
+        self.row_filters.push(filter);
+    }
+
+    // take_row_filters returns the row predicates of the current selection.
+    pub fn take_row_filters(&mut self) -> Vec<Expr> {
+        std::mem::take(&mut self.row_filters)
+    }
 }
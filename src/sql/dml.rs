@@ -0,0 +1,460 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::sql::copy::{expected, join, parse_word};
+use sqlparser::ast::{
+    Assignment, Expr, Ident, ObjectName, SelectItem, Statement, TableAlias, TableFactor,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::{IsOptional, Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::fmt;
+
+/// DmlStatement is the INSERT, UPDATE, DELETE or MERGE statement with the postgres clauses
+/// that sql parser doesn't know, so the statement is parsed here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DmlStatement {
+    // Modify is INSERT, UPDATE or DELETE with ON CONFLICT or RETURNING clause. rest of the
+    // statement is parsed by the sql parser.
+    Modify {
+        statement: Statement,
+        on_conflict: Option<OnConflict>,
+        returning: Vec<SelectItem>,
+    },
+    Merge(MergeStatement),
+}
+
+/// ConflictTarget is the unique index which is checked for the conflict.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictTarget {
+    Any,
+    Columns(Vec<Ident>),
+    Constraint(ObjectName),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictAction {
+    DoNothing,
+    DoUpdate {
+        assignments: Vec<Assignment>,
+        selection: Option<Expr>,
+    },
+}
+
+/// OnConflict is the ON CONFLICT clause of the INSERT statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnConflict {
+    pub target: ConflictTarget,
+    pub action: ConflictAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeAction {
+    Update(Vec<Assignment>),
+    Delete,
+    // Insert inserts the default values if there is no values.
+    Insert {
+        columns: Vec<Ident>,
+        values: Option<Vec<Expr>>,
+    },
+    DoNothing,
+}
+
+/// MergeClause is the WHEN clause of the MERGE statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeClause {
+    pub matched: bool,
+    pub predicate: Option<Expr>,
+    pub action: MergeAction,
+}
+
+/// MergeStatement is the MERGE statement of postgres.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeStatement {
+    pub table_name: ObjectName,
+    pub alias: Option<TableAlias>,
+    pub source: TableFactor,
+    pub on: Expr,
+    pub clauses: Vec<MergeClause>,
+}
+
+impl DmlStatement {
+    // parse parses the given query as DML statement with the clauses that sql parser doesn't
+    // know. None is returned if the query is not such a statement.
+    pub fn parse(sql: &str) -> Result<Option<DmlStatement>, ParserError> {
+        let keyword = sql
+            .trim_start()
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        if !["INSERT", "UPDATE", "DELETE", "MERGE"].contains(&keyword.as_str()) {
+            return Ok(None);
+        }
+        let dialect = PostgreSqlDialect {};
+        let tokens = Tokenizer::new(&dialect, sql)
+            .tokenize()
+            .map_err(|e| ParserError::TokenizerError(e.message))?;
+        if keyword == "MERGE" {
+            let mut parser = Parser::new(tokens, &dialect);
+            let merge = parse_merge(&mut parser)?;
+            expect_end(&mut parser)?;
+            return Ok(Some(DmlStatement::Merge(merge)));
+        }
+        let position = match clause_position(&tokens) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let mut parser = Parser::new(tokens[..position].to_vec(), &dialect);
+        let statement = parser.parse_statement()?;
+        if parser.peek_token() != Token::EOF {
+            return Err(expected("end of statement", parser.peek_token()));
+        }
+        let mut parser = Parser::new(tokens[position..].to_vec(), &dialect);
+        let on_conflict = if parse_word(&mut parser, "ON") {
+            if !matches!(statement, Statement::Insert { .. }) {
+                return Err(expected("RETURNING", parser.peek_token()));
+            }
+            Some(parse_on_conflict(&mut parser)?)
+        } else {
+            None
+        };
+        let returning = if parse_word(&mut parser, "RETURNING") {
+            parser.parse_comma_separated(Parser::parse_select_item)?
+        } else {
+            vec![]
+        };
+        expect_end(&mut parser)?;
+        Ok(Some(DmlStatement::Modify {
+            statement,
+            on_conflict,
+            returning,
+        }))
+    }
+}
+
+// clause_position returns the position of the ON CONFLICT or RETURNING clause of the
+// statement. clauses of the subqueries are skipped.
+fn clause_position(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (position, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Word(word) if depth == 0 && word.quote_style.is_none() => {
+                if word.value.eq_ignore_ascii_case("RETURNING") {
+                    return Some(position);
+                }
+                let next = tokens[position + 1..]
+                    .iter()
+                    .find(|token| !matches!(token, Token::Whitespace(_)));
+                if word.value.eq_ignore_ascii_case("ON")
+                    && matches!(next, Some(Token::Word(next)) if next.quote_style.is_none()
+                        && next.value.eq_ignore_ascii_case("CONFLICT"))
+                {
+                    return Some(position);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_on_conflict(parser: &mut Parser) -> Result<OnConflict, ParserError> {
+    expect_word(parser, "CONFLICT")?;
+    let target = if parser.consume_token(&Token::LParen) {
+        let columns = parser.parse_comma_separated(Parser::parse_identifier)?;
+        parser.expect_token(&Token::RParen)?;
+        ConflictTarget::Columns(columns)
+    } else if parse_word(parser, "ON") {
+        expect_word(parser, "CONSTRAINT")?;
+        ConflictTarget::Constraint(parser.parse_object_name()?)
+    } else {
+        ConflictTarget::Any
+    };
+    expect_word(parser, "DO")?;
+    let action = if parse_word(parser, "NOTHING") {
+        ConflictAction::DoNothing
+    } else {
+        expect_word(parser, "UPDATE")?;
+        expect_word(parser, "SET")?;
+        let assignments = parser.parse_comma_separated(parse_assignment)?;
+        let selection = if parse_word(parser, "WHERE") {
+            Some(parser.parse_expr()?)
+        } else {
+            None
+        };
+        ConflictAction::DoUpdate {
+            assignments,
+            selection,
+        }
+    };
+    Ok(OnConflict { target, action })
+}
+
+fn parse_merge(parser: &mut Parser) -> Result<MergeStatement, ParserError> {
+    expect_word(parser, "MERGE")?;
+    expect_word(parser, "INTO")?;
+    let table_name = parser.parse_object_name()?;
+    let alias = if parse_word(parser, "AS") || !is_word(&parser.peek_token(), "USING") {
+        Some(TableAlias {
+            name: parser.parse_identifier()?,
+            columns: vec![],
+        })
+    } else {
+        None
+    };
+    expect_word(parser, "USING")?;
+    let source = parser.parse_table_factor()?;
+    expect_word(parser, "ON")?;
+    let on = parser.parse_expr()?;
+    let mut clauses = vec![];
+    while parse_word(parser, "WHEN") {
+        clauses.push(parse_merge_clause(parser)?);
+    }
+    if clauses.is_empty() {
+        return Err(expected("WHEN", parser.peek_token()));
+    }
+    Ok(MergeStatement {
+        table_name,
+        alias,
+        source,
+        on,
+        clauses,
+    })
+}
+
+fn parse_merge_clause(parser: &mut Parser) -> Result<MergeClause, ParserError> {
+    let matched = !parse_word(parser, "NOT");
+    expect_word(parser, "MATCHED")?;
+    let predicate = if parse_word(parser, "AND") {
+        Some(parser.parse_expr()?)
+    } else {
+        None
+    };
+    expect_word(parser, "THEN")?;
+    let action = if parse_word(parser, "DO") {
+        expect_word(parser, "NOTHING")?;
+        MergeAction::DoNothing
+    } else if matched && parse_word(parser, "UPDATE") {
+        expect_word(parser, "SET")?;
+        MergeAction::Update(parser.parse_comma_separated(parse_assignment)?)
+    } else if matched && parse_word(parser, "DELETE") {
+        MergeAction::Delete
+    } else if !matched && parse_word(parser, "INSERT") {
+        let columns = parser.parse_parenthesized_column_list(IsOptional::Optional)?;
+        let values = if parse_word(parser, "DEFAULT") {
+            expect_word(parser, "VALUES")?;
+            None
+        } else {
+            expect_word(parser, "VALUES")?;
+            parser.expect_token(&Token::LParen)?;
+            let values = parser.parse_comma_separated(Parser::parse_expr)?;
+            parser.expect_token(&Token::RParen)?;
+            Some(values)
+        };
+        MergeAction::Insert { columns, values }
+    } else {
+        return Err(expected("MERGE action", parser.peek_token()));
+    };
+    Ok(MergeClause {
+        matched,
+        predicate,
+        action,
+    })
+}
+
+// parse_assignment parses the assignment of a single column. eg: phone = EXCLUDED.phone
+fn parse_assignment(parser: &mut Parser) -> Result<Assignment, ParserError> {
+    let id = vec![parser.parse_identifier()?];
+    parser.expect_token(&Token::Eq)?;
+    let value = parser.parse_expr()?;
+    Ok(Assignment { id, value })
+}
+
+fn is_word(token: &Token, word: &str) -> bool {
+    matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word))
+}
+
+fn expect_word(parser: &mut Parser, word: &str) -> Result<(), ParserError> {
+    if parse_word(parser, word) {
+        return Ok(());
+    }
+    Err(expected(word, parser.peek_token()))
+}
+
+fn expect_end(parser: &mut Parser) -> Result<(), ParserError> {
+    parser.consume_token(&Token::SemiColon);
+    if parser.peek_token() != Token::EOF {
+        return Err(expected("end of statement", parser.peek_token()));
+    }
+    Ok(())
+}
+
+impl fmt::Display for DmlStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DmlStatement::Modify {
+                statement,
+                on_conflict,
+                returning,
+            } => {
+                write!(f, "{}", statement)?;
+                if let Some(on_conflict) = on_conflict {
+                    write!(f, " {}", on_conflict)?;
+                }
+                if returning.len() > 0 {
+                    write!(f, " RETURNING {}", join(returning))?;
+                }
+                Ok(())
+            }
+            DmlStatement::Merge(merge) => write!(f, "{}", merge),
+        }
+    }
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ON CONFLICT")?;
+        match &self.target {
+            ConflictTarget::Any => {}
+            ConflictTarget::Columns(columns) => write!(f, " ({})", join(columns))?,
+            ConflictTarget::Constraint(name) => write!(f, " ON CONSTRAINT {}", name)?,
+        }
+        match &self.action {
+            ConflictAction::DoNothing => write!(f, " DO NOTHING"),
+            ConflictAction::DoUpdate {
+                assignments,
+                selection,
+            } => {
+                write!(f, " DO UPDATE SET {}", join(assignments))?;
+                if let Some(selection) = selection {
+                    write!(f, " WHERE {}", selection)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for MergeStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MERGE INTO {}", self.table_name)?;
+        if let Some(alias) = &self.alias {
+            write!(f, " AS {}", alias)?;
+        }
+        write!(f, " USING {} ON {}", self.source, self.on)?;
+        for clause in &self.clauses {
+            write!(f, " {}", clause)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MergeClause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.matched {
+            write!(f, "WHEN MATCHED")?;
+        } else {
+            write!(f, "WHEN NOT MATCHED")?;
+        }
+        if let Some(predicate) = &self.predicate {
+            write!(f, " AND {}", predicate)?;
+        }
+        match &self.action {
+            MergeAction::Update(assignments) => write!(f, " THEN UPDATE SET {}", join(assignments)),
+            MergeAction::Delete => write!(f, " THEN DELETE"),
+            MergeAction::Insert { columns, values } => {
+                write!(f, " THEN INSERT")?;
+                if columns.len() > 0 {
+                    write!(f, " ({})", join(columns))?;
+                }
+                match values {
+                    Some(values) => write!(f, " VALUES ({})", join(values)),
+                    None => write!(f, " DEFAULT VALUES"),
+                }
+            }
+            MergeAction::DoNothing => write!(f, " THEN DO NOTHING"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sql: &str) -> String {
+        DmlStatement::parse(sql).unwrap().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_dml_statement() {
+        // statements known to the sql parser are left to it.
+        assert!(DmlStatement::parse("SELECT * FROM kids").unwrap().is_none());
+        assert!(DmlStatement::parse("INSERT INTO kids (id) VALUES (1)")
+            .unwrap()
+            .is_none());
+        // RETURNING of the subquery is not the clause of the statement.
+        assert!(
+            DmlStatement::parse("INSERT INTO kids SELECT * FROM (SELECT 1 AS returning) AS r")
+                .unwrap()
+                .is_none()
+        );
+
+        assert_eq!(
+            parse("insert into kids (id, phone) values (1, '1') returning id, phone"),
+            "INSERT INTO kids (id, phone) VALUES (1, '1') RETURNING id, phone"
+        );
+        assert_eq!(
+            parse("INSERT INTO kids (id) VALUES (1) ON CONFLICT DO NOTHING"),
+            "INSERT INTO kids (id) VALUES (1) ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(
+            parse("INSERT INTO kids (id, name) VALUES (1, 'a') ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name WHERE kids.id > 0 RETURNING *;"),
+            "INSERT INTO kids (id, name) VALUES (1, 'a') ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name WHERE kids.id > 0 RETURNING *"
+        );
+        assert_eq!(
+            parse(
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT ON CONSTRAINT kids_pkey DO NOTHING"
+            ),
+            "INSERT INTO kids (id) VALUES (1) ON CONFLICT ON CONSTRAINT kids_pkey DO NOTHING"
+        );
+        assert_eq!(
+            parse("UPDATE kids SET name = 'a' WHERE id = 1 RETURNING phone AS p"),
+            "UPDATE kids SET name = 'a' WHERE id = 1 RETURNING phone AS p"
+        );
+        assert_eq!(
+            parse("DELETE FROM kids RETURNING *"),
+            "DELETE FROM kids RETURNING *"
+        );
+        assert_eq!(
+            parse("merge into kids k using audit a on k.id = a.id when matched and a.deleted then delete when matched then update set name = a.name when not matched then insert (id, name) values (a.id, a.name)"),
+            "MERGE INTO kids AS k USING audit AS a ON k.id = a.id WHEN MATCHED AND a.deleted THEN DELETE WHEN MATCHED THEN UPDATE SET name = a.name WHEN NOT MATCHED THEN INSERT (id, name) VALUES (a.id, a.name)"
+        );
+        assert_eq!(
+            parse("MERGE INTO kids USING (SELECT 1 AS id) AS s ON kids.id = s.id WHEN NOT MATCHED THEN INSERT DEFAULT VALUES WHEN MATCHED THEN DO NOTHING"),
+            "MERGE INTO kids USING (SELECT 1 AS id) AS s ON kids.id = s.id WHEN NOT MATCHED THEN INSERT DEFAULT VALUES WHEN MATCHED THEN DO NOTHING"
+        );
+
+        for sql in [
+            "UPDATE kids SET name = 'a' ON CONFLICT DO NOTHING",
+            "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE name = 'a'",
+            "INSERT INTO kids (id) VALUES (1) RETURNING id; SELECT 1",
+            "MERGE INTO kids USING audit ON kids.id = audit.id",
+            "MERGE INTO kids USING audit ON kids.id = audit.id WHEN NOT MATCHED THEN DELETE",
+        ] {
+            assert!(DmlStatement::parse(sql).is_err(), "{}", sql);
+        }
+    }
+}
@@ -25,6 +25,14 @@ pub enum QueryRewriterError {
     UnAuthorizedInsert,
     UnAuthorizedUpdate,
     UnAthorizedCopy,
+    UnAuthorizedCopyTarget,
+    UnAuthorizedDelete,
+    UnAuthorizedTruncate,
+    UnAuthorizedDDL,
+    UnAuthorizedGrant,
+    UnAuthorizedMaintenance,
+    UnAuthorizedStatement(String),
+    Unsupported(String),
 }
 
 impl Display for QueryRewriterError {
@@ -63,6 +71,30 @@ impl Display for QueryRewriterError {
             QueryRewriterError::UnAthorizedCopy => {
                 write!(f, "unauthorized copy")
             }
+            QueryRewriterError::UnAuthorizedCopyTarget => {
+                write!(f, "unauthorized copy to or from a server file or program")
+            }
+            QueryRewriterError::UnAuthorizedDelete => {
+                write!(f, "unauthorized delete")
+            }
+            QueryRewriterError::UnAuthorizedTruncate => {
+                write!(f, "unauthorized truncate")
+            }
+            QueryRewriterError::UnAuthorizedDDL => {
+                write!(f, "unauthorized ddl statement")
+            }
+            QueryRewriterError::UnAuthorizedGrant => {
+                write!(f, "unauthorized grant")
+            }
+            QueryRewriterError::UnAuthorizedMaintenance => {
+                write!(f, "unauthorized maintenance statement")
+            }
+            QueryRewriterError::UnAuthorizedStatement(statement) => {
+                write!(f, "unauthorized statement {}", statement)
+            }
+            QueryRewriterError::Unsupported(construct) => {
+                write!(f, "unsupported sql construct {}", construct)
+            }
         }
     }
 }
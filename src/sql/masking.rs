@@ -0,0 +1,362 @@
+// Copyright 2022 poonai
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::sql::rule_engine::parse_row_filter;
+use anyhow::anyhow;
+use sqlparser::ast::{DataType, Expr, Ident, ObjectName, Value};
+
+/// MaskingStrategy decides how the protected column is rewritten in the projection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaskingStrategy {
+    // Null rewrites the column with NULL of the column type.
+    Null,
+    // Omit removes the column from the wildcard selection. selecting the column
+    // explicitly is rejected.
+    Omit,
+    // Constant rewrites the column with the given value.
+    Constant(String),
+    // Md5 rewrites the column with the md5 hash of the value.
+    Md5,
+    // LastFour keeps only the last four characters or digits of the value.
+    LastFour,
+    // EmailDomain keeps only the domain of the email.
+    EmailDomain,
+    // Fake rewrites the column with a fixed value of the column type.
+    Fake,
+    // Reject fails the query which selects the column.
+    Reject,
+    // DataRow keeps the column as it is in the projection, since it's value is masked
+    // in the result rows. it's not a policy strategy, masked columns of the policy use it.
+    DataRow,
+}
+
+impl MaskingStrategy {
+    // from_policy parses the strategy of the policy. constant strategy carries the value
+    // after the colon. eg: constant:n/a. data_type is the information_schema data type
+    // of the column, constant value is refused if it's not a valid value of the type.
+    pub fn from_policy(
+        strategy: &str,
+        data_type: Option<&String>,
+    ) -> Result<MaskingStrategy, anyhow::Error> {
+        if let Some(value) = strategy.strip_prefix("constant:") {
+            // type of the column is unknown if the column is not visible in the
+            // information schema, so the value is used as it is.
+            if data_type.is_some() && !TypeClass::from_data_type(data_type).is_valid(value) {
+                return Err(anyhow!(
+                    "constant {} is not a valid value of the column type {}",
+                    value,
+                    data_type.unwrap()
+                ));
+            }
+            return Ok(MaskingStrategy::Constant(value.to_string()));
+        }
+        match strategy {
+            "null" => Ok(MaskingStrategy::Null),
+            "omit" => Ok(MaskingStrategy::Omit),
+            "md5" => Ok(MaskingStrategy::Md5),
+            "last4" => Ok(MaskingStrategy::LastFour),
+            "email_domain" => Ok(MaskingStrategy::EmailDomain),
+            "fake" => Ok(MaskingStrategy::Fake),
+            "reject" => Ok(MaskingStrategy::Reject),
+            _ => Err(anyhow!("unsupported masking strategy {}", strategy)),
+        }
+    }
+
+    // mask_expr returns the masked expression of the given column. data_type is the
+    // information_schema data type of the column, which is used to keep the type of
+    // the masked value same as the column. None is returned for omit and reject.
+    // functions are qualified with pg_catalog, so that they can't be shadowed by the
+    // functions of the user in the search path.
+    pub fn mask_expr(&self, column: &Expr, data_type: Option<&String>) -> Option<Expr> {
+        let class = TypeClass::from_data_type(data_type);
+        let masked = match self {
+            MaskingStrategy::Omit | MaskingStrategy::Reject => return None,
+            MaskingStrategy::DataRow => return Some(column.clone()),
+            MaskingStrategy::Null => None,
+            MaskingStrategy::Constant(value) => {
+                Some(typed_value(Value::SingleQuotedString(value.clone()), data_type))
+            }
+            MaskingStrategy::Md5 if class == TypeClass::Text => {
+                parse_masked_expr(format!("pg_catalog.md5(CAST({} AS TEXT))", column))
+            }
+            MaskingStrategy::LastFour if class == TypeClass::Text => parse_masked_expr(format!(
+                "CASE WHEN pg_catalog.length({col}) > 4 THEN pg_catalog.concat(pg_catalog.repeat('*', pg_catalog.length({col}) - 4), pg_catalog.right({col}, 4)) ELSE pg_catalog.repeat('*', pg_catalog.length({col})) END",
+                col = column
+            )),
+            // last four digits of the number are kept as number.
+            MaskingStrategy::LastFour if class == TypeClass::Integer => {
+                parse_masked_expr(format!("({} % 10000)", column))
+            }
+            MaskingStrategy::EmailDomain if class == TypeClass::Text => parse_masked_expr(
+                format!(
+                    "pg_catalog.concat('****@', pg_catalog.split_part({}, '@', 2))",
+                    column
+                ),
+            ),
+            MaskingStrategy::Fake => class
+                .fake_value()
+                .map(|value| typed_value(Value::SingleQuotedString(value.to_string()), data_type)),
+            _ => None,
+        };
+        // strategies which can't be applied on the column type fall back to NULL.
+        Some(masked.unwrap_or_else(|| typed_value(Value::Null, data_type)))
+    }
+
+    // name returns the name of the strategy used in the policy.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaskingStrategy::Null => "null",
+            MaskingStrategy::Omit => "omit",
+            MaskingStrategy::Constant(_) => "constant",
+            MaskingStrategy::Md5 => "md5",
+            MaskingStrategy::LastFour => "last4",
+            MaskingStrategy::EmailDomain => "email_domain",
+            MaskingStrategy::Fake => "fake",
+            MaskingStrategy::Reject => "reject",
+            MaskingStrategy::DataRow => "data_row",
+        }
+    }
+}
+
+// is_null_expr tells whether the masked expression is NULL, which is the case for the
+// strategies that can't be applied on the column type.
+pub fn is_null_expr(expr: &Expr) -> bool {
+    match expr {
+        Expr::Value(Value::Null) => true,
+        Expr::Cast { expr, .. } => is_null_expr(expr),
+        _ => false,
+    }
+}
+
+// TypeClass groups the information_schema data types which are masked alike.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TypeClass {
+    Text,
+    Integer,
+    Numeric,
+    Boolean,
+    Date,
+    Timestamp,
+    Time,
+    Uuid,
+    Json,
+    Bytea,
+    Other,
+}
+
+impl TypeClass {
+    fn from_data_type(data_type: Option<&String>) -> TypeClass {
+        match data_type.map(|data_type| data_type.as_str()) {
+            Some("text") | Some("character varying") | Some("character") | Some("name") => {
+                TypeClass::Text
+            }
+            Some("smallint") | Some("integer") | Some("bigint") => TypeClass::Integer,
+            Some("numeric") | Some("real") | Some("double precision") => TypeClass::Numeric,
+            Some("boolean") => TypeClass::Boolean,
+            Some("date") => TypeClass::Date,
+            Some("timestamp without time zone") | Some("timestamp with time zone") => {
+                TypeClass::Timestamp
+            }
+            Some("time without time zone") | Some("time with time zone") => TypeClass::Time,
+            Some("uuid") => TypeClass::Uuid,
+            Some("json") | Some("jsonb") => TypeClass::Json,
+            Some("bytea") => TypeClass::Bytea,
+            _ => TypeClass::Other,
+        }
+    }
+
+    fn fake_value(&self) -> Option<&'static str> {
+        match self {
+            TypeClass::Text => Some("****"),
+            TypeClass::Integer | TypeClass::Numeric => Some("0"),
+            TypeClass::Boolean => Some("false"),
+            TypeClass::Date => Some("1970-01-01"),
+            TypeClass::Timestamp => Some("1970-01-01 00:00:00"),
+            TypeClass::Time => Some("00:00:00"),
+            TypeClass::Uuid => Some("00000000-0000-0000-0000-000000000000"),
+            TypeClass::Json => Some("{}"),
+            TypeClass::Bytea => Some("\\x"),
+            TypeClass::Other => None,
+        }
+    }
+
+    // is_valid tells whether the value can be casted to the type. only the ISO format is
+    // accepted for the date and time types. value of the other types can't be validated,
+    // so it's never valid.
+    fn is_valid(&self, value: &str) -> bool {
+        let is_digits =
+            |value: &str| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit());
+        let is_time = |value: &str| {
+            let splits = value.splitn(3, ':').collect::<Vec<&str>>();
+            splits.len() == 3
+                && splits
+                    .iter()
+                    .all(|split| split.len() == 2 && is_digits(split))
+        };
+        let is_date = |value: &str| {
+            let splits = value.splitn(3, '-').collect::<Vec<&str>>();
+            splits.len() == 3
+                && splits[0].len() == 4
+                && splits[1].len() == 2
+                && splits[2].len() == 2
+                && splits.iter().all(|split| is_digits(split))
+        };
+        match self {
+            TypeClass::Text => true,
+            TypeClass::Integer => value.parse::<i64>().is_ok(),
+            TypeClass::Numeric => value.parse::<f64>().is_ok(),
+            TypeClass::Boolean => matches!(
+                value.to_lowercase().as_str(),
+                "true" | "false" | "t" | "f" | "yes" | "no" | "on" | "off" | "1" | "0"
+            ),
+            TypeClass::Date => is_date(value),
+            TypeClass::Timestamp => match value.split_once(' ') {
+                Some((date, time)) => is_date(date) && is_time(time),
+                None => is_date(value),
+            },
+            TypeClass::Time => is_time(value),
+            TypeClass::Uuid => {
+                value.len() == 36
+                    && value.char_indices().all(|(idx, c)| match idx {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_hexdigit(),
+                    })
+            }
+            TypeClass::Json => serde_json::from_str::<serde_json::Value>(value).is_ok(),
+            TypeClass::Bytea => match value.strip_prefix("\\x") {
+                Some(hex) => hex.len() % 2 == 0 && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+                None => false,
+            },
+            TypeClass::Other => false,
+        }
+    }
+}
+
+// typed_value casts the value to the column type. text types are casted to text instead
+// of the column type, so that the value is not truncated by the length of character type.
+// value is not casted only if the column type is unknown.
+fn typed_value(value: Value, data_type: Option<&String>) -> Expr {
+    let type_name = match (TypeClass::from_data_type(data_type), data_type) {
+        (_, None) => return Expr::Value(value),
+        (TypeClass::Text, _) => String::from("text"),
+        (_, Some(data_type)) => data_type.clone(),
+    };
+    Expr::Cast {
+        expr: Box::new(Expr::Value(value)),
+        data_type: DataType::Custom(ObjectName(vec![Ident::new(type_name)])),
+    }
+}
+
+fn parse_masked_expr(expr: String) -> Option<Expr> {
+    parse_row_filter(&expr).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masking_strategy() {
+        let integer = String::from("integer");
+        let inet = String::from("inet");
+        assert_eq!(
+            MaskingStrategy::from_policy("constant:n/a", None).unwrap(),
+            MaskingStrategy::Constant(String::from("n/a"))
+        );
+        assert_eq!(
+            MaskingStrategy::from_policy("last4", Some(&integer)).unwrap(),
+            MaskingStrategy::LastFour
+        );
+        assert!(MaskingStrategy::from_policy("shuffle", None).is_err());
+        // constant must be a valid value of the column type.
+        assert_eq!(
+            MaskingStrategy::from_policy("constant:-1", Some(&integer)).unwrap(),
+            MaskingStrategy::Constant(String::from("-1"))
+        );
+        assert!(MaskingStrategy::from_policy("constant:n/a", Some(&integer)).is_err());
+        assert!(MaskingStrategy::from_policy("constant:1') OR (1=1", Some(&integer)).is_err());
+        assert!(MaskingStrategy::from_policy("constant:0.0.0.0", Some(&inet)).is_err());
+        for (data_type, valid, invalid) in [
+            ("boolean", "false", "nope"),
+            ("date", "1970-01-01", "01/01/1970"),
+            (
+                "timestamp with time zone",
+                "1970-01-01 00:00:00",
+                "yesterday",
+            ),
+            ("time without time zone", "00:00:00", "noon"),
+            ("uuid", "00000000-0000-0000-0000-000000000000", "0000"),
+            ("jsonb", "{\"a\": 1}", "{a}"),
+            ("bytea", "\\x00ff", "\\x0"),
+        ] {
+            let data_type = String::from(data_type);
+            assert!(
+                MaskingStrategy::from_policy(&format!("constant:{}", valid), Some(&data_type))
+                    .is_ok()
+            );
+            assert!(MaskingStrategy::from_policy(
+                &format!("constant:{}", invalid),
+                Some(&data_type)
+            )
+            .is_err());
+        }
+
+        let column = Expr::Identifier(Ident::new("phone"));
+        let text = String::from("character varying");
+        let date = String::from("date");
+        let mask = |strategy: MaskingStrategy, data_type: Option<&String>| {
+            strategy.mask_expr(&column, data_type).unwrap().to_string()
+        };
+        assert_eq!(
+            mask(MaskingStrategy::Md5, Some(&text)),
+            "pg_catalog.md5(CAST(phone AS TEXT))"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::EmailDomain, Some(&text)),
+            "pg_catalog.concat('****@', pg_catalog.split_part(phone, '@', 2))"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::LastFour, Some(&text)),
+            "CASE WHEN pg_catalog.length(phone) > 4 THEN pg_catalog.concat(pg_catalog.repeat('*', pg_catalog.length(phone) - 4), pg_catalog.right(phone, 4)) ELSE pg_catalog.repeat('*', pg_catalog.length(phone)) END"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::Null, Some(&date)),
+            "CAST(NULL AS date)"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::Fake, Some(&date)),
+            "CAST('1970-01-01' AS date)"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::Constant(String::from("n/a")), Some(&text)),
+            "CAST('n/a' AS text)"
+        );
+        // md5 can't keep the type of the date.
+        assert_eq!(
+            mask(MaskingStrategy::Md5, Some(&date)),
+            "CAST(NULL AS date)"
+        );
+        // NULL keeps the type which is not known to the masking.
+        assert_eq!(
+            mask(MaskingStrategy::Md5, Some(&inet)),
+            "CAST(NULL AS inet)"
+        );
+        assert_eq!(
+            mask(MaskingStrategy::Fake, Some(&inet)),
+            "CAST(NULL AS inet)"
+        );
+        // column without type information is masked without cast.
+        assert_eq!(mask(MaskingStrategy::Md5, None), "NULL");
+        assert!(MaskingStrategy::Omit.mask_expr(&column, None).is_none());
+    }
+}
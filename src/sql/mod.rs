@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod copy;
 pub mod ctx;
+pub mod dml;
 pub mod error;
+pub mod masking;
 pub mod query_rewriter;
 pub mod rule_engine;
 
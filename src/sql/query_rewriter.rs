@@ -12,17 +12,54 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::sql::copy::{table_query, CopySource, CopyStatement, CopyTarget};
 use crate::sql::ctx::Ctx;
+use crate::sql::dml::{ConflictAction, DmlStatement, MergeAction, MergeStatement, OnConflict};
 use crate::sql::error::QueryRewriterError;
-use crate::sql::rule_engine::RuleEngine;
+use crate::sql::rule_engine::{ProtectedFilterAction, RuleEngine};
 use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
 use log::*;
 use sqlparser::ast::{
-    Assignment, Expr, FunctionArg, FunctionArgExpr, Ident, ObjectName, Query, Select, SelectItem,
-    SetExpr, Statement, TableFactor, TableWithJoins, TrimWhereField, Value,
+    Assignment, BinaryOperator, Expr, Function, FunctionArg, FunctionArgExpr, Ident,
+    JoinConstraint, JoinOperator, ObjectName, Query, Select, SelectItem, SetExpr, Statement,
+    TableAlias, TableFactor, TableWithJoins, TrimWhereField, Value,
 };
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::{Parser, ParserError};
+/// Rewrite is a change made to the user query by the policy. it's reported to the user,
+/// so that the masked values are not mistaken for the real ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rewrite {
+    // MaskedColumn is the protected column which is replaced with the masked value.
+    MaskedColumn {
+        table: String,
+        column: String,
+        strategy: String,
+    },
+    // OmittedColumn is the protected column which is left out of the wildcard.
+    OmittedColumn {
+        table: String,
+        column: String,
+    },
+    // NulledExpression is the selected expression which is replaced with NULL, since it
+    // refers the protected column.
+    NulledExpression {
+        alias: String,
+    },
+    // NeutralisedFilter is the filter on the protected column which is replaced with NULL.
+    NeutralisedFilter {
+        table: Option<String>,
+        column: String,
+    },
+    // RowFilter is the row predicate of the policy which is added to the statement.
+    RowFilter {
+        table: String,
+        predicate: String,
+    },
+}
+
 // QueryRewriter validates the user query and rewrites if neccessary.
 pub struct QueryRewriter<T: RuleEngine + Clone> {
     // rule engine is responsible for handling all the rules which are enforced by
@@ -31,6 +68,8 @@ pub struct QueryRewriter<T: RuleEngine + Clone> {
     rule_engine: T,
     // metrics store all the tables and it's columns name it has accesssed.
     metrics: HashMap<String, HashSet<String>>,
+    // rewrites holds the changes made to the last rewritten statement.
+    rewrites: Vec<Rewrite>,
 }
 
 impl<T: RuleEngine + Clone> QueryRewriter<T> {
@@ -40,6 +79,7 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
             rule_engine: rule_engine,
             namespaces: ns,
             metrics: Default::default(),
+            rewrites: vec![],
         };
     }
 
@@ -48,21 +88,166 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
         statement: &mut Statement,
         state: &Ctx,
     ) -> Result<HashMap<String, HashSet<String>>, QueryRewriterError> {
+        self.rewrites.clear();
+        self.rewrite_statement(statement, state)?;
+        let metrics = std::mem::replace(&mut self.metrics, HashMap::default());
+        Ok(metrics)
+    }
+
+    // rewrite_copy validates the COPY statement. rows copied to the client are read with
+    // the query, so COPY of a table is rewritten as the query of the table and the query
+    // is rewritten like any other SELECT. copy of the server files and programs is denied.
+    pub fn rewrite_copy(
+        &mut self,
+        copy: &mut CopyStatement,
+        state: &Ctx,
+    ) -> Result<HashMap<String, HashSet<String>>, QueryRewriterError> {
+        self.rewrites.clear();
+        if !self.rule_engine.is_copy_allowed() {
+            return Err(QueryRewriterError::UnAthorizedCopy);
+        }
+        match copy.target {
+            CopyTarget::File(_) | CopyTarget::Program(_) => {
+                return Err(QueryRewriterError::UnAuthorizedCopyTarget);
+            }
+            _ => {}
+        }
+        if let CopySource::Table {
+            table_name,
+            columns,
+        } = &copy.source
+        {
+            let allowed_attributes = self.rule_engine.get_allowed_copy_attributes();
+            if !self.is_operation_allowed(table_name, columns, allowed_attributes) {
+                return Err(QueryRewriterError::UnAthorizedCopy);
+            }
+            if copy.to {
+                copy.source = CopySource::Query(Box::new(table_query(table_name, columns)?));
+            }
+        }
+        if let CopySource::Query(query) = &mut copy.source {
+            self.handle_query(query, state)?;
+        }
+        let metrics = std::mem::replace(&mut self.metrics, HashMap::default());
+        Ok(metrics)
+    }
+
+    // rewrite_dml validates the DML statement with the clauses that sql parser doesn't know.
+    // RETURNING is validated like the projection of the target table. ON CONFLICT DO UPDATE
+    // and MERGE need the permission of every operation they can do.
+    pub fn rewrite_dml(
+        &mut self,
+        dml: &mut DmlStatement,
+        state: &Ctx,
+    ) -> Result<HashMap<String, HashSet<String>>, QueryRewriterError> {
+        self.rewrites.clear();
+        match dml {
+            DmlStatement::Modify {
+                statement,
+                on_conflict,
+                returning,
+            } => {
+                self.rewrite_statement(statement, state)?;
+                let (table_name, alias, selection) = match statement {
+                    Statement::Insert { table_name, .. } => (table_name.clone(), None, None),
+                    Statement::Update {
+                        table, selection, ..
+                    } => match &table.relation {
+                        TableFactor::Table { name, alias, .. } => {
+                            (name.clone(), alias.clone(), Some(selection))
+                        }
+                        _ => return Err(QueryRewriterError::UnAuthorizedUpdate),
+                    },
+                    Statement::Delete {
+                        table_name,
+                        selection,
+                    } => (table_name.clone(), None, Some(selection)),
+                    _ => {
+                        return Err(QueryRewriterError::UnAuthorizedStatement(
+                            get_statement_keyword(statement),
+                        ))
+                    }
+                };
+                if let Some(on_conflict) = on_conflict {
+                    self.handle_on_conflict(&table_name, on_conflict, state)?;
+                }
+                if returning.len() > 0 {
+                    // updated and deleted rows are returned only if they are visible to
+                    // the user.
+                    if let Some(selection) = selection {
+                        if let Some(filter) = self.get_row_filter(
+                            &table_name,
+                            alias.as_ref(),
+                            self.rule_engine.get_view_row_filters(),
+                        )? {
+                            self.add_row_filter_rewrite(&table_name, &filter);
+                            *selection = and_row_filters(selection.take(), vec![filter]);
+                        }
+                    }
+                    let mut table_state = state.clone();
+                    self.handle_table(&mut table_state, &table_name, alias.as_ref())?;
+                    let mut projection = Vec::with_capacity(returning.len());
+                    for item in returning.iter_mut() {
+                        projection.extend(self.handle_selection(&table_state, item)?);
+                    }
+                    *returning = projection;
+                }
+            }
+            DmlStatement::Merge(merge) => self.handle_merge(merge, state)?,
+        }
+        let metrics = std::mem::replace(&mut self.metrics, HashMap::default());
+        Ok(metrics)
+    }
+
+    // take_rewrites returns the changes made to the last rewritten statement.
+    pub fn take_rewrites(&mut self) -> Vec<Rewrite> {
+        std::mem::take(&mut self.rewrites)
+    }
+
+    // rewrite_statement validates the statement against the action of its statement
+    // class. statements which are not classified are denied.
+    fn rewrite_statement(
+        &mut self,
+        statement: &mut Statement,
+        state: &Ctx,
+    ) -> Result<(), QueryRewriterError> {
         match statement {
             Statement::Query(query) => {
                 self.handle_query(query, state)?;
             }
             Statement::Update {
-                table, assignments, ..
+                table,
+                assignments,
+                selection,
             } => {
                 if !self.rule_engine.is_update_allowed() {
                     return Err(QueryRewriterError::UnAuthorizedUpdate);
                 }
                 self.handle_update(&table, &assignments)?;
+                if let TableFactor::Table { name, alias, .. } = &table.relation {
+                    // assigned values and where clause can't refer the protected columns.
+                    let mut table_state = state.clone();
+                    self.handle_table(&mut table_state, name, alias.as_ref())?;
+                    for assignment in assignments.iter_mut() {
+                        self.handle_filter_expr(&table_state, &mut assignment.value)?;
+                    }
+                    if let Some(selection) = selection.as_mut() {
+                        self.handle_filter_expr(&table_state, selection)?;
+                    }
+                    if let Some(filter) = self.get_row_filter(
+                        name,
+                        alias.as_ref(),
+                        self.rule_engine.get_update_row_filters(),
+                    )? {
+                        self.add_row_filter_rewrite(name, &filter);
+                        *selection = and_row_filters(selection.take(), vec![filter]);
+                    }
+                }
             }
             Statement::Insert {
                 columns,
                 table_name,
+                source,
                 ..
             } => {
                 if !self.rule_engine.is_insert_allowed() {
@@ -72,6 +257,9 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
                 if !self.is_operation_allowed(&table_name, &columns, allowed_attributes) {
                     return Err(QueryRewriterError::UnAuthorizedInsert);
                 }
+                // source query can read the protected columns.
+                // eg: INSERT INTO public_kids SELECT phone FROM kids
+                self.handle_query(source, state)?;
             }
             Statement::Copy {
                 table_name,
@@ -86,10 +274,96 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
                     return Err(QueryRewriterError::UnAthorizedCopy);
                 }
             }
-            _ => {}
+            Statement::Delete {
+                table_name,
+                selection,
+            } => {
+                if !self.rule_engine.is_delete_allowed() {
+                    return Err(QueryRewriterError::UnAuthorizedDelete);
+                }
+                // delete is a table level operation so there is no columns to validate.
+                let allowed_attributes = self.rule_engine.get_allowed_delete_attributes();
+                if !self.is_operation_allowed(&table_name, &vec![], allowed_attributes) {
+                    return Err(QueryRewriterError::UnAuthorizedDelete);
+                }
+                if let Some(selection) = selection.as_mut() {
+                    let mut table_state = state.clone();
+                    self.handle_table(&mut table_state, table_name, None)?;
+                    self.handle_filter_expr(&table_state, selection)?;
+                }
+                if let Some(filter) = self.get_row_filter(
+                    table_name,
+                    None,
+                    self.rule_engine.get_delete_row_filters(),
+                )? {
+                    self.add_row_filter_rewrite(table_name, &filter);
+                    *selection = and_row_filters(selection.take(), vec![filter]);
+                }
+            }
+            Statement::Truncate { table_name, .. } => {
+                if !self.rule_engine.is_truncate_allowed() {
+                    return Err(QueryRewriterError::UnAuthorizedTruncate);
+                }
+                let allowed_attributes = self.rule_engine.get_allowed_truncate_attributes();
+                if !self.is_operation_allowed(&table_name, &vec![], allowed_attributes) {
+                    return Err(QueryRewriterError::UnAuthorizedTruncate);
+                }
+            }
+            Statement::CreateTable { .. }
+            | Statement::CreateView { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::AlterTable { .. }
+            | Statement::Drop { .. } => {
+                if !self.rule_engine.is_ddl_allowed() {
+                    return Err(QueryRewriterError::UnAuthorizedDDL);
+                }
+            }
+            Statement::Grant { .. } | Statement::Revoke { .. } => {
+                if !self.rule_engine.is_grant_allowed() {
+                    return Err(QueryRewriterError::UnAuthorizedGrant);
+                }
+            }
+            Statement::Analyze { .. } => {
+                if !self.rule_engine.is_maintenance_allowed() {
+                    return Err(QueryRewriterError::UnAuthorizedMaintenance);
+                }
+            }
+            // explain analyze executes the statement and prepared statement are executed
+            // later. so validate the inner statement.
+            Statement::Explain { statement, .. } | Statement::Prepare { statement, .. } => {
+                self.rewrite_statement(statement, state)?;
+            }
+            Statement::SetVariable { variable, .. } => {
+                // changing role or session authorization escalates the privilege of
+                // the target session. quoted variable is same as the unquoted one.
+                // eg: SET "role" TO postgres
+                // RESET ROLE and RESET SESSION AUTHORIZATION are not parsed, so they
+                // are refused as parser error.
+                let variable = variable.value.to_lowercase();
+                if is_privileged_setting(&variable) {
+                    return Err(QueryRewriterError::UnAuthorizedStatement(format!(
+                        "SET {}",
+                        variable
+                    )));
+                }
+            }
+            // session and transaction statements doesn't touch the data.
+            Statement::StartTransaction { .. }
+            | Statement::SetTransaction { .. }
+            | Statement::Commit { .. }
+            | Statement::Rollback { .. }
+            | Statement::ShowVariable { .. }
+            | Statement::Execute { .. }
+            | Statement::Deallocate { .. } => {}
+            _ => {
+                return Err(QueryRewriterError::UnAuthorizedStatement(
+                    get_statement_keyword(statement),
+                ));
+            }
         }
-        let metrics = std::mem::replace(&mut self.metrics, HashMap::default());
-        Ok(metrics)
+        Ok(())
     }
 
     pub fn is_operation_allowed(
@@ -210,7 +484,11 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
         }
         // we'll evaulate the body first because that is the data which will be retrived for the
         // subsequent query evaluation.
-        self.handle_set_expr(&mut query.body, &local_state)
+        let body_state = self.handle_set_expr(&mut query.body, &local_state)?;
+        for order_by in &mut query.order_by {
+            self.handle_filter_expr(&body_state, &mut order_by.expr)?;
+        }
+        Ok(body_state)
     }
 
     // handle_set_expr handles set exprs which are basically query, insert,
@@ -223,6 +501,15 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
         match expr {
             SetExpr::Query(query) => return self.handle_query(query, state),
             SetExpr::Select(select) => return self.handle_select(select, state),
+            SetExpr::Values(values) => {
+                // values can have subqueries. eg: INSERT INTO kids VALUES ((SELECT ...))
+                for row in &mut values.0 {
+                    for expr in row {
+                        self.handle_filter_expr(state, expr)?;
+                    }
+                }
+                Ok(state.clone())
+            }
             SetExpr::SetOperation {
                 op,
                 all: _,
@@ -237,9 +524,10 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
                 // columns.
                 Ok(right_state)
             }
-            _ => {
-                unreachable!("not handled set expr {:?}", expr);
-            }
+            _ => Err(QueryRewriterError::Unsupported(format!(
+                "set expression {}",
+                expr
+            ))),
         }
     }
 
@@ -250,20 +538,14 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
         select: &mut Select,
         state: &Ctx,
     ) -> Result<Ctx, QueryRewriterError> {
+        // select without from is still validated because the projection can refer the
+        // columns of the outer query. eg: SELECT (SELECT k.phone) FROM kids k
         let mut local_state = state.clone();
-        // select projection are not from a table so we don't need to do anythings here.
-        // TODO: I'm not convinced about the fast path.
-        if select.from.len() == 0 {
-            return Ok(local_state);
-        }
+        // row filters of the from tables are added to the where clause.
+        let mut row_filters = vec![];
         // from selection defines what all fields that are allowed for the from tables.
         for from in &mut select.from {
-            let factor_state = self.handle_table_factor(state, &mut from.relation)?;
-            local_state.merge_state(factor_state);
-            for join in &mut from.joins {
-                let factor_state = self.handle_table_factor(state, &mut join.relation)?;
-                local_state.merge_state(factor_state);
-            }
+            row_filters.extend(self.handle_table_with_joins(state, &mut local_state, from)?);
         }
         let mut projection = Vec::with_capacity(select.projection.len());
         // filter out the the allowed projection if it's wildcard. otherwise,
@@ -272,220 +554,803 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
             projection.extend(self.handle_selection(&local_state, selection)?);
         }
         select.projection = projection;
+        // protected columns can't be used to filter or group the rows. otherwise, user can
+        // infer the protected values.
+        if let Some(selection) = &mut select.selection {
+            self.handle_filter_expr(&local_state, selection)?;
+        }
+        for expr in &mut select.group_by {
+            self.handle_filter_expr(&local_state, expr)?;
+        }
+        if let Some(having) = &mut select.having {
+            self.handle_filter_expr(&local_state, having)?;
+        }
+        // row filters are added after the validation because the row filter can refer
+        // the protected columns.
+        select.selection = and_row_filters(select.selection.take(), row_filters);
         Ok(local_state)
     }
 
-    // handle_table_factor handles (FROM table). here all the possible columns allowed for the
-    // given table is decided.
-    fn handle_table_factor(
+    // handle_table_with_joins handles the from table along with it's joins and returns the
+    // row filters that can be added to the where clause. where clause drops the null
+    // extended rows of the outer joins, so the row filters of the nullable tables are
+    // added to their join constraint or the table is replaced with the filtered subquery.
+    fn handle_table_with_joins(
         &mut self,
         state: &Ctx,
-        table_factor: &mut TableFactor,
-    ) -> Result<Ctx, QueryRewriterError> {
-        let mut local_state = state.clone();
-        match table_factor {
-            TableFactor::Table {
-                name,
-                alias,
-                args: _args,
-                with_hints: _with_hints,
-            } => {
-                let mut table_name = join_indents(&name.0);
-                local_state.add_from_src(table_name.clone());
-                // before checking the rule engine. we have to check the state becauase this can be cte table
-                // or some aliased table so we have to check the state before advancing to the rule engine.
-                let protected_columns = match local_state.get_protected_columns(&table_name) {
-                    Some(cols) => Some(cols),
-                    None => {
-                        let mut cols = None;
-                        if let Some(protected_cols) =
-                            self.rule_engine.get_protected_columns(&table_name)
-                        {
-                            cols = Some(protected_cols);
-                        } else {
-                            for ns in &self.namespaces {
-                                let ns_table_name = format!("{}.{}", ns, &table_name);
-                                if let Some(columns) =
-                                    self.rule_engine.get_protected_columns(&ns_table_name)
-                                {
-                                    if columns.len() == 0 {
-                                        return Err(QueryRewriterError::UnAuthorizedColumn((
-                                            Some(table_name),
-                                            "".to_string(),
-                                        )));
-                                    }
-                                    cols = Some(columns);
-                                    table_name = ns_table_name;
-                                    break;
-                                }
-                            }
-                        }
-                        cols
+        local_state: &mut Ctx,
+        table: &mut TableWithJoins,
+    ) -> Result<Vec<Expr>, QueryRewriterError> {
+        let mut row_filters = vec![];
+        // tables before the last right or full join are null extended by it.
+        let last_outer_join = table.joins.iter().rposition(|join| {
+            matches!(
+                join.join_operator,
+                JoinOperator::RightOuter(_) | JoinOperator::FullOuter(_)
+            )
+        });
+        let mut factor_state = self.handle_table_factor(state, &mut table.relation)?;
+        let relation_filters = factor_state.take_row_filters();
+        if last_outer_join.is_some() {
+            filter_table_factor(&mut table.relation, relation_filters)?;
+        } else {
+            row_filters.extend(relation_filters);
+        }
+        local_state.merge_state(factor_state);
+        for (i, join) in table.joins.iter_mut().enumerate() {
+            let mut factor_state = self.handle_table_factor(state, &mut join.relation)?;
+            let join_filters = factor_state.take_row_filters();
+            self.handle_join(local_state, factor_state, &mut join.join_operator)?;
+            let nullable = matches!(
+                join.join_operator,
+                JoinOperator::LeftOuter(_) | JoinOperator::FullOuter(_)
+            ) || last_outer_join.map_or(false, |last| i < last);
+            match &mut join.join_operator {
+                // rows of the right side are matched only if they pass the row filters.
+                JoinOperator::LeftOuter(JoinConstraint::On(constraint))
+                | JoinOperator::Inner(JoinConstraint::On(constraint))
+                    if nullable =>
+                {
+                    if join_filters.len() > 0 {
+                        *constraint =
+                            and_row_filters(Some(constraint.clone()), join_filters).unwrap();
                     }
-                };
-                if protected_columns.is_none() {
-                    return Ok(local_state);
-                }
-                let protected_columns = protected_columns.unwrap();
-                if let Some(alias) = alias {
-                    let alias_name = alias.name.value.clone();
-                    local_state.overwrite_table_info(&table_name, alias_name.clone());
-                    table_name = alias_name;
-                } else {
-                    let from_table_name = join_indents(&name.0);
-                    local_state.overwrite_table_info(&table_name, from_table_name.clone());
-                    table_name = from_table_name;
-                }
-                local_state.memorize_protected_columns(table_name.clone(), protected_columns);
-            }
-            TableFactor::Derived {
-                subquery, alias, ..
-            } => {
-                // derived table are the subquery in the FROM clause.
-                // eg: SELECT * from (select * from premimum users limit by 10) as users;
-                if alias.is_none() {
-                    return Err(QueryRewriterError::FromNeedAlias);
-                }
-                let subquery_alias = alias.as_ref().unwrap();
-                // we have a subquery now.
-                self.handle_query(subquery, &local_state)?;
-                local_state.add_from_src(subquery_alias.name.value.clone());
-            }
-            TableFactor::NestedJoin(table) => {
-                let factor_state = self.handle_table_factor(state, &mut table.relation)?;
-                local_state.merge_state(factor_state);
-                for join in &mut table.joins {
-                    let factor_state = self.handle_table_factor(state, &mut join.relation)?;
-                    local_state.merge_state(factor_state);
                 }
-            }
-            _ => {
-                unreachable!("not handled statement {:?}", table_factor);
+                _ if nullable => filter_table_factor(&mut join.relation, join_filters)?,
+                _ => row_filters.extend(join_filters),
             }
         }
-        Ok(local_state)
+        Ok(row_filters)
     }
 
-    // handle_selection handles the selected field. This is bottom down of the evaluation, here
-    // we validate that the selected field is in the allowed columns. if not it'll throw an
-    // unauthorized error. But, query rewriter always tries to rewrite it's possible to
-    // adhere the rule.
-    fn handle_selection(
+    // handle_on_conflict validates the ON CONFLICT clause. DO UPDATE updates the existing
+    // row, so it needs the update permission of the assigned columns.
+    fn handle_on_conflict(
         &mut self,
+        table_name: &ObjectName,
+        on_conflict: &mut OnConflict,
         state: &Ctx,
-        selection: &mut SelectItem,
-    ) -> Result<Vec<SelectItem>, QueryRewriterError> {
-        match selection {
-            SelectItem::UnnamedExpr(expr) => {
-                if let Err(e) = self.handle_expr(state, expr) {
-                    match e {
-                        QueryRewriterError::RewriteExpr { alias_name } => {
-                            return Ok(vec![SelectItem::ExprWithAlias {
-                                expr: Expr::Value(Value::Null),
-                                alias: Ident {
-                                    value: alias_name,
-                                    quote_style: Some('"'),
-                                },
-                            }]);
-                        }
-                        _ => return Err(e),
+    ) -> Result<(), QueryRewriterError> {
+        let (assignments, selection) = match &mut on_conflict.action {
+            ConflictAction::DoNothing => return Ok(()),
+            ConflictAction::DoUpdate {
+                assignments,
+                selection,
+            } => (assignments, selection),
+        };
+        if !self.rule_engine.is_update_allowed() {
+            return Err(QueryRewriterError::UnAuthorizedUpdate);
+        }
+        let columns = assignments
+            .iter()
+            .flat_map(|assignment| assignment.id.clone())
+            .collect::<Vec<Ident>>();
+        let allowed_attributes = self.rule_engine.get_allowed_update_attributes();
+        if !self.is_operation_allowed(table_name, &columns, allowed_attributes) {
+            return Err(QueryRewriterError::UnAuthorizedUpdate);
+        }
+        // assigned values and the condition can refer the columns of the existing row.
+        let mut table_state = state.clone();
+        self.handle_table(&mut table_state, table_name, None)?;
+        for assignment in assignments.iter_mut() {
+            self.handle_filter_expr(&table_state, &mut assignment.value)?;
+        }
+        if let Some(selection) = selection.as_mut() {
+            self.handle_filter_expr(&table_state, selection)?;
+        }
+        if let Some(filter) =
+            self.get_row_filter(table_name, None, self.rule_engine.get_update_row_filters())?
+        {
+            self.add_row_filter_rewrite(table_name, &filter);
+            *selection = and_row_filters(selection.take(), vec![filter]);
+        }
+        Ok(())
+    }
+
+    // handle_merge validates the MERGE statement. every action of the statement needs it's
+    // permission and the join condition, predicates and values can't refer the protected
+    // columns.
+    fn handle_merge(
+        &mut self,
+        merge: &mut MergeStatement,
+        state: &Ctx,
+    ) -> Result<(), QueryRewriterError> {
+        for clause in &merge.clauses {
+            match &clause.action {
+                MergeAction::Update(assignments) => {
+                    if !self.rule_engine.is_update_allowed() {
+                        return Err(QueryRewriterError::UnAuthorizedUpdate);
+                    }
+                    let columns = assignments
+                        .iter()
+                        .flat_map(|assignment| assignment.id.clone())
+                        .collect::<Vec<Ident>>();
+                    let allowed_attributes = self.rule_engine.get_allowed_update_attributes();
+                    if !self.is_operation_allowed(&merge.table_name, &columns, allowed_attributes) {
+                        return Err(QueryRewriterError::UnAuthorizedUpdate);
                     }
                 }
-                return Ok(vec![SelectItem::UnnamedExpr(expr.clone())]);
+                MergeAction::Delete => {
+                    if !self.rule_engine.is_delete_allowed() {
+                        return Err(QueryRewriterError::UnAuthorizedDelete);
+                    }
+                    let allowed_attributes = self.rule_engine.get_allowed_delete_attributes();
+                    if !self.is_operation_allowed(&merge.table_name, &vec![], allowed_attributes) {
+                        return Err(QueryRewriterError::UnAuthorizedDelete);
+                    }
+                }
+                MergeAction::Insert { columns, .. } => {
+                    if !self.rule_engine.is_insert_allowed() {
+                        return Err(QueryRewriterError::UnAuthorizedInsert);
+                    }
+                    let allowed_attributes = self.rule_engine.get_allowed_insert_attributes();
+                    if !self.is_operation_allowed(&merge.table_name, columns, allowed_attributes) {
+                        return Err(QueryRewriterError::UnAuthorizedInsert);
+                    }
+                }
+                MergeAction::DoNothing => {}
             }
-            SelectItem::Wildcard => {
-                // for wildcard we just rewrite with all the allowed columns.
-                return Ok(state.build_allowed_column_expr(&mut self.metrics));
+        }
+        let mut local_state = state.clone();
+        self.handle_table(&mut local_state, &merge.table_name, merge.alias.as_ref())?;
+        let mut source_state = self.handle_table_factor(state, &mut merge.source)?;
+        let source_filters = source_state.take_row_filters();
+        local_state.merge_state(source_state);
+        self.handle_filter_expr(&local_state, &mut merge.on)?;
+        for clause in &mut merge.clauses {
+            if let Some(predicate) = clause.predicate.as_mut() {
+                self.handle_filter_expr(&local_state, predicate)?;
             }
-            SelectItem::ExprWithAlias { expr, alias } => {
-                if let Err(e) = self.handle_expr(state, expr) {
-                    match e {
-                        QueryRewriterError::RewriteExpr { .. } => {
-                            return Ok(vec![SelectItem::ExprWithAlias {
-                                expr: Expr::Value(Value::Null),
-                                alias: alias.clone(),
-                            }]);
-                        }
-                        _ => return Err(e),
+            match &mut clause.action {
+                MergeAction::Update(assignments) => {
+                    for assignment in assignments.iter_mut() {
+                        self.handle_filter_expr(&local_state, &mut assignment.value)?;
                     }
                 }
-                return Ok(vec![selection.clone()]);
+                MergeAction::Insert {
+                    values: Some(values),
+                    ..
+                } => {
+                    for value in values.iter_mut() {
+                        self.handle_filter_expr(&local_state, value)?;
+                    }
+                }
+                _ => {}
             }
-            SelectItem::QualifiedWildcard(object_name) => {
-                // first ident must be table.
-                let table_name = &object_name.0[0].value;
-                let selections = state.column_expr_for_table(table_name, true, &mut self.metrics);
-                if selections.len() != 0 {
-                    return Ok(selections);
+            let filters = if clause.matched {
+                // target rows are updated or deleted only if they pass the row filter of
+                // the operation.
+                let row_filters = match &clause.action {
+                    MergeAction::Update(_) => self.rule_engine.get_update_row_filters(),
+                    MergeAction::Delete => self.rule_engine.get_delete_row_filters(),
+                    _ => continue,
+                };
+                match self.get_row_filter(&merge.table_name, merge.alias.as_ref(), row_filters)? {
+                    Some(filter) => {
+                        self.add_row_filter_rewrite(&merge.table_name, &filter);
+                        vec![filter]
+                    }
+                    None => continue,
                 }
-                if let Some(properties) = self.metrics.get_mut(table_name) {
-                    properties.insert(format!("{}.*", table_name));
-                } else {
-                    let mut properties = HashSet::new();
-                    properties.insert(format!("{}.*", table_name));
-                    self.metrics.insert(table_name.clone(), properties);
+            } else {
+                // source rows hidden by the row filter never match the target, so they
+                // have to be left out of the insert as well.
+                source_filters.clone()
+            };
+            clause.predicate = and_row_filters(clause.predicate.take(), filters);
+        }
+        if source_filters.len() > 0 {
+            merge.on = and_row_filters(Some(merge.on.clone()), source_filters).unwrap();
+        }
+        Ok(())
+    }
+
+    // handle_join merges the state of the joined table and validates the join constraint.
+    // protected columns can't be used to join the tables, since the join filters the rows
+    // by the column values.
+    fn handle_join(
+        &mut self,
+        local_state: &mut Ctx,
+        factor_state: Ctx,
+        join_operator: &mut JoinOperator,
+    ) -> Result<(), QueryRewriterError> {
+        let constraint = match join_operator {
+            JoinOperator::Inner(constraint)
+            | JoinOperator::LeftOuter(constraint)
+            | JoinOperator::RightOuter(constraint)
+            | JoinOperator::FullOuter(constraint) => constraint,
+            _ => {
+                local_state.merge_state(factor_state);
+                return Ok(());
+            }
+        };
+        // natural join uses the common columns of both the sides.
+        if let JoinConstraint::Natural = constraint {
+            let protected = local_state
+                .natural_join_column(&factor_state, &self.namespaces)
+                .or_else(|| factor_state.natural_join_column(local_state, &self.namespaces));
+            if let Some((table_name, column_name)) = protected {
+                return Err(QueryRewriterError::UnAuthorizedColumn((
+                    Some(table_name),
+                    column_name,
+                )));
+            }
+        }
+        local_state.merge_state(factor_state);
+        match constraint {
+            JoinConstraint::On(constraint) => self.handle_filter_expr(local_state, constraint)?,
+            JoinConstraint::Using(columns) => {
+                // USING column can't be neutralised like the ON constraint, so it's rejected.
+                for column in columns.iter() {
+                    if !local_state.is_allowed_column_ident(&column.value, &mut self.metrics) {
+                        return Err(QueryRewriterError::UnAuthorizedColumn((
+                            local_state.get_protected_table(&column.value),
+                            column.value.clone(),
+                        )));
+                    }
                 }
-                return Ok(vec![SelectItem::QualifiedWildcard(object_name.clone())]);
             }
+            JoinConstraint::Natural | JoinConstraint::None => {}
         }
+        Ok(())
     }
-    // handle_expr will handle all the selection expr. eg:
-    // SUM(balance) or balance...
-    fn handle_expr(&mut self, state: &Ctx, expr: &mut Expr) -> Result<(), QueryRewriterError> {
+
+    // handle_filter_expr validates the expression used in the where clause, join constraint,
+    // ordering and grouping. protected columns are either rejected or neutralised based on
+    // the policy.
+    fn handle_filter_expr(
+        &mut self,
+        state: &Ctx,
+        expr: &mut Expr,
+    ) -> Result<(), QueryRewriterError> {
+        let action = self.rule_engine.get_protected_filter_action();
+        self.validate_filter_expr(state, expr, &action)
+    }
+
+    fn validate_filter_expr(
+        &mut self,
+        state: &Ctx,
+        expr: &mut Expr,
+        action: &ProtectedFilterAction,
+    ) -> Result<(), QueryRewriterError> {
         match expr {
-            Expr::Identifier(object_name) => {
-                // it's a single expression. if we have one table the we pick that.
-                if !state.is_allowed_column_ident(&object_name.value, &mut self.metrics) {
-                    // column is not allowed but it's a valid column.
-                    // so, rewriting the query to return NULL for the given column.
-                    // the main reason to do this is that, folks who uses postgres
-                    // with other analytical tools won't find any disturbance.
-                    // eg: metabase.
-                    return Err(QueryRewriterError::RewriteExpr {
-                        alias_name: object_name.value.clone(),
-                    });
+            Expr::Identifier(ident) => {
+                if !state.is_allowed_column_ident(&ident.value, &mut self.metrics) {
+                    let column = ident.value.clone();
+                    return handle_protected_filter(expr, None, column, action, &mut self.rewrites);
                 }
             }
-            Expr::CompoundIdentifier(identifiers) => {
-                let (table_name, column_name) = get_column_from_idents(&identifiers);
+            Expr::CompoundIdentifier(idents) => {
+                let (table_name, column_name) = get_column_from_idents(&idents);
                 if !state.is_allowed_column(&table_name, &column_name) {
-                    return Err(QueryRewriterError::RewriteExpr {
-                        alias_name: join_indents(&identifiers),
-                    });
+                    return handle_protected_filter(
+                        expr,
+                        Some(table_name),
+                        column_name,
+                        action,
+                        &mut self.rewrites,
+                    );
                 }
             }
-            Expr::Subquery(query) => {
-                // we don't need to do any merge of the state.
-                // because this comes as select projection.
+            Expr::Subquery(query) | Expr::Exists(query) => {
                 self.handle_query(query, state)?;
             }
-            Expr::Function(function) => {
-                // validate all the args whether it's allowed or not.
-                // for the function args we'll rewrite with NULL value if it's not allowed.
-                for arg in &mut function.args {
-                    match arg {
-                        FunctionArg::Unnamed(arg_expr) => {
-                            let expr = match arg_expr {
-                                FunctionArgExpr::Expr(expr) => expr,
-                                FunctionArgExpr::QualifiedWildcard(_) => return Ok(()),
-                                FunctionArgExpr::Wildcard => return Ok(()),
-                            };
-                            if let Err(_) = self.handle_expr(state, expr) {
-                                *expr = Expr::Value(Value::Null)
-                            }
-                        }
-                        FunctionArg::Named { name: _name, arg } => {
-                            let expr = match arg {
-                                FunctionArgExpr::Expr(expr) => expr,
-                                FunctionArgExpr::QualifiedWildcard(_) => return Ok(()),
-                                FunctionArgExpr::Wildcard => return Ok(()),
-                            };
-                            if let Err(_) = self.handle_expr(state, expr) {
-                                *expr = Expr::Value(Value::Null)
-                            }
-                        }
-                    };
-                }
+            Expr::InSubquery { expr, subquery, .. } => {
+                self.validate_filter_expr(state, expr, action)?;
+                self.handle_query(subquery, state)?;
+            }
+            Expr::BinaryOp { left, right, .. }
+            | Expr::IsDistinctFrom(left, right)
+            | Expr::IsNotDistinctFrom(left, right) => {
+                self.validate_filter_expr(state, left, action)?;
+                self.validate_filter_expr(state, right, action)?;
+            }
+            Expr::UnaryOp { expr, .. }
+            | Expr::Nested(expr)
+            | Expr::IsNull(expr)
+            | Expr::IsNotNull(expr)
+            | Expr::Cast { expr, .. }
+            | Expr::TryCast { expr, .. }
+            | Expr::Extract { expr, .. }
+            | Expr::Collate { expr, .. } => {
+                self.validate_filter_expr(state, expr, action)?;
+            }
+            Expr::InList { expr, list, .. } => {
+                self.validate_filter_expr(state, expr, action)?;
+                for item in list {
+                    self.validate_filter_expr(state, item, action)?;
+                }
+            }
+            Expr::Between {
+                expr, low, high, ..
+            } => {
+                self.validate_filter_expr(state, expr, action)?;
+                self.validate_filter_expr(state, low, action)?;
+                self.validate_filter_expr(state, high, action)?;
+            }
+            Expr::Substring {
+                expr,
+                substring_from,
+                substring_for,
+            } => {
+                self.validate_filter_expr(state, expr, action)?;
+                if let Some(from) = substring_from {
+                    self.validate_filter_expr(state, from, action)?;
+                }
+                if let Some(expr) = substring_for {
+                    self.validate_filter_expr(state, expr, action)?;
+                }
+            }
+            Expr::Trim { expr, trim_where } => {
+                self.validate_filter_expr(state, expr, action)?;
+                if let Some((_, expr)) = trim_where {
+                    self.validate_filter_expr(state, expr, action)?;
+                }
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                if let Some(operand) = operand {
+                    self.validate_filter_expr(state, operand, action)?;
+                }
+                for expr in conditions.iter_mut().chain(results.iter_mut()) {
+                    self.validate_filter_expr(state, expr, action)?;
+                }
+                if let Some(else_result) = else_result {
+                    self.validate_filter_expr(state, else_result, action)?;
+                }
+            }
+            Expr::Function(function) => {
+                check_function(function)?;
+                for arg in &mut function.args {
+                    match arg {
+                        FunctionArg::Unnamed(FunctionArgExpr::Expr(expr))
+                        | FunctionArg::Named {
+                            arg: FunctionArgExpr::Expr(expr),
+                            ..
+                        } => self.validate_filter_expr(state, expr, action)?,
+                        _ => {}
+                    }
+                }
+                if let Some(over) = &mut function.over {
+                    for expr in &mut over.partition_by {
+                        self.validate_filter_expr(state, expr, action)?;
+                    }
+                    for order_by in &mut over.order_by {
+                        self.validate_filter_expr(state, &mut order_by.expr, action)?;
+                    }
+                }
+            }
+            Expr::Tuple(exprs) => {
+                for expr in exprs {
+                    self.validate_filter_expr(state, expr, action)?;
+                }
+            }
+            Expr::MapAccess { column, .. } => {
+                self.validate_filter_expr(state, column, action)?;
+            }
+            Expr::Value(_) | Expr::TypedString { .. } => {}
+            _ => {
+                return Err(QueryRewriterError::Unsupported(format!(
+                    "expression {}",
+                    expr
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // get_row_filter returns the row filter of the given table. columns of the filter are
+    // prefixed with the alias or the table name, so that the filter is not ambiguous
+    // in joins. unqualified table name is resolved against all the schemas of the row
+    // filters and it's refused if more than one schema has the filter of the table.
+    fn get_row_filter(
+        &self,
+        table_name: &ObjectName,
+        alias: Option<&TableAlias>,
+        row_filters: &HashMap<String, Expr>,
+    ) -> Result<Option<Expr>, QueryRewriterError> {
+        if row_filters.len() == 0 {
+            return Ok(None);
+        }
+        // unquoted identifiers are case insensitive in postgres.
+        let idents = table_name
+            .0
+            .iter()
+            .map(|ident| match ident.quote_style {
+                Some(_) => ident.value.clone(),
+                None => ident.value.to_lowercase(),
+            })
+            .collect::<Vec<String>>();
+        let mut filter = None;
+        if idents.len() >= 2 {
+            let name = idents[idents.len() - 2..].join(".");
+            filter = row_filters.get(&name).cloned();
+        } else {
+            let mut matched = row_filters.iter().filter(|(name, _)| {
+                name.split_once('.')
+                    .map_or(false, |(_, table)| table == idents[0])
+            });
+            if let Some((_, expr)) = matched.next() {
+                if matched.next().is_some() {
+                    return Err(QueryRewriterError::UnAuthorizedColumn((
+                        Some(join_indents(&table_name.0)),
+                        "".to_string(),
+                    )));
+                }
+                filter = Some(expr.clone());
+            }
+        }
+        let mut filter = match filter {
+            Some(filter) => filter,
+            None => return Ok(None),
+        };
+        let qualifier = match alias {
+            Some(alias) => vec![alias.name.clone()],
+            None => table_name.0.clone(),
+        };
+        qualify_row_filter(&mut filter, &qualifier)?;
+        Ok(Some(filter))
+    }
+
+    // add_row_filter_rewrite records the row filter added to the given table.
+    fn add_row_filter_rewrite(&mut self, table_name: &ObjectName, filter: &Expr) {
+        self.rewrites.push(Rewrite::RowFilter {
+            table: join_indents(&table_name.0),
+            predicate: filter.to_string(),
+        });
+    }
+
+    // handle_table adds the given table to the state along with it's protected columns.
+    fn handle_table(
+        &self,
+        local_state: &mut Ctx,
+        name: &ObjectName,
+        alias: Option<&TableAlias>,
+    ) -> Result<(), QueryRewriterError> {
+        let mut table_name = join_indents(&name.0);
+        // aliased table can be referred only by the alias name.
+        match alias {
+            Some(alias) => local_state.add_from_src(alias.name.value.clone()),
+            None => local_state.add_from_src(table_name.clone()),
+        }
+        // before checking the rule engine. we have to check the state becauase this can be cte table
+        // or some aliased table so we have to check the state before advancing to the rule engine.
+        let protected_columns = match local_state.get_protected_columns(&table_name) {
+            Some(cols) => Some(cols),
+            None => {
+                let mut cols = None;
+                if let Some(protected_cols) = self.rule_engine.get_protected_columns(&table_name) {
+                    cols = Some(protected_cols);
+                } else {
+                    for ns in &self.namespaces {
+                        let ns_table_name = format!("{}.{}", ns, &table_name);
+                        if let Some(columns) =
+                            self.rule_engine.get_protected_columns(&ns_table_name)
+                        {
+                            if columns.len() == 0 {
+                                return Err(QueryRewriterError::UnAuthorizedColumn((
+                                    Some(table_name),
+                                    "".to_string(),
+                                )));
+                            }
+                            cols = Some(columns);
+                            table_name = ns_table_name;
+                            break;
+                        }
+                    }
+                }
+                cols
+            }
+        };
+        if protected_columns.is_none() {
+            return Ok(());
+        }
+        let protected_columns = protected_columns.unwrap();
+        let column_masks = self
+            .rule_engine
+            .get_column_masks()
+            .get(&table_name)
+            .cloned();
+        if let Some(alias) = alias {
+            let alias_name = alias.name.value.clone();
+            local_state.overwrite_table_info(&table_name, alias_name.clone());
+            table_name = alias_name;
+        } else {
+            let from_table_name = join_indents(&name.0);
+            local_state.overwrite_table_info(&table_name, from_table_name.clone());
+            table_name = from_table_name;
+        }
+        local_state.memorize_protected_columns(table_name.clone(), protected_columns);
+        if let Some(column_masks) = column_masks {
+            local_state.memorize_column_masks(table_name, column_masks);
+        }
+        Ok(())
+    }
+
+    // handle_table_factor handles (FROM table). here all the possible columns allowed for the
+    // given table is decided.
+    fn handle_table_factor(
+        &mut self,
+        state: &Ctx,
+        table_factor: &mut TableFactor,
+    ) -> Result<Ctx, QueryRewriterError> {
+        let mut local_state = state.clone();
+        match table_factor {
+            TableFactor::Table {
+                name,
+                alias,
+                args: _args,
+                with_hints: _with_hints,
+            } => {
+                if let Some(filter) = self.get_row_filter(
+                    name,
+                    alias.as_ref(),
+                    self.rule_engine.get_view_row_filters(),
+                )? {
+                    self.add_row_filter_rewrite(name, &filter);
+                    local_state.add_row_filter(filter);
+                }
+                self.handle_table(&mut local_state, name, alias.as_ref())?;
+            }
+            TableFactor::Derived {
+                lateral,
+                subquery,
+                alias,
+            } => {
+                // lateral subquery can refer the preceding from tables, which are not part
+                // of the state here.
+                if *lateral {
+                    return Err(QueryRewriterError::Unsupported(String::from(
+                        "LATERAL subquery",
+                    )));
+                }
+                // derived table are the subquery in the FROM clause.
+                // eg: SELECT * from (select * from premimum users limit by 10) as users;
+                if alias.is_none() {
+                    return Err(QueryRewriterError::FromNeedAlias);
+                }
+                let subquery_alias = alias.as_ref().unwrap();
+                // we have a subquery now.
+                self.handle_query(subquery, &local_state)?;
+                local_state.add_from_src(subquery_alias.name.value.clone());
+            }
+            TableFactor::NestedJoin(table) => {
+                for filter in self.handle_table_with_joins(state, &mut local_state, table)? {
+                    local_state.add_row_filter(filter);
+                }
+            }
+            _ => {
+                return Err(QueryRewriterError::Unsupported(format!(
+                    "table factor {}",
+                    table_factor
+                )));
+            }
+        }
+        Ok(local_state)
+    }
+
+    // handle_selection handles the selected field. This is bottom down of the evaluation, here
+    // we validate that the selected field is in the allowed columns. if not it'll throw an
+    // unauthorized error. But, query rewriter always tries to rewrite it's possible to
+    // adhere the rule.
+    fn handle_selection(
+        &mut self,
+        state: &Ctx,
+        selection: &mut SelectItem,
+    ) -> Result<Vec<SelectItem>, QueryRewriterError> {
+        match selection {
+            SelectItem::UnnamedExpr(expr) => {
+                if let Some(masked) = self.handle_masked_column(state, expr, None)? {
+                    return Ok(masked);
+                }
+                if let Err(e) = self.handle_expr(state, expr) {
+                    match e {
+                        QueryRewriterError::RewriteExpr { alias_name } => {
+                            self.rewrites.push(Rewrite::NulledExpression {
+                                alias: alias_name.clone(),
+                            });
+                            return Ok(vec![SelectItem::ExprWithAlias {
+                                expr: Expr::Value(Value::Null),
+                                alias: Ident {
+                                    value: alias_name,
+                                    quote_style: Some('"'),
+                                },
+                            }]);
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                return Ok(vec![SelectItem::UnnamedExpr(expr.clone())]);
+            }
+            SelectItem::Wildcard => {
+                // for wildcard we just rewrite with all the allowed columns.
+                return state.build_allowed_column_expr(&mut self.metrics, &mut self.rewrites);
+            }
+            SelectItem::ExprWithAlias { expr, alias } => {
+                if let Some(masked) = self.handle_masked_column(state, expr, Some(alias))? {
+                    return Ok(masked);
+                }
+                if let Err(e) = self.handle_expr(state, expr) {
+                    match e {
+                        QueryRewriterError::RewriteExpr { .. } => {
+                            self.rewrites.push(Rewrite::NulledExpression {
+                                alias: alias.value.clone(),
+                            });
+                            return Ok(vec![SelectItem::ExprWithAlias {
+                                expr: Expr::Value(Value::Null),
+                                alias: alias.clone(),
+                            }]);
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                return Ok(vec![selection.clone()]);
+            }
+            SelectItem::QualifiedWildcard(object_name) => {
+                // table name can be qualified with schema. eg: public.kids.*
+                let table_name = &join_indents(&object_name.0);
+                let selections = state.column_expr_for_table(
+                    table_name,
+                    true,
+                    &mut self.metrics,
+                    &mut self.rewrites,
+                )?;
+                if state.get_protected_columns(table_name).is_some() {
+                    return Ok(selections);
+                }
+                if let Some(properties) = self.metrics.get_mut(table_name) {
+                    properties.insert(format!("{}.*", table_name));
+                } else {
+                    let mut properties = HashSet::new();
+                    properties.insert(format!("{}.*", table_name));
+                    self.metrics.insert(table_name.clone(), properties);
+                }
+                return Ok(vec![SelectItem::QualifiedWildcard(object_name.clone())]);
+            }
+        }
+    }
+    // handle_masked_column rewrites the selected protected column with the masked expression
+    // of the column. None is returned if the expression is not a protected column.
+    fn handle_masked_column(
+        &mut self,
+        state: &Ctx,
+        expr: &Expr,
+        alias: Option<&Ident>,
+    ) -> Result<Option<Vec<SelectItem>>, QueryRewriterError> {
+        let (table_name, column_name, alias_name) = match expr {
+            Expr::Identifier(ident) => match state.get_protected_table(&ident.value) {
+                Some(table_name) => (table_name, ident.value.clone(), ident.value.clone()),
+                None => return Ok(None),
+            },
+            Expr::CompoundIdentifier(idents) => {
+                let (table_name, column_name) = get_column_from_idents(idents);
+                if state.is_allowed_column(&table_name, &column_name) {
+                    return Ok(None);
+                }
+                (table_name, column_name, join_indents(idents))
+            }
+            _ => return Ok(None),
+        };
+        // column is masked in the result rows only if it's selected as it is.
+        if state.is_row_masked(&table_name, &column_name) {
+            return Ok(Some(vec![match alias {
+                Some(alias) => SelectItem::ExprWithAlias {
+                    expr: expr.clone(),
+                    alias: alias.clone(),
+                },
+                None => SelectItem::UnnamedExpr(expr.clone()),
+            }]));
+        }
+        let alias = match alias {
+            Some(alias) => alias.clone(),
+            None => Ident {
+                value: alias_name,
+                quote_style: Some('"'),
+            },
+        };
+        match state.mask_column(&table_name, &column_name, expr, &mut self.rewrites)? {
+            Some(expr) => Ok(Some(vec![SelectItem::ExprWithAlias { expr, alias }])),
+            // omitted column can't be selected explicitly.
+            None => Err(QueryRewriterError::UnAuthorizedColumn((
+                Some(table_name),
+                column_name,
+            ))),
+        }
+    }
+
+    // handle_expr will handle all the selection expr. eg:
+    // SUM(balance) or balance...
+    fn handle_expr(&mut self, state: &Ctx, expr: &mut Expr) -> Result<(), QueryRewriterError> {
+        match expr {
+            Expr::Identifier(object_name) => {
+                // it's a single expression. if we have one table the we pick that.
+                if !state.is_allowed_column_ident(&object_name.value, &mut self.metrics) {
+                    // column is not allowed but it's a valid column.
+                    // so, rewriting the query to return NULL for the given column.
+                    // the main reason to do this is that, folks who uses postgres
+                    // with other analytical tools won't find any disturbance.
+                    // eg: metabase.
+                    return Err(QueryRewriterError::RewriteExpr {
+                        alias_name: object_name.value.clone(),
+                    });
+                }
+            }
+            Expr::CompoundIdentifier(identifiers) => {
+                let (table_name, column_name) = get_column_from_idents(&identifiers);
+                if !state.is_allowed_column(&table_name, &column_name) {
+                    return Err(QueryRewriterError::RewriteExpr {
+                        alias_name: join_indents(&identifiers),
+                    });
+                }
+            }
+            Expr::Subquery(query) => {
+                // we don't need to do any merge of the state.
+                // because this comes as select projection.
+                self.handle_query(query, state)?;
+            }
+            Expr::Function(function) => {
+                check_function(function)?;
+                // validate all the args whether it's allowed or not.
+                // for the function args we'll rewrite with NULL value if it's not allowed.
+                for arg in &mut function.args {
+                    let arg_expr = match arg {
+                        FunctionArg::Unnamed(arg_expr) => arg_expr,
+                        FunctionArg::Named { name: _name, arg } => arg,
+                    };
+                    let expr = match arg_expr {
+                        FunctionArgExpr::Expr(expr) => expr,
+                        // whole row of the protected table can't be passed to the function.
+                        // eg: SELECT row_to_json(kids.*) FROM kids
+                        FunctionArgExpr::QualifiedWildcard(object_name) => {
+                            let table_name = join_indents(&object_name.0);
+                            if state.get_protected_columns(&table_name).is_some() {
+                                return Err(QueryRewriterError::Unsupported(format!(
+                                    "function argument {}.*",
+                                    table_name
+                                )));
+                            }
+                            continue;
+                        }
+                        // eg: count(*)
+                        FunctionArgExpr::Wildcard => continue,
+                    };
+                    if let Err(_) = self.handle_expr(state, expr) {
+                        *expr = Expr::Value(Value::Null)
+                    }
+                }
+                // window partitions and orders the rows like the filters.
+                if let Some(over) = &mut function.over {
+                    for expr in &mut over.partition_by {
+                        self.handle_filter_expr(state, expr)?;
+                    }
+                    for order_by in &mut over.order_by {
+                        self.handle_filter_expr(state, &mut order_by.expr)?;
+                    }
+                }
             }
             Expr::Case {
                 operand,
@@ -623,48 +1488,336 @@ impl<T: RuleEngine + Clone> QueryRewriter<T> {
             | Expr::InList { .. }
             | Expr::InSubquery { .. }
             | Expr::Between { .. } => {
-                // these are list of expression used by where cause so we just
-                // simply don't do anything.
-                log::warn!("where clause expression executer, please report to author if you find this log. ");
+                // these are predicates used in the projection. eg: SELECT phone IS NULL FROM kids.
+                // predicate on the protected column is rewritten with NULL.
+                if let Err(_) =
+                    self.validate_filter_expr(state, expr, &ProtectedFilterAction::Reject)
+                {
+                    return Err(QueryRewriterError::RewriteExpr {
+                        alias_name: String::from("?column?"),
+                    });
+                }
             }
-            Expr::MapAccess { .. } => {
-                // map access needs to be handled.
+            Expr::MapAccess { column, .. } => {
+                self.handle_expr(state, column)?;
             }
-            Expr::TableColumnAccess { .. } => {
-                // table coulumn needs to be handled.
+            _ => {
+                return Err(QueryRewriterError::Unsupported(format!(
+                    "expression {}",
+                    expr
+                )))
             }
-            _ => unreachable!("unknown expression {} {:?}", expr, expr),
         }
         Ok(())
     }
 }
 
-/// join_indents will join the indent with dotted operation.
-pub fn join_indents(idents: &Vec<Ident>) -> String {
-    return idents
-        .iter()
-        .map(|i| i.value.clone())
-        .collect::<Vec<String>>()
-        .join(".");
+// is_privileged_setting tells whether changing the setting escalates the privilege of
+// the target session.
+fn is_privileged_setting(setting: &str) -> bool {
+    setting == "role" || setting == "session_authorization"
 }
 
-// get_column_from_idents returns the column name and table.
-pub fn get_column_from_idents(idents: &Vec<Ident>) -> (String, String) {
-    assert_eq!(idents.len() >= 2, true);
-    (
-        idents[..idents.len() - 1]
-            .iter()
-            .map(|i| i.value.clone())
-            .collect::<Vec<String>>()
-            .join("."),
-        idents[idents.len() - 1].value.clone(),
+// check_function rejects the function calls which change the privilege of the target
+// session. eg: SELECT set_config('role', 'postgres', false)
+fn check_function(function: &Function) -> Result<(), QueryRewriterError> {
+    let name = match function.name.0.last() {
+        Some(name) => name.value.to_lowercase(),
+        None => return Ok(()),
+    };
+    if name != "set_config" {
+        return Ok(());
+    }
+    match function.args.first() {
+        Some(FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(
+            Value::SingleQuotedString(setting),
+        )))) => {
+            let setting = setting.trim().to_lowercase();
+            if is_privileged_setting(&setting) {
+                return Err(QueryRewriterError::UnAuthorizedStatement(format!(
+                    "set_config {}",
+                    setting
+                )));
+            }
+            Ok(())
+        }
+        // setting which is not a literal can't be validated.
+        _ => Err(QueryRewriterError::UnAuthorizedStatement(String::from(
+            "set_config",
+        ))),
+    }
+}
+
+// handle_protected_filter rejects the filter which refers the protected column or
+// rewrites it with NULL if the policy asks to neutralise.
+fn handle_protected_filter(
+    expr: &mut Expr,
+    table_name: Option<String>,
+    column_name: String,
+    action: &ProtectedFilterAction,
+    rewrites: &mut Vec<Rewrite>,
+) -> Result<(), QueryRewriterError> {
+    match action {
+        ProtectedFilterAction::Reject => Err(QueryRewriterError::UnAuthorizedColumn((
+            table_name,
+            column_name,
+        ))),
+        ProtectedFilterAction::Neutralise => {
+            *expr = Expr::Value(Value::Null);
+            rewrites.push(Rewrite::NeutralisedFilter {
+                table: table_name,
+                column: column_name,
+            });
+            Ok(())
+        }
+    }
+}
+
+// and_row_filters adds the row filters to the given selection with AND operator.
+pub fn and_row_filters(selection: Option<Expr>, row_filters: Vec<Expr>) -> Option<Expr> {
+    // AND has higher precedence than OR so OR expressions are nested.
+    let wrap = |expr: Expr| match expr {
+        Expr::BinaryOp {
+            op: BinaryOperator::Or,
+            ..
+        } => Expr::Nested(Box::new(expr)),
+        _ => expr,
+    };
+    row_filters
+        .into_iter()
+        .fold(selection, |selection, filter| match selection {
+            Some(selection) => Some(Expr::BinaryOp {
+                left: Box::new(wrap(selection)),
+                op: BinaryOperator::And,
+                right: Box::new(wrap(filter)),
+            }),
+            None => Some(filter),
+        })
+}
+
+// filter_table_factor replaces the table with the subquery which filters the rows of the
+// table. eg: orders AS o -> (SELECT * FROM orders AS o WHERE o.region = 'eu') AS o
+fn filter_table_factor(
+    table_factor: &mut TableFactor,
+    row_filters: Vec<Expr>,
+) -> Result<(), QueryRewriterError> {
+    let filter = match and_row_filters(None, row_filters) {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    let sql = match table_factor {
+        TableFactor::Table {
+            name,
+            alias: Some(alias),
+            args,
+            ..
+        } if args.len() == 0 => format!(
+            "SELECT * FROM (SELECT * FROM {} AS {} WHERE {}) AS {}",
+            name, alias.name, filter, alias
+        ),
+        // unaliased table is referred by the table name.
+        TableFactor::Table {
+            name,
+            alias: None,
+            args,
+            ..
+        } if args.len() == 0 => format!(
+            "SELECT * FROM (SELECT * FROM {} WHERE {}) AS {}",
+            name,
+            filter,
+            name.0[name.0.len() - 1]
+        ),
+        _ => {
+            return Err(QueryRewriterError::Unsupported(format!(
+                "row filter of the outer joined table factor {}",
+                table_factor
+            )))
+        }
+    };
+    let dialect = PostgreSqlDialect {};
+    if let Some(Statement::Query(query)) = Parser::parse_sql(&dialect, &sql)?.pop() {
+        if let SetExpr::Select(mut select) = query.body {
+            if let Some(from) = select.from.pop() {
+                *table_factor = from.relation;
+                return Ok(());
+            }
+        }
+    }
+    Err(QueryRewriterError::Unsupported(format!(
+        "row filter of the outer joined table factor {}",
+        table_factor
+    )))
+}
+
+// qualify_row_filter prefixes the columns of the row filter with the given qualifier.
+// subqueries are left as it is, since their columns belong to their own tables. row
+// filter with any other expression is refused, so that no column is left unqualified.
+pub fn qualify_row_filter(expr: &mut Expr, qualifier: &Vec<Ident>) -> Result<(), ParserError> {
+    match expr {
+        Expr::Identifier(ident) => {
+            // sql value functions are parsed as identifier.
+            let value_functions = [
+                "current_user",
+                "session_user",
+                "user",
+                "current_date",
+                "current_time",
+                "current_timestamp",
+                "localtime",
+                "localtimestamp",
+            ];
+            if ident.quote_style.is_none()
+                && value_functions.contains(&ident.value.to_lowercase().as_str())
+            {
+                return Ok(());
+            }
+            let mut idents = qualifier.clone();
+            idents.push(ident.clone());
+            *expr = Expr::CompoundIdentifier(idents);
+        }
+        Expr::BinaryOp { left, right, .. }
+        | Expr::IsDistinctFrom(left, right)
+        | Expr::IsNotDistinctFrom(left, right) => {
+            qualify_row_filter(left, qualifier)?;
+            qualify_row_filter(right, qualifier)?;
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::TryCast { expr, .. }
+        | Expr::Extract { expr, .. }
+        | Expr::Collate { expr, .. }
+        | Expr::InSubquery { expr, .. } => qualify_row_filter(expr, qualifier)?,
+        Expr::InList { expr, list, .. } => {
+            qualify_row_filter(expr, qualifier)?;
+            for item in list {
+                qualify_row_filter(item, qualifier)?;
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            qualify_row_filter(expr, qualifier)?;
+            qualify_row_filter(low, qualifier)?;
+            qualify_row_filter(high, qualifier)?;
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            qualify_row_filter(expr, qualifier)?;
+            if let Some(from) = substring_from {
+                qualify_row_filter(from, qualifier)?;
+            }
+            if let Some(expr) = substring_for {
+                qualify_row_filter(expr, qualifier)?;
+            }
+        }
+        Expr::Trim { expr, trim_where } => {
+            qualify_row_filter(expr, qualifier)?;
+            if let Some((_, expr)) = trim_where {
+                qualify_row_filter(expr, qualifier)?;
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            if let Some(operand) = operand {
+                qualify_row_filter(operand, qualifier)?;
+            }
+            for expr in conditions.iter_mut().chain(results.iter_mut()) {
+                qualify_row_filter(expr, qualifier)?;
+            }
+            if let Some(else_result) = else_result {
+                qualify_row_filter(else_result, qualifier)?;
+            }
+        }
+        Expr::Function(function) => {
+            for arg in &mut function.args {
+                match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(expr))
+                    | FunctionArg::Named {
+                        arg: FunctionArgExpr::Expr(expr),
+                        ..
+                    } => qualify_row_filter(expr, qualifier)?,
+                    _ => {
+                        return Err(ParserError::ParserError(format!(
+                            "unsupported function argument in the row filter {}",
+                            function
+                        )))
+                    }
+                }
+            }
+            if function.over.is_some() {
+                return Err(ParserError::ParserError(format!(
+                    "window function in the row filter {}",
+                    function
+                )));
+            }
+        }
+        Expr::Tuple(exprs) => {
+            for expr in exprs {
+                qualify_row_filter(expr, qualifier)?;
+            }
+        }
+        Expr::Subquery(_)
+        | Expr::Exists(_)
+        | Expr::Value(_)
+        | Expr::TypedString { .. }
+        | Expr::CompoundIdentifier(_) => {}
+        _ => {
+            return Err(ParserError::ParserError(format!(
+                "unsupported expression in the row filter {}",
+                expr
+            )))
+        }
+    }
+    Ok(())
+}
+
+// get_statement_keyword returns the leading keyword of the statement. It's used to
+// report the unclassified statements without leaking the whole query.
+pub fn get_statement_keyword(statement: &Statement) -> String {
+    format!("{}", statement)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_uppercase()
+}
+
+/// join_indents will join the indent with dotted operation.
+pub fn join_indents(idents: &Vec<Ident>) -> String {
+    return idents
+        .iter()
+        .map(|i| i.value.clone())
+        .collect::<Vec<String>>()
+        .join(".");
+}
+
+// get_column_from_idents returns the column name and table.
+pub fn get_column_from_idents(idents: &Vec<Ident>) -> (String, String) {
+    assert_eq!(idents.len() >= 2, true);
+    (
+        idents[..idents.len() - 1]
+            .iter()
+            .map(|i| i.value.clone())
+            .collect::<Vec<String>>()
+            .join("."),
+        idents[idents.len() - 1].value.clone(),
     )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::sql::rule_engine::HardRuleEngine;
+    use crate::sql::masking::MaskingStrategy;
+    use crate::sql::rule_engine::{parse_row_filter, HardRuleEngine};
     use serde::Deserialize;
     use serde_json;
     use sqlparser::dialect::PostgreSqlDialect;
@@ -1261,6 +2414,195 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_insert_source() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            insert_allowed: true,
+            insert_allowed_attributes: HashMap::from([(String::from("public.audit"), vec![])]),
+            ..Default::default()
+        };
+        let state = Ctx::new(HashMap::from([(
+            String::from("public.kids"),
+            vec![String::from("phone"), String::from("id")],
+        )]));
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "INSERT INTO audit SELECT id, phone FROM kids",
+            "INSERT INTO audit SELECT id, NULL AS \"phone\" FROM kids",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state,
+            "INSERT INTO audit VALUES ((SELECT phone FROM kids LIMIT 1))",
+            "INSERT INTO audit VALUES ((SELECT NULL AS \"phone\" FROM kids LIMIT 1))",
+        );
+    }
+
+    fn rewrite_dml<T: RuleEngine + Clone>(
+        rewriter: &mut QueryRewriter<T>,
+        state: &Ctx,
+        sql: &str,
+    ) -> Result<String, QueryRewriterError> {
+        let mut dml = DmlStatement::parse(sql).unwrap().unwrap();
+        rewriter.rewrite_dml(&mut dml, state)?;
+        Ok(dml.to_string())
+    }
+
+    #[test]
+    fn test_dml() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            insert_allowed: true,
+            insert_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            delete_allowed: true,
+            delete_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            ..Default::default()
+        };
+        let state = Ctx::new(HashMap::from([(
+            String::from("public.kids"),
+            vec![String::from("phone"), String::from("id")],
+        )]));
+        let mut rewriter = QueryRewriter::new(rule_engine.clone(), vec!["public".to_string()]);
+
+        // returned protected columns are masked like the selected ones.
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id, phone) VALUES (1, '1') RETURNING id, phone"
+            ),
+            Ok(String::from(
+                "INSERT INTO kids (id, phone) VALUES (1, '1') RETURNING id, NULL AS \"phone\""
+            ))
+        );
+        assert_eq!(
+            rewrite_dml(&mut rewriter, &state, "DELETE FROM kids RETURNING *"),
+            Ok(String::from(
+                "DELETE FROM kids RETURNING NULL AS \"phone\", id"
+            ))
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO NOTHING"
+            ),
+            Ok(String::from(
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO NOTHING"
+            ))
+        );
+        // upsert updates the existing row, so it needs update permission.
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET id = EXCLUDED.id"
+            ),
+            Err(QueryRewriterError::UnAuthorizedUpdate)
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "MERGE INTO kids USING audit ON kids.id = audit.id WHEN MATCHED THEN UPDATE SET id = audit.id"
+            ),
+            Err(QueryRewriterError::UnAuthorizedUpdate)
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "UPDATE kids SET id = 1 RETURNING phone"
+            ),
+            Err(QueryRewriterError::UnAuthorizedUpdate)
+        );
+
+        let rule_engine = HardRuleEngine {
+            update_allowed: true,
+            update_allowed_attributes: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("id")],
+            )]),
+            update_row_filters: HashMap::from([(
+                String::from("public.kids"),
+                parse_row_filter("tenant_id = 'acme'").unwrap(),
+            )]),
+            view_row_filters: HashMap::from([(
+                String::from("public.audit"),
+                parse_row_filter("visible").unwrap(),
+            )]),
+            ..rule_engine
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET id = EXCLUDED.id"
+            ),
+            Ok(String::from("INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET id = EXCLUDED.id WHERE kids.tenant_id = 'acme'"))
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET phone = EXCLUDED.phone"
+            ),
+            Err(QueryRewriterError::UnAuthorizedUpdate)
+        );
+        // existing protected value can't be copied to the other column.
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "INSERT INTO kids (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET id = kids.phone"
+            ),
+            Err(QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("phone")
+            )))
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "UPDATE kids SET id = 1 RETURNING phone"
+            ),
+            Ok(String::from(
+                "UPDATE kids SET id = 1 WHERE kids.tenant_id = 'acme' RETURNING NULL AS \"phone\""
+            ))
+        );
+        // protected column of the target can't be used to match the source rows.
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "MERGE INTO kids USING audit ON kids.phone = audit.phone WHEN MATCHED THEN DELETE"
+            ),
+            Err(QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("phone")
+            )))
+        );
+        assert_eq!(
+            rewrite_dml(
+                &mut rewriter,
+                &state,
+                "MERGE INTO kids USING audit ON kids.id = audit.id WHEN MATCHED THEN UPDATE SET id = audit.id WHEN NOT MATCHED THEN INSERT (id) VALUES (audit.id)"
+            ),
+            Ok(String::from("MERGE INTO kids USING audit ON kids.id = audit.id AND audit.visible WHEN MATCHED AND kids.tenant_id = 'acme' THEN UPDATE SET id = audit.id WHEN NOT MATCHED AND audit.visible THEN INSERT (id) VALUES (audit.id)"))
+        );
+    }
+
     #[test]
     fn test_update() {
         let rule_engine = HardRuleEngine {
@@ -1314,50 +2656,970 @@ mod tests {
     }
 
     #[test]
-    fn test_protected_table() {
+    fn test_statement_class() {
         let rule_engine = HardRuleEngine {
-            protected_columns: HashMap::from([(String::from("kids"), vec![])]),
-            insert_allowed: false,
-            update_allowed: false,
+            protected_columns: HashMap::from([(String::from("kids"), vec![String::from("id")])]),
             ..Default::default()
         };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let state = Ctx::new(get_table_info());
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "DELETE FROM kids WHERE phone = '9843421696'",
+            QueryRewriterError::UnAuthorizedDelete,
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "EXPLAIN DELETE FROM kids",
+            QueryRewriterError::UnAuthorizedDelete,
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "TRUNCATE TABLE kids",
+            QueryRewriterError::UnAuthorizedTruncate,
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "DROP TABLE kids",
+            QueryRewriterError::UnAuthorizedDDL,
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SET role = 'postgres'",
+            QueryRewriterError::UnAuthorizedStatement(String::from("SET role")),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SET \"role\" TO postgres",
+            QueryRewriterError::UnAuthorizedStatement(String::from("SET role")),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SET \"session_authorization\" TO postgres",
+            QueryRewriterError::UnAuthorizedStatement(String::from("SET session_authorization")),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT set_config('ROLE', 'postgres', false)",
+            QueryRewriterError::UnAuthorizedStatement(String::from("set_config role")),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids WHERE set_config('session_authorization', 'postgres', false) IS NOT NULL",
+            QueryRewriterError::UnAuthorizedStatement(String::from(
+                "set_config session_authorization",
+            )),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT set_config(name, 'postgres', false) FROM kids",
+            QueryRewriterError::UnAuthorizedStatement(String::from("set_config")),
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT set_config('search_path', 'public', false)",
+            "SELECT set_config('search_path', 'public', false)",
+        );
+        let dialect = PostgreSqlDialect {};
+        for query in ["RESET ROLE", "RESET SESSION AUTHORIZATION"] {
+            assert!(Parser::parse_sql(&dialect, query).is_err(), "{}", query);
+        }
+        assert_rewriter(&mut rewriter, state.clone(), "BEGIN", "START TRANSACTION");
 
-        let state = Ctx::new(HashMap::from([
-            (
-                String::from("kids"),
-                vec![
-                    String::from("phone"),
-                    String::from("id"),
-                    String::from("name"),
-                    String::from("address"),
-                ],
-            ),
-            (
-                String::from("transactions"),
-                vec![
-                    String::from("merchant_name"),
-                    String::from("kid_id"),
-                    String::from("id"),
-                    String::from("amount"),
-                ],
-            ),
-        ]));
-
-        let mut rewriter = QueryRewriter::new(rule_engine.clone(), vec!["public".to_string()]);
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(String::from("kids"), vec![String::from("id")])]),
+            delete_allowed: true,
+            delete_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            ddl_allowed: true,
+            ..Default::default()
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
         assert_rewriter(
             &mut rewriter,
             state.clone(),
-            "select * from kids",
-            "SELECT NULL AS \"phone\", NULL AS \"id\", NULL AS \"name\", NULL AS \"address\" FROM kids",
+            "DELETE FROM kids WHERE phone = '9843421696'",
+            "DELETE FROM kids WHERE phone = '9843421696'",
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "DELETE FROM public.accounts",
+            QueryRewriterError::UnAuthorizedDelete,
         );
+        assert_rewriter(&mut rewriter, state, "DROP TABLE kids", "DROP TABLE kids");
+    }
 
+    #[test]
+    fn test_row_filters() {
+        let rule_engine = HardRuleEngine {
+            update_allowed: true,
+            update_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            delete_allowed: true,
+            delete_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            view_row_filters: HashMap::from([
+                (
+                    String::from("public.kids"),
+                    parse_row_filter("tenant_id = 'acme'").unwrap(),
+                ),
+                (
+                    String::from("public.orders"),
+                    parse_row_filter("region IN ('eu', 'us')").unwrap(),
+                ),
+            ]),
+            update_row_filters: HashMap::from([(
+                String::from("public.kids"),
+                parse_row_filter("tenant_id = 'acme'").unwrap(),
+            )]),
+            delete_row_filters: HashMap::from([(
+                String::from("public.kids"),
+                parse_row_filter("tenant_id = 'acme'").unwrap(),
+            )]),
+            ..Default::default()
+        };
         let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let state = Ctx::new(get_table_info());
         assert_rewriter(
             &mut rewriter,
-            state,
-            "select * from kids join transactions on transactions.kid_id = kids.id",
-            "SELECT NULL AS \"phone\", NULL AS \"id\", NULL AS \"name\", NULL AS \"address\", transactions.* FROM kids JOIN transactions ON transactions.kid_id = kids.id",
+            state.clone(),
+            "SELECT * FROM kids WHERE id = 1 OR id = 2",
+            "SELECT * FROM kids WHERE (id = 1 OR id = 2) AND kids.tenant_id = 'acme'",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM public.kids AS k LEFT JOIN orders AS o ON o.kid_id = k.id",
+            "SELECT k.id FROM public.kids AS k LEFT JOIN orders AS o ON o.kid_id = k.id AND o.region IN ('eu', 'us') WHERE k.tenant_id = 'acme'",
+        );
+        // row filters of the null extended tables are applied before the join, so that
+        // the outer join is not turned into the inner join by the where clause.
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM public.kids AS k RIGHT JOIN orders AS o ON o.kid_id = k.id",
+            "SELECT k.id FROM (SELECT * FROM public.kids AS k WHERE k.tenant_id = 'acme') AS k RIGHT JOIN orders AS o ON o.kid_id = k.id WHERE o.region IN ('eu', 'us')",
         );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM kids AS k FULL JOIN orders AS o ON o.kid_id = k.id",
+            "SELECT k.id FROM (SELECT * FROM kids AS k WHERE k.tenant_id = 'acme') AS k FULL JOIN (SELECT * FROM orders AS o WHERE o.region IN ('eu', 'us')) AS o ON o.kid_id = k.id",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM kids NATURAL LEFT JOIN orders",
+            "SELECT kids.id FROM kids NATURAL LEFT JOIN (SELECT * FROM orders WHERE orders.region IN ('eu', 'us')) AS orders WHERE kids.tenant_id = 'acme'",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM kids AS k JOIN orders AS o ON o.kid_id = k.id RIGHT JOIN parents AS p ON p.id = k.id",
+            "SELECT k.id FROM (SELECT * FROM kids AS k WHERE k.tenant_id = 'acme') AS k JOIN orders AS o ON o.kid_id = k.id AND o.region IN ('eu', 'us') RIGHT JOIN parents AS p ON p.id = k.id",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "WITH recent AS (SELECT id FROM kids) SELECT id FROM recent WHERE id IN (SELECT kid_id FROM orders)",
+            "WITH recent AS (SELECT id FROM kids WHERE kids.tenant_id = 'acme') SELECT id FROM recent WHERE id IN (SELECT kid_id FROM orders WHERE orders.region IN ('eu', 'us'))",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "UPDATE kids SET phone = '9843421696' WHERE id = 1",
+            "UPDATE kids SET phone = '9843421696' WHERE id = 1 AND kids.tenant_id = 'acme'",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state,
+            "DELETE FROM kids",
+            "DELETE FROM kids WHERE kids.tenant_id = 'acme'",
+        );
+        assert!(parse_row_filter("tenant_id = 1; DROP TABLE kids").is_err());
+        assert!(parse_row_filter("row_number() OVER (ORDER BY id) < 10").is_err());
+
+        // every column of the nested expressions is qualified.
+        let rule_engine = HardRuleEngine {
+            view_row_filters: HashMap::from([(
+                String::from("public.kids"),
+                parse_row_filter(
+                    "CASE WHEN archived THEN owner = current_user ELSE region IS DISTINCT FROM 'cn' END",
+                )
+                .unwrap(),
+            )]),
+            ..Default::default()
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            Ctx::new(get_table_info()),
+            "SELECT k.id FROM kids AS k",
+            "SELECT k.id FROM kids AS k WHERE CASE WHEN k.archived THEN k.owner = current_user ELSE k.region IS DISTINCT FROM 'cn' END",
+        );
+    }
+
+    #[test]
+    fn test_row_filter_table_name() {
+        // sales schema has only the row filter, so it's not part of the namespaces.
+        let rule_engine = HardRuleEngine {
+            view_row_filters: HashMap::from([
+                (
+                    String::from("public.kids"),
+                    parse_row_filter("tenant_id = 'acme'").unwrap(),
+                ),
+                (
+                    String::from("sales.orders"),
+                    parse_row_filter("region = 'eu'").unwrap(),
+                ),
+                (
+                    String::from("public.invoices"),
+                    parse_row_filter("region = 'eu'").unwrap(),
+                ),
+                (
+                    String::from("sales.invoices"),
+                    parse_row_filter("region = 'us'").unwrap(),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let state = Ctx::new(get_table_info());
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM orders",
+            "SELECT id FROM orders WHERE orders.region = 'eu'",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM ORDERS",
+            "SELECT id FROM ORDERS WHERE ORDERS.region = 'eu'",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM Public.KIDS AS k",
+            "SELECT id FROM Public.KIDS AS k WHERE k.tenant_id = 'acme'",
+        );
+        // quoted identifiers are case sensitive.
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM \"Orders\"",
+            "SELECT id FROM \"Orders\"",
+        );
+        // invoices has the row filter in more than one schema.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM invoices",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("invoices")),
+                String::from(""),
+            )),
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state,
+            "SELECT id FROM sales.invoices",
+            "SELECT id FROM sales.invoices WHERE sales.invoices.region = 'us'",
+        );
+    }
+
+    #[test]
+    fn test_protected_table() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(String::from("kids"), vec![])]),
+            insert_allowed: false,
+            update_allowed: false,
+            ..Default::default()
+        };
+
+        let state = Ctx::new(HashMap::from([
+            (
+                String::from("kids"),
+                vec![
+                    String::from("phone"),
+                    String::from("id"),
+                    String::from("name"),
+                    String::from("address"),
+                ],
+            ),
+            (
+                String::from("transactions"),
+                vec![
+                    String::from("merchant_name"),
+                    String::from("kid_id"),
+                    String::from("id"),
+                    String::from("amount"),
+                ],
+            ),
+        ]));
+
+        let mut rewriter = QueryRewriter::new(rule_engine.clone(), vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "select * from kids",
+            "SELECT NULL AS \"phone\", NULL AS \"id\", NULL AS \"name\", NULL AS \"address\" FROM kids",
+        );
+
+        // join condition can't refer the columns of the protected table.
+        let mut rewriter = QueryRewriter::new(rule_engine.clone(), vec!["public".to_string()]);
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "select * from kids join transactions on transactions.kid_id = kids.id",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("id"),
+            )),
+        );
+
+        let rule_engine = HardRuleEngine {
+            protected_filter_action: ProtectedFilterAction::Neutralise,
+            ..rule_engine
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state,
+            "select * from kids join transactions on transactions.kid_id = kids.id",
+            "SELECT NULL AS \"phone\", NULL AS \"id\", NULL AS \"name\", NULL AS \"address\", transactions.* FROM kids JOIN transactions ON transactions.kid_id = NULL",
+        );
+    }
+
+    #[test]
+    fn test_join_constraint() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(String::from("kids"), vec![String::from("phone")])]),
+            ..Default::default()
+        };
+        let state = Ctx::new(HashMap::from([
+            (
+                String::from("kids"),
+                vec![String::from("id"), String::from("phone")],
+            ),
+            (
+                String::from("parents"),
+                vec![String::from("id"), String::from("phone")],
+            ),
+            (
+                String::from("orders"),
+                vec![String::from("order_id"), String::from("kid_id")],
+            ),
+        ]));
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let protected_phone = |table: &str| {
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from(table)),
+                String::from("phone"),
+            ))
+        };
+
+        // USING and NATURAL join filter the rows by the protected column like ON.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM kids AS k JOIN parents AS p USING (phone)",
+            protected_phone("k"),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM kids LEFT JOIN parents USING (id, phone)",
+            protected_phone("kids"),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT parents.id FROM parents FULL JOIN kids USING (phone)",
+            protected_phone("kids"),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM kids NATURAL JOIN parents",
+            protected_phone("kids"),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT parents.id FROM parents NATURAL RIGHT JOIN kids",
+            protected_phone("kids"),
+        );
+        // columns of the derived table are not known, so it may have the protected column.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM kids NATURAL JOIN (SELECT '1' AS phone) AS p",
+            protected_phone("kids"),
+        );
+        // joins in the parenthesis are validated as well.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM (kids JOIN parents USING (phone))",
+            protected_phone("kids"),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM (kids JOIN parents ON kids.phone = parents.phone)",
+            protected_phone("kids"),
+        );
+
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT kids.id FROM kids NATURAL JOIN orders",
+            "SELECT kids.id FROM kids NATURAL JOIN orders",
+        );
+        let dialect = PostgreSqlDialect {};
+        let mut statements =
+            Parser::parse_sql(&dialect, "SELECT kids.id FROM kids JOIN parents USING (id)")
+                .unwrap();
+        assert!(rewriter.rewrite(&mut statements[0], &state).is_ok());
+    }
+
+    #[test]
+    fn test_protected_filters() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            update_allowed: true,
+            update_allowed_attributes: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("name")],
+            )]),
+            ..Default::default()
+        };
+        let state = Ctx::new(HashMap::from([(
+            String::from("public.kids"),
+            vec![
+                String::from("phone"),
+                String::from("id"),
+                String::from("name"),
+                String::from("address"),
+            ],
+        )]));
+        let mut rewriter = QueryRewriter::new(rule_engine.clone(), vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids WHERE id = 1 ORDER BY name",
+            "SELECT id FROM kids WHERE id = 1 ORDER BY name",
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids WHERE phone = '9843421696'",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.id FROM kids AS k ORDER BY k.phone",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("k")),
+                String::from("phone"),
+            )),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT count(id) FROM kids GROUP BY phone",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids WHERE id IN (SELECT id FROM kids WHERE phone IS NOT NULL)",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "UPDATE kids SET name = phone",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT phone IS NULL FROM kids",
+            "SELECT NULL AS \"?column?\" FROM kids",
+        );
+
+        let rule_engine = HardRuleEngine {
+            protected_filter_action: ProtectedFilterAction::Neutralise,
+            ..rule_engine
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state,
+            "SELECT id FROM kids WHERE phone = '9843421696' ORDER BY phone",
+            "SELECT id FROM kids WHERE NULL = '9843421696' ORDER BY NULL",
+        );
+    }
+
+    #[test]
+    fn test_row_masked_columns() {
+        // phone is masked in the result rows, which is possible only if it's selected
+        // as it is.
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([
+                (String::from("public.kids"), vec![String::from("phone")]),
+                (String::from("public.kids_view"), vec![]),
+            ]),
+            column_masks: HashMap::from([(
+                String::from("public.kids"),
+                HashMap::from([(String::from("phone"), MaskingStrategy::DataRow)]),
+            )]),
+            ..Default::default()
+        };
+        let state = Ctx::new(HashMap::from([
+            (
+                String::from("public.kids"),
+                vec![String::from("id"), String::from("phone")],
+            ),
+            (
+                String::from("public.kids_view"),
+                vec![String::from("id"), String::from("phone")],
+            ),
+        ]));
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT phone, k.phone AS p FROM kids AS k",
+            "SELECT phone, k.phone AS p FROM kids AS k",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT * FROM kids",
+            "SELECT id, kids.phone AS \"phone\" FROM kids",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT phone || '' FROM kids",
+            "SELECT NULL || '' FROM kids",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT phone::text FROM kids",
+            "SELECT NULL AS \"phone\" FROM kids",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT lower(phone), row_to_json(kids) FROM kids",
+            "SELECT lower(NULL), row_to_json(NULL) FROM kids",
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids WHERE phone = '1'",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        // view of the masked table is protected entirely.
+        assert_error(
+            &mut rewriter,
+            state,
+            "SELECT phone FROM kids_view",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids_view")),
+                String::from(""),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_column_masks() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![
+                    String::from("phone"),
+                    String::from("name"),
+                    String::from("address"),
+                ],
+            )]),
+            column_masks: HashMap::from([(
+                String::from("public.kids"),
+                HashMap::from([
+                    (String::from("phone"), MaskingStrategy::LastFour),
+                    (String::from("name"), MaskingStrategy::Md5),
+                    (String::from("address"), MaskingStrategy::Omit),
+                ]),
+            )]),
+            ..Default::default()
+        };
+        let mut state = Ctx::new(HashMap::from([(
+            String::from("public.kids"),
+            vec![
+                String::from("id"),
+                String::from("phone"),
+                String::from("name"),
+                String::from("address"),
+            ],
+        )]));
+        state.set_column_types(HashMap::from([(
+            String::from("public.kids"),
+            HashMap::from([
+                (String::from("id"), String::from("integer")),
+                (String::from("phone"), String::from("bigint")),
+                (String::from("name"), String::from("character varying")),
+                (String::from("address"), String::from("text")),
+            ]),
+        )]));
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT * FROM kids",
+            "SELECT id, (kids.phone % 10000) AS \"phone\", pg_catalog.md5(CAST(kids.name AS TEXT)) AS \"name\" FROM kids",
+        );
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT k.name AS n, phone FROM kids AS k",
+            "SELECT pg_catalog.md5(CAST(k.name AS TEXT)) AS n, (phone % 10000) AS \"phone\" FROM kids AS k",
+        );
+        // omitted column can't be selected explicitly.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT address FROM kids",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("address"),
+            )),
+        );
+
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            column_masks: HashMap::from([(
+                String::from("public.kids"),
+                HashMap::from([(String::from("phone"), MaskingStrategy::Reject)]),
+            )]),
+            ..Default::default()
+        };
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        assert_error(
+            &mut rewriter,
+            state,
+            "SELECT * FROM kids",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("phone"),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![
+                    String::from("phone"),
+                    String::from("name"),
+                    String::from("address"),
+                ],
+            )]),
+            column_masks: HashMap::from([(
+                String::from("public.kids"),
+                HashMap::from([
+                    (String::from("phone"), MaskingStrategy::LastFour),
+                    (String::from("address"), MaskingStrategy::Omit),
+                ]),
+            )]),
+            view_row_filters: HashMap::from([(
+                String::from("public.kids"),
+                parse_row_filter("tenant_id = 'acme'").unwrap(),
+            )]),
+            protected_filter_action: ProtectedFilterAction::Neutralise,
+            ..Default::default()
+        };
+        let mut state = Ctx::new(HashMap::from([(
+            String::from("public.kids"),
+            vec![
+                String::from("id"),
+                String::from("phone"),
+                String::from("name"),
+                String::from("address"),
+            ],
+        )]));
+        state.set_column_types(HashMap::from([(
+            String::from("public.kids"),
+            HashMap::from([(String::from("phone"), String::from("bigint"))]),
+        )]));
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let dialect = PostgreSqlDialect {};
+        let mut statements =
+            Parser::parse_sql(&dialect, "SELECT * FROM kids WHERE name = 'sam'").unwrap();
+        rewriter.rewrite(&mut statements[0], &state).unwrap();
+        let mut rewrites = rewriter.take_rewrites();
+        rewrites.sort();
+        assert_eq!(
+            rewrites,
+            vec![
+                Rewrite::MaskedColumn {
+                    table: String::from("kids"),
+                    column: String::from("name"),
+                    strategy: String::from("null"),
+                },
+                Rewrite::MaskedColumn {
+                    table: String::from("kids"),
+                    column: String::from("phone"),
+                    strategy: String::from("last4"),
+                },
+                Rewrite::OmittedColumn {
+                    table: String::from("kids"),
+                    column: String::from("address"),
+                },
+                Rewrite::NeutralisedFilter {
+                    table: None,
+                    column: String::from("name"),
+                },
+                Rewrite::RowFilter {
+                    table: String::from("kids"),
+                    predicate: String::from("kids.tenant_id = 'acme'"),
+                },
+            ]
+        );
+
+        // rewrites of the previous statement are not carried over.
+        let mut statements = Parser::parse_sql(&dialect, "SELECT id FROM orders").unwrap();
+        rewriter.rewrite(&mut statements[0], &state).unwrap();
+        assert!(rewriter.take_rewrites().is_empty());
+    }
+
+    #[test]
+    fn test_copy() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            copy_allowed: true,
+            copy_allowed_attributes: HashMap::from([(String::from("public.kids"), vec![])]),
+            ..Default::default()
+        };
+        let state = Ctx::new(get_table_info());
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        let mut assert_copy = |input: &str, output: Result<&str, QueryRewriterError>| {
+            let mut copy = CopyStatement::parse(input).unwrap().unwrap();
+            let result = rewriter
+                .rewrite_copy(&mut copy, &state)
+                .map(|_| copy.to_string());
+            assert_eq!(result, output.map(|output| output.to_string()));
+        };
+        assert_copy(
+            "COPY kids (id, phone) TO STDOUT WITH CSV",
+            Ok("COPY (SELECT id, NULL AS \"phone\" FROM kids) TO STDOUT WITH (FORMAT csv)"),
+        );
+        assert_copy(
+            "COPY (SELECT id, phone FROM kids) TO STDOUT",
+            Ok("COPY (SELECT id, NULL AS \"phone\" FROM kids) TO STDOUT"),
+        );
+        assert_copy("COPY kids FROM STDIN", Ok("COPY kids FROM STDIN"));
+        assert_copy(
+            "COPY kids TO '/tmp/kids.csv'",
+            Err(QueryRewriterError::UnAuthorizedCopyTarget),
+        );
+        assert_copy(
+            "COPY kids FROM PROGRAM 'curl http://example.com/kids.csv'",
+            Err(QueryRewriterError::UnAuthorizedCopyTarget),
+        );
+        assert_copy(
+            "COPY (SELECT * FROM kids WHERE phone = '1234') TO STDOUT",
+            Err(QueryRewriterError::UnAuthorizedColumn((
+                None,
+                String::from("phone"),
+            ))),
+        );
+        // copy of the tables which are not allowed for copy.
+        assert_copy(
+            "COPY transactions TO STDOUT",
+            Err(QueryRewriterError::UnAthorizedCopy),
+        );
+    }
+
+    #[test]
+    fn test_unsupported_constructs() {
+        let rule_engine = HardRuleEngine {
+            protected_columns: HashMap::from([(
+                String::from("public.kids"),
+                vec![String::from("phone")],
+            )]),
+            ..Default::default()
+        };
+        let state = Ctx::new(get_table_info());
+        let mut rewriter = QueryRewriter::new(rule_engine, vec!["public".to_string()]);
+        // projection of the select without from is validated against the outer query.
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT (SELECT k.phone) FROM kids AS k",
+            "SELECT (SELECT NULL AS \"k.phone\") FROM kids AS k",
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT x.phone FROM kids AS k, LATERAL (SELECT k.phone) AS x",
+            QueryRewriterError::Unsupported(String::from("LATERAL subquery")),
+        );
+        // whole row of the protected table can't be passed to the function.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT row_to_json(kids.*) FROM kids",
+            QueryRewriterError::Unsupported(String::from("function argument kids.*")),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT json_agg(k.*) FROM kids AS k",
+            QueryRewriterError::Unsupported(String::from("function argument k.*")),
+        );
+        // arguments after the wildcard are still validated.
+        assert_rewriter(
+            &mut rewriter,
+            state.clone(),
+            "SELECT concat(p.*, k.phone), count(*) FROM kids AS k, parents AS p",
+            "SELECT concat(p.*, NULL), count(*) FROM kids AS k, parents AS p",
+        );
+        // window can't partition or order the rows by the protected column.
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id, row_number() OVER (PARTITION BY phone ORDER BY id) FROM kids",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id, rank() OVER (ORDER BY kids.phone) FROM kids",
+            QueryRewriterError::UnAuthorizedColumn((
+                Some(String::from("kids")),
+                String::from("phone"),
+            )),
+        );
+        assert_error(
+            &mut rewriter,
+            state.clone(),
+            "SELECT id FROM kids ORDER BY row_number() OVER (PARTITION BY phone)",
+            QueryRewriterError::UnAuthorizedColumn((None, String::from("phone"))),
+        );
+
+        // rewriter must not panic on any statement that the parser accepts.
+        let dialect = PostgreSqlDialect {};
+        let corpus = [
+            "SELECT * FROM kids",
+            "SELECT kids.* FROM kids",
+            "SELECT * FROM unknown_table",
+            "SELECT 1",
+            "SELECT (SELECT phone FROM kids LIMIT 1)",
+            "SELECT phone FROM kids WHERE (id, phone) IN (SELECT id, phone FROM kids)",
+            "SELECT * FROM kids AS k JOIN parents AS p ON k.id = p.id",
+            "SELECT * FROM kids NATURAL JOIN parents",
+            "SELECT * FROM kids JOIN parents USING (id)",
+            "SELECT * FROM kids CROSS JOIN parents",
+            "SELECT * FROM (kids JOIN parents ON kids.id = parents.id)",
+            "SELECT * FROM kids, LATERAL (SELECT kids.phone) AS x",
+            "SELECT * FROM (VALUES (1, 'a'), (2, 'b')) AS t (id, name)",
+            "VALUES (1, 2), (3, 4)",
+            "SELECT phone FROM kids UNION SELECT phone FROM kids",
+            "SELECT phone FROM kids INTERSECT SELECT phone FROM kids",
+            "SELECT phone FROM kids EXCEPT SELECT phone FROM kids",
+            "SELECT ARRAY[1, 2, 3]",
+            "SELECT phone[1] FROM kids",
+            "SELECT * FROM kids WHERE phone = ANY(ARRAY['1', '2'])",
+            "SELECT * FROM generate_series(1, 10)",
+            "SELECT * FROM unnest(ARRAY[1, 2])",
+            "WITH k AS (SELECT * FROM kids) SELECT phone FROM k",
+            "WITH RECURSIVE t (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM t) SELECT n FROM t",
+            "SELECT phone FROM kids GROUP BY ROLLUP (phone)",
+            "SELECT phone FROM kids GROUP BY GROUPING SETS ((phone), (id))",
+            "SELECT count(*) FILTER (WHERE phone IS NULL) FROM kids",
+            "SELECT phone::text FROM kids",
+            "SELECT phone COLLATE \"C\" FROM kids",
+            "SELECT EXTRACT(YEAR FROM now())",
+            "SELECT position('a' IN phone) FROM kids",
+            "SELECT listagg(phone, ',') FROM kids",
+            "SELECT phone FROM kids ORDER BY phone LIMIT 1 OFFSET 1",
+            "SELECT phone FROM kids FETCH FIRST 1 ROWS ONLY",
+            "SELECT * FROM kids FOR UPDATE",
+            "SELECT phone FROM kids WHERE EXISTS (SELECT 1 FROM parents)",
+            "SELECT phone FROM kids WHERE phone LIKE '9%'",
+            "SELECT phone FROM kids WHERE phone ILIKE '9%'",
+            "SELECT phone FROM kids WHERE phone SIMILAR TO '9%'",
+            "SELECT INTERVAL '1 day'",
+            "SELECT DATE '2022-01-01'",
+            "SELECT CASE WHEN phone IS NULL THEN 1 ELSE 2 END FROM kids",
+            "INSERT INTO kids (id) VALUES (1)",
+            "INSERT INTO kids SELECT * FROM parents",
+            "UPDATE kids SET phone = '1' WHERE id = 1",
+            "UPDATE kids SET phone = p.phone FROM parents AS p WHERE p.id = kids.id",
+            "DELETE FROM kids WHERE phone = '1'",
+            "TRUNCATE kids",
+            "COPY kids TO STDOUT",
+            "COPY kids (id, phone) FROM STDIN",
+            "CREATE TABLE t (id INT)",
+            "CREATE TABLE t AS SELECT phone FROM kids",
+            "CREATE VIEW v AS SELECT phone FROM kids",
+            "ALTER TABLE kids ADD COLUMN age INT",
+            "DROP TABLE kids",
+            "GRANT SELECT ON kids TO reporting",
+            "EXPLAIN SELECT phone FROM kids",
+            "EXPLAIN ANALYZE SELECT phone FROM kids",
+            "PREPARE q AS SELECT phone FROM kids",
+            "EXECUTE q",
+            "DEALLOCATE q",
+            "SET search_path TO public",
+            "SET ROLE admin",
+            "SHOW search_path",
+            "BEGIN",
+            "COMMIT",
+            "ROLLBACK",
+            "DECLARE c CURSOR FOR SELECT phone FROM kids",
+            "FETCH NEXT FROM c",
+            "LISTEN channel",
+            "SELECT pg_sleep(1)",
+        ];
+        for query in corpus {
+            let mut statements = match Parser::parse_sql(&dialect, query) {
+                Ok(statements) => statements,
+                Err(_) => continue,
+            };
+            for statement in &mut statements {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    rewriter.rewrite(statement, &state)
+                }));
+                assert!(result.is_ok(), "rewriter panicked for {}", query);
+            }
+        }
     }
 
     #[test]
@@ -12,8 +12,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::sql::masking::MaskingStrategy;
+use crate::sql::query_rewriter::qualify_row_filter;
+use sqlparser::ast::Expr;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
 use std::collections::HashMap;
 
+/// ProtectedFilterAction decides how the protected columns referenced in the where clause,
+/// ordering, grouping and join conditions are handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtectedFilterAction {
+    // Reject fails the query.
+    Reject,
+    // Neutralise rewrites the protected column with NULL.
+    Neutralise,
+}
+
+impl Default for ProtectedFilterAction {
+    fn default() -> Self {
+        ProtectedFilterAction::Reject
+    }
+}
+
+impl ProtectedFilterAction {
+    pub fn from_policy(action: Option<&String>) -> ProtectedFilterAction {
+        match action.map(|action| action.as_str()) {
+            Some("neutralise") => ProtectedFilterAction::Neutralise,
+            _ => ProtectedFilterAction::Reject,
+        }
+    }
+}
+
 pub trait RuleEngine {
     fn is_table_protected(&self, table_name: &String) -> bool;
     fn get_allowed_columns(&self, table_name: &String, columns: Vec<String>) -> Vec<String>;
@@ -25,6 +56,18 @@ pub trait RuleEngine {
     fn get_allowed_copy_attributes(&self) -> &HashMap<String, Vec<String>>;
     fn get_allowed_update_attributes(&self) -> &HashMap<String, Vec<String>>;
     fn is_protected_column(&self, table_name: &String, column: &String) -> bool;
+    fn is_delete_allowed(&self) -> bool;
+    fn get_allowed_delete_attributes(&self) -> &HashMap<String, Vec<String>>;
+    fn is_truncate_allowed(&self) -> bool;
+    fn get_allowed_truncate_attributes(&self) -> &HashMap<String, Vec<String>>;
+    fn is_ddl_allowed(&self) -> bool;
+    fn is_grant_allowed(&self) -> bool;
+    fn is_maintenance_allowed(&self) -> bool;
+    fn get_view_row_filters(&self) -> &HashMap<String, Expr>;
+    fn get_update_row_filters(&self) -> &HashMap<String, Expr>;
+    fn get_delete_row_filters(&self) -> &HashMap<String, Expr>;
+    fn get_protected_filter_action(&self) -> ProtectedFilterAction;
+    fn get_column_masks(&self) -> &HashMap<String, HashMap<String, MaskingStrategy>>;
 }
 
 #[derive(Debug, Default, Clone)]
@@ -38,6 +81,21 @@ pub struct HardRuleEngine {
     pub copy_allowed_attributes: HashMap<String, Vec<String>>,
     pub insert_allowed_attributes: HashMap<String, Vec<String>>,
     pub update_allowed_attributes: HashMap<String, Vec<String>>,
+    pub delete_allowed: bool,
+    pub delete_allowed_attributes: HashMap<String, Vec<String>>,
+    pub truncate_allowed: bool,
+    pub truncate_allowed_attributes: HashMap<String, Vec<String>>,
+    pub ddl_allowed: bool,
+    pub grant_allowed: bool,
+    pub maintenance_allowed: bool,
+    // row filters are the predicates of the table which are added to the
+    // where clause of the statement.
+    pub view_row_filters: HashMap<String, Expr>,
+    pub update_row_filters: HashMap<String, Expr>,
+    pub delete_row_filters: HashMap<String, Expr>,
+    pub protected_filter_action: ProtectedFilterAction,
+    // column_masks holds the masking strategy of the protected columns of the table.
+    pub column_masks: HashMap<String, HashMap<String, MaskingStrategy>>,
 }
 
 impl RuleEngine for HardRuleEngine {
@@ -109,6 +167,54 @@ impl RuleEngine for HardRuleEngine {
     fn is_copy_allowed(&self) -> bool {
         self.copy_allowed
     }
+
+    fn is_delete_allowed(&self) -> bool {
+        self.delete_allowed
+    }
+
+    fn get_allowed_delete_attributes(&self) -> &HashMap<String, Vec<String>> {
+        &self.delete_allowed_attributes
+    }
+
+    fn is_truncate_allowed(&self) -> bool {
+        self.truncate_allowed
+    }
+
+    fn get_allowed_truncate_attributes(&self) -> &HashMap<String, Vec<String>> {
+        &self.truncate_allowed_attributes
+    }
+
+    fn is_ddl_allowed(&self) -> bool {
+        self.ddl_allowed
+    }
+
+    fn is_grant_allowed(&self) -> bool {
+        self.grant_allowed
+    }
+
+    fn is_maintenance_allowed(&self) -> bool {
+        self.maintenance_allowed
+    }
+
+    fn get_view_row_filters(&self) -> &HashMap<String, Expr> {
+        &self.view_row_filters
+    }
+
+    fn get_update_row_filters(&self) -> &HashMap<String, Expr> {
+        &self.update_row_filters
+    }
+
+    fn get_delete_row_filters(&self) -> &HashMap<String, Expr> {
+        &self.delete_row_filters
+    }
+
+    fn get_protected_filter_action(&self) -> ProtectedFilterAction {
+        self.protected_filter_action.clone()
+    }
+
+    fn get_column_masks(&self) -> &HashMap<String, HashMap<String, MaskingStrategy>> {
+        &self.column_masks
+    }
 }
 
 impl HardRuleEngine {
@@ -117,13 +223,28 @@ impl HardRuleEngine {
     ) -> HardRuleEngine {
         HardRuleEngine {
             protected_columns,
-            insert_allowed: false,
-            update_allowed: false,
-            copy_allowed: false,
-            view_allowed: false,
-            copy_allowed_attributes: HashMap::default(),
-            update_allowed_attributes: HashMap::default(),
-            insert_allowed_attributes: HashMap::default(),
+            ..Default::default()
         }
     }
 }
+
+/// parse_row_filter parses the row filter predicate of the policy to sql expression.
+pub fn parse_row_filter(predicate: &str) -> Result<Expr, ParserError> {
+    let dialect = PostgreSqlDialect {};
+    let tokens = Tokenizer::new(&dialect, predicate)
+        .tokenize()
+        .map_err(|e| ParserError::TokenizerError(e.message))?;
+    let mut parser = Parser::new(tokens, &dialect);
+    let expr = parser.parse_expr()?;
+    // predicate must be a single expression.
+    if parser.peek_token() != Token::EOF {
+        return Err(ParserError::ParserError(format!(
+            "unexpected token after the row filter {}",
+            predicate
+        )));
+    }
+    // columns of the row filter are qualified with the table when it's added to the
+    // statement, so the filter with unsupported expressions is refused upfront.
+    qualify_row_filter(&mut expr.clone(), &vec![])?;
+    Ok(expr)
+}